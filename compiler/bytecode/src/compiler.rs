@@ -4,21 +4,84 @@ use moon_core::ast::{BinaryOp, Expr, Program, Stmt, UnaryOp};
 use moon_core::span::Span;
 use moon_runtime::Value;
 
-use crate::instr::{Instr, InstrKind};
+use crate::instr::{Capture, InstrKind};
 use crate::module::{FuncId, Function, Module};
 
+/// Functions the VM implements itself. They are in the module, with no code, so calls to
+/// them resolve like any other.
+pub(crate) const BUILTINS: &[&str] = &["gc"];
+
 #[derive(Debug, Clone)]
 pub struct CompileError {
     pub message: String,
     pub span: Span,
 }
 
+impl std::fmt::Display for CompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "compile error: {}", self.message)
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+/// The code of a function being compiled: its encoded instructions and their span table.
+#[derive(Debug, Default)]
+pub(crate) struct Code {
+    pub(crate) bytes: Vec<u8>,
+    pub(crate) spans: Vec<(u32, Span)>,
+}
+
+impl Code {
+    pub(crate) fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub(crate) fn emit(&mut self, kind: InstrKind, span: Span) {
+        let offset = self.bytes.len() as u32;
+        if self.spans.last().is_none_or(|(_, last)| *last != span) {
+            self.spans.push((offset, span));
+        }
+        kind.encode(&mut self.bytes);
+    }
+
+    /// Points the jump emitted at offset `at` to `target`.
+    pub(crate) fn patch_jump(&mut self, at: usize, target: usize) {
+        match InstrKind::decode(&self.bytes, at) {
+            Ok((InstrKind::Jump(_) | InstrKind::JumpIfFalse(_) | InstrKind::JumpIfTrue(_), _)) => {
+                self.bytes[at + 1..at + 5].copy_from_slice(&(target as u32).to_le_bytes());
+            }
+            _ => panic!("expected jump at {at}"),
+        }
+    }
+}
+
+/// Constant pool entries are deduplicated by value.
+#[derive(Debug, PartialEq, Eq, Hash)]
+enum ConstKey {
+    Int(i64),
+    String(String),
+    Function(String),
+}
+
+#[derive(Debug)]
+struct Local {
+    name: String,
+    slot: u16,
+    // Some closure holds an upvalue over this local, to be closed when its scope ends.
+    captured: bool,
+}
+
 #[derive(Debug, Default)]
 struct FunctionCtx {
     // Locals defined in the current function, split by lexical scopes.
-    scopes: Vec<HashSet<String>>,
-    // Names available via the current closure environment (if this function is a closure).
-    closure_env: HashSet<String>,
+    scopes: Vec<Vec<Local>>,
+    next_slot: u16,
+    max_slots: u16,
+    // Closures see the variables of the function creating them. Each one they use becomes
+    // an upvalue, numbered in order of first use.
+    is_closure: bool,
+    captures: Vec<(String, Capture)>,
 }
 
 impl FunctionCtx {
@@ -26,47 +89,94 @@ impl FunctionCtx {
         Self::default()
     }
 
-    fn new_function(params: &[String], closure_env: Vec<String>) -> Self {
+    fn new_function(params: &[String], is_closure: bool, span: Span) -> Result<Self, CompileError> {
         let mut ctx = Self {
-            scopes: vec![HashSet::new()],
-            closure_env: closure_env.into_iter().collect(),
+            scopes: vec![Vec::new()],
+            is_closure,
+            ..Self::default()
         };
         for p in params {
-            ctx.define_local(p.clone());
+            ctx.define_local(p.clone(), span)?;
         }
-        ctx
+        Ok(ctx)
     }
 
     fn push_scope(&mut self) {
-        self.scopes.push(HashSet::new());
+        self.scopes.push(Vec::new());
     }
 
-    fn pop_scope(&mut self) {
-        self.scopes.pop();
+    /// Ends the innermost scope. Returns its first slot if closures captured any of its
+    /// locals: those must be closed before the slots are reused.
+    fn pop_scope(&mut self) -> Option<u16> {
+        // The slots of the scope are free again for the next one.
+        let scope = self.scopes.pop()?;
+        self.next_slot -= scope.len() as u16;
+        if scope.iter().any(|local| local.captured) {
+            Some(self.next_slot)
+        } else {
+            None
+        }
     }
 
-    fn define_local(&mut self, name: String) {
+    fn define_local(&mut self, name: String, span: Span) -> Result<u16, CompileError> {
+        let slot = self.next_slot;
+        self.next_slot = slot.checked_add(1).ok_or_else(|| CompileError {
+            message: "too many local variables in one function".to_string(),
+            span,
+        })?;
+        self.max_slots = self.max_slots.max(self.next_slot);
         if let Some(scope) = self.scopes.last_mut() {
-            scope.insert(name);
+            scope.push(Local {
+                name,
+                slot,
+                captured: false,
+            });
         }
+        Ok(slot)
     }
 
-    fn visible_names(&self) -> Vec<String> {
-        let mut set: HashSet<String> = self.closure_env.clone();
-        for scope in &self.scopes {
-            for name in scope {
-                set.insert(name.clone());
-            }
-        }
-        let mut v: Vec<String> = set.into_iter().collect();
-        v.sort();
-        v
+    fn local(&self, name: &str) -> Option<u16> {
+        self.scopes
+            .iter()
+            .rev()
+            .flat_map(|scope| scope.iter().rev())
+            .find(|local| local.name == name)
+            .map(|local| local.slot)
     }
+
+    /// Like `local`, and records that a closure captures it.
+    fn capture_local(&mut self, name: &str) -> Option<u16> {
+        let local = self
+            .scopes
+            .iter_mut()
+            .rev()
+            .flat_map(|scope| scope.iter_mut().rev())
+            .find(|local| local.name == name)?;
+        local.captured = true;
+        Some(local.slot)
+    }
+}
+
+/// What a name refers to at the point it is used.
+enum Resolved {
+    Local(u16),
+    Upvalue(u16),
+    Global(u32),
+    Function(FuncId),
 }
 
 struct Compiler {
     functions: Vec<Function>,
     by_name: HashMap<String, FuncId>,
+    // One context per function being compiled; a closure's sits on top of its creator's.
+    ctxs: Vec<FunctionCtx>,
+    globals: Vec<String>,
+    global_ids: HashMap<String, u32>,
+    // Names some top-level `let` defines. Such a name is looked up as a global even where it
+    // also names a function, since whether the global exists yet is only known at runtime.
+    top_level_lets: HashSet<String>,
+    constants: Vec<Value>,
+    constant_ids: HashMap<ConstKey, u32>,
     next_lambda_id: usize,
 }
 
@@ -75,6 +185,12 @@ impl Compiler {
         Self {
             functions: Vec::new(),
             by_name: HashMap::new(),
+            ctxs: Vec::new(),
+            globals: Vec::new(),
+            global_ids: HashMap::new(),
+            top_level_lets: HashSet::new(),
+            constants: Vec::new(),
+            constant_ids: HashMap::new(),
             next_lambda_id: 0,
         }
     }
@@ -91,49 +207,141 @@ impl Compiler {
         self.functions.push(Function {
             name,
             params,
+            slots: 0,
             code: Vec::new(),
+            spans: Vec::new(),
         });
         id
     }
 
+    /// Index of `value` (an int, string or function) in the constant pool.
+    fn constant(&mut self, value: Value) -> u32 {
+        let key = match &value {
+            Value::Int(i) => ConstKey::Int(*i),
+            Value::String(s) => ConstKey::String(s.clone()),
+            Value::Function(name) => ConstKey::Function(name.clone()),
+            other => unreachable!("not a constant: {other:?}"),
+        };
+        *self.constant_ids.entry(key).or_insert_with(|| {
+            self.constants.push(value);
+            (self.constants.len() - 1) as u32
+        })
+    }
+
+    fn ctx(&mut self) -> &mut FunctionCtx {
+        self.ctxs.last_mut().expect("a function is being compiled")
+    }
+
+    fn global(&mut self, name: &str) -> u32 {
+        if let Some(&id) = self.global_ids.get(name) {
+            return id;
+        }
+        let id = self.globals.len() as u32;
+        self.globals.push(name.to_string());
+        self.global_ids.insert(name.to_string(), id);
+        id
+    }
+
+    /// What `name`, used at `span`, refers to.
+    fn resolve(&mut self, name: &str, span: Span) -> Result<Resolved, CompileError> {
+        let depth = self.ctxs.len() - 1;
+        if let Some(slot) = self.ctxs[depth].local(name) {
+            return Ok(Resolved::Local(slot));
+        }
+        if let Some(idx) = self.resolve_capture(depth, name, span)? {
+            return Ok(Resolved::Upvalue(idx));
+        }
+        if !self.top_level_lets.contains(name) {
+            if let Some(&id) = self.by_name.get(name) {
+                return Ok(Resolved::Function(id));
+            }
+        }
+        // Unknown names are globals too: the VM reports them if still undefined when used.
+        Ok(Resolved::Global(self.global(name)))
+    }
+
+    /// The upvalue of the closure compiled at `depth` that holds `name`, added (along with the
+    /// ones of the enclosing closures it goes through) if this is its first use.
+    fn resolve_capture(
+        &mut self,
+        depth: usize,
+        name: &str,
+        span: Span,
+    ) -> Result<Option<u16>, CompileError> {
+        let ctx = &self.ctxs[depth];
+        if let Some(idx) = ctx.captures.iter().position(|(n, _)| n == name) {
+            return operand(idx, "captured variables", span).map(Some);
+        }
+        if !ctx.is_closure || depth == 0 {
+            return Ok(None);
+        }
+        let source = match self.ctxs[depth - 1].capture_local(name) {
+            Some(slot) => Capture::Local(slot),
+            None => match self.resolve_capture(depth - 1, name, span)? {
+                Some(idx) => Capture::Upvalue(idx),
+                None => return Ok(None),
+            },
+        };
+        let captures = &mut self.ctxs[depth].captures;
+        captures.push((name.to_string(), source));
+        // `MakeClosure` stores the count as a `u16` too, so check that rather than the index.
+        let count: u16 = operand(captures.len(), "captured variables", span)?;
+        Ok(Some(count - 1))
+    }
+
+    /// Compiles `body` as function `id`, in the context on top of the stack, and pops it.
     fn compile_function_body(
         &mut self,
         id: FuncId,
         body: &Expr,
-        ctx: &mut FunctionCtx,
-    ) -> Result<(), CompileError> {
-        let mut code = Vec::new();
-        self.compile_expr(body, &mut code, ctx)?;
-        emit(&mut code, InstrKind::Return, body.span());
-        self.functions[id].code = code;
-        Ok(())
+    ) -> Result<FunctionCtx, CompileError> {
+        let mut code = Code::default();
+        self.compile_tail_expr(body, &mut code)?;
+        code.emit(InstrKind::Return, body.span());
+        let ctx = self.ctxs.pop().expect("function context");
+        self.functions[id].code = code.bytes;
+        self.functions[id].spans = code.spans;
+        self.functions[id].slots = ctx.max_slots;
+        Ok(ctx)
     }
 
-    fn compile_stmts(
-        &mut self,
-        stmts: &[Stmt],
-        code: &mut Vec<Instr>,
-        ctx: &mut FunctionCtx,
-    ) -> Result<(), CompileError> {
+    fn compile_stmts(&mut self, stmts: &[Stmt], code: &mut Code) -> Result<(), CompileError> {
         for stmt in stmts {
             match stmt {
                 Stmt::Let {
                     name, expr, span, ..
                 } => {
-                    self.compile_expr(expr, code, ctx)?;
-                    emit(code, InstrKind::DefineVar(name.clone()), *span);
-                    ctx.define_local(name.clone());
+                    self.compile_expr(expr, code)?;
+                    // Main's top-level `let`s define globals; any other `let` a local slot.
+                    if self.ctx().scopes.is_empty() {
+                        let id = self.global(name);
+                        code.emit(InstrKind::DefineGlobal(id), *span);
+                    } else {
+                        let slot = self.ctx().define_local(name.clone(), *span)?;
+                        code.emit(InstrKind::StoreLocal(slot), *span);
+                    }
                 }
                 Stmt::Assign { target, expr, span } => match target {
                     Expr::Ident(name, name_span) => {
-                        self.compile_expr(expr, code, ctx)?;
-                        emit(code, InstrKind::SetVar(name.clone()), *name_span);
+                        self.compile_expr(expr, code)?;
+                        let kind = match self.resolve(name, *name_span)? {
+                            Resolved::Local(slot) => InstrKind::StoreLocal(slot),
+                            Resolved::Upvalue(idx) => InstrKind::StoreUpvalue(idx),
+                            Resolved::Global(id) => InstrKind::StoreGlobal(id),
+                            Resolved::Function(_) => {
+                                return Err(CompileError {
+                                    message: format!("cannot assign to function {name}"),
+                                    span: *name_span,
+                                })
+                            }
+                        };
+                        code.emit(kind, *name_span);
                     }
                     Expr::Index { target, index, .. } => {
-                        self.compile_expr(target, code, ctx)?;
-                        self.compile_expr(index, code, ctx)?;
-                        self.compile_expr(expr, code, ctx)?;
-                        emit(code, InstrKind::IndexSet, *span);
+                        self.compile_expr(target, code)?;
+                        self.compile_expr(index, code)?;
+                        self.compile_expr(expr, code)?;
+                        code.emit(InstrKind::IndexSet, *span);
                     }
                     _ => {
                         return Err(CompileError {
@@ -144,145 +352,126 @@ impl Compiler {
                 },
                 Stmt::Return { expr, span } => {
                     match expr {
-                        Some(expr) => self.compile_expr(expr, code, ctx)?,
-                        None => emit(code, InstrKind::Push(Value::Unit), *span),
+                        Some(expr) => self.compile_tail_expr(expr, code)?,
+                        None => code.emit(InstrKind::Unit, *span),
                     }
-                    emit(code, InstrKind::Return, *span);
+                    code.emit(InstrKind::Return, *span);
                 }
                 Stmt::Fn { .. } => {
                     // Functions are top-level items. They don't execute in main.
                 }
                 Stmt::Expr { expr, .. } => {
-                    self.compile_expr(expr, code, ctx)?;
-                    emit(code, InstrKind::Pop, expr.span());
+                    self.compile_expr(expr, code)?;
+                    code.emit(InstrKind::Pop, expr.span());
                 }
             }
         }
         Ok(())
     }
 
-    fn compile_expr(
-        &mut self,
-        expr: &Expr,
-        code: &mut Vec<Instr>,
-        ctx: &mut FunctionCtx,
-    ) -> Result<(), CompileError> {
+    fn compile_expr(&mut self, expr: &Expr, code: &mut Code) -> Result<(), CompileError> {
         match expr {
-            Expr::Int(i, span) => emit(code, InstrKind::Push(Value::Int(*i)), *span),
-            Expr::Bool(b, span) => emit(code, InstrKind::Push(Value::Bool(*b)), *span),
-            Expr::String(s, span) => emit(code, InstrKind::Push(Value::String(s.clone())), *span),
-            Expr::Ident(name, span) => emit(code, InstrKind::LoadVar(name.clone()), *span),
-            Expr::Group { expr, .. } => return self.compile_expr(expr, code, ctx),
+            Expr::Int(i, span) => {
+                let idx = self.constant(Value::Int(*i));
+                code.emit(InstrKind::Const(idx), *span);
+            }
+            Expr::Bool(true, span) => code.emit(InstrKind::True, *span),
+            Expr::Bool(false, span) => code.emit(InstrKind::False, *span),
+            Expr::String(s, span) => {
+                let idx = self.constant(Value::String(s.clone()));
+                code.emit(InstrKind::Const(idx), *span);
+            }
+            Expr::Ident(name, span) => {
+                let kind = match self.resolve(name, *span)? {
+                    Resolved::Local(slot) => InstrKind::LoadLocal(slot),
+                    Resolved::Upvalue(idx) => InstrKind::LoadUpvalue(idx),
+                    Resolved::Global(id) => InstrKind::LoadGlobal(id),
+                    Resolved::Function(_) => {
+                        InstrKind::Const(self.constant(Value::Function(name.clone())))
+                    }
+                };
+                code.emit(kind, *span);
+            }
+            Expr::Group { expr, .. } => return self.compile_expr(expr, code),
 
             Expr::Fn {
                 params, body, span, ..
             } => {
                 let name = self.fresh_lambda_name();
 
-                // Capture all currently-visible locals (incl. closure env of this function).
-                let captures = ctx.visible_names();
-
-                // Compile the function body into a new module function.
+                // Compile the function body into a new module function. The variables of
+                // this function it uses become its upvalues.
                 let param_names: Vec<String> = params.iter().map(|p| p.name.clone()).collect();
-                let id = self.define_stub(name.clone(), param_names.clone());
+                let id = self.define_stub(name, param_names.clone());
 
-                let mut inner_ctx = FunctionCtx::new_function(&param_names, captures.clone());
-                self.compile_function_body(id, body, &mut inner_ctx)?;
+                let inner_ctx = FunctionCtx::new_function(&param_names, true, *span)?;
+                self.ctxs.push(inner_ctx);
+                let inner_ctx = self.compile_function_body(id, body)?;
 
-                emit(code, InstrKind::MakeClosure(name.clone(), captures), *span);
+                let captures = inner_ctx.captures.into_iter().map(|(_, c)| c).collect();
+                code.emit(InstrKind::MakeClosure(id as u32, captures), *span);
             }
 
             Expr::Array { elements, span } => {
                 for e in elements {
-                    self.compile_expr(e, code, ctx)?;
+                    self.compile_expr(e, code)?;
                 }
-                emit(code, InstrKind::MakeArray(elements.len()), *span);
+                let n = operand(elements.len(), "elements in an array literal", *span)?;
+                code.emit(InstrKind::MakeArray(n), *span);
             }
             Expr::Object { props, span } => {
-                let mut keys = Vec::with_capacity(props.len());
                 for (k, v) in props {
-                    keys.push(k.clone());
-                    self.compile_expr(v, code, ctx)?;
+                    let idx = self.constant(Value::String(k.clone()));
+                    code.emit(InstrKind::Const(idx), *span);
+                    self.compile_expr(v, code)?;
                 }
-                emit(code, InstrKind::MakeObject(keys), *span);
+                let n = operand(props.len(), "properties in an object literal", *span)?;
+                code.emit(InstrKind::MakeObject(n), *span);
             }
             Expr::Index {
                 target,
                 index,
                 span,
             } => {
-                self.compile_expr(target, code, ctx)?;
-                self.compile_expr(index, code, ctx)?;
-                emit(code, InstrKind::IndexGet, *span);
+                self.compile_expr(target, code)?;
+                self.compile_expr(index, code)?;
+                code.emit(InstrKind::IndexGet, *span);
             }
 
-            Expr::Block { stmts, tail, span } => {
-                emit(code, InstrKind::PushScope, *span);
-                ctx.push_scope();
-                self.compile_stmts(stmts, code, ctx)?;
-                match tail {
-                    Some(expr) => self.compile_expr(expr, code, ctx)?,
-                    None => emit(code, InstrKind::Push(Value::Unit), *span),
-                }
-                ctx.pop_scope();
-                emit(code, InstrKind::PopScope, *span);
-            }
-
-            Expr::If {
-                cond,
-                then_branch,
-                else_branch,
-                span,
-            } => {
-                self.compile_expr(cond, code, ctx)?;
-                let jmp_false_at = code.len();
-                emit(code, InstrKind::JumpIfFalse(usize::MAX), *span);
-                emit(code, InstrKind::Pop, cond.span()); // pop condition (true)
-
-                self.compile_expr(then_branch, code, ctx)?;
-                let jmp_end_at = code.len();
-                emit(code, InstrKind::Jump(usize::MAX), *span);
-
-                // else:
-                let else_ip = code.len();
-                patch_jump(code, jmp_false_at, else_ip);
-                emit(code, InstrKind::Pop, cond.span()); // pop condition (false)
-                self.compile_expr(else_branch, code, ctx)?;
-
-                let end_ip = code.len();
-                patch_jump(code, jmp_end_at, end_ip);
+            Expr::Block { .. } | Expr::If { .. } | Expr::Call { .. } => {
+                return self.compile_in_position(expr, false, code)
             }
 
             Expr::Unary { op, expr, span } => {
-                self.compile_expr(expr, code, ctx)?;
+                self.compile_expr(expr, code)?;
                 match op {
-                    UnaryOp::Neg => emit(code, InstrKind::Neg, *span),
-                    UnaryOp::Not => emit(code, InstrKind::Not, *span),
+                    UnaryOp::Neg => code.emit(InstrKind::Neg, *span),
+                    UnaryOp::Not => code.emit(InstrKind::Not, *span),
                 }
             }
 
             Expr::Binary { lhs, op, rhs, span } => match op {
                 BinaryOp::And => {
-                    self.compile_expr(lhs, code, ctx)?;
+                    self.compile_expr(lhs, code)?;
                     let jmp_false_at = code.len();
-                    emit(code, InstrKind::JumpIfFalse(usize::MAX), *span);
-                    emit(code, InstrKind::Pop, lhs.span()); // pop true
-                    self.compile_expr(rhs, code, ctx)?;
+                    code.emit(InstrKind::JumpIfFalse(u32::MAX), *span);
+                    code.emit(InstrKind::Pop, lhs.span()); // pop true
+                    self.compile_expr(rhs, code)?;
                     let end_ip = code.len();
-                    patch_jump(code, jmp_false_at, end_ip);
+                    code.patch_jump(jmp_false_at, end_ip);
                 }
                 BinaryOp::Or => {
-                    self.compile_expr(lhs, code, ctx)?;
+                    self.compile_expr(lhs, code)?;
                     let jmp_true_at = code.len();
-                    emit(code, InstrKind::JumpIfTrue(usize::MAX), *span);
-                    emit(code, InstrKind::Pop, lhs.span()); // pop false
-                    self.compile_expr(rhs, code, ctx)?;
+                    code.emit(InstrKind::JumpIfTrue(u32::MAX), *span);
+                    code.emit(InstrKind::Pop, lhs.span()); // pop false
+                    self.compile_expr(rhs, code)?;
                     let end_ip = code.len();
-                    patch_jump(code, jmp_true_at, end_ip);
+                    code.patch_jump(jmp_true_at, end_ip);
                 }
                 _ => {
-                    self.compile_expr(lhs, code, ctx)?;
-                    self.compile_expr(rhs, code, ctx)?;
+                    self.compile_expr(lhs, code)?;
+                    self.compile_expr(rhs, code)?;
                     let kind = match op {
                         BinaryOp::Add => InstrKind::Add,
                         BinaryOp::Sub => InstrKind::Sub,
@@ -297,43 +486,160 @@ impl Compiler {
                         BinaryOp::Ge => InstrKind::Ge,
                         BinaryOp::And | BinaryOp::Or => unreachable!("handled above"),
                     };
-                    emit(code, kind, *span);
+                    code.emit(kind, *span);
                 }
             },
+        }
+
+        Ok(())
+    }
+
+    /// Compiles `expr` as the value a function returns: a call there becomes a tail call,
+    /// and so does one in the tail of a block or a branch of an `if` there.
+    fn compile_tail_expr(&mut self, expr: &Expr, code: &mut Code) -> Result<(), CompileError> {
+        match expr {
+            Expr::Group { expr, .. } => self.compile_tail_expr(expr, code),
+            Expr::Block { .. } | Expr::If { .. } | Expr::Call { .. } => {
+                self.compile_in_position(expr, true, code)
+            }
+            _ => self.compile_expr(expr, code),
+        }
+    }
+
+    /// Blocks, `if`s and calls, in tail position (`tail`) or not.
+    fn compile_in_position(
+        &mut self,
+        expr: &Expr,
+        tail: bool,
+        code: &mut Code,
+    ) -> Result<(), CompileError> {
+        let compile_sub = |c: &mut Self, expr: &Expr, code: &mut Code| match tail {
+            true => c.compile_tail_expr(expr, code),
+            false => c.compile_expr(expr, code),
+        };
+        match expr {
+            Expr::Block {
+                stmts,
+                tail: tail_expr,
+                span,
+            } => {
+                self.ctx().push_scope();
+                self.compile_stmts(stmts, code)?;
+                match tail_expr {
+                    Some(expr) => compile_sub(self, expr, code)?,
+                    None => code.emit(InstrKind::Unit, *span),
+                }
+                // Not reached after a tail call, which closes all the frame's upvalues itself.
+                if let Some(slot) = self.ctx().pop_scope() {
+                    code.emit(InstrKind::CloseUpvalues(slot), *span);
+                }
+            }
+
+            Expr::If {
+                cond,
+                then_branch,
+                else_branch,
+                span,
+            } => {
+                self.compile_expr(cond, code)?;
+                let jmp_false_at = code.len();
+                code.emit(InstrKind::JumpIfFalse(u32::MAX), *span);
+                code.emit(InstrKind::Pop, cond.span()); // pop condition (true)
+
+                compile_sub(self, then_branch, code)?;
+                let jmp_end_at = code.len();
+                code.emit(InstrKind::Jump(u32::MAX), *span);
+
+                // else:
+                let else_ip = code.len();
+                code.patch_jump(jmp_false_at, else_ip);
+                code.emit(InstrKind::Pop, cond.span()); // pop condition (false)
+                compile_sub(self, else_branch, code)?;
+
+                let end_ip = code.len();
+                code.patch_jump(jmp_end_at, end_ip);
+            }
 
             Expr::Call { callee, args, span } => {
+                // A name that can only be a top-level function is called directly.
+                if let Expr::Ident(name, name_span) = callee.as_ref() {
+                    if let Resolved::Function(id) = self.resolve(name, *name_span)? {
+                        for arg in args {
+                            self.compile_expr(arg, code)?;
+                        }
+                        let argc = operand(args.len(), "arguments in a call", *span)?;
+                        let kind = match tail {
+                            true => InstrKind::TailCall(id as u32, argc),
+                            false => InstrKind::Call(id as u32, argc),
+                        };
+                        code.emit(kind, *span);
+                        return Ok(());
+                    }
+                }
+
                 // Evaluate callee first, then args (left-to-right), then call.
-                self.compile_expr(callee, code, ctx)?;
+                self.compile_expr(callee, code)?;
                 for arg in args {
-                    self.compile_expr(arg, code, ctx)?;
+                    self.compile_expr(arg, code)?;
                 }
-                emit(code, InstrKind::CallValue(args.len()), *span);
+                let argc = operand(args.len(), "arguments in a call", *span)?;
+                let kind = match tail {
+                    true => InstrKind::TailCallValue(argc),
+                    false => InstrKind::CallValue(argc),
+                };
+                code.emit(kind, *span);
             }
-        }
 
+            _ => unreachable!("not a block, if or call"),
+        }
         Ok(())
     }
 }
 
 pub fn compile(program: &Program) -> Result<Module, CompileError> {
+    compile_with_natives(program, &[])
+}
+
+/// Like `compile`, for a program that may call functions the host implements: each
+/// `(name, number of parameters)` in `natives` gets a function with no code, like the
+/// builtins, which the VM runs with what the host registered (`Vm::register_native`).
+pub fn compile_with_natives(
+    program: &Program,
+    natives: &[(&str, usize)],
+) -> Result<Module, CompileError> {
     let mut c = Compiler::new();
 
     // Reserve main at 0.
     c.functions.push(Function {
         name: "<main>".to_string(),
         params: Vec::new(),
+        slots: 0,
         code: Vec::new(),
+        spans: Vec::new(),
     });
     let main_id = 0usize;
 
     // Builtins (implemented in the VM).
     // We treat them as functions in the module so they can be called like normal.
-    {
-        c.define_stub("gc".to_string(), Vec::new());
+    for name in BUILTINS {
+        c.define_stub(name.to_string(), Vec::new());
+    }
+    for (name, arity) in natives {
+        if c.by_name.contains_key(*name) {
+            return Err(CompileError {
+                message: format!("duplicate function: {name}"),
+                span: Span::new(0, 0),
+            });
+        }
+        let params = (0..*arity).map(|i| format!("arg{i}")).collect();
+        c.define_stub(name.to_string(), params);
     }
 
     // Collect function ids first so calls can refer to functions declared later.
     for stmt in &program.stmts {
+        if let Stmt::Let { name, .. } = stmt {
+            c.top_level_lets.insert(name.clone());
+        }
         if let Stmt::Fn { name, params, .. } = stmt {
             if c.by_name.contains_key(name) {
                 return Err(CompileError {
@@ -353,15 +659,16 @@ pub fn compile(program: &Program) -> Result<Module, CompileError> {
         };
         let id = *c.by_name.get(name).expect("function id exists");
         let params = c.functions[id].params.clone();
-        let mut ctx = FunctionCtx::new_function(&params, Vec::new());
-        c.compile_function_body(id, body, &mut ctx)?;
+        let ctx = FunctionCtx::new_function(&params, false, stmt.span())?;
+        c.ctxs.push(ctx);
+        c.compile_function_body(id, body)?;
     }
 
     // Compile main.
     {
-        let mut code = Vec::new();
-        let mut ctx = FunctionCtx::new_main();
-        c.compile_stmts(&program.stmts, &mut code, &mut ctx)?;
+        let mut code = Code::default();
+        c.ctxs.push(FunctionCtx::new_main());
+        c.compile_stmts(&program.stmts, &mut code)?;
 
         let end_span = program
             .tail
@@ -371,29 +678,29 @@ pub fn compile(program: &Program) -> Result<Module, CompileError> {
             .unwrap_or(Span::new(0, 0));
 
         match &program.tail {
-            Some(expr) => c.compile_expr(expr, &mut code, &mut ctx)?,
-            None => emit(&mut code, InstrKind::Push(Value::Unit), end_span),
+            Some(expr) => c.compile_expr(expr, &mut code)?,
+            None => code.emit(InstrKind::Unit, end_span),
         }
-        emit(&mut code, InstrKind::Return, end_span);
-        c.functions[main_id].code = code;
+        code.emit(InstrKind::Return, end_span);
+        let ctx = c.ctxs.pop().expect("main context");
+        c.functions[main_id].code = code.bytes;
+        c.functions[main_id].spans = code.spans;
+        c.functions[main_id].slots = ctx.max_slots;
     }
 
     Ok(Module {
         functions: c.functions,
         by_name: c.by_name,
+        globals: c.globals,
+        constants: c.constants,
         main: main_id,
     })
 }
 
-fn patch_jump(code: &mut [Instr], at: usize, target: usize) {
-    match &mut code[at].kind {
-        InstrKind::Jump(dst) | InstrKind::JumpIfFalse(dst) | InstrKind::JumpIfTrue(dst) => {
-            *dst = target
-        }
-        _ => panic!("expected jump at {at}"),
-    }
-}
-
-fn emit(code: &mut Vec<Instr>, kind: InstrKind, span: Span) {
-    code.push(Instr::new(kind, span));
+/// `n` as an operand of type `T`, or an error naming what there are too many of.
+fn operand<T: TryFrom<usize>>(n: usize, what: &str, span: Span) -> Result<T, CompileError> {
+    T::try_from(n).map_err(|_| CompileError {
+        message: format!("too many {what}"),
+        span,
+    })
 }
@@ -1,34 +1,37 @@
 use moon_core::span::Span;
-use moon_runtime::Value;
-
-use crate::module::FuncId;
 
+/// A decoded instruction, at byte `offset` of its function's code.
 #[derive(Debug, Clone)]
 pub struct Instr {
+    pub offset: usize,
     pub kind: InstrKind,
     pub span: Span,
 }
 
-impl Instr {
-    pub fn new(kind: InstrKind, span: Span) -> Self {
-        Self { kind, span }
-    }
-}
-
-#[derive(Debug, Clone)]
+/// An instruction and its operands. In a `Function` it is stored encoded (`encode`): one
+/// opcode byte, then the operands in little-endian order, with the widths used here.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum InstrKind {
-    // Constants / stack ops
-    Push(Value),
+    // Constants / stack ops. `Const` pushes an entry of the module's constant pool.
+    Const(u32),
+    Unit,
+    True,
+    False,
     Pop,
 
-    // Scopes (HashMap-based, like the interpreter)
-    PushScope,
-    PopScope,
-
-    // Variables
-    LoadVar(String),
-    DefineVar(String),
-    SetVar(String),
+    // Variables, resolved by the compiler: locals are slots of the current frame, upvalues
+    // are the captured variables of the running closure, globals are slots of a module-wide
+    // table.
+    LoadLocal(u16),
+    StoreLocal(u16),
+    LoadUpvalue(u16),
+    StoreUpvalue(u16),
+    // Moves the locals from this slot up that closures captured off the stack, as their
+    // scope ends.
+    CloseUpvalues(u16),
+    LoadGlobal(u32),
+    DefineGlobal(u32),
+    StoreGlobal(u32),
 
     // Ops
     Neg,
@@ -45,38 +48,311 @@ pub enum InstrKind {
     Gt,
     Ge,
 
-    // Control flow
-    Jump(usize),
-    JumpIfFalse(usize),
-    JumpIfTrue(usize),
+    // Control flow. Targets are byte offsets in the function's code.
+    Jump(u32),
+    JumpIfFalse(u32),
+    JumpIfTrue(u32),
 
     // Calls
-    Call(FuncId, usize),
-    CallValue(usize),
+    Call(u32, u16),
+    CallValue(u16),
     Return,
+    // Calls in tail position: the callee takes over the caller's frame instead of pushing a
+    // new one. A builtin callee runs like a normal call, and the `Return` after it follows.
+    TailCall(u32, u16),
+    TailCallValue(u16),
 
     // Closures
-    MakeClosure(String, Vec<String>),
+    MakeClosure(u32, Vec<Capture>),
 
-    // Heap / aggregates
-    MakeArray(usize),
-    MakeObject(Vec<String>),
+    // Heap / aggregates. `MakeObject(n)` takes n key/value pairs, each key a string.
+    MakeArray(u32),
+    MakeObject(u32),
     IndexGet,
     IndexSet,
 }
 
+/// Where `MakeClosure` takes an upvalue from, in the function creating the closure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capture {
+    // A local of the creating function: the upvalue over its slot.
+    Local(u16),
+    // One of the creating function's own upvalues (it is a closure too).
+    Upvalue(u16),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodeError {
+    pub message: String,
+    pub offset: usize,
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at offset {}", self.message, self.offset)
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+mod op {
+    pub const CONST: u8 = 0;
+    pub const UNIT: u8 = 1;
+    pub const TRUE: u8 = 2;
+    pub const FALSE: u8 = 3;
+    pub const POP: u8 = 4;
+    pub const LOAD_LOCAL: u8 = 5;
+    pub const STORE_LOCAL: u8 = 6;
+    pub const LOAD_UPVALUE: u8 = 7;
+    pub const STORE_UPVALUE: u8 = 8;
+    pub const CLOSE_UPVALUES: u8 = 9;
+    pub const LOAD_GLOBAL: u8 = 10;
+    pub const DEFINE_GLOBAL: u8 = 11;
+    pub const STORE_GLOBAL: u8 = 12;
+    pub const NEG: u8 = 13;
+    pub const NOT: u8 = 14;
+    pub const ADD: u8 = 15;
+    pub const SUB: u8 = 16;
+    pub const MUL: u8 = 17;
+    pub const DIV: u8 = 18;
+    pub const MOD: u8 = 19;
+    pub const EQ: u8 = 20;
+    pub const NE: u8 = 21;
+    pub const LT: u8 = 22;
+    pub const LE: u8 = 23;
+    pub const GT: u8 = 24;
+    pub const GE: u8 = 25;
+    pub const JUMP: u8 = 26;
+    pub const JUMP_IF_FALSE: u8 = 27;
+    pub const JUMP_IF_TRUE: u8 = 28;
+    pub const CALL: u8 = 29;
+    pub const CALL_VALUE: u8 = 30;
+    pub const RETURN: u8 = 31;
+    pub const MAKE_CLOSURE: u8 = 32;
+    pub const MAKE_ARRAY: u8 = 33;
+    pub const MAKE_OBJECT: u8 = 34;
+    pub const INDEX_GET: u8 = 35;
+    pub const INDEX_SET: u8 = 36;
+    pub const TAIL_CALL: u8 = 37;
+    pub const TAIL_CALL_VALUE: u8 = 38;
+
+    // Capture kinds in `MakeClosure`.
+    pub const CAPTURE_LOCAL: u8 = 0;
+    pub const CAPTURE_UPVALUE: u8 = 1;
+}
+
+impl InstrKind {
+    /// Appends the encoded instruction to `out`. A closure has at most `u16::MAX` captures.
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        out.push(self.opcode());
+        match self {
+            InstrKind::Const(n)
+            | InstrKind::LoadGlobal(n)
+            | InstrKind::DefineGlobal(n)
+            | InstrKind::StoreGlobal(n)
+            | InstrKind::Jump(n)
+            | InstrKind::JumpIfFalse(n)
+            | InstrKind::JumpIfTrue(n)
+            | InstrKind::MakeArray(n)
+            | InstrKind::MakeObject(n) => out.extend_from_slice(&n.to_le_bytes()),
+            InstrKind::LoadLocal(n)
+            | InstrKind::StoreLocal(n)
+            | InstrKind::LoadUpvalue(n)
+            | InstrKind::StoreUpvalue(n)
+            | InstrKind::CloseUpvalues(n)
+            | InstrKind::CallValue(n)
+            | InstrKind::TailCallValue(n) => out.extend_from_slice(&n.to_le_bytes()),
+            InstrKind::Call(id, argc) | InstrKind::TailCall(id, argc) => {
+                out.extend_from_slice(&id.to_le_bytes());
+                out.extend_from_slice(&argc.to_le_bytes());
+            }
+            InstrKind::MakeClosure(id, captures) => {
+                out.extend_from_slice(&id.to_le_bytes());
+                let count = u16::try_from(captures.len()).expect("the compiler caps captures");
+                out.extend_from_slice(&count.to_le_bytes());
+                for capture in captures {
+                    let (kind, idx) = match capture {
+                        Capture::Local(slot) => (op::CAPTURE_LOCAL, slot),
+                        Capture::Upvalue(idx) => (op::CAPTURE_UPVALUE, idx),
+                    };
+                    out.push(kind);
+                    out.extend_from_slice(&idx.to_le_bytes());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Decodes the instruction starting at `at`; returns it and the offset of the next one.
+    #[inline]
+    pub fn decode(code: &[u8], at: usize) -> Result<(InstrKind, usize), DecodeError> {
+        let mut r = Reader { code, at };
+        let opcode = r.u8()?;
+        let kind = match opcode {
+            op::CONST => InstrKind::Const(r.u32()?),
+            op::UNIT => InstrKind::Unit,
+            op::TRUE => InstrKind::True,
+            op::FALSE => InstrKind::False,
+            op::POP => InstrKind::Pop,
+            op::LOAD_LOCAL => InstrKind::LoadLocal(r.u16()?),
+            op::STORE_LOCAL => InstrKind::StoreLocal(r.u16()?),
+            op::LOAD_UPVALUE => InstrKind::LoadUpvalue(r.u16()?),
+            op::STORE_UPVALUE => InstrKind::StoreUpvalue(r.u16()?),
+            op::CLOSE_UPVALUES => InstrKind::CloseUpvalues(r.u16()?),
+            op::LOAD_GLOBAL => InstrKind::LoadGlobal(r.u32()?),
+            op::DEFINE_GLOBAL => InstrKind::DefineGlobal(r.u32()?),
+            op::STORE_GLOBAL => InstrKind::StoreGlobal(r.u32()?),
+            op::NEG => InstrKind::Neg,
+            op::NOT => InstrKind::Not,
+            op::ADD => InstrKind::Add,
+            op::SUB => InstrKind::Sub,
+            op::MUL => InstrKind::Mul,
+            op::DIV => InstrKind::Div,
+            op::MOD => InstrKind::Mod,
+            op::EQ => InstrKind::Eq,
+            op::NE => InstrKind::Ne,
+            op::LT => InstrKind::Lt,
+            op::LE => InstrKind::Le,
+            op::GT => InstrKind::Gt,
+            op::GE => InstrKind::Ge,
+            op::JUMP => InstrKind::Jump(r.u32()?),
+            op::JUMP_IF_FALSE => InstrKind::JumpIfFalse(r.u32()?),
+            op::JUMP_IF_TRUE => InstrKind::JumpIfTrue(r.u32()?),
+            op::CALL => InstrKind::Call(r.u32()?, r.u16()?),
+            op::CALL_VALUE => InstrKind::CallValue(r.u16()?),
+            op::RETURN => InstrKind::Return,
+            op::TAIL_CALL => InstrKind::TailCall(r.u32()?, r.u16()?),
+            op::TAIL_CALL_VALUE => InstrKind::TailCallValue(r.u16()?),
+            op::MAKE_CLOSURE => {
+                let id = r.u32()?;
+                let n = r.u16()?;
+                let mut captures = Vec::with_capacity(n as usize);
+                for _ in 0..n {
+                    let kind_at = r.at;
+                    captures.push(match r.u8()? {
+                        op::CAPTURE_LOCAL => Capture::Local(r.u16()?),
+                        op::CAPTURE_UPVALUE => Capture::Upvalue(r.u16()?),
+                        other => {
+                            return Err(DecodeError {
+                                message: format!("unknown capture kind {other}"),
+                                offset: kind_at,
+                            })
+                        }
+                    });
+                }
+                InstrKind::MakeClosure(id, captures)
+            }
+            op::MAKE_ARRAY => InstrKind::MakeArray(r.u32()?),
+            op::MAKE_OBJECT => InstrKind::MakeObject(r.u32()?),
+            op::INDEX_GET => InstrKind::IndexGet,
+            op::INDEX_SET => InstrKind::IndexSet,
+            other => {
+                return Err(DecodeError {
+                    message: format!("unknown opcode {other}"),
+                    offset: at,
+                })
+            }
+        };
+        Ok((kind, r.at))
+    }
+
+    fn opcode(&self) -> u8 {
+        match self {
+            InstrKind::Const(_) => op::CONST,
+            InstrKind::Unit => op::UNIT,
+            InstrKind::True => op::TRUE,
+            InstrKind::False => op::FALSE,
+            InstrKind::Pop => op::POP,
+            InstrKind::LoadLocal(_) => op::LOAD_LOCAL,
+            InstrKind::StoreLocal(_) => op::STORE_LOCAL,
+            InstrKind::LoadUpvalue(_) => op::LOAD_UPVALUE,
+            InstrKind::StoreUpvalue(_) => op::STORE_UPVALUE,
+            InstrKind::CloseUpvalues(_) => op::CLOSE_UPVALUES,
+            InstrKind::LoadGlobal(_) => op::LOAD_GLOBAL,
+            InstrKind::DefineGlobal(_) => op::DEFINE_GLOBAL,
+            InstrKind::StoreGlobal(_) => op::STORE_GLOBAL,
+            InstrKind::Neg => op::NEG,
+            InstrKind::Not => op::NOT,
+            InstrKind::Add => op::ADD,
+            InstrKind::Sub => op::SUB,
+            InstrKind::Mul => op::MUL,
+            InstrKind::Div => op::DIV,
+            InstrKind::Mod => op::MOD,
+            InstrKind::Eq => op::EQ,
+            InstrKind::Ne => op::NE,
+            InstrKind::Lt => op::LT,
+            InstrKind::Le => op::LE,
+            InstrKind::Gt => op::GT,
+            InstrKind::Ge => op::GE,
+            InstrKind::Jump(_) => op::JUMP,
+            InstrKind::JumpIfFalse(_) => op::JUMP_IF_FALSE,
+            InstrKind::JumpIfTrue(_) => op::JUMP_IF_TRUE,
+            InstrKind::Call(..) => op::CALL,
+            InstrKind::CallValue(_) => op::CALL_VALUE,
+            InstrKind::Return => op::RETURN,
+            InstrKind::TailCall(..) => op::TAIL_CALL,
+            InstrKind::TailCallValue(_) => op::TAIL_CALL_VALUE,
+            InstrKind::MakeClosure(..) => op::MAKE_CLOSURE,
+            InstrKind::MakeArray(_) => op::MAKE_ARRAY,
+            InstrKind::MakeObject(_) => op::MAKE_OBJECT,
+            InstrKind::IndexGet => op::INDEX_GET,
+            InstrKind::IndexSet => op::INDEX_SET,
+        }
+    }
+}
+
+struct Reader<'a> {
+    code: &'a [u8],
+    at: usize,
+}
+
+impl Reader<'_> {
+    #[inline]
+    fn bytes<const N: usize>(&mut self) -> Result<[u8; N], DecodeError> {
+        let bytes = self
+            .code
+            .get(self.at..self.at + N)
+            .ok_or_else(|| DecodeError {
+                message: "truncated instruction".to_string(),
+                offset: self.at,
+            })?;
+        self.at += N;
+        Ok(bytes.try_into().expect("slice of length N"))
+    }
+
+    #[inline]
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.bytes::<1>()?[0])
+    }
+
+    #[inline]
+    fn u16(&mut self) -> Result<u16, DecodeError> {
+        Ok(u16::from_le_bytes(self.bytes()?))
+    }
+
+    #[inline]
+    fn u32(&mut self) -> Result<u32, DecodeError> {
+        Ok(u32::from_le_bytes(self.bytes()?))
+    }
+}
+
 impl std::fmt::Display for InstrKind {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            InstrKind::Push(v) => write!(f, "Push {v:?}"),
+            InstrKind::Const(idx) => write!(f, "Const {idx}"),
+            InstrKind::Unit => write!(f, "Unit"),
+            InstrKind::True => write!(f, "True"),
+            InstrKind::False => write!(f, "False"),
             InstrKind::Pop => write!(f, "Pop"),
 
-            InstrKind::PushScope => write!(f, "PushScope"),
-            InstrKind::PopScope => write!(f, "PopScope"),
-
-            InstrKind::LoadVar(name) => write!(f, "LoadVar {name}"),
-            InstrKind::DefineVar(name) => write!(f, "DefineVar {name}"),
-            InstrKind::SetVar(name) => write!(f, "SetVar {name}"),
+            InstrKind::LoadLocal(slot) => write!(f, "LoadLocal {slot}"),
+            InstrKind::StoreLocal(slot) => write!(f, "StoreLocal {slot}"),
+            InstrKind::LoadUpvalue(idx) => write!(f, "LoadUpvalue {idx}"),
+            InstrKind::StoreUpvalue(idx) => write!(f, "StoreUpvalue {idx}"),
+            InstrKind::CloseUpvalues(slot) => write!(f, "CloseUpvalues {slot}"),
+            InstrKind::LoadGlobal(idx) => write!(f, "LoadGlobal {idx}"),
+            InstrKind::DefineGlobal(idx) => write!(f, "DefineGlobal {idx}"),
+            InstrKind::StoreGlobal(idx) => write!(f, "StoreGlobal {idx}"),
 
             InstrKind::Neg => write!(f, "Neg"),
             InstrKind::Not => write!(f, "Not"),
@@ -99,13 +375,25 @@ impl std::fmt::Display for InstrKind {
             InstrKind::Call(id, argc) => write!(f, "Call f{id} argc={argc}"),
             InstrKind::CallValue(argc) => write!(f, "CallValue argc={argc}"),
             InstrKind::Return => write!(f, "Return"),
+            InstrKind::TailCall(id, argc) => write!(f, "TailCall f{id} argc={argc}"),
+            InstrKind::TailCallValue(argc) => write!(f, "TailCallValue argc={argc}"),
 
-            InstrKind::MakeClosure(name, captures) => {
-                write!(f, "MakeClosure {name} captures={captures:?}")
+            InstrKind::MakeClosure(id, captures) => {
+                write!(f, "MakeClosure f{id} captures=[")?;
+                for (i, capture) in captures.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    match capture {
+                        Capture::Local(slot) => write!(f, "local {slot}")?,
+                        Capture::Upvalue(idx) => write!(f, "upvalue {idx}")?,
+                    }
+                }
+                write!(f, "]")
             }
 
             InstrKind::MakeArray(n) => write!(f, "MakeArray {n}"),
-            InstrKind::MakeObject(keys) => write!(f, "MakeObject keys={keys:?}"),
+            InstrKind::MakeObject(n) => write!(f, "MakeObject {n}"),
             InstrKind::IndexGet => write!(f, "IndexGet"),
             InstrKind::IndexSet => write!(f, "IndexSet"),
         }
@@ -1,7 +1,12 @@
 mod compiler;
 mod instr;
 mod module;
+pub mod moonc;
+mod optimize;
+mod verify;
 
-pub use compiler::{compile, CompileError};
-pub use instr::{Instr, InstrKind};
-pub use module::{FuncId, Function, Module};
+pub use compiler::{compile, compile_with_natives, CompileError};
+pub use instr::{Capture, DecodeError, Instr, InstrKind};
+pub use module::{constant_text, FuncId, Function, Module};
+pub use optimize::optimize;
+pub use verify::{verify, verify_with_natives, VerifyError};
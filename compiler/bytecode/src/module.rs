@@ -1,6 +1,9 @@
 use std::collections::HashMap;
 
-use crate::Instr;
+use moon_core::span::Span;
+use moon_runtime::Value;
+
+use crate::instr::{DecodeError, Instr, InstrKind};
 
 pub type FuncId = usize;
 
@@ -8,13 +11,52 @@ pub type FuncId = usize;
 pub struct Function {
     pub name: String,
     pub params: Vec<String>,
-    pub code: Vec<Instr>,
+    // Local slots a call reserves on the stack; the parameters are the first ones.
+    pub slots: u16,
+    // Encoded instructions (see `InstrKind::encode`).
+    pub code: Vec<u8>,
+    // Where each run of instructions with the same span starts, ascending by offset: the
+    // span of an instruction is the one of the last entry at or before it.
+    pub spans: Vec<(u32, Span)>,
+}
+
+impl Function {
+    /// The source span of the instruction at byte `offset`.
+    pub fn span_at(&self, offset: usize) -> Span {
+        let idx = self
+            .spans
+            .partition_point(|(start, _)| *start as usize <= offset);
+        match idx.checked_sub(1) {
+            Some(idx) => self.spans[idx].1,
+            None => Span::new(0, 0),
+        }
+    }
+
+    /// Decodes the whole function, for tools (the VM decodes as it goes).
+    pub fn instrs(&self) -> Result<Vec<Instr>, DecodeError> {
+        let mut out = Vec::new();
+        let mut offset = 0;
+        while offset < self.code.len() {
+            let (kind, next) = InstrKind::decode(&self.code, offset)?;
+            out.push(Instr {
+                offset,
+                kind,
+                span: self.span_at(offset),
+            });
+            offset = next;
+        }
+        Ok(out)
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Module {
     pub functions: Vec<Function>,
     pub by_name: HashMap<String, FuncId>,
+    // Names of the global slots, by index.
+    pub globals: Vec<String>,
+    // Values `Const` pushes, by index: ints, strings (object keys too) and function names.
+    pub constants: Vec<Value>,
     pub main: FuncId,
 }
 
@@ -22,4 +64,31 @@ impl Module {
     pub fn get_func(&self, id: FuncId) -> Option<&Function> {
         self.functions.get(id)
     }
+
+    /// `kind` as text, with what its operands stand for: the constant a `Const` pushes, the
+    /// name of a called or captured function.
+    pub fn describe(&self, kind: &InstrKind) -> String {
+        let note = match kind {
+            InstrKind::Const(idx) => self.constants.get(*idx as usize).map(constant_text),
+            InstrKind::Call(id, _) | InstrKind::TailCall(id, _) | InstrKind::MakeClosure(id, _) => {
+                self.get_func(*id as usize).map(|f| f.name.clone())
+            }
+            InstrKind::LoadGlobal(id)
+            | InstrKind::DefineGlobal(id)
+            | InstrKind::StoreGlobal(id) => self.globals.get(*id as usize).cloned(),
+            _ => None,
+        };
+        match note {
+            Some(note) => format!("{kind} ({note})"),
+            None => kind.to_string(),
+        }
+    }
+}
+
+/// A constant the way the disassembler shows it: strings quoted, the rest as displayed.
+pub fn constant_text(value: &Value) -> String {
+    match value {
+        Value::String(s) => format!("{s:?}"),
+        other => other.to_string(),
+    }
 }
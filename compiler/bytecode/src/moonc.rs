@@ -0,0 +1,291 @@
+//! `.moonc` files: a compiled `Module` on disk, so `moon vm` can skip the frontend.
+//!
+//! Layout (integers little-endian, strings as a `u32` byte length then UTF-8):
+//!
+//! ```text
+//! magic "MOONC", version u16
+//! source path: string, source hash: u64 (`source_hash` of the text compiled)
+//! main: u32
+//! globals: u32 count, then names
+//! constants: u32 count, then a tag byte each (0 int i64, 1 string, 2 function name)
+//! functions: u32 count, then each:
+//!   name, params (u32 count, then names), slots u16,
+//!   code (u32 length, then bytes), spans (u32 count, then offset/file/start/end as u32)
+//! ```
+//!
+//! `by_name` is not stored: it is every function but `main`, by name.
+//!
+//! `read` checks the file is well formed (magic, version, lengths, UTF-8, every function's code
+//! decodes) and reports a `LoadError` otherwise; it never panics on bad input.
+
+use std::collections::HashMap;
+
+use moon_core::span::{FileId, Span};
+use moon_runtime::Value;
+
+use crate::instr::InstrKind;
+use crate::module::{Function, Module};
+
+pub const MAGIC: &[u8; 5] = b"MOONC";
+/// Bumped whenever the layout or the instruction encoding changes.
+pub const VERSION: u16 = 2;
+
+const CONST_INT: u8 = 0;
+const CONST_STRING: u8 = 1;
+const CONST_FUNCTION: u8 = 2;
+
+/// A module read back from a `.moonc` file, with the source it was compiled from.
+#[derive(Debug, Clone)]
+pub struct CompiledFile {
+    pub module: Module,
+    pub source_path: String,
+    pub source_hash: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoadError {
+    pub message: String,
+    pub offset: usize,
+}
+
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at byte {}", self.message, self.offset)
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+/// FNV-1a of the source text: enough to tell whether a `.moonc` is stale.
+pub fn source_hash(text: &str) -> u64 {
+    text.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+        (hash ^ b as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// Encodes `module`, compiled from the source at `source_path` with text `source_text`.
+pub fn write(module: &Module, source_path: &str, source_text: &str) -> Vec<u8> {
+    let mut w = Writer::default();
+    w.out.extend_from_slice(MAGIC);
+    w.u16(VERSION);
+    w.str(source_path);
+    w.out
+        .extend_from_slice(&source_hash(source_text).to_le_bytes());
+    w.u32(module.main as u32);
+
+    w.u32(module.globals.len() as u32);
+    for name in &module.globals {
+        w.str(name);
+    }
+
+    w.u32(module.constants.len() as u32);
+    for value in &module.constants {
+        match value {
+            Value::Int(i) => {
+                w.out.push(CONST_INT);
+                w.out.extend_from_slice(&i.to_le_bytes());
+            }
+            Value::String(s) => {
+                w.out.push(CONST_STRING);
+                w.str(s);
+            }
+            Value::Function(name) => {
+                w.out.push(CONST_FUNCTION);
+                w.str(name);
+            }
+            other => unreachable!("not a constant: {other:?}"),
+        }
+    }
+
+    w.u32(module.functions.len() as u32);
+    for func in &module.functions {
+        w.str(&func.name);
+        w.u32(func.params.len() as u32);
+        for param in &func.params {
+            w.str(param);
+        }
+        w.u16(func.slots);
+        w.u32(func.code.len() as u32);
+        w.out.extend_from_slice(&func.code);
+        w.u32(func.spans.len() as u32);
+        for (offset, span) in &func.spans {
+            w.u32(*offset);
+            w.u32(span.file.0);
+            w.u32(span.start as u32);
+            w.u32(span.end as u32);
+        }
+    }
+    w.out
+}
+
+/// Decodes a `.moonc` file.
+pub fn read(bytes: &[u8]) -> Result<CompiledFile, LoadError> {
+    let mut r = Reader { bytes, at: 0 };
+    if r.take(MAGIC.len()).ok() != Some(MAGIC.as_slice()) {
+        return Err(r.error_at(0, "not a .moonc file"));
+    }
+    let version = r.u16()?;
+    if version != VERSION {
+        return Err(r.error_at(
+            MAGIC.len(),
+            format!("unsupported .moonc version {version} (expected {VERSION})"),
+        ));
+    }
+    let source_path = r.str()?;
+    let source_hash = u64::from_le_bytes(r.array()?);
+    let main = r.u32()? as usize;
+
+    let globals = r.list(Reader::str)?;
+
+    let constants = r.list(|r| {
+        let tag_at = r.at;
+        match r.u8()? {
+            CONST_INT => Ok(Value::Int(i64::from_le_bytes(r.array()?))),
+            CONST_STRING => Ok(Value::String(r.str()?)),
+            CONST_FUNCTION => Ok(Value::Function(r.str()?)),
+            other => Err(r.error_at(tag_at, format!("unknown constant tag {other}"))),
+        }
+    })?;
+
+    let functions = r.list(|r| {
+        let name = r.str()?;
+        let params = r.list(Reader::str)?;
+        let slots = r.u16()?;
+        let len = r.u32()? as usize;
+        let code_at = r.at;
+        let code = r.take(len)?.to_vec();
+        let spans = r.list(|r| {
+            let offset = r.u32()?;
+            let file = FileId(r.u32()?);
+            let start = r.u32()? as usize;
+            let end = r.u32()? as usize;
+            Ok((offset, Span::in_file(file, start, end)))
+        })?;
+
+        let mut offset = 0;
+        while offset < code.len() {
+            offset = match InstrKind::decode(&code, offset) {
+                Ok((_, next)) => next,
+                Err(e) => {
+                    return Err(r.error_at(code_at + e.offset, format!("in {name}: {}", e.message)))
+                }
+            };
+        }
+        Ok(Function {
+            name,
+            params,
+            slots,
+            code,
+            spans,
+        })
+    })?;
+
+    if r.at != bytes.len() {
+        return Err(r.error_at(r.at, "trailing bytes"));
+    }
+    if main >= functions.len() {
+        return Err(r.error_at(0, format!("main function f{main} does not exist")));
+    }
+
+    let by_name: HashMap<String, usize> = functions
+        .iter()
+        .enumerate()
+        .filter(|(id, _)| *id != main)
+        .map(|(id, func)| (func.name.clone(), id))
+        .collect();
+
+    Ok(CompiledFile {
+        module: Module {
+            functions,
+            by_name,
+            globals,
+            constants,
+            main,
+        },
+        source_path,
+        source_hash,
+    })
+}
+
+#[derive(Default)]
+struct Writer {
+    out: Vec<u8>,
+}
+
+impl Writer {
+    fn u16(&mut self, n: u16) {
+        self.out.extend_from_slice(&n.to_le_bytes());
+    }
+
+    fn u32(&mut self, n: u32) {
+        self.out.extend_from_slice(&n.to_le_bytes());
+    }
+
+    fn str(&mut self, s: &str) {
+        self.u32(s.len() as u32);
+        self.out.extend_from_slice(s.as_bytes());
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn error_at(&self, offset: usize, message: impl Into<String>) -> LoadError {
+        LoadError {
+            message: message.into(),
+            offset,
+        }
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], LoadError> {
+        let end = self
+            .at
+            .checked_add(n)
+            .filter(|end| *end <= self.bytes.len());
+        let Some(end) = end else {
+            return Err(self.error_at(self.at, "unexpected end of file"));
+        };
+        let bytes = &self.bytes[self.at..end];
+        self.at = end;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], LoadError> {
+        Ok(self.take(N)?.try_into().expect("slice of length N"))
+    }
+
+    fn u8(&mut self) -> Result<u8, LoadError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, LoadError> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, LoadError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn str(&mut self) -> Result<String, LoadError> {
+        let len = self.u32()? as usize;
+        let at = self.at;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| self.error_at(at, "invalid UTF-8"))
+    }
+
+    /// A `u32` count, then that many items. The count is not trusted for preallocation.
+    fn list<T>(
+        &mut self,
+        mut item: impl FnMut(&mut Self) -> Result<T, LoadError>,
+    ) -> Result<Vec<T>, LoadError> {
+        let n = self.u32()?;
+        let mut out = Vec::new();
+        for _ in 0..n {
+            out.push(item(self)?);
+        }
+        Ok(out)
+    }
+}
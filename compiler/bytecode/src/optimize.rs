@@ -0,0 +1,575 @@
+//! Bytecode optimizer (`moon vm -O`, `moon build -O`, `moon disasm -O`).
+//!
+//! Works per function on the decoded instructions, with jump targets turned into instruction
+//! indices, and repeats these passes until none changes anything:
+//! - constant folding: `Const 1; Const 2; Add` becomes `Const 3` (never when the VM would
+//!   fail or overflow, so errors stay where they were)
+//! - constant branches: a `JumpIfFalse` after `True`/`False` either goes away or becomes a
+//!   `Jump`
+//! - jump threading: a jump to a `Jump` goes straight to its target, a jump to a `Return`
+//!   returns
+//! - dead code: instructions no path from the start reaches (code after `Return`, the arm of
+//!   an `if` that cannot run)
+//! - peephole: a push without side effects followed by `Pop`, a `Jump` to the next
+//!   instruction, a `CloseUpvalues` right before another or before `Return` (which closes
+//!   everything in the frame anyway)
+//!
+//! Then functions nothing refers to anymore and unused constants are dropped from the module.
+//! The result runs exactly like the input, and passes `verify` if the input did.
+
+use std::collections::HashMap;
+
+use moon_core::span::Span;
+use moon_runtime::Value;
+
+use crate::compiler::Code;
+use crate::instr::{Instr, InstrKind};
+use crate::module::{FuncId, Function, Module};
+
+pub fn optimize(module: &mut Module) {
+    if !ids_in_range(module) {
+        return; // a malformed module: leave it as it is for `verify`
+    }
+    for id in 0..module.functions.len() {
+        // Builtin stubs have no code; code that does not decode is left for `verify`.
+        let Ok(instrs) = module.functions[id].instrs() else {
+            continue;
+        };
+        if instrs.is_empty() {
+            continue;
+        }
+        let mut ops = to_ops(instrs);
+        for _ in 0..MAX_ROUNDS {
+            let changed = fold_constants(&mut ops, &mut module.constants)
+                | fold_branches(&mut ops)
+                | thread_jumps(&mut ops)
+                | remove_unreachable(&mut ops)
+                | peephole(&mut ops);
+            if !changed {
+                break;
+            }
+        }
+        let func = &mut module.functions[id];
+        let code = encode(&ops);
+        func.code = code.bytes;
+        func.spans = code.spans;
+    }
+    remove_unused_functions(module);
+    remove_unused_constants(module);
+}
+
+/// Whether every function id and constant index the module refers to exists, which the
+/// renumbering at the end relies on. The compiler only makes such modules; a `.moonc` file can
+/// hold anything.
+fn ids_in_range(module: &Module) -> bool {
+    let funcs = module.functions.len();
+    if module.main >= funcs || module.by_name.values().any(|id| *id >= funcs) {
+        return false;
+    }
+    module.functions.iter().all(|func| {
+        func.instrs()
+            .into_iter()
+            .flatten()
+            .all(|instr| match instr.kind {
+                InstrKind::Call(id, _)
+                | InstrKind::TailCall(id, _)
+                | InstrKind::MakeClosure(id, _) => (id as usize) < funcs,
+                InstrKind::Const(idx) => (idx as usize) < module.constants.len(),
+                _ => true,
+            })
+    })
+}
+
+/// Every pass shrinks the code or shortens a jump chain, so this is only a backstop.
+const MAX_ROUNDS: usize = 64;
+
+/// An instruction while optimizing. Jump operands are indices in the function's `Op`s.
+#[derive(Debug, Clone)]
+struct Op {
+    kind: InstrKind,
+    span: Span,
+}
+
+fn to_ops(instrs: Vec<Instr>) -> Vec<Op> {
+    let index: HashMap<usize, u32> = instrs
+        .iter()
+        .enumerate()
+        .map(|(i, instr)| (instr.offset, i as u32))
+        .collect();
+    instrs
+        .into_iter()
+        .map(|instr| {
+            let mut kind = instr.kind;
+            if let Some(dst) = jump_target_mut(&mut kind) {
+                // An offset that is not an instruction is left as is: past the end.
+                *dst = index.get(&(*dst as usize)).copied().unwrap_or(u32::MAX);
+            }
+            Op {
+                kind,
+                span: instr.span,
+            }
+        })
+        .collect()
+}
+
+fn encode(ops: &[Op]) -> Code {
+    let mut code = Code::default();
+    let mut offsets = Vec::with_capacity(ops.len());
+    for op in ops {
+        offsets.push(code.len());
+        code.emit(op.kind.clone(), op.span);
+    }
+    for (op, at) in ops.iter().zip(&offsets) {
+        if let Some(dst) = jump_target(&op.kind) {
+            let target = offsets.get(dst as usize).copied().unwrap_or(code.len());
+            code.patch_jump(*at, target);
+        }
+    }
+    code
+}
+
+fn jump_target(kind: &InstrKind) -> Option<u32> {
+    match kind {
+        InstrKind::Jump(dst) | InstrKind::JumpIfFalse(dst) | InstrKind::JumpIfTrue(dst) => {
+            Some(*dst)
+        }
+        _ => None,
+    }
+}
+
+fn jump_target_mut(kind: &mut InstrKind) -> Option<&mut u32> {
+    match kind {
+        InstrKind::Jump(dst) | InstrKind::JumpIfFalse(dst) | InstrKind::JumpIfTrue(dst) => {
+            Some(dst)
+        }
+        _ => None,
+    }
+}
+
+/// Which ops some jump lands on. Code can only be merged or removed where nothing jumps in.
+fn jump_targets(ops: &[Op]) -> Vec<bool> {
+    let mut targets = vec![false; ops.len()];
+    for op in ops {
+        if let Some(dst) = jump_target(&op.kind) {
+            if let Some(t) = targets.get_mut(dst as usize) {
+                *t = true;
+            }
+        }
+    }
+    targets
+}
+
+/// Drops the ops marked in `remove`. A jump to a removed op lands on the next one kept.
+fn compact(ops: &mut Vec<Op>, remove: &[bool]) -> bool {
+    if !remove.contains(&true) {
+        return false;
+    }
+    let mut new_index = Vec::with_capacity(ops.len() + 1);
+    let mut kept = 0u32;
+    for removed in remove {
+        new_index.push(kept);
+        if !removed {
+            kept += 1;
+        }
+    }
+    new_index.push(kept);
+
+    let mut i = 0;
+    ops.retain(|_| {
+        i += 1;
+        !remove[i - 1]
+    });
+    for op in ops.iter_mut() {
+        if let Some(dst) = jump_target_mut(&mut op.kind) {
+            if let Some(new) = new_index.get(*dst as usize) {
+                *dst = *new;
+            }
+        }
+    }
+    true
+}
+
+/// The value a push of a constant puts on the stack.
+fn constant(kind: &InstrKind, constants: &[Value]) -> Option<Value> {
+    match kind {
+        InstrKind::True => Some(Value::Bool(true)),
+        InstrKind::False => Some(Value::Bool(false)),
+        InstrKind::Const(idx) => match constants.get(*idx as usize)? {
+            v @ (Value::Int(_) | Value::String(_)) => Some(v.clone()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// The instruction pushing `value`, adding it to the pool if needed.
+fn push_constant(value: Value, constants: &mut Vec<Value>) -> InstrKind {
+    match value {
+        Value::Bool(true) => InstrKind::True,
+        Value::Bool(false) => InstrKind::False,
+        value => {
+            let idx = match constants.iter().position(|c| *c == value) {
+                Some(idx) => idx,
+                None => {
+                    constants.push(value);
+                    constants.len() - 1
+                }
+            };
+            InstrKind::Const(idx as u32)
+        }
+    }
+}
+
+/// `a op b` as the VM computes it, or `None` if it would fail (or is not a binary op).
+fn fold_binary(op: &InstrKind, a: &Value, b: &Value) -> Option<Value> {
+    use Value::{Bool, Int};
+    let value = match (op, a, b) {
+        (InstrKind::Add, Int(a), Int(b)) => Int(a.checked_add(*b)?),
+        (InstrKind::Add, Value::String(a), Value::String(b)) => Value::String(format!("{a}{b}")),
+        (InstrKind::Sub, Int(a), Int(b)) => Int(a.checked_sub(*b)?),
+        (InstrKind::Mul, Int(a), Int(b)) => Int(a.checked_mul(*b)?),
+        (InstrKind::Div, Int(a), Int(b)) => Int(a.checked_div(*b)?),
+        (InstrKind::Mod, Int(a), Int(b)) => Int(a.checked_rem(*b)?),
+        (InstrKind::Eq, a, b) => Bool(a == b),
+        (InstrKind::Ne, a, b) => Bool(a != b),
+        (InstrKind::Lt, Int(a), Int(b)) => Bool(a < b),
+        (InstrKind::Le, Int(a), Int(b)) => Bool(a <= b),
+        (InstrKind::Gt, Int(a), Int(b)) => Bool(a > b),
+        (InstrKind::Ge, Int(a), Int(b)) => Bool(a >= b),
+        _ => return None,
+    };
+    Some(value)
+}
+
+fn fold_unary(op: &InstrKind, v: &Value) -> Option<Value> {
+    match (op, v) {
+        (InstrKind::Neg, Value::Int(i)) => Some(Value::Int(i.checked_neg()?)),
+        (InstrKind::Not, Value::Bool(b)) => Some(Value::Bool(!b)),
+        _ => None,
+    }
+}
+
+fn fold_constants(ops: &mut Vec<Op>, constants: &mut Vec<Value>) -> bool {
+    let targets = jump_targets(ops);
+    let mut remove = vec![false; ops.len()];
+    let mut i = 0;
+    while i < ops.len() {
+        let Some(a) = constant(&ops[i].kind, constants) else {
+            i += 1;
+            continue;
+        };
+        if i + 2 < ops.len() && !targets[i + 1] && !targets[i + 2] {
+            if let Some(b) = constant(&ops[i + 1].kind, constants) {
+                if let Some(v) = fold_binary(&ops[i + 2].kind, &a, &b) {
+                    ops[i] = Op {
+                        kind: push_constant(v, constants),
+                        span: ops[i + 2].span,
+                    };
+                    remove[i + 1] = true;
+                    remove[i + 2] = true;
+                    i += 3;
+                    continue;
+                }
+            }
+        }
+        if i + 1 < ops.len() && !targets[i + 1] {
+            if let Some(v) = fold_unary(&ops[i + 1].kind, &a) {
+                ops[i] = Op {
+                    kind: push_constant(v, constants),
+                    span: ops[i + 1].span,
+                };
+                remove[i + 1] = true;
+                i += 2;
+                continue;
+            }
+        }
+        i += 1;
+    }
+    compact(ops, &remove)
+}
+
+/// `True; JumpIfFalse t`: the jump is never taken, drop it. `False; JumpIfFalse t`: it always
+/// is; if `t` pops the condition, skip both the push and that `Pop`.
+fn fold_branches(ops: &mut Vec<Op>) -> bool {
+    let targets = jump_targets(ops);
+    let mut remove = vec![false; ops.len()];
+    let mut changed = false;
+    for i in 0..ops.len().saturating_sub(1) {
+        let cond = match ops[i].kind {
+            InstrKind::True => true,
+            InstrKind::False => false,
+            _ => continue,
+        };
+        let (dst, jumps_on) = match ops[i + 1].kind {
+            InstrKind::JumpIfFalse(dst) => (dst, false),
+            InstrKind::JumpIfTrue(dst) => (dst, true),
+            _ => continue,
+        };
+        if targets[i + 1] || remove[i] {
+            continue;
+        }
+        let dst = dst as usize;
+        if cond != jumps_on {
+            remove[i + 1] = true;
+        } else if matches!(
+            ops.get(dst),
+            Some(Op {
+                kind: InstrKind::Pop,
+                ..
+            })
+        ) && dst + 1 < ops.len()
+        {
+            ops[i].kind = InstrKind::Jump(dst as u32 + 1);
+            remove[i + 1] = true;
+        } else {
+            ops[i + 1].kind = InstrKind::Jump(dst as u32);
+            changed = true;
+        }
+    }
+    compact(ops, &remove) | changed
+}
+
+/// Points jumps past the `Jump`s they land on; a `Jump` to a `Return` becomes the `Return`.
+fn thread_jumps(ops: &mut [Op]) -> bool {
+    let mut changed = false;
+    for i in 0..ops.len() {
+        let Some(start) = jump_target(&ops[i].kind) else {
+            continue;
+        };
+        let mut dst = start;
+        let mut hops = 0;
+        while let Some(Op {
+            kind: InstrKind::Jump(next),
+            ..
+        }) = ops.get(dst as usize)
+        {
+            // A cycle of jumps (an infinite loop) has no end to thread to.
+            if *next == dst || hops == ops.len() {
+                break;
+            }
+            dst = *next;
+            hops += 1;
+        }
+        if matches!(ops[i].kind, InstrKind::Jump(_))
+            && matches!(
+                ops.get(dst as usize),
+                Some(Op {
+                    kind: InstrKind::Return,
+                    ..
+                })
+            )
+        {
+            ops[i].kind = InstrKind::Return;
+            changed = true;
+        } else if dst != start {
+            *jump_target_mut(&mut ops[i].kind).expect("a jump") = dst;
+            changed = true;
+        }
+    }
+    changed
+}
+
+fn remove_unreachable(ops: &mut Vec<Op>) -> bool {
+    let mut reached = vec![false; ops.len()];
+    let mut pending = vec![0usize];
+    while let Some(i) = pending.pop() {
+        if i >= ops.len() || reached[i] {
+            continue;
+        }
+        reached[i] = true;
+        match ops[i].kind {
+            InstrKind::Return => {}
+            InstrKind::Jump(dst) => pending.push(dst as usize),
+            InstrKind::JumpIfFalse(dst) | InstrKind::JumpIfTrue(dst) => {
+                pending.push(i + 1);
+                pending.push(dst as usize);
+            }
+            _ => pending.push(i + 1),
+        }
+    }
+    let remove: Vec<bool> = reached.iter().map(|r| !r).collect();
+    compact(ops, &remove)
+}
+
+fn peephole(ops: &mut Vec<Op>) -> bool {
+    let targets = jump_targets(ops);
+    let mut remove = vec![false; ops.len()];
+    let mut i = 0;
+    while i < ops.len() {
+        let next = ops.get(i + 1).map(|op| &op.kind);
+        let next_is_free = i + 1 < ops.len() && !targets[i + 1];
+        match (&ops[i].kind, next) {
+            (push, Some(InstrKind::Pop)) if next_is_free && is_pure_push(push) => {
+                remove[i] = true;
+                remove[i + 1] = true;
+                i += 2;
+                continue;
+            }
+            (InstrKind::Jump(dst), _) if *dst as usize == i + 1 => remove[i] = true,
+            (InstrKind::CloseUpvalues(_), Some(InstrKind::Return)) if next_is_free => {
+                remove[i] = true
+            }
+            (InstrKind::CloseUpvalues(a), Some(InstrKind::CloseUpvalues(b))) if next_is_free => {
+                ops[i + 1].kind = InstrKind::CloseUpvalues(*a.min(b));
+                remove[i] = true;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    compact(ops, &remove)
+}
+
+/// Pushes that cannot fail and change nothing else, so pushing and popping right away is a
+/// no-op. (`LoadGlobal` can fail on an undefined global.)
+fn is_pure_push(kind: &InstrKind) -> bool {
+    matches!(
+        kind,
+        InstrKind::Const(_)
+            | InstrKind::Unit
+            | InstrKind::True
+            | InstrKind::False
+            | InstrKind::LoadLocal(_)
+            | InstrKind::LoadUpvalue(_)
+    )
+}
+
+/// Keeps the functions reachable from `main` (through calls, closures, function constants and
+/// globals falling back to the function of the same name) and renumbers them.
+fn remove_unused_functions(module: &mut Module) {
+    let decoded: Vec<Vec<InstrKind>> = module
+        .functions
+        .iter()
+        .map(|f| {
+            f.instrs()
+                .map(|instrs| instrs.into_iter().map(|i| i.kind).collect())
+                .unwrap_or_default()
+        })
+        .collect();
+    if module
+        .functions
+        .iter()
+        .zip(&decoded)
+        .any(|(f, d)| d.is_empty() && !f.code.is_empty())
+    {
+        return; // some code does not decode: leave the module as it is for `verify`
+    }
+
+    let mut used = vec![false; module.functions.len()];
+    let mut pending = vec![module.main];
+    while let Some(id) = pending.pop() {
+        let Some(seen) = used.get_mut(id) else {
+            continue;
+        };
+        if *seen {
+            continue;
+        }
+        *seen = true;
+        for kind in &decoded[id] {
+            let name = match kind {
+                InstrKind::Call(callee, _)
+                | InstrKind::TailCall(callee, _)
+                | InstrKind::MakeClosure(callee, _) => {
+                    pending.push(*callee as usize);
+                    continue;
+                }
+                InstrKind::Const(idx) => match module.constants.get(*idx as usize) {
+                    Some(Value::Function(name)) => name,
+                    _ => continue,
+                },
+                InstrKind::LoadGlobal(idx) => match module.globals.get(*idx as usize) {
+                    Some(name) => name,
+                    None => continue,
+                },
+                _ => continue,
+            };
+            if let Some(callee) = module.by_name.get(name) {
+                pending.push(*callee);
+            }
+        }
+    }
+    if !used.contains(&false) {
+        return;
+    }
+
+    let mut new_id: Vec<Option<FuncId>> = Vec::with_capacity(used.len());
+    let mut kept = 0;
+    for u in &used {
+        new_id.push(u.then_some(kept));
+        kept += *u as usize;
+    }
+    let renumber = |id: u32| new_id[id as usize].expect("called functions are kept") as u32;
+
+    let functions = std::mem::take(&mut module.functions);
+    for ((func, kinds), id) in functions.into_iter().zip(decoded).zip(&new_id) {
+        if id.is_none() {
+            continue;
+        }
+        let renumbered = kinds.into_iter().map(|kind| match kind {
+            InstrKind::Call(callee, argc) => InstrKind::Call(renumber(callee), argc),
+            InstrKind::TailCall(callee, argc) => InstrKind::TailCall(renumber(callee), argc),
+            InstrKind::MakeClosure(callee, captures) => {
+                InstrKind::MakeClosure(renumber(callee), captures)
+            }
+            other => other,
+        });
+        module.functions.push(Function {
+            code: reencode(&func, renumbered),
+            ..func
+        });
+    }
+    module.by_name.retain(|_, id| new_id[*id].is_some());
+    for id in module.by_name.values_mut() {
+        *id = new_id[*id].expect("retained");
+    }
+    module.main = new_id[module.main].expect("main is used");
+}
+
+fn remove_unused_constants(module: &mut Module) {
+    let mut used = vec![false; module.constants.len()];
+    for func in &module.functions {
+        for instr in func.instrs().into_iter().flatten() {
+            if let InstrKind::Const(idx) = instr.kind {
+                if let Some(u) = used.get_mut(idx as usize) {
+                    *u = true;
+                }
+            }
+        }
+    }
+    if !used.contains(&false) {
+        return;
+    }
+
+    let mut new_idx = Vec::with_capacity(used.len());
+    let mut kept = 0u32;
+    for u in &used {
+        new_idx.push(kept);
+        kept += *u as u32;
+    }
+    for func in &mut module.functions {
+        let Ok(instrs) = func.instrs() else {
+            continue;
+        };
+        let renumbered = instrs.into_iter().map(|instr| match instr.kind {
+            InstrKind::Const(idx) => InstrKind::Const(new_idx[idx as usize]),
+            other => other,
+        });
+        func.code = reencode(func, renumbered);
+    }
+    let mut i = 0;
+    module.constants.retain(|_| {
+        i += 1;
+        used[i - 1]
+    });
+}
+
+/// `func`'s code with its instructions replaced one for one by `kinds`, which encode to the
+/// same sizes (only operands that are not jump targets change), so offsets and spans stay.
+fn reencode(func: &Function, kinds: impl Iterator<Item = InstrKind>) -> Vec<u8> {
+    let mut code = Vec::with_capacity(func.code.len());
+    for kind in kinds {
+        kind.encode(&mut code);
+    }
+    debug_assert_eq!(code.len(), func.code.len());
+    code
+}
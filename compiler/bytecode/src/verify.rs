@@ -0,0 +1,326 @@
+//! Static checks on a `Module`, run before the VM executes it.
+//!
+//! The compiler only produces valid modules, but a `.moonc` file or a hand-built `Module` can
+//! hold anything. `verify` makes sure that, for every function:
+//! - the code decodes, and every jump lands on an instruction of the same function
+//! - constants, globals, local slots, upvalues and function ids are in range
+//! - `Call` passes as many arguments as the callee has parameters
+//! - the stack never underflows, every path reaching an instruction arrives with the same
+//!   stack depth, and no path runs past the end of the code
+//!
+//! Functions with no code are builtin stubs (see `BUILTINS`), or stand for host functions
+//! (`verify_with_natives`): the VM runs those itself.
+
+use moon_core::span::Span;
+use moon_runtime::Value;
+
+use crate::compiler::BUILTINS;
+use crate::instr::{Capture, Instr, InstrKind};
+use crate::module::{Function, Module};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyError {
+    pub message: String,
+    pub span: Span,
+}
+
+impl std::fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid bytecode: {}", self.message)
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+pub fn verify(module: &Module) -> Result<(), VerifyError> {
+    verify_with_natives(module, &[])
+}
+
+/// Like `verify`, for a VM that runs the functions named in `natives` with host code: those
+/// may have no code, like the builtins.
+pub fn verify_with_natives(module: &Module, natives: &[&str]) -> Result<(), VerifyError> {
+    let module_error = |message: String| VerifyError {
+        message,
+        span: Span::new(0, 0),
+    };
+    if module.get_func(module.main).is_none() {
+        return Err(module_error(format!(
+            "main function f{} does not exist",
+            module.main
+        )));
+    }
+    let mut by_name: Vec<_> = module.by_name.iter().collect();
+    by_name.sort();
+    for (name, id) in by_name {
+        match module.get_func(*id) {
+            Some(func) if func.name == *name => {}
+            Some(func) => {
+                return Err(module_error(format!(
+                    "{name} refers to f{id}, which is {}",
+                    func.name
+                )))
+            }
+            None => return Err(module_error(format!("{name} refers to missing f{id}"))),
+        }
+    }
+
+    let mut decoded = Vec::with_capacity(module.functions.len());
+    for func in &module.functions {
+        let instrs = func.instrs().map_err(|e| VerifyError {
+            message: format!("in {} at {}: {}", func.name, e.offset, e.message),
+            span: func.span_at(e.offset),
+        })?;
+        decoded.push(instrs);
+    }
+
+    // How many upvalues each function's closures carry: what `MakeClosure` gives it, the same
+    // at every site. Functions never made into closures have none.
+    let mut upvalues: Vec<Option<usize>> = vec![None; module.functions.len()];
+    for (func, instrs) in module.functions.iter().zip(&decoded) {
+        for instr in instrs {
+            let InstrKind::MakeClosure(id, captures) = &instr.kind else {
+                continue;
+            };
+            let Some(made) = upvalues.get_mut(*id as usize) else {
+                continue; // reported with the function making it
+            };
+            match *made {
+                Some(n) if n != captures.len() => {
+                    return Err(instr_error(
+                        func,
+                        instr,
+                        format!(
+                            "closures of {} are made with both {n} and {} upvalues",
+                            module.functions[*id as usize].name,
+                            captures.len()
+                        ),
+                    ))
+                }
+                _ => *made = Some(captures.len()),
+            }
+        }
+    }
+
+    for (id, (func, instrs)) in module.functions.iter().zip(&decoded).enumerate() {
+        if instrs.is_empty() {
+            let name = func.name.as_str();
+            if !BUILTINS.contains(&name) && !natives.contains(&name) {
+                return Err(module_error(format!("{} has no code", func.name)));
+            }
+            continue;
+        }
+        let check = FunctionCheck::new(module, func, instrs, upvalues[id].unwrap_or(0));
+        for instr in instrs {
+            check.operands(instr)?;
+        }
+        check.stack_depths()?;
+    }
+    Ok(())
+}
+
+/// The checks on one function's decoded instructions.
+struct FunctionCheck<'a> {
+    module: &'a Module,
+    func: &'a Function,
+    instrs: &'a [Instr],
+    upvalues: usize,
+}
+
+impl<'a> FunctionCheck<'a> {
+    fn new(module: &'a Module, func: &'a Function, instrs: &'a [Instr], upvalues: usize) -> Self {
+        Self {
+            module,
+            func,
+            instrs,
+            upvalues,
+        }
+    }
+
+    fn error(&self, instr: &Instr, message: impl std::fmt::Display) -> VerifyError {
+        instr_error(self.func, instr, message)
+    }
+
+    fn operands(&self, instr: &Instr) -> Result<(), VerifyError> {
+        let in_range = |idx: usize, len: usize, what: &str| {
+            if idx < len {
+                Ok(())
+            } else {
+                Err(self.error(instr, format!("{what} {idx} out of range ({len} in total)")))
+            }
+        };
+        // Arguments fill the first slots even if `slots` says fewer.
+        let slots = (self.func.slots as usize).max(self.func.params.len());
+
+        match &instr.kind {
+            InstrKind::Const(idx) => {
+                in_range(*idx as usize, self.module.constants.len(), "constant")?;
+                if let Value::Function(name) = &self.module.constants[*idx as usize] {
+                    if !self.module.by_name.contains_key(name) {
+                        return Err(self.error(instr, format!("undefined function: {name}")));
+                    }
+                }
+            }
+            InstrKind::LoadLocal(slot) | InstrKind::StoreLocal(slot) => {
+                in_range(*slot as usize, slots, "local slot")?
+            }
+            // Closing past the last slot is harmless: there is nothing to close.
+            InstrKind::CloseUpvalues(_) => {}
+            InstrKind::LoadUpvalue(idx) | InstrKind::StoreUpvalue(idx) => {
+                in_range(*idx as usize, self.upvalues, "upvalue")?
+            }
+            InstrKind::LoadGlobal(id)
+            | InstrKind::DefineGlobal(id)
+            | InstrKind::StoreGlobal(id) => {
+                in_range(*id as usize, self.module.globals.len(), "global")?
+            }
+            InstrKind::Jump(dst) | InstrKind::JumpIfFalse(dst) | InstrKind::JumpIfTrue(dst) => {
+                self.target(instr, *dst)?;
+            }
+            InstrKind::Call(id, argc) | InstrKind::TailCall(id, argc) => {
+                in_range(*id as usize, self.module.functions.len(), "function")?;
+                let callee = &self.module.functions[*id as usize];
+                if *argc as usize != callee.params.len() {
+                    return Err(self.error(
+                        instr,
+                        format!(
+                            "{} expects {} arguments, the call passes {argc}",
+                            callee.name,
+                            callee.params.len()
+                        ),
+                    ));
+                }
+            }
+            InstrKind::MakeClosure(id, captures) => {
+                in_range(*id as usize, self.module.functions.len(), "function")?;
+                for capture in captures {
+                    match capture {
+                        Capture::Local(slot) => in_range(*slot as usize, slots, "local slot")?,
+                        Capture::Upvalue(idx) => in_range(*idx as usize, self.upvalues, "upvalue")?,
+                    }
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Index of the instruction a jump from `instr` to `dst` lands on.
+    fn target(&self, instr: &Instr, dst: u32) -> Result<usize, VerifyError> {
+        self.instrs
+            .binary_search_by_key(&(dst as usize), |i| i.offset)
+            .map_err(|_| {
+                let why = if dst as usize >= self.func.code.len() {
+                    "is past the end of the code"
+                } else {
+                    "is not the start of an instruction"
+                };
+                self.error(instr, format!("jump target {dst} {why}"))
+            })
+    }
+
+    /// Follows every path through the code from the start, tracking how many temporaries
+    /// are on the stack (above the local slots).
+    fn stack_depths(&self) -> Result<(), VerifyError> {
+        let mut depths: Vec<Option<usize>> = vec![None; self.instrs.len()];
+        depths[0] = Some(0);
+        let mut pending = vec![0];
+
+        while let Some(i) = pending.pop() {
+            let instr = &self.instrs[i];
+            let depth = depths[i].expect("pending instructions have a depth");
+            let (pops, pushes) = stack_effect(&instr.kind);
+            if depth < pops {
+                return Err(self.error(
+                    instr,
+                    format!(
+                        "stack underflow: {} takes {pops} values, the stack has {depth}",
+                        instr.kind
+                    ),
+                ));
+            }
+            let after = depth - pops + pushes;
+
+            let mut next = Vec::with_capacity(2);
+            match instr.kind {
+                InstrKind::Return => {}
+                InstrKind::Jump(dst) => next.push(self.target(instr, dst)?),
+                InstrKind::JumpIfFalse(dst) | InstrKind::JumpIfTrue(dst) => {
+                    next.push(i + 1);
+                    next.push(self.target(instr, dst)?);
+                }
+                _ => next.push(i + 1),
+            }
+            for succ in next {
+                if succ == self.instrs.len() {
+                    return Err(self.error(instr, "runs past the end of the code"));
+                }
+                match depths[succ] {
+                    None => {
+                        depths[succ] = Some(after);
+                        pending.push(succ);
+                    }
+                    Some(seen) if seen != after => {
+                        return Err(self.error(
+                            &self.instrs[succ],
+                            format!("reached with stack depth {seen} and {after}"),
+                        ))
+                    }
+                    Some(_) => {}
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn instr_error(func: &Function, instr: &Instr, message: impl std::fmt::Display) -> VerifyError {
+    VerifyError {
+        message: format!("in {} at {}: {message}", func.name, instr.offset),
+        span: instr.span,
+    }
+}
+
+/// How many values `kind` pops, then how many it pushes. A conditional jump only peeks at
+/// its condition: it pops and pushes it back.
+fn stack_effect(kind: &InstrKind) -> (usize, usize) {
+    match kind {
+        InstrKind::Const(_)
+        | InstrKind::Unit
+        | InstrKind::True
+        | InstrKind::False
+        | InstrKind::LoadLocal(_)
+        | InstrKind::LoadUpvalue(_)
+        | InstrKind::LoadGlobal(_)
+        | InstrKind::MakeClosure(..) => (0, 1),
+        InstrKind::Pop
+        | InstrKind::StoreLocal(_)
+        | InstrKind::StoreUpvalue(_)
+        | InstrKind::DefineGlobal(_)
+        | InstrKind::StoreGlobal(_)
+        | InstrKind::Return => (1, 0),
+        InstrKind::CloseUpvalues(_) | InstrKind::Jump(_) => (0, 0),
+        InstrKind::Neg | InstrKind::Not | InstrKind::JumpIfFalse(_) | InstrKind::JumpIfTrue(_) => {
+            (1, 1)
+        }
+        InstrKind::Add
+        | InstrKind::Sub
+        | InstrKind::Mul
+        | InstrKind::Div
+        | InstrKind::Mod
+        | InstrKind::Eq
+        | InstrKind::Ne
+        | InstrKind::Lt
+        | InstrKind::Le
+        | InstrKind::Gt
+        | InstrKind::Ge
+        | InstrKind::IndexGet => (2, 1),
+        InstrKind::IndexSet => (3, 0),
+        // A tail call leaves the frame, unless the callee is a builtin: then it is an ordinary
+        // call and the code after it runs.
+        InstrKind::Call(_, argc) | InstrKind::TailCall(_, argc) => (*argc as usize, 1),
+        // The callee sits below the arguments.
+        InstrKind::CallValue(argc) | InstrKind::TailCallValue(argc) => (*argc as usize + 1, 1),
+        InstrKind::MakeArray(n) => (*n as usize, 1),
+        InstrKind::MakeObject(n) => (2 * *n as usize, 1),
+    }
+}
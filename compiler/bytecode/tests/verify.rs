@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+
+use moon_bytecode::{verify, verify_with_natives, Capture, Function, InstrKind, Module};
+use moon_runtime::Value;
+
+fn func(name: &str, params: &[&str], slots: u16, instrs: &[InstrKind]) -> Function {
+    let mut code = Vec::new();
+    for kind in instrs {
+        kind.encode(&mut code);
+    }
+    Function {
+        name: name.to_string(),
+        params: params.iter().map(|p| p.to_string()).collect(),
+        slots,
+        code,
+        spans: Vec::new(),
+    }
+}
+
+/// A module whose main is `functions[0]`; the others are callable by name.
+fn module(functions: Vec<Function>) -> Module {
+    let by_name = functions
+        .iter()
+        .enumerate()
+        .skip(1)
+        .map(|(id, f)| (f.name.clone(), id))
+        .collect::<HashMap<_, _>>();
+    Module {
+        functions,
+        by_name,
+        globals: vec!["g".to_string()],
+        constants: vec![Value::Int(1)],
+        main: 0,
+    }
+}
+
+fn main_only(instrs: &[InstrKind]) -> Module {
+    module(vec![func("<main>", &[], 1, instrs)])
+}
+
+fn error(module: &Module) -> String {
+    verify(module).unwrap_err().message
+}
+
+#[test]
+fn well_formed_modules_pass() {
+    use InstrKind::*;
+    let m = module(vec![
+        func(
+            "<main>",
+            &[],
+            1,
+            &[
+                Const(0),
+                StoreLocal(0),
+                LoadLocal(0),
+                Call(1, 1),
+                MakeClosure(2, vec![Capture::Local(0)]),
+                CallValue(0),
+                Add,
+                Return,
+            ],
+        ),
+        func("inc", &["x"], 1, &[LoadLocal(0), Const(0), Add, Return]),
+        func("<lambda#0>", &[], 0, &[LoadUpvalue(0), Return]),
+    ]);
+    verify(&m).unwrap();
+}
+
+#[test]
+fn branches_that_agree_on_the_stack_depth_pass() {
+    use InstrKind::*;
+    // if true { 1 } else { 2 }: both arms leave one value.
+    let m = main_only(&[
+        True,            // 0
+        JumpIfFalse(17), // 1
+        Pop,             // 6
+        Const(0),        // 7
+        Jump(23),        // 12
+        Pop,             // 17
+        Const(0),        // 18
+        Return,          // 23
+    ]);
+    verify(&m).unwrap();
+}
+
+#[test]
+fn jumps_past_the_end_are_rejected() {
+    use InstrKind::*;
+    let m = main_only(&[Jump(99), Unit, Return]);
+    assert_eq!(
+        error(&m),
+        "in <main> at 0: jump target 99 is past the end of the code"
+    );
+}
+
+#[test]
+fn jumps_into_the_middle_of_an_instruction_are_rejected() {
+    use InstrKind::*;
+    let m = main_only(&[Jump(6), Const(0), Return]);
+    assert_eq!(
+        error(&m),
+        "in <main> at 0: jump target 6 is not the start of an instruction"
+    );
+}
+
+#[test]
+fn stack_underflow_is_rejected() {
+    use InstrKind::*;
+    let m = main_only(&[Const(0), Add, Return]);
+    assert_eq!(
+        error(&m),
+        "in <main> at 5: stack underflow: Add takes 2 values, the stack has 1"
+    );
+}
+
+#[test]
+fn paths_merging_with_different_depths_are_rejected() {
+    use InstrKind::*;
+    // The false path skips the `Unit`, so `Return` is reached with 1 or 2 values.
+    let m = main_only(&[True, JumpIfFalse(7), Unit, Return]);
+    assert_eq!(
+        error(&m),
+        "in <main> at 7: reached with stack depth 1 and 2"
+    );
+}
+
+#[test]
+fn running_past_the_end_is_rejected() {
+    use InstrKind::*;
+    let m = main_only(&[Unit]);
+    assert_eq!(error(&m), "in <main> at 0: runs past the end of the code");
+}
+
+#[test]
+fn missing_functions_and_wrong_arities_are_rejected() {
+    use InstrKind::*;
+    let m = main_only(&[Call(7, 0), Return]);
+    assert_eq!(
+        error(&m),
+        "in <main> at 0: function 7 out of range (1 in total)"
+    );
+
+    let m = module(vec![
+        func("<main>", &[], 0, &[Unit, Call(1, 1), Return]),
+        func("two", &["a", "b"], 2, &[LoadLocal(0), Return]),
+    ]);
+    assert_eq!(
+        error(&m),
+        "in <main> at 1: two expects 2 arguments, the call passes 1"
+    );
+}
+
+#[test]
+fn out_of_range_operands_are_rejected() {
+    use InstrKind::*;
+    assert_eq!(
+        error(&main_only(&[Const(3), Return])),
+        "in <main> at 0: constant 3 out of range (1 in total)"
+    );
+    assert_eq!(
+        error(&main_only(&[LoadLocal(1), Return])),
+        "in <main> at 0: local slot 1 out of range (1 in total)"
+    );
+    assert_eq!(
+        error(&main_only(&[LoadGlobal(1), Return])),
+        "in <main> at 0: global 1 out of range (1 in total)"
+    );
+    assert_eq!(
+        error(&main_only(&[LoadUpvalue(0), Return])),
+        "in <main> at 0: upvalue 0 out of range (0 in total)"
+    );
+}
+
+#[test]
+fn a_bad_main_or_name_table_is_rejected() {
+    let mut m = main_only(&[InstrKind::Unit, InstrKind::Return]);
+    m.main = 4;
+    assert_eq!(error(&m), "main function f4 does not exist");
+
+    let mut m = main_only(&[InstrKind::Unit, InstrKind::Return]);
+    m.by_name.insert("f".to_string(), 9);
+    assert_eq!(error(&m), "f refers to missing f9");
+}
+
+#[test]
+fn only_builtins_and_natives_may_have_no_code() {
+    let m = module(vec![
+        func("<main>", &[], 0, &[InstrKind::Unit, InstrKind::Return]),
+        func("gc", &[], 0, &[]),
+    ]);
+    verify(&m).unwrap();
+
+    let m = module(vec![
+        func("<main>", &[], 0, &[InstrKind::Unit, InstrKind::Return]),
+        func("f", &[], 0, &[]),
+    ]);
+    assert_eq!(error(&m), "f has no code");
+    verify_with_natives(&m, &["f"]).unwrap();
+}
@@ -16,6 +16,7 @@ impl Program {
 pub enum Stmt {
     Let {
         name: String,
+        name_span: Span,
         ty: Option<TypeExpr>,
         expr: Expr,
         span: Span,
@@ -31,6 +32,7 @@ pub enum Stmt {
     },
     Fn {
         name: String,
+        name_span: Span,
         params: Vec<Param>,
         ret_ty: TypeExpr,
         body: Expr,
@@ -57,6 +59,7 @@ impl Stmt {
 #[derive(Debug, Clone, PartialEq)]
 pub struct Param {
     pub name: String,
+    pub name_span: Span,
     pub ty: TypeExpr,
     pub span: Span,
 }
@@ -0,0 +1,123 @@
+use std::sync::Arc;
+
+use super::{GreenElement, GreenNode, GreenToken, SyntaxKind, SyntaxNode};
+use crate::error::ParseError;
+use crate::lexer::{Token, TokenKind, Trivia, TriviaKind};
+use crate::parser::Parser;
+use crate::span::FileId;
+
+/// Builds the CST of `text` from its tokens and trivia (see `lex_with_trivia`).
+///
+/// The tree comes out of `parser::Parser`, the same run that builds the AST, so it has the
+/// grammar and the error messages of `parser::parse`; only valid programs get a tree.
+pub fn parse(
+    text: &str,
+    tokens: &[Token],
+    trivia: &[Trivia],
+    file: FileId,
+) -> Result<SyntaxNode, ParseError> {
+    let builder = TreeBuilder::new(text, trivia);
+    let (_, builder) = Parser::with_tree(tokens.to_vec(), builder).parse_program_with_tree()?;
+    Ok(builder.expect("parser keeps its tree").finish(file))
+}
+
+/// Position in the current node's children where a node may later be opened, to wrap
+/// something already parsed (the left operand of a binary expression, a callee, ...).
+#[derive(Debug, Copy, Clone, Default)]
+pub(crate) struct Checkpoint(usize);
+
+/// The green tree under construction. The parser tells it where nodes start and end and
+/// which tokens it consumes; the trivia in between is added here.
+pub(crate) struct TreeBuilder<'a> {
+    text: &'a str,
+    trivia: &'a [Trivia],
+    next_trivia: usize,
+    // Nodes being built: kind and the children collected so far.
+    stack: Vec<(SyntaxKind, Vec<GreenElement>)>,
+}
+
+impl<'a> TreeBuilder<'a> {
+    pub(crate) fn new(text: &'a str, trivia: &'a [Trivia]) -> Self {
+        Self {
+            text,
+            trivia,
+            next_trivia: 0,
+            stack: vec![(SyntaxKind::Program, Vec::new())],
+        }
+    }
+
+    /// The `Program` node, with whatever trivia follows the last token.
+    pub(crate) fn finish(mut self, file: FileId) -> SyntaxNode {
+        self.flush_trivia(self.text.len());
+        let (kind, children) = self.stack.pop().expect("program node");
+        debug_assert!(self.stack.is_empty());
+        SyntaxNode::new_root(Arc::new(GreenNode::new(kind, children)), file)
+    }
+
+    fn push(&mut self, element: GreenElement) {
+        self.stack.last_mut().expect("open node").1.push(element);
+    }
+
+    fn flush_trivia(&mut self, before: usize) {
+        while let Some(t) = self
+            .trivia
+            .get(self.next_trivia)
+            .filter(|t| t.span.start < before)
+        {
+            let kind = match t.kind {
+                TriviaKind::Whitespace => SyntaxKind::Whitespace,
+                TriviaKind::LineComment => SyntaxKind::Comment,
+            };
+            let text = self.text[t.span.start..t.span.end].to_string();
+            self.next_trivia += 1;
+            self.push(GreenElement::Token(GreenToken {
+                kind,
+                text,
+                value: None,
+            }));
+        }
+    }
+
+    /// Opens a node at the token starting at `start`. Trivia in front of it goes to the node
+    /// that is open now, so nodes start at a real token.
+    pub(crate) fn start_node(&mut self, kind: SyntaxKind, start: usize) {
+        self.flush_trivia(start);
+        self.stack.push((kind, Vec::new()));
+    }
+
+    /// A checkpoint before the token starting at `start`.
+    pub(crate) fn checkpoint(&mut self, start: usize) -> Checkpoint {
+        self.flush_trivia(start);
+        Checkpoint(self.stack.last().expect("open node").1.len())
+    }
+
+    /// Opens a node that takes the children added since `checkpoint`.
+    pub(crate) fn start_node_at(&mut self, checkpoint: Checkpoint, kind: SyntaxKind) {
+        let children = self
+            .stack
+            .last_mut()
+            .expect("open node")
+            .1
+            .split_off(checkpoint.0);
+        self.stack.push((kind, children));
+    }
+
+    pub(crate) fn finish_node(&mut self) {
+        let (kind, children) = self.stack.pop().expect("open node");
+        self.push(GreenElement::Node(Arc::new(GreenNode::new(kind, children))));
+    }
+
+    pub(crate) fn token(&mut self, token: &Token) {
+        self.flush_trivia(token.span.start);
+        let text = self.text[token.span.start..token.span.end].to_string();
+        let value = match token.kind {
+            TokenKind::Int(_) | TokenKind::String(_) => Some(token.kind.clone()),
+            _ => None,
+        };
+        self.push(GreenElement::Token(GreenToken {
+            kind: SyntaxKind::from_token(&token.kind),
+            text,
+            value,
+        }));
+    }
+}
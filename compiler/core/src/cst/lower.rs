@@ -0,0 +1,234 @@
+use super::{SyntaxKind, SyntaxNode, SyntaxToken};
+use crate::ast::{BinaryOp, Expr, Param, Program, Stmt, TypeExpr, UnaryOp};
+use crate::lexer::TokenKind;
+
+/// Derives the AST from a tree built by `cst::parse`. Spans follow the conventions of
+/// `parser::parse` (a `let` span stops before its `;`, a `return` span includes it, ...), so
+/// both routes give equal programs.
+pub fn lower(root: &SyntaxNode) -> Program {
+    assert_eq!(root.kind(), SyntaxKind::Program, "lower expects a Program");
+    let (stmts, tail) = sequence(root);
+    Program::new(stmts, tail)
+}
+
+fn sequence(node: &SyntaxNode) -> (Vec<Stmt>, Option<Expr>) {
+    let mut stmts = Vec::new();
+    let mut tail = None;
+    for child in node.child_nodes() {
+        match child.kind() {
+            SyntaxKind::LetStmt
+            | SyntaxKind::AssignStmt
+            | SyntaxKind::ReturnStmt
+            | SyntaxKind::FnStmt
+            | SyntaxKind::ExprStmt => stmts.push(stmt(&child)),
+            _ => tail = Some(expr(&child)),
+        }
+    }
+    (stmts, tail)
+}
+
+fn stmt(node: &SyntaxNode) -> Stmt {
+    let nodes = node.child_nodes();
+    let tokens = node.child_tokens();
+    match node.kind() {
+        SyntaxKind::LetStmt => {
+            let name = &tokens[1];
+            let ty = nodes.iter().find(|n| is_type(n)).map(type_expr);
+            let expr = expr(nodes.last().expect("let initializer"));
+            Stmt::Let {
+                name: name.text().to_string(),
+                name_span: name.span(),
+                ty,
+                span: tokens[0].span().merge(expr.span()),
+                expr,
+            }
+        }
+        SyntaxKind::AssignStmt => {
+            let target = expr(&nodes[0]);
+            let value = expr(&nodes[1]);
+            Stmt::Assign {
+                span: target.span().merge(value.span()),
+                target,
+                expr: value,
+            }
+        }
+        SyntaxKind::ReturnStmt => Stmt::Return {
+            expr: nodes.first().map(expr),
+            span: node.span(),
+        },
+        SyntaxKind::FnStmt => {
+            let name = &tokens[1];
+            Stmt::Fn {
+                name: name.text().to_string(),
+                name_span: name.span(),
+                params: params(&nodes[0]),
+                ret_ty: type_expr(&nodes[1]),
+                body: expr(&nodes[2]),
+                span: node.span(),
+            }
+        }
+        SyntaxKind::ExprStmt => {
+            let expr = expr(&nodes[0]);
+            Stmt::Expr {
+                span: expr.span(),
+                expr,
+            }
+        }
+        other => unreachable!("not a statement: {other:?}"),
+    }
+}
+
+fn is_type(node: &SyntaxNode) -> bool {
+    matches!(node.kind(), SyntaxKind::NamedType | SyntaxKind::GenericType)
+}
+
+fn type_expr(node: &SyntaxNode) -> TypeExpr {
+    let base = &node.child_tokens()[0];
+    match node.kind() {
+        SyntaxKind::NamedType => TypeExpr::Named(base.text().to_string(), base.span()),
+        SyntaxKind::GenericType => TypeExpr::Generic {
+            base: base.text().to_string(),
+            args: node.child_nodes().iter().map(type_expr).collect(),
+            span: node.span(),
+        },
+        other => unreachable!("not a type: {other:?}"),
+    }
+}
+
+fn params(list: &SyntaxNode) -> Vec<Param> {
+    list.child_nodes()
+        .iter()
+        .map(|param| {
+            let name = &param.child_tokens()[0];
+            let ty = type_expr(&param.child_nodes()[0]);
+            Param {
+                name: name.text().to_string(),
+                name_span: name.span(),
+                span: name.span().merge(ty.span()),
+                ty,
+            }
+        })
+        .collect()
+}
+
+fn expr(node: &SyntaxNode) -> Expr {
+    let nodes = node.child_nodes();
+    let tokens = node.child_tokens();
+    let span = node.span();
+    match node.kind() {
+        SyntaxKind::Literal => literal(&tokens[0]),
+        SyntaxKind::NameRef => Expr::Ident(tokens[0].text().to_string(), span),
+        SyntaxKind::FnExpr => Expr::Fn {
+            params: params(&nodes[0]),
+            ret_ty: type_expr(&nodes[1]),
+            body: Box::new(expr(&nodes[2])),
+            span,
+        },
+        SyntaxKind::ArrayExpr => Expr::Array {
+            elements: nodes.iter().map(expr).collect(),
+            span,
+        },
+        SyntaxKind::ObjectExpr => Expr::Object {
+            props: nodes
+                .iter()
+                .map(|prop| {
+                    let key = &prop.child_tokens()[0];
+                    let key = match key.kind() {
+                        SyntaxKind::String => string_value(key),
+                        _ => key.text().to_string(),
+                    };
+                    (key, expr(&prop.child_nodes()[0]))
+                })
+                .collect(),
+            span,
+        },
+        SyntaxKind::BlockExpr => {
+            let (stmts, tail) = sequence(node);
+            Expr::Block {
+                stmts,
+                tail: tail.map(Box::new),
+                span,
+            }
+        }
+        SyntaxKind::IfExpr => Expr::If {
+            cond: Box::new(expr(&nodes[0])),
+            then_branch: Box::new(expr(&nodes[1])),
+            else_branch: Box::new(expr(&nodes[2])),
+            span,
+        },
+        SyntaxKind::UnaryExpr => {
+            let op = match tokens[0].kind() {
+                SyntaxKind::Minus => UnaryOp::Neg,
+                SyntaxKind::Bang => UnaryOp::Not,
+                other => unreachable!("not a unary operator: {other:?}"),
+            };
+            Expr::Unary {
+                op,
+                expr: Box::new(expr(&nodes[0])),
+                span,
+            }
+        }
+        SyntaxKind::BinaryExpr => Expr::Binary {
+            lhs: Box::new(expr(&nodes[0])),
+            op: binary_op(tokens[0].kind()),
+            rhs: Box::new(expr(&nodes[1])),
+            span,
+        },
+        SyntaxKind::CallExpr => Expr::Call {
+            callee: Box::new(expr(&nodes[0])),
+            args: nodes[1].child_nodes().iter().map(expr).collect(),
+            span,
+        },
+        SyntaxKind::IndexExpr => Expr::Index {
+            target: Box::new(expr(&nodes[0])),
+            index: Box::new(expr(&nodes[1])),
+            span,
+        },
+        SyntaxKind::GroupExpr => Expr::Group {
+            expr: Box::new(expr(&nodes[0])),
+            span,
+        },
+        other => unreachable!("not an expression: {other:?}"),
+    }
+}
+
+fn literal(token: &SyntaxToken) -> Expr {
+    let span = token.span();
+    match token.kind() {
+        SyntaxKind::Int => match token.value() {
+            Some(TokenKind::Int(i)) => Expr::Int(*i, span),
+            other => unreachable!("int literal lexed as {other:?}"),
+        },
+        SyntaxKind::String => Expr::String(string_value(token), span),
+        SyntaxKind::TrueKw => Expr::Bool(true, span),
+        SyntaxKind::FalseKw => Expr::Bool(false, span),
+        other => unreachable!("not a literal: {other:?}"),
+    }
+}
+
+/// The value of a string literal, escapes resolved.
+fn string_value(token: &SyntaxToken) -> String {
+    match token.value() {
+        Some(TokenKind::String(s)) => s.clone(),
+        other => unreachable!("string literal lexed as {other:?}"),
+    }
+}
+
+fn binary_op(kind: SyntaxKind) -> BinaryOp {
+    match kind {
+        SyntaxKind::Plus => BinaryOp::Add,
+        SyntaxKind::Minus => BinaryOp::Sub,
+        SyntaxKind::Star => BinaryOp::Mul,
+        SyntaxKind::Slash => BinaryOp::Div,
+        SyntaxKind::Percent => BinaryOp::Mod,
+        SyntaxKind::EqualEqual => BinaryOp::Eq,
+        SyntaxKind::BangEqual => BinaryOp::Ne,
+        SyntaxKind::Less => BinaryOp::Lt,
+        SyntaxKind::LessEqual => BinaryOp::Le,
+        SyntaxKind::Greater => BinaryOp::Gt,
+        SyntaxKind::GreaterEqual => BinaryOp::Ge,
+        SyntaxKind::AndAnd => BinaryOp::And,
+        SyntaxKind::OrOr => BinaryOp::Or,
+        other => unreachable!("not a binary operator: {other:?}"),
+    }
+}
@@ -0,0 +1,436 @@
+//! Lossless concrete syntax tree.
+//!
+//! The AST keeps only what evaluation needs. The CST keeps every byte of the source, whitespace
+//! and comments included, so tools can reproduce the text exactly: `root.text() == source`.
+//!
+//! It is split the usual way (as in rowan/Roslyn):
+//! - the *green* tree (`GreenNode`) is immutable and position-free: each node knows its kind,
+//!   its length and its children. Identical subtrees could be shared between edits.
+//! - the *red* tree (`SyntaxNode`) is a cheap cursor over it that adds absolute offsets and
+//!   parent links, built lazily while walking.
+//!
+//! Trivia (whitespace and comments) are ordinary tokens. A node never starts or ends with
+//! trivia: what sits between two nodes belongs to their parent, and what comes after the last
+//! token belongs to the `Program`.
+//!
+//! `parse` builds the tree from the lexer output, with the parser of `parser::parse`; `lower`
+//! derives the `ast::Program` from it (equal, spans included, to what `parser::parse` produces).
+
+pub(crate) mod builder;
+mod lower;
+
+use std::fmt;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use crate::lexer::TokenKind;
+use crate::span::{FileId, Span};
+
+pub use builder::parse;
+pub use lower::lower;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum SyntaxKind {
+    // Trivia
+    Whitespace,
+    Comment,
+
+    // Tokens
+    Ident,
+    Int,
+    String,
+    LetKw,
+    FnKw,
+    ReturnKw,
+    IfKw,
+    ElseKw,
+    TrueKw,
+    FalseKw,
+    Plus,
+    Minus,
+    Arrow,
+    Star,
+    Slash,
+    Percent,
+    Bang,
+    Equal,
+    EqualEqual,
+    BangEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    AndAnd,
+    OrOr,
+    LParen,
+    RParen,
+    LBrace,
+    RBrace,
+    LBracket,
+    RBracket,
+    Hash,
+    Comma,
+    Colon,
+    Semicolon,
+
+    // Nodes
+    Program,
+    LetStmt,
+    AssignStmt,
+    ReturnStmt,
+    FnStmt,
+    ExprStmt,
+    ParamList,
+    Param,
+    NamedType,
+    GenericType,
+    Literal,
+    NameRef,
+    FnExpr,
+    ArrayExpr,
+    ObjectExpr,
+    Prop,
+    BlockExpr,
+    IfExpr,
+    UnaryExpr,
+    BinaryExpr,
+    CallExpr,
+    ArgList,
+    IndexExpr,
+    GroupExpr,
+}
+
+impl SyntaxKind {
+    pub fn is_trivia(self) -> bool {
+        matches!(self, SyntaxKind::Whitespace | SyntaxKind::Comment)
+    }
+
+    fn from_token(kind: &TokenKind) -> SyntaxKind {
+        match kind {
+            TokenKind::Ident(_) => SyntaxKind::Ident,
+            TokenKind::Int(_) => SyntaxKind::Int,
+            TokenKind::String(_) => SyntaxKind::String,
+            TokenKind::Let => SyntaxKind::LetKw,
+            TokenKind::Fn => SyntaxKind::FnKw,
+            TokenKind::Return => SyntaxKind::ReturnKw,
+            TokenKind::If => SyntaxKind::IfKw,
+            TokenKind::Else => SyntaxKind::ElseKw,
+            TokenKind::True => SyntaxKind::TrueKw,
+            TokenKind::False => SyntaxKind::FalseKw,
+            TokenKind::Plus => SyntaxKind::Plus,
+            TokenKind::Minus => SyntaxKind::Minus,
+            TokenKind::Arrow => SyntaxKind::Arrow,
+            TokenKind::Star => SyntaxKind::Star,
+            TokenKind::Slash => SyntaxKind::Slash,
+            TokenKind::Percent => SyntaxKind::Percent,
+            TokenKind::Bang => SyntaxKind::Bang,
+            TokenKind::Equal => SyntaxKind::Equal,
+            TokenKind::EqualEqual => SyntaxKind::EqualEqual,
+            TokenKind::BangEqual => SyntaxKind::BangEqual,
+            TokenKind::Less => SyntaxKind::Less,
+            TokenKind::LessEqual => SyntaxKind::LessEqual,
+            TokenKind::Greater => SyntaxKind::Greater,
+            TokenKind::GreaterEqual => SyntaxKind::GreaterEqual,
+            TokenKind::AndAnd => SyntaxKind::AndAnd,
+            TokenKind::OrOr => SyntaxKind::OrOr,
+            TokenKind::LParen => SyntaxKind::LParen,
+            TokenKind::RParen => SyntaxKind::RParen,
+            TokenKind::LBrace => SyntaxKind::LBrace,
+            TokenKind::RBrace => SyntaxKind::RBrace,
+            TokenKind::LBracket => SyntaxKind::LBracket,
+            TokenKind::RBracket => SyntaxKind::RBracket,
+            TokenKind::Hash => SyntaxKind::Hash,
+            TokenKind::Comma => SyntaxKind::Comma,
+            TokenKind::Colon => SyntaxKind::Colon,
+            TokenKind::Semicolon => SyntaxKind::Semicolon,
+            TokenKind::Eof => unreachable!("EOF is not part of the tree"),
+        }
+    }
+}
+
+// ---- Green tree ---------------------------------------------------------------------------
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GreenToken {
+    pub kind: SyntaxKind,
+    pub text: String,
+    // What the lexer made of an `Int` or `String` literal, escapes resolved.
+    pub value: Option<TokenKind>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GreenElement {
+    Node(Arc<GreenNode>),
+    Token(GreenToken),
+}
+
+impl GreenElement {
+    pub fn len(&self) -> usize {
+        match self {
+            GreenElement::Node(node) => node.len,
+            GreenElement::Token(token) => token.text.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GreenNode {
+    pub kind: SyntaxKind,
+    // Length in bytes of the text under this node.
+    pub len: usize,
+    pub children: Vec<GreenElement>,
+}
+
+impl GreenNode {
+    pub fn new(kind: SyntaxKind, children: Vec<GreenElement>) -> Self {
+        let len = children.iter().map(GreenElement::len).sum();
+        Self {
+            kind,
+            len,
+            children,
+        }
+    }
+
+    fn write_text(&self, out: &mut String) {
+        for child in &self.children {
+            match child {
+                GreenElement::Node(node) => node.write_text(out),
+                GreenElement::Token(token) => out.push_str(&token.text),
+            }
+        }
+    }
+}
+
+// ---- Red tree -----------------------------------------------------------------------------
+
+/// A node of the tree at a position in a file.
+#[derive(Clone)]
+pub struct SyntaxNode(Rc<NodeData>);
+
+struct NodeData {
+    green: Arc<GreenNode>,
+    offset: usize,
+    file: FileId,
+    parent: Option<SyntaxNode>,
+}
+
+/// A token of the tree: a child of `parent`'s green node.
+#[derive(Clone)]
+pub struct SyntaxToken {
+    parent: SyntaxNode,
+    index: usize,
+    offset: usize,
+}
+
+#[derive(Clone, Debug)]
+pub enum SyntaxElement {
+    Node(SyntaxNode),
+    Token(SyntaxToken),
+}
+
+impl SyntaxNode {
+    pub fn new_root(green: Arc<GreenNode>, file: FileId) -> Self {
+        SyntaxNode(Rc::new(NodeData {
+            green,
+            offset: 0,
+            file,
+            parent: None,
+        }))
+    }
+
+    pub fn kind(&self) -> SyntaxKind {
+        self.0.green.kind
+    }
+
+    pub fn green(&self) -> &Arc<GreenNode> {
+        &self.0.green
+    }
+
+    pub fn file(&self) -> FileId {
+        self.0.file
+    }
+
+    pub fn span(&self) -> Span {
+        let start = self.0.offset;
+        Span::in_file(self.0.file, start, start + self.0.green.len)
+    }
+
+    /// The exact source text under this node, trivia included.
+    pub fn text(&self) -> String {
+        let mut out = String::with_capacity(self.0.green.len);
+        self.0.green.write_text(&mut out);
+        out
+    }
+
+    pub fn parent(&self) -> Option<SyntaxNode> {
+        self.0.parent.clone()
+    }
+
+    /// This node's parent, its parent, and so on up to the root.
+    pub fn ancestors(&self) -> impl Iterator<Item = SyntaxNode> {
+        std::iter::successors(self.parent(), SyntaxNode::parent)
+    }
+
+    pub fn children(&self) -> Vec<SyntaxElement> {
+        let mut offset = self.0.offset;
+        let mut out = Vec::with_capacity(self.0.green.children.len());
+        for (index, child) in self.0.green.children.iter().enumerate() {
+            out.push(match child {
+                GreenElement::Node(green) => SyntaxElement::Node(SyntaxNode(Rc::new(NodeData {
+                    green: green.clone(),
+                    offset,
+                    file: self.0.file,
+                    parent: Some(self.clone()),
+                }))),
+                GreenElement::Token(_) => SyntaxElement::Token(SyntaxToken {
+                    parent: self.clone(),
+                    index,
+                    offset,
+                }),
+            });
+            offset += child.len();
+        }
+        out
+    }
+
+    pub fn child_nodes(&self) -> Vec<SyntaxNode> {
+        self.children()
+            .into_iter()
+            .filter_map(|c| match c {
+                SyntaxElement::Node(node) => Some(node),
+                SyntaxElement::Token(_) => None,
+            })
+            .collect()
+    }
+
+    /// Direct child tokens that are not trivia.
+    pub fn child_tokens(&self) -> Vec<SyntaxToken> {
+        self.children()
+            .into_iter()
+            .filter_map(|c| match c {
+                SyntaxElement::Token(token) if !token.kind().is_trivia() => Some(token),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Every token under this node, trivia included, in source order.
+    pub fn tokens(&self) -> Vec<SyntaxToken> {
+        let mut out = Vec::new();
+        for child in self.children() {
+            match child {
+                SyntaxElement::Node(node) => out.extend(node.tokens()),
+                SyntaxElement::Token(token) => out.push(token),
+            }
+        }
+        out
+    }
+
+    /// The deepest node whose text contains `offset`.
+    pub fn covering_node(&self, offset: usize) -> SyntaxNode {
+        let mut node = self.clone();
+        'descend: loop {
+            for child in node.child_nodes() {
+                let sp = child.span();
+                if sp.start <= offset && offset < sp.end {
+                    node = child;
+                    continue 'descend;
+                }
+            }
+            return node;
+        }
+    }
+
+    /// An indented dump of the tree, one element per line (`LetStmt@0..10`, `Ident@4..5 "x"`).
+    pub fn debug_tree(&self) -> String {
+        let mut out = String::new();
+        self.write_tree(&mut out, 0);
+        out
+    }
+
+    fn write_tree(&self, out: &mut String, depth: usize) {
+        let sp = self.span();
+        out.push_str(&format!(
+            "{}{:?}@{}..{}\n",
+            "  ".repeat(depth),
+            self.kind(),
+            sp.start,
+            sp.end
+        ));
+        for child in self.children() {
+            match child {
+                SyntaxElement::Node(node) => node.write_tree(out, depth + 1),
+                SyntaxElement::Token(token) => {
+                    let sp = token.span();
+                    out.push_str(&format!(
+                        "{}{:?}@{}..{} {:?}\n",
+                        "  ".repeat(depth + 1),
+                        token.kind(),
+                        sp.start,
+                        sp.end,
+                        token.text()
+                    ));
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Debug for SyntaxNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sp = self.span();
+        write!(f, "{:?}@{}..{}", self.kind(), sp.start, sp.end)
+    }
+}
+
+impl SyntaxToken {
+    fn green(&self) -> &GreenToken {
+        match &self.parent.0.green.children[self.index] {
+            GreenElement::Token(token) => token,
+            GreenElement::Node(_) => unreachable!("token index points at a node"),
+        }
+    }
+
+    pub fn kind(&self) -> SyntaxKind {
+        self.green().kind
+    }
+
+    pub fn text(&self) -> &str {
+        &self.green().text
+    }
+
+    /// The lexed value of an `Int` or `String` token.
+    pub fn value(&self) -> Option<&TokenKind> {
+        self.green().value.as_ref()
+    }
+
+    pub fn span(&self) -> Span {
+        Span::in_file(
+            self.parent.0.file,
+            self.offset,
+            self.offset + self.green().text.len(),
+        )
+    }
+
+    pub fn parent(&self) -> &SyntaxNode {
+        &self.parent
+    }
+}
+
+impl fmt::Debug for SyntaxToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sp = self.span();
+        write!(
+            f,
+            "{:?}@{}..{} {:?}",
+            self.kind(),
+            sp.start,
+            sp.end,
+            self.text()
+        )
+    }
+}
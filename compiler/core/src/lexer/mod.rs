@@ -1,7 +1,7 @@
 use crate::error::LexError;
-use crate::span::Span;
+use crate::span::{FileId, Span};
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum TokenKind {
     Ident(String),
     Int(i64),
@@ -54,26 +54,76 @@ pub struct Token {
     pub span: Span,
 }
 
+/// Text between tokens. The parser never sees it; tools that reproduce or highlight the source
+/// (formatter, semantic tokens) get it from `lex_with_trivia`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TriviaKind {
+    Whitespace,
+    // `// ...` up to (not including) the newline.
+    LineComment,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Trivia {
+    pub kind: TriviaKind,
+    pub span: Span,
+}
+
 pub fn lex(input: &str) -> Result<Vec<Token>, LexError> {
+    lex_file(input, FileId::default())
+}
+
+/// Like `lex`, but every span is tagged with `file` (see `SourceMap`).
+pub fn lex_file(input: &str, file: FileId) -> Result<Vec<Token>, LexError> {
+    lex_impl(input, file, None)
+}
+
+/// Like `lex_file`, but also returns the trivia (whitespace runs and comments), in order.
+/// Tokens and trivia together cover the input exactly.
+pub fn lex_with_trivia(input: &str, file: FileId) -> Result<(Vec<Token>, Vec<Trivia>), LexError> {
+    let mut trivia = Vec::new();
+    let tokens = lex_impl(input, file, Some(&mut trivia))?;
+    Ok((tokens, trivia))
+}
+
+fn lex_impl(
+    input: &str,
+    file: FileId,
+    mut trivia: Option<&mut Vec<Trivia>>,
+) -> Result<Vec<Token>, LexError> {
     let mut tokens = Vec::new();
     let bytes = input.as_bytes();
     let mut i = 0usize;
+    let mut push_trivia = |kind, start, end| {
+        if let Some(trivia) = trivia.as_deref_mut() {
+            trivia.push(Trivia {
+                kind,
+                span: Span::in_file(file, start, end),
+            });
+        }
+    };
 
     while i < bytes.len() {
         let b = bytes[i];
 
         // Whitespace
         if b == b' ' || b == b'\t' || b == b'\n' || b == b'\r' {
-            i += 1;
+            let start = i;
+            while i < bytes.len() && matches!(bytes[i], b' ' | b'\t' | b'\n' | b'\r') {
+                i += 1;
+            }
+            push_trivia(TriviaKind::Whitespace, start, i);
             continue;
         }
 
         // Line comment: //...
         if b == b'/' && i + 1 < bytes.len() && bytes[i + 1] == b'/' {
+            let start = i;
             i += 2;
             while i < bytes.len() && bytes[i] != b'\n' {
                 i += 1;
             }
+            push_trivia(TriviaKind::LineComment, start, i);
             continue;
         }
 
@@ -97,7 +147,7 @@ pub fn lex(input: &str) -> Result<Vec<Token>, LexError> {
             };
             tokens.push(Token {
                 kind,
-                span: Span::new(start, i),
+                span: Span::in_file(file, start, i),
             });
             continue;
         }
@@ -112,11 +162,11 @@ pub fn lex(input: &str) -> Result<Vec<Token>, LexError> {
             let text = &input[start..i];
             let value = text.parse::<i64>().map_err(|_| LexError {
                 message: format!("invalid integer literal: {text}"),
-                span: Span::new(start, i),
+                span: Span::in_file(file, start, i),
             })?;
             tokens.push(Token {
                 kind: TokenKind::Int(value),
-                span: Span::new(start, i),
+                span: Span::in_file(file, start, i),
             });
             continue;
         }
@@ -133,7 +183,7 @@ pub fn lex(input: &str) -> Result<Vec<Token>, LexError> {
                     i += 1; // skip closing quote
                     tokens.push(Token {
                         kind: TokenKind::String(out),
-                        span: Span::new(start, i),
+                        span: Span::in_file(file, start, i),
                     });
                     closed = true;
                     break;
@@ -145,7 +195,7 @@ pub fn lex(input: &str) -> Result<Vec<Token>, LexError> {
                     if i >= bytes.len() {
                         return Err(LexError {
                             message: "unterminated string literal".to_string(),
-                            span: Span::new(start, i),
+                            span: Span::in_file(file, start, i),
                         });
                     }
                     let esc = bytes[i];
@@ -157,7 +207,7 @@ pub fn lex(input: &str) -> Result<Vec<Token>, LexError> {
                         _ => {
                             return Err(LexError {
                                 message: format!("unknown escape: \\{}", esc as char),
-                                span: Span::new(i - 1, i + 1),
+                                span: Span::in_file(file, i - 1, i + 1),
                             })
                         }
                     };
@@ -175,14 +225,14 @@ pub fn lex(input: &str) -> Result<Vec<Token>, LexError> {
                 return Err(LexError {
                     message: "non-ascii characters are not supported in string literals yet"
                         .to_string(),
-                    span: Span::new(i, i + 1),
+                    span: Span::in_file(file, i, i + 1),
                 });
             }
 
             if !closed {
                 return Err(LexError {
                     message: "unterminated string literal".to_string(),
-                    span: Span::new(start, input.len()),
+                    span: Span::in_file(file, start, input.len()),
                 });
             }
 
@@ -246,7 +296,7 @@ pub fn lex(input: &str) -> Result<Vec<Token>, LexError> {
                 } else {
                     return Err(LexError {
                         message: "unexpected '&' (did you mean '&&'?)".to_string(),
-                        span: Span::new(i, i + 1),
+                        span: Span::in_file(file, i, i + 1),
                     });
                 }
             }
@@ -256,7 +306,7 @@ pub fn lex(input: &str) -> Result<Vec<Token>, LexError> {
                 } else {
                     return Err(LexError {
                         message: "unexpected '|' (did you mean '||'?)".to_string(),
-                        span: Span::new(i, i + 1),
+                        span: Span::in_file(file, i, i + 1),
                     });
                 }
             }
@@ -264,7 +314,7 @@ pub fn lex(input: &str) -> Result<Vec<Token>, LexError> {
             _ => {
                 return Err(LexError {
                     message: format!("unexpected character: '{}'", b as char),
-                    span: Span::new(i, i + 1),
+                    span: Span::in_file(file, i, i + 1),
                 })
             }
         };
@@ -272,13 +322,13 @@ pub fn lex(input: &str) -> Result<Vec<Token>, LexError> {
         i += len;
         tokens.push(Token {
             kind,
-            span: Span::new(start, i),
+            span: Span::in_file(file, start, i),
         });
     }
 
     tokens.push(Token {
         kind: TokenKind::Eof,
-        span: Span::new(input.len(), input.len()),
+        span: Span::in_file(file, input.len(), input.len()),
     });
 
     Ok(tokens)
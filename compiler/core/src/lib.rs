@@ -1,6 +1,9 @@
 pub mod ast;
+pub mod cst;
 pub mod error;
 pub mod lexer;
+pub mod line_index;
 pub mod parser;
+pub mod resolver;
 pub mod source;
 pub mod span;
@@ -0,0 +1,141 @@
+/// Precomputed line starts for a text, so offset <-> (line, col) conversions are O(log n)
+/// instead of rescanning the text on every call.
+///
+/// All lines/columns here are 0-based. Columns come in two flavours:
+/// - UTF-8 columns: byte offset from the start of the line (what the CLI renders)
+/// - UTF-16 columns: code units from the start of the line (what LSP positions use)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineIndex {
+    len: usize,
+    // Byte offset where each line starts. Always contains at least `0`.
+    line_starts: Vec<usize>,
+    // Non-ASCII chars per line (only lines that have any), needed for UTF-16 columns.
+    wide_chars: Vec<(u32, Vec<WideChar>)>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct LineCol {
+    pub line: u32,
+    pub col: u32,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct WideChar {
+    // Byte column where the char starts.
+    start: u32,
+    len_utf8: u32,
+    len_utf16: u32,
+}
+
+impl LineIndex {
+    pub fn new(text: &str) -> Self {
+        let mut line_starts = vec![0];
+        let mut wide_chars: Vec<(u32, Vec<WideChar>)> = Vec::new();
+        let mut line = 0u32;
+        let mut line_start = 0usize;
+
+        for (i, ch) in text.char_indices() {
+            if ch == '\n' {
+                line += 1;
+                line_start = i + 1;
+                line_starts.push(line_start);
+                continue;
+            }
+            if !ch.is_ascii() {
+                let wide = WideChar {
+                    start: (i - line_start) as u32,
+                    len_utf8: ch.len_utf8() as u32,
+                    len_utf16: ch.len_utf16() as u32,
+                };
+                match wide_chars.last_mut() {
+                    Some((l, chars)) if *l == line => chars.push(wide),
+                    _ => wide_chars.push((line, vec![wide])),
+                }
+            }
+        }
+
+        Self {
+            len: text.len(),
+            line_starts,
+            wide_chars,
+        }
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.line_starts.len()
+    }
+
+    /// Maps a byte offset (clamped to the text) to its 0-based line and UTF-8 column.
+    pub fn line_col(&self, offset: usize) -> LineCol {
+        let offset = offset.min(self.len);
+        let line = self.line_starts.partition_point(|&s| s <= offset) - 1;
+        LineCol {
+            line: line as u32,
+            col: (offset - self.line_starts[line]) as u32,
+        }
+    }
+
+    /// Maps a byte offset to its 0-based line and UTF-16 column.
+    pub fn utf16_line_col(&self, offset: usize) -> LineCol {
+        let LineCol { line, col } = self.line_col(offset);
+        let mut col16 = col;
+        for wide in self.wide_chars_on(line) {
+            if wide.start >= col {
+                break;
+            }
+            // An offset inside a char counts the whole char (like iterating `char_indices`).
+            col16 = col16 + wide.len_utf16 - wide.len_utf8.min(col - wide.start);
+        }
+        LineCol { line, col: col16 }
+    }
+
+    /// Maps a 0-based line + UTF-8 column back to a byte offset. Out-of-range positions clamp
+    /// to the end of the line (or the text).
+    pub fn offset(&self, pos: LineCol) -> usize {
+        let Some((start, end)) = self.line_range(pos.line) else {
+            return self.len;
+        };
+        (start + pos.col as usize).min(end)
+    }
+
+    /// Maps a 0-based line + UTF-16 column back to a byte offset. A column in the middle of a
+    /// surrogate pair lands on the end of that char.
+    pub fn offset_utf16(&self, pos: LineCol) -> usize {
+        let Some((start, end)) = self.line_range(pos.line) else {
+            return self.len;
+        };
+
+        // Running (UTF-16 col - UTF-8 col) for the chars before the one being looked at.
+        let target = pos.col as i64;
+        let mut delta = 0i64;
+        for wide in self.wide_chars_on(pos.line) {
+            let wide_start16 = wide.start as i64 + delta;
+            if target <= wide_start16 {
+                break;
+            }
+            if target < wide_start16 + wide.len_utf16 as i64 {
+                return (start + (wide.start + wide.len_utf8) as usize).min(end);
+            }
+            delta += wide.len_utf16 as i64 - wide.len_utf8 as i64;
+        }
+        (start + (target - delta) as usize).min(end)
+    }
+
+    /// Byte range of a 0-based line, excluding the trailing `\n`.
+    pub fn line_range(&self, line: u32) -> Option<(usize, usize)> {
+        let line = line as usize;
+        let start = *self.line_starts.get(line)?;
+        let end = match self.line_starts.get(line + 1) {
+            Some(next) => next - 1,
+            None => self.len,
+        };
+        Some((start, end))
+    }
+
+    fn wide_chars_on(&self, line: u32) -> &[WideChar] {
+        match self.wide_chars.binary_search_by_key(&line, |(l, _)| *l) {
+            Ok(i) => &self.wide_chars[i].1,
+            Err(_) => &[],
+        }
+    }
+}
@@ -1,10 +1,18 @@
 use crate::ast::{BinaryOp, Expr, Param, Program, Stmt, TypeExpr, UnaryOp};
+use crate::cst::builder::{Checkpoint, TreeBuilder};
+use crate::cst::SyntaxKind;
 use crate::error::ParseError;
 use crate::lexer::{Token, TokenKind};
 
-pub struct Parser {
+/// Messages of errors tools recognize (the LSP offers quick fixes for them).
+pub const EXPECTED_LET_SEMICOLON: &str = "expected ';' after let statement";
+pub const EXPECTED_ELSE: &str = "expected 'else'";
+
+/// The grammar of Moon. It builds the AST and, for `cst::parse`, the lossless tree alongside.
+pub struct Parser<'a> {
     tokens: Vec<Token>,
     pos: usize,
+    tree: Option<TreeBuilder<'a>>,
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -13,17 +21,36 @@ enum Terminator {
     RBrace,
 }
 
-impl Parser {
+impl<'a> Parser<'a> {
     pub fn new(tokens: Vec<Token>) -> Self {
-        Self { tokens, pos: 0 }
+        Self {
+            tokens,
+            pos: 0,
+            tree: None,
+        }
+    }
+
+    pub(crate) fn with_tree(tokens: Vec<Token>, tree: TreeBuilder<'a>) -> Self {
+        Self {
+            tokens,
+            pos: 0,
+            tree: Some(tree),
+        }
+    }
+
+    pub fn parse_program(self) -> Result<Program, ParseError> {
+        self.parse_program_with_tree().map(|(program, _)| program)
     }
 
-    pub fn parse_program(mut self) -> Result<Program, ParseError> {
+    pub(crate) fn parse_program_with_tree(
+        mut self,
+    ) -> Result<(Program, Option<TreeBuilder<'a>>), ParseError> {
         let (stmts, tail) = self.parse_sequence(Terminator::Eof)?;
-        Ok(Program::new(stmts, tail))
+        Ok((Program::new(stmts, tail), self.tree))
     }
 
     fn parse_let_stmt(&mut self) -> Result<Stmt, ParseError> {
+        self.start_node(SyntaxKind::LetStmt);
         let let_tok = self.expect(|k| matches!(k, TokenKind::Let), "expected 'let'")?;
 
         let name_tok = self.next();
@@ -51,12 +78,14 @@ impl Parser {
 
         self.expect(
             |k| matches!(k, TokenKind::Semicolon),
-            "expected ';' after let statement",
+            EXPECTED_LET_SEMICOLON,
         )?;
+        self.finish_node();
 
         let span = let_tok.span.merge(expr.span());
         Ok(Stmt::Let {
             name,
+            name_span: name_tok.span,
             ty,
             expr,
             span,
@@ -64,10 +93,12 @@ impl Parser {
     }
 
     fn parse_return_stmt(&mut self) -> Result<Stmt, ParseError> {
+        self.start_node(SyntaxKind::ReturnStmt);
         let ret_tok = self.expect(|k| matches!(k, TokenKind::Return), "expected 'return'")?;
 
         // `return;`
         if let Some(semi) = self.maybe(|k| matches!(k, TokenKind::Semicolon)) {
+            self.finish_node();
             let span = ret_tok.span.merge(semi.span);
             return Ok(Stmt::Return { expr: None, span });
         }
@@ -78,6 +109,7 @@ impl Parser {
             |k| matches!(k, TokenKind::Semicolon),
             "expected ';' after return",
         )?;
+        self.finish_node();
 
         let span = ret_tok.span.merge(semi.span);
         Ok(Stmt::Return {
@@ -87,6 +119,7 @@ impl Parser {
     }
 
     fn parse_fn_stmt(&mut self) -> Result<Stmt, ParseError> {
+        self.start_node(SyntaxKind::FnStmt);
         let fn_tok = self.expect(|k| matches!(k, TokenKind::Fn), "expected 'fn'")?;
 
         let name_tok = self.next();
@@ -100,49 +133,45 @@ impl Parser {
             }
         };
 
-        self.expect(
-            |k| matches!(k, TokenKind::LParen),
-            "expected '(' after fn name",
-        )?;
-        let mut params = Vec::new();
-        if !matches!(self.peek().kind, TokenKind::RParen) {
-            loop {
-                let param_name_tok = self.next();
-                let param_name = match param_name_tok.kind {
-                    TokenKind::Ident(s) => s,
-                    _ => {
-                        return Err(ParseError {
-                            message: "expected parameter name".to_string(),
-                            span: param_name_tok.span,
-                        })
-                    }
-                };
+        let (params, ret_ty, body) = self.parse_signature("expected '(' after fn name")?;
+        self.finish_node();
+        let span = fn_tok.span.merge(body.span());
+        Ok(Stmt::Fn {
+            name,
+            name_span: name_tok.span,
+            params,
+            ret_ty,
+            body,
+            span,
+        })
+    }
 
-                self.expect(
-                    |k| matches!(k, TokenKind::Colon),
-                    "expected ':' after parameter name",
-                )?;
-                let ty = self.parse_type()?;
-                let span = param_name_tok.span.merge(ty.span());
-                params.push(Param {
-                    name: param_name,
-                    ty,
-                    span,
-                });
+    fn parse_fn_expr(&mut self, start: Checkpoint, fn_tok: Token) -> Result<Expr, ParseError> {
+        self.start_node_at(start, SyntaxKind::FnExpr);
+        let (params, ret_ty, body) = self.parse_signature("expected '(' after 'fn'")?;
+        self.finish_node();
+        let span = fn_tok.span.merge(body.span());
+        Ok(Expr::Fn {
+            params,
+            ret_ty,
+            body: Box::new(body),
+            span,
+        })
+    }
 
-                if self.maybe(|k| matches!(k, TokenKind::Comma)).is_some() {
-                    if matches!(self.peek().kind, TokenKind::RParen) {
-                        break;
-                    }
-                    continue;
-                }
-                break;
-            }
-        }
-        self.expect(
+    /// `(params) -> Type { body }`, after `fn` or `fn name`.
+    fn parse_signature(
+        &mut self,
+        open_message: &'static str,
+    ) -> Result<(Vec<Param>, TypeExpr, Expr), ParseError> {
+        self.start_node(SyntaxKind::ParamList);
+        self.expect(|k| matches!(k, TokenKind::LParen), open_message)?;
+        let (params, _) = self.parse_comma_list(
             |k| matches!(k, TokenKind::RParen),
             "expected ')' after parameters",
+            Self::parse_param,
         )?;
+        self.finish_node();
 
         self.expect(
             |k| matches!(k, TokenKind::Arrow),
@@ -151,50 +180,51 @@ impl Parser {
         let ret_ty = self.parse_type()?;
 
         let body = self.parse_block_expr()?;
-        let span = fn_tok.span.merge(body.span());
-        Ok(Stmt::Fn {
-            name,
-            params,
-            ret_ty,
-            body,
-            span,
-        })
+        Ok((params, ret_ty, body))
     }
 
-    fn parse_fn_expr(&mut self, fn_tok: Token) -> Result<Expr, ParseError> {
+    fn parse_param(&mut self) -> Result<Param, ParseError> {
+        self.start_node(SyntaxKind::Param);
+        let name_tok = self.next();
+        let name = match name_tok.kind {
+            TokenKind::Ident(s) => s,
+            _ => {
+                return Err(ParseError {
+                    message: "expected parameter name".to_string(),
+                    span: name_tok.span,
+                })
+            }
+        };
+
         self.expect(
-            |k| matches!(k, TokenKind::LParen),
-            "expected '(' after 'fn'",
+            |k| matches!(k, TokenKind::Colon),
+            "expected ':' after parameter name",
         )?;
+        let ty = self.parse_type()?;
+        self.finish_node();
+        let span = name_tok.span.merge(ty.span());
+        Ok(Param {
+            name,
+            name_span: name_tok.span,
+            ty,
+            span,
+        })
+    }
 
-        let mut params = Vec::new();
-        if !matches!(self.peek().kind, TokenKind::RParen) {
+    /// `item, item, ...` with an optional trailing comma, then the closing token.
+    fn parse_comma_list<T>(
+        &mut self,
+        close: fn(&TokenKind) -> bool,
+        message: &'static str,
+        mut item: impl FnMut(&mut Self) -> Result<T, ParseError>,
+    ) -> Result<(Vec<T>, Token), ParseError> {
+        let mut items = Vec::new();
+        if !close(&self.peek().kind) {
             loop {
-                let param_name_tok = self.next();
-                let param_name = match param_name_tok.kind {
-                    TokenKind::Ident(s) => s,
-                    _ => {
-                        return Err(ParseError {
-                            message: "expected parameter name".to_string(),
-                            span: param_name_tok.span,
-                        })
-                    }
-                };
-
-                self.expect(
-                    |k| matches!(k, TokenKind::Colon),
-                    "expected ':' after parameter name",
-                )?;
-                let ty = self.parse_type()?;
-                let span = param_name_tok.span.merge(ty.span());
-                params.push(Param {
-                    name: param_name,
-                    ty,
-                    span,
-                });
+                items.push(item(self)?);
 
                 if self.maybe(|k| matches!(k, TokenKind::Comma)).is_some() {
-                    if matches!(self.peek().kind, TokenKind::RParen) {
+                    if close(&self.peek().kind) {
                         break;
                     }
                     continue;
@@ -202,44 +232,24 @@ impl Parser {
                 break;
             }
         }
-
-        self.expect(
-            |k| matches!(k, TokenKind::RParen),
-            "expected ')' after parameters",
-        )?;
-
-        self.expect(
-            |k| matches!(k, TokenKind::Arrow),
-            "expected '->' after parameters",
-        )?;
-        let ret_ty = self.parse_type()?;
-
-        let body = self.parse_block_expr()?;
-        let span = fn_tok.span.merge(body.span());
-        Ok(Expr::Fn {
-            params,
-            ret_ty,
-            body: Box::new(body),
-            span,
-        })
+        let close = self.expect(close, message)?;
+        Ok((items, close))
     }
 
     fn parse_expr(&mut self, min_prec: u8) -> Result<Expr, ParseError> {
+        let start = self.checkpoint();
         let mut lhs = self.parse_prefix()?;
-        lhs = self.parse_postfix(lhs)?;
-
-        loop {
-            let (op, prec) = match self.peek_infix() {
-                Some(x) => x,
-                None => break,
-            };
+        lhs = self.parse_postfix(start, lhs)?;
 
+        while let Some((op, prec)) = self.peek_infix() {
             if prec < min_prec {
                 break;
             }
 
+            self.start_node_at(start, SyntaxKind::BinaryExpr);
             let op_tok = self.next();
             let rhs = self.parse_expr(prec + 1)?;
+            self.finish_node();
             let span = lhs.span().merge(rhs.span());
             lhs = Expr::Binary {
                 lhs: Box::new(lhs),
@@ -253,20 +263,38 @@ impl Parser {
     }
 
     fn parse_prefix(&mut self) -> Result<Expr, ParseError> {
+        let start = self.checkpoint();
         let tok = self.next();
         match tok.kind {
-            TokenKind::Int(i) => Ok(Expr::Int(i, tok.span)),
-            TokenKind::True => Ok(Expr::Bool(true, tok.span)),
-            TokenKind::False => Ok(Expr::Bool(false, tok.span)),
-            TokenKind::String(s) => Ok(Expr::String(s, tok.span)),
-            TokenKind::Ident(s) => Ok(Expr::Ident(s, tok.span)),
-            TokenKind::Fn => self.parse_fn_expr(tok),
-            TokenKind::If => self.parse_if_expr(tok),
-            TokenKind::LBrace => self.parse_block_expr_from_open(tok),
-            TokenKind::LBracket => self.parse_array_expr_from_open(tok),
-            TokenKind::Hash => self.parse_object_expr(tok),
+            TokenKind::Int(i) => {
+                self.leaf_node(start, SyntaxKind::Literal);
+                Ok(Expr::Int(i, tok.span))
+            }
+            TokenKind::True => {
+                self.leaf_node(start, SyntaxKind::Literal);
+                Ok(Expr::Bool(true, tok.span))
+            }
+            TokenKind::False => {
+                self.leaf_node(start, SyntaxKind::Literal);
+                Ok(Expr::Bool(false, tok.span))
+            }
+            TokenKind::String(s) => {
+                self.leaf_node(start, SyntaxKind::Literal);
+                Ok(Expr::String(s, tok.span))
+            }
+            TokenKind::Ident(s) => {
+                self.leaf_node(start, SyntaxKind::NameRef);
+                Ok(Expr::Ident(s, tok.span))
+            }
+            TokenKind::Fn => self.parse_fn_expr(start, tok),
+            TokenKind::If => self.parse_if_expr(start, tok),
+            TokenKind::LBrace => self.parse_block_expr_from_open(start, tok),
+            TokenKind::LBracket => self.parse_array_expr_from_open(start, tok),
+            TokenKind::Hash => self.parse_object_expr(start, tok),
             TokenKind::Minus => {
+                self.start_node_at(start, SyntaxKind::UnaryExpr);
                 let expr = self.parse_expr(7)?;
+                self.finish_node();
                 Ok(Expr::Unary {
                     op: UnaryOp::Neg,
                     span: tok.span.merge(expr.span()),
@@ -274,7 +302,9 @@ impl Parser {
                 })
             }
             TokenKind::Bang => {
+                self.start_node_at(start, SyntaxKind::UnaryExpr);
                 let expr = self.parse_expr(7)?;
+                self.finish_node();
                 Ok(Expr::Unary {
                     op: UnaryOp::Not,
                     span: tok.span.merge(expr.span()),
@@ -282,8 +312,10 @@ impl Parser {
                 })
             }
             TokenKind::LParen => {
+                self.start_node_at(start, SyntaxKind::GroupExpr);
                 let expr = self.parse_expr(0)?;
                 let close = self.expect(|k| matches!(k, TokenKind::RParen), "expected ')'")?;
+                self.finish_node();
                 Ok(Expr::Group {
                     span: tok.span.merge(close.span),
                     expr: Box::new(expr),
@@ -296,14 +328,14 @@ impl Parser {
         }
     }
 
-    fn parse_postfix(&mut self, mut expr: Expr) -> Result<Expr, ParseError> {
+    fn parse_postfix(&mut self, start: Checkpoint, mut expr: Expr) -> Result<Expr, ParseError> {
         loop {
             if matches!(self.peek().kind, TokenKind::LParen) {
-                expr = self.parse_call_expr(expr)?;
+                expr = self.parse_call_expr(start, expr)?;
                 continue;
             }
             if matches!(self.peek().kind, TokenKind::LBracket) {
-                expr = self.parse_index_expr(expr)?;
+                expr = self.parse_index_expr(start, expr)?;
                 continue;
             }
             break;
@@ -311,24 +343,17 @@ impl Parser {
         Ok(expr)
     }
 
-    fn parse_call_expr(&mut self, callee: Expr) -> Result<Expr, ParseError> {
+    fn parse_call_expr(&mut self, start: Checkpoint, callee: Expr) -> Result<Expr, ParseError> {
+        self.start_node_at(start, SyntaxKind::CallExpr);
+        self.start_node(SyntaxKind::ArgList);
         let open = self.expect(|k| matches!(k, TokenKind::LParen), "expected '('")?;
-        let mut args = Vec::new();
-        if !matches!(self.peek().kind, TokenKind::RParen) {
-            loop {
-                let arg = self.parse_expr(0)?;
-                args.push(arg);
-
-                if self.maybe(|k| matches!(k, TokenKind::Comma)).is_some() {
-                    if matches!(self.peek().kind, TokenKind::RParen) {
-                        break;
-                    }
-                    continue;
-                }
-                break;
-            }
-        }
-        let close = self.expect(|k| matches!(k, TokenKind::RParen), "expected ')'")?;
+        let (args, close) = self.parse_comma_list(
+            |k| matches!(k, TokenKind::RParen),
+            "expected ')'",
+            |p| p.parse_expr(0),
+        )?;
+        self.finish_node();
+        self.finish_node();
 
         let span = callee.span().merge(open.span).merge(close.span);
         Ok(Expr::Call {
@@ -338,10 +363,12 @@ impl Parser {
         })
     }
 
-    fn parse_index_expr(&mut self, target: Expr) -> Result<Expr, ParseError> {
+    fn parse_index_expr(&mut self, start: Checkpoint, target: Expr) -> Result<Expr, ParseError> {
+        self.start_node_at(start, SyntaxKind::IndexExpr);
         let open = self.expect(|k| matches!(k, TokenKind::LBracket), "expected '['")?;
         let index = self.parse_expr(0)?;
         let close = self.expect(|k| matches!(k, TokenKind::RBracket), "expected ']'")?;
+        self.finish_node();
 
         let span = target.span().merge(open.span).merge(close.span);
         Ok(Expr::Index {
@@ -351,76 +378,69 @@ impl Parser {
         })
     }
 
-    fn parse_array_expr_from_open(&mut self, open: Token) -> Result<Expr, ParseError> {
-        let mut elements = Vec::new();
-        if !matches!(self.peek().kind, TokenKind::RBracket) {
-            loop {
-                let elem = self.parse_expr(0)?;
-                elements.push(elem);
-
-                if self.maybe(|k| matches!(k, TokenKind::Comma)).is_some() {
-                    if matches!(self.peek().kind, TokenKind::RBracket) {
-                        break;
-                    }
-                    continue;
-                }
-                break;
-            }
-        }
-        let close = self.expect(|k| matches!(k, TokenKind::RBracket), "expected ']'")?;
+    fn parse_array_expr_from_open(
+        &mut self,
+        start: Checkpoint,
+        open: Token,
+    ) -> Result<Expr, ParseError> {
+        self.start_node_at(start, SyntaxKind::ArrayExpr);
+        let (elements, close) = self.parse_comma_list(
+            |k| matches!(k, TokenKind::RBracket),
+            "expected ']'",
+            |p| p.parse_expr(0),
+        )?;
+        self.finish_node();
         let span = open.span.merge(close.span);
         Ok(Expr::Array { elements, span })
     }
 
-    fn parse_object_expr(&mut self, hash: Token) -> Result<Expr, ParseError> {
+    fn parse_object_expr(&mut self, start: Checkpoint, hash: Token) -> Result<Expr, ParseError> {
+        self.start_node_at(start, SyntaxKind::ObjectExpr);
         self.expect(|k| matches!(k, TokenKind::LBrace), "expected '{' after '#'")?;
+        let (props, close) = self.parse_comma_list(
+            |k| matches!(k, TokenKind::RBrace),
+            "expected '}'",
+            Self::parse_prop,
+        )?;
+        self.finish_node();
+        let span = hash.span.merge(close.span);
+        Ok(Expr::Object { props, span })
+    }
 
-        let mut props: Vec<(String, Expr)> = Vec::new();
-        if !matches!(self.peek().kind, TokenKind::RBrace) {
-            loop {
-                let key_tok = self.next();
-                let key = match key_tok.kind {
-                    TokenKind::Ident(s) => s,
-                    TokenKind::String(s) => s,
-                    _ => {
-                        return Err(ParseError {
-                            message: "expected object key (identifier or string)".to_string(),
-                            span: key_tok.span,
-                        })
-                    }
-                };
-
-                self.expect(|k| matches!(k, TokenKind::Colon), "expected ':' after key")?;
-                let value = self.parse_expr(0)?;
-                props.push((key, value));
-
-                if self.maybe(|k| matches!(k, TokenKind::Comma)).is_some() {
-                    if matches!(self.peek().kind, TokenKind::RBrace) {
-                        break;
-                    }
-                    continue;
-                }
-                break;
+    fn parse_prop(&mut self) -> Result<(String, Expr), ParseError> {
+        self.start_node(SyntaxKind::Prop);
+        let key_tok = self.next();
+        let key = match key_tok.kind {
+            TokenKind::Ident(s) => s,
+            TokenKind::String(s) => s,
+            _ => {
+                return Err(ParseError {
+                    message: "expected object key (identifier or string)".to_string(),
+                    span: key_tok.span,
+                })
             }
-        }
+        };
 
-        let close = self.expect(|k| matches!(k, TokenKind::RBrace), "expected '}'")?;
-        let span = hash.span.merge(close.span);
-        Ok(Expr::Object { props, span })
+        self.expect(|k| matches!(k, TokenKind::Colon), "expected ':' after key")?;
+        let value = self.parse_expr(0)?;
+        self.finish_node();
+        Ok((key, value))
     }
 
-    fn parse_if_expr(&mut self, if_tok: Token) -> Result<Expr, ParseError> {
+    fn parse_if_expr(&mut self, start: Checkpoint, if_tok: Token) -> Result<Expr, ParseError> {
+        self.start_node_at(start, SyntaxKind::IfExpr);
         let cond = self.parse_expr(0)?;
 
         let then_branch = self.parse_block_expr()?;
 
-        self.expect(|k| matches!(k, TokenKind::Else), "expected 'else'")?;
+        self.expect(|k| matches!(k, TokenKind::Else), EXPECTED_ELSE)?;
 
         let else_branch = match self.peek().kind {
             TokenKind::If => {
                 // else if ...
+                let start = self.checkpoint();
                 let tok = self.next();
-                self.parse_if_expr(tok)?
+                self.parse_if_expr(start, tok)?
             }
             TokenKind::LBrace => self.parse_block_expr()?,
             _ => {
@@ -431,6 +451,7 @@ impl Parser {
                 });
             }
         };
+        self.finish_node();
 
         let span = if_tok.span.merge(else_branch.span());
         Ok(Expr::If {
@@ -442,13 +463,20 @@ impl Parser {
     }
 
     fn parse_block_expr(&mut self) -> Result<Expr, ParseError> {
+        let start = self.checkpoint();
         let open = self.expect(|k| matches!(k, TokenKind::LBrace), "expected '{'")?;
-        self.parse_block_expr_from_open(open)
+        self.parse_block_expr_from_open(start, open)
     }
 
-    fn parse_block_expr_from_open(&mut self, open: Token) -> Result<Expr, ParseError> {
+    fn parse_block_expr_from_open(
+        &mut self,
+        start: Checkpoint,
+        open: Token,
+    ) -> Result<Expr, ParseError> {
+        self.start_node_at(start, SyntaxKind::BlockExpr);
         let (stmts, tail) = self.parse_sequence(Terminator::RBrace)?;
         let close = self.expect(|k| matches!(k, TokenKind::RBrace), "expected '}'")?;
+        self.finish_node();
 
         let span = open.span.merge(close.span);
         Ok(Expr::Block {
@@ -499,10 +527,11 @@ impl Parser {
                 _ => {}
             }
 
+            let start = self.checkpoint();
             let expr = self.parse_expr(0)?;
 
             // Assignment statement: <lvalue> = <expr>;
-            if self.maybe(|k| matches!(k, TokenKind::Equal)).is_some() {
+            if matches!(self.peek().kind, TokenKind::Equal) {
                 if !is_assignable(&expr) {
                     return Err(ParseError {
                         message: "invalid assignment target".to_string(),
@@ -510,11 +539,14 @@ impl Parser {
                     });
                 }
 
+                self.start_node_at(start, SyntaxKind::AssignStmt);
+                self.next();
                 let rhs = self.parse_expr(0)?;
                 self.expect(
                     |k| matches!(k, TokenKind::Semicolon),
                     "expected ';' after assignment",
                 )?;
+                self.finish_node();
 
                 let span = expr.span().merge(rhs.span());
                 stmts.push(Stmt::Assign {
@@ -525,7 +557,10 @@ impl Parser {
                 continue;
             }
 
-            if self.maybe(|k| matches!(k, TokenKind::Semicolon)).is_some() {
+            if matches!(self.peek().kind, TokenKind::Semicolon) {
+                self.start_node_at(start, SyntaxKind::ExprStmt);
+                self.next();
+                self.finish_node();
                 stmts.push(Stmt::Expr {
                     span: expr.span(),
                     expr,
@@ -548,35 +583,40 @@ impl Parser {
     }
 
     fn parse_type(&mut self) -> Result<TypeExpr, ParseError> {
+        let start = self.checkpoint();
         let tok = self.next();
         match tok.kind {
             TokenKind::Ident(base) => {
                 let base_span = tok.span;
-                if self.maybe(|k| matches!(k, TokenKind::Less)).is_some() {
-                    let mut args = Vec::new();
-                    if matches!(self.peek().kind, TokenKind::Greater) {
-                        return Err(ParseError {
-                            message: "expected type argument".to_string(),
-                            span: self.peek().span,
-                        });
-                    }
-                    loop {
-                        let ty = self.parse_type()?;
-                        args.push(ty);
-                        if self.maybe(|k| matches!(k, TokenKind::Comma)).is_some() {
-                            continue;
-                        }
-                        break;
+                if !matches!(self.peek().kind, TokenKind::Less) {
+                    self.leaf_node(start, SyntaxKind::NamedType);
+                    return Ok(TypeExpr::Named(base, base_span));
+                }
+
+                self.start_node_at(start, SyntaxKind::GenericType);
+                self.next();
+                let mut args = Vec::new();
+                if matches!(self.peek().kind, TokenKind::Greater) {
+                    return Err(ParseError {
+                        message: "expected type argument".to_string(),
+                        span: self.peek().span,
+                    });
+                }
+                loop {
+                    let ty = self.parse_type()?;
+                    args.push(ty);
+                    if self.maybe(|k| matches!(k, TokenKind::Comma)).is_some() {
+                        continue;
                     }
-                    let close = self.expect(
-                        |k| matches!(k, TokenKind::Greater),
-                        "expected '>' to close type arguments",
-                    )?;
-                    let span = base_span.merge(close.span);
-                    Ok(TypeExpr::Generic { base, args, span })
-                } else {
-                    Ok(TypeExpr::Named(base, base_span))
+                    break;
                 }
+                let close = self.expect(
+                    |k| matches!(k, TokenKind::Greater),
+                    "expected '>' to close type arguments",
+                )?;
+                self.finish_node();
+                let span = base_span.merge(close.span);
+                Ok(TypeExpr::Generic { base, args, span })
             }
             _ => Err(ParseError {
                 message: "expected type name".to_string(),
@@ -605,6 +645,43 @@ impl Parser {
         Some(op)
     }
 
+    // ---- Tree building (no-ops unless a `TreeBuilder` is attached) --------------------------
+
+    fn start_node(&mut self, kind: SyntaxKind) {
+        let start = self.peek().span.start;
+        if let Some(tree) = &mut self.tree {
+            tree.start_node(kind, start);
+        }
+    }
+
+    fn checkpoint(&mut self) -> Checkpoint {
+        let start = self.peek().span.start;
+        match &mut self.tree {
+            Some(tree) => tree.checkpoint(start),
+            None => Checkpoint::default(),
+        }
+    }
+
+    fn start_node_at(&mut self, checkpoint: Checkpoint, kind: SyntaxKind) {
+        if let Some(tree) = &mut self.tree {
+            tree.start_node_at(checkpoint, kind);
+        }
+    }
+
+    fn finish_node(&mut self) {
+        if let Some(tree) = &mut self.tree {
+            tree.finish_node();
+        }
+    }
+
+    /// Wraps the token consumed since `checkpoint` in a node of its own.
+    fn leaf_node(&mut self, checkpoint: Checkpoint, kind: SyntaxKind) {
+        self.start_node_at(checkpoint, kind);
+        self.finish_node();
+    }
+
+    // ---- Tokens -------------------------------------------------------------------------
+
     fn peek(&self) -> &Token {
         self.tokens
             .get(self.pos)
@@ -614,6 +691,9 @@ impl Parser {
     fn next(&mut self) -> Token {
         let tok = self.peek().clone();
         if !matches!(tok.kind, TokenKind::Eof) {
+            if let Some(tree) = &mut self.tree {
+                tree.token(&tok);
+            }
             self.pos += 1;
         }
         tok
@@ -0,0 +1,296 @@
+use std::collections::HashMap;
+
+use crate::ast::{Expr, Param, Program, Stmt};
+use crate::span::Span;
+
+pub type DefId = usize;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DefKind {
+    Function,
+    Global,
+    Local,
+    Param,
+    Builtin,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Definition {
+    pub name: String,
+    pub kind: DefKind,
+    // Span of the defining name (`x` in `let x = ...`). Builtins have an empty span.
+    pub span: Span,
+    // Region of the source where the binding is in scope.
+    pub scope: Span,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Reference {
+    pub def: DefId,
+    pub span: Span,
+    // `true` for assignment targets (`x = ...`).
+    pub write: bool,
+}
+
+/// Result of binding every identifier use in a program to its definition.
+#[derive(Debug, Clone, Default)]
+pub struct Resolution {
+    pub defs: Vec<Definition>,
+    pub refs: Vec<Reference>,
+    pub unresolved: Vec<(String, Span)>,
+}
+
+impl Resolution {
+    pub fn definition(&self, def: DefId) -> &Definition {
+        &self.defs[def]
+    }
+
+    /// The definition named at `offset`, either through a use or the defining name itself.
+    /// The end of a span counts as inside it, so a cursor right after an identifier works.
+    pub fn def_at(&self, offset: usize) -> Option<DefId> {
+        let hit = |sp: Span| sp.start <= offset && offset <= sp.end && sp.start < sp.end;
+        self.refs
+            .iter()
+            .find(|r| hit(r.span))
+            .map(|r| r.def)
+            .or_else(|| self.defs.iter().position(|d| hit(d.span)))
+    }
+
+    pub fn references(&self, def: DefId) -> impl Iterator<Item = &Reference> {
+        self.refs.iter().filter(move |r| r.def == def)
+    }
+
+    /// Definitions visible at `offset`, innermost first; shadowed bindings are skipped.
+    pub fn visible_at(&self, offset: usize) -> Vec<DefId> {
+        let mut visible: Vec<DefId> = (0..self.defs.len())
+            .filter(|&id| {
+                let scope = self.defs[id].scope;
+                scope.start <= offset && offset <= scope.end
+            })
+            .collect();
+        // The innermost binding of a name starts last.
+        visible.sort_by_key(|&id| std::cmp::Reverse(self.defs[id].scope.start));
+        let mut seen = std::collections::HashSet::new();
+        visible.retain(|&id| seen.insert(self.defs[id].name.clone()));
+        visible
+    }
+}
+
+pub fn resolve(program: &Program) -> Resolution {
+    Resolver::new(program).run(program)
+}
+
+struct Scope {
+    names: HashMap<String, DefId>,
+    end: usize,
+}
+
+struct Resolver {
+    res: Resolution,
+    globals: HashMap<String, DefId>,
+    funcs: HashMap<String, DefId>,
+    scopes: Vec<Scope>,
+    program_span: Span,
+}
+
+impl Resolver {
+    fn new(program: &Program) -> Self {
+        let spans: Vec<Span> = program
+            .stmts
+            .iter()
+            .map(Stmt::span)
+            .chain(program.tail.as_ref().map(Expr::span))
+            .collect();
+        let file = spans.first().map(|sp| sp.file).unwrap_or_default();
+        let end = spans.iter().map(|sp| sp.end).max().unwrap_or(0);
+        let program_span = Span::in_file(file, 0, end);
+        Self {
+            res: Resolution::default(),
+            globals: HashMap::new(),
+            funcs: HashMap::new(),
+            scopes: Vec::new(),
+            program_span,
+        }
+    }
+
+    fn run(mut self, program: &Program) -> Resolution {
+        let everywhere = self.program_span;
+
+        // Builtins.
+        let gc = self.define("gc", DefKind::Builtin, Span::new(0, 0), everywhere);
+        self.funcs.insert("gc".to_string(), gc);
+
+        // Functions are items: visible in the whole program, regardless of order.
+        for stmt in &program.stmts {
+            if let Stmt::Fn {
+                name, name_span, ..
+            } = stmt
+            {
+                let id = self.define(name, DefKind::Function, *name_span, everywhere);
+                self.funcs.insert(name.clone(), id);
+            }
+        }
+
+        self.resolve_stmts(&program.stmts);
+        if let Some(tail) = &program.tail {
+            self.resolve_expr(tail);
+        }
+        self.res
+    }
+
+    fn define(&mut self, name: &str, kind: DefKind, span: Span, scope: Span) -> DefId {
+        self.res.defs.push(Definition {
+            name: name.to_string(),
+            kind,
+            span,
+            scope,
+        });
+        self.res.defs.len() - 1
+    }
+
+    fn define_var(&mut self, name: &str, span: Span, visible_from: usize) -> DefId {
+        match self.scopes.last() {
+            Some(scope) => {
+                let scope_span = Span::in_file(span.file, visible_from, scope.end);
+                let id = self.define(name, DefKind::Local, span, scope_span);
+                self.scopes
+                    .last_mut()
+                    .expect("scope exists")
+                    .names
+                    .insert(name.to_string(), id);
+                id
+            }
+            None => {
+                let scope_span = Span::in_file(span.file, visible_from, self.program_span.end);
+                let id = self.define(name, DefKind::Global, span, scope_span);
+                self.globals.insert(name.to_string(), id);
+                id
+            }
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Option<DefId> {
+        // Same order as the typechecker/interpreter: locals, globals, then functions.
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|s| s.names.get(name).copied())
+            .or_else(|| self.globals.get(name).copied())
+            .or_else(|| self.funcs.get(name).copied())
+    }
+
+    fn use_name(&mut self, name: &str, span: Span, write: bool) {
+        match self.lookup(name) {
+            Some(def) => self.res.refs.push(Reference { def, span, write }),
+            None => self.res.unresolved.push((name.to_string(), span)),
+        }
+    }
+
+    fn with_params(&mut self, params: &[Param], body: &Expr) {
+        let body_span = body.span();
+        let mut names = HashMap::new();
+        for p in params {
+            let id = self.define(&p.name, DefKind::Param, p.name_span, body_span);
+            names.insert(p.name.clone(), id);
+        }
+        self.scopes.push(Scope {
+            names,
+            end: body_span.end,
+        });
+        self.resolve_expr(body);
+        self.scopes.pop();
+    }
+
+    fn resolve_stmts(&mut self, stmts: &[Stmt]) {
+        for stmt in stmts {
+            match stmt {
+                Stmt::Let {
+                    name,
+                    name_span,
+                    expr,
+                    span,
+                    ..
+                } => {
+                    // The initializer sees the outer binding (`let x = x + 1;`).
+                    self.resolve_expr(expr);
+                    self.define_var(name, *name_span, span.end);
+                }
+                Stmt::Assign { target, expr, .. } => {
+                    match target {
+                        Expr::Ident(name, sp) => self.use_name(name, *sp, true),
+                        other => self.resolve_expr(other),
+                    }
+                    self.resolve_expr(expr);
+                }
+                Stmt::Return { expr, .. } => {
+                    if let Some(expr) = expr {
+                        self.resolve_expr(expr);
+                    }
+                }
+                Stmt::Fn { params, body, .. } => {
+                    // Function bodies see globals and functions, but not the caller's locals.
+                    let saved = std::mem::take(&mut self.scopes);
+                    self.with_params(params, body);
+                    self.scopes = saved;
+                }
+                Stmt::Expr { expr, .. } => self.resolve_expr(expr),
+            }
+        }
+    }
+
+    fn resolve_expr(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Int(..) | Expr::Bool(..) | Expr::String(..) => {}
+            Expr::Ident(name, sp) => self.use_name(name, *sp, false),
+            Expr::Fn { params, body, .. } => self.with_params(params, body),
+            Expr::Array { elements, .. } => {
+                for e in elements {
+                    self.resolve_expr(e);
+                }
+            }
+            Expr::Object { props, .. } => {
+                for (_, v) in props {
+                    self.resolve_expr(v);
+                }
+            }
+            Expr::Block {
+                stmts, tail, span, ..
+            } => {
+                self.scopes.push(Scope {
+                    names: HashMap::new(),
+                    end: span.end,
+                });
+                self.resolve_stmts(stmts);
+                if let Some(tail) = tail {
+                    self.resolve_expr(tail);
+                }
+                self.scopes.pop();
+            }
+            Expr::If {
+                cond,
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                self.resolve_expr(cond);
+                self.resolve_expr(then_branch);
+                self.resolve_expr(else_branch);
+            }
+            Expr::Unary { expr, .. } | Expr::Group { expr, .. } => self.resolve_expr(expr),
+            Expr::Binary { lhs, rhs, .. } => {
+                self.resolve_expr(lhs);
+                self.resolve_expr(rhs);
+            }
+            Expr::Call { callee, args, .. } => {
+                self.resolve_expr(callee);
+                for a in args {
+                    self.resolve_expr(a);
+                }
+            }
+            Expr::Index { target, index, .. } => {
+                self.resolve_expr(target);
+                self.resolve_expr(index);
+            }
+        }
+    }
+}
@@ -1,10 +1,15 @@
-use crate::span::Span;
+use crate::line_index::{LineCol, LineIndex};
+use crate::span::{FileId, Span};
 use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 
 #[derive(Debug, Clone)]
 pub struct Source {
     pub path: PathBuf,
-    pub text: String,
+    // Never changes after `new`, so the line index built from it stays right.
+    text: String,
+    // Built on first use.
+    line_index: OnceLock<LineIndex>,
 }
 
 impl Source {
@@ -12,31 +17,28 @@ impl Source {
         Self {
             path: path.into(),
             text: text.into(),
+            line_index: OnceLock::new(),
         }
     }
 
     pub fn from_path(path: impl AsRef<Path>) -> std::io::Result<Self> {
         let path = path.as_ref().to_path_buf();
         let text = std::fs::read_to_string(&path)?;
-        Ok(Self { path, text })
+        Ok(Self::new(path, text))
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn line_index(&self) -> &LineIndex {
+        self.line_index.get_or_init(|| LineIndex::new(&self.text))
     }
 
     pub fn line_col(&self, offset: usize) -> (usize, usize) {
         // 1-based line/col.
-        let mut line = 1usize;
-        let mut col = 1usize;
-        for (i, b) in self.text.as_bytes().iter().enumerate() {
-            if i >= offset {
-                break;
-            }
-            if *b == b'\n' {
-                line += 1;
-                col = 1;
-            } else {
-                col += 1;
-            }
-        }
-        (line, col)
+        let LineCol { line, col } = self.line_index().line_col(offset);
+        (line as usize + 1, col as usize + 1)
     }
 
     pub fn render_span(&self, span: Span, message: &str) -> String {
@@ -45,11 +47,10 @@ impl Source {
         let (line, col) = self.line_col(start);
 
         // Extract the line that contains `start`.
-        let line_start = self.text[..start].rfind('\n').map(|i| i + 1).unwrap_or(0);
-        let line_end = self.text[start..]
-            .find('\n')
-            .map(|i| start + i)
-            .unwrap_or(self.text.len());
+        let (line_start, line_end) = self
+            .line_index()
+            .line_range(line as u32 - 1)
+            .unwrap_or((start, start));
 
         let line_text = &self.text[line_start..line_end];
 
@@ -75,3 +76,64 @@ impl Source {
         )
     }
 }
+
+/// Registry of every source file in a compilation, so a `Span` (which carries a `FileId`)
+/// can always be resolved back to its path, line and column.
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+    files: Vec<Source>,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, source: Source) -> FileId {
+        let id = FileId(self.files.len() as u32);
+        self.files.push(source);
+        id
+    }
+
+    pub fn get(&self, file: FileId) -> Option<&Source> {
+        self.files.get(file.0 as usize)
+    }
+
+    pub fn find_by_path(&self, path: &Path) -> Option<FileId> {
+        self.files
+            .iter()
+            .position(|s| s.path == path)
+            .map(|i| FileId(i as u32))
+    }
+
+    pub fn files(&self) -> impl Iterator<Item = (FileId, &Source)> {
+        self.files
+            .iter()
+            .enumerate()
+            .map(|(i, s)| (FileId(i as u32), s))
+    }
+
+    /// Resolves a span to `(path, line, col)` (1-based line/col).
+    pub fn location(&self, span: Span) -> Option<(&Path, usize, usize)> {
+        let source = self.get(span.file)?;
+        let (line, col) = source.line_col(span.start);
+        Some((source.path.as_path(), line, col))
+    }
+
+    pub fn render_span(&self, span: Span, message: &str) -> String {
+        match self.get(span.file) {
+            Some(source) => source.render_span(span, message),
+            // Unknown file: still report the message instead of dropping it.
+            None => format!("<unknown file {}>: {}", span.file.0, message),
+        }
+    }
+}
+
+impl std::ops::Index<FileId> for SourceMap {
+    type Output = Source;
+
+    fn index(&self, file: FileId) -> &Source {
+        self.get(file)
+            .expect("file is registered in the source map")
+    }
+}
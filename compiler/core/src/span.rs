@@ -1,16 +1,33 @@
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// Identifies a source file registered in a `SourceMap`.
+///
+/// `FileId(0)` is the default file: single-file tools (tests, `lex(text)`) never need to
+/// think about file identity.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default, PartialOrd, Ord)]
+pub struct FileId(pub u32);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub struct Span {
+    pub file: FileId,
     pub start: usize,
     pub end: usize,
 }
 
 impl Span {
     pub fn new(start: usize, end: usize) -> Self {
-        Self { start, end }
+        Self {
+            file: FileId::default(),
+            start,
+            end,
+        }
+    }
+
+    pub fn in_file(file: FileId, start: usize, end: usize) -> Self {
+        Self { file, start, end }
     }
 
     pub fn merge(self, other: Span) -> Span {
         Span {
+            file: self.file,
             start: self.start.min(other.start),
             end: self.end.max(other.end),
         }
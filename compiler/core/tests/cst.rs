@@ -0,0 +1,141 @@
+use std::fs;
+use std::path::PathBuf;
+
+use moon_core::cst::{self, SyntaxKind, SyntaxNode};
+use moon_core::lexer::{lex_with_trivia, TokenKind};
+use moon_core::parser::parse;
+use moon_core::span::FileId;
+
+fn tree(src: &str) -> SyntaxNode {
+    let (tokens, trivia) = lex_with_trivia(src, FileId(3)).unwrap();
+    cst::parse(src, &tokens, &trivia, FileId(3)).unwrap()
+}
+
+// Text round trip, and lowering gives the very AST (spans included) of the regular parser.
+fn assert_lossless(src: &str) {
+    let root = tree(src);
+    assert_eq!(root.text(), src);
+    assert_eq!(root.span().end, src.len());
+
+    let (tokens, _) = lex_with_trivia(src, FileId(3)).unwrap();
+    assert_eq!(cst::lower(&root), parse(tokens).unwrap(), "{src}");
+}
+
+#[test]
+fn round_trips_every_construct() {
+    let sources = [
+        "",
+        "   // only a comment\n",
+        "let x: Array<Int> = [1, 2,]; x",
+        "let o = #{ a: 1, \"b c\": 2, }; o[\"a\"] = 3; o",
+        "fn add(a: Int, b: Int,) -> Int { a + b }\nadd(1, 2)",
+        "let f = fn(x: Int) -> Int { return x * 2; }; f(3)",
+        "fn g() -> Unit { return; } g();",
+        "if 1 < 2 && !false { -1 } else if true { (2 + 3) % 4 } else { 0 }",
+        "let xs = [[1]]; xs[0][0] = 2; xs[0][0] >= 1 || xs == xs",
+        "let s = \"tab\\t \\\"quoted\\\" \\\\ \\n\"; { let t = s; t }",
+        "  let  a =1 ;// trailing\n\n\t{ a } // tail comment\n",
+    ];
+    for src in sources {
+        assert_lossless(src);
+    }
+}
+
+#[test]
+fn round_trips_the_examples() {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../../examples");
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().is_some_and(|e| e == "moon") {
+            assert_lossless(&fs::read_to_string(&path).unwrap());
+        }
+    }
+}
+
+#[test]
+fn trivia_sits_between_nodes_not_at_their_edges() {
+    let root = tree("// doc\nlet x = 1 + // why\n  2;\nx ");
+    let let_stmt = &root.child_nodes()[0];
+    assert_eq!(let_stmt.kind(), SyntaxKind::LetStmt);
+    assert_eq!(let_stmt.text(), "let x = 1 + // why\n  2;");
+
+    // The comment inside the expression belongs to the binary node it splits.
+    let binary = &let_stmt.child_nodes()[0];
+    assert_eq!(binary.kind(), SyntaxKind::BinaryExpr);
+    assert!(binary
+        .tokens()
+        .iter()
+        .any(|t| t.kind() == SyntaxKind::Comment && t.text() == "// why"));
+
+    // Leading comment and trailing whitespace belong to the program.
+    let program_tokens: Vec<SyntaxKind> = root
+        .children()
+        .into_iter()
+        .filter_map(|c| match c {
+            cst::SyntaxElement::Token(t) => Some(t.kind()),
+            cst::SyntaxElement::Node(_) => None,
+        })
+        .collect();
+    assert_eq!(
+        program_tokens,
+        [
+            SyntaxKind::Comment,
+            SyntaxKind::Whitespace,
+            SyntaxKind::Whitespace,
+            SyntaxKind::Whitespace
+        ]
+    );
+}
+
+#[test]
+fn navigates_up_and_down_with_positions() {
+    let src = "fn f(n: Int) -> Int { n * 2 }";
+    let root = tree(src);
+    let at = src.find("n * 2").unwrap();
+
+    let name = root.covering_node(at);
+    assert_eq!(name.kind(), SyntaxKind::NameRef);
+    assert_eq!(name.span().file, FileId(3));
+    let kinds: Vec<SyntaxKind> = name.ancestors().map(|n| n.kind()).collect();
+    assert_eq!(
+        kinds,
+        [
+            SyntaxKind::BinaryExpr,
+            SyntaxKind::BlockExpr,
+            SyntaxKind::FnStmt,
+            SyntaxKind::Program
+        ]
+    );
+
+    let dump = root.debug_tree();
+    assert!(dump.starts_with("Program@0..29\n  FnStmt@0..29\n    FnKw@0..2 \"fn\"\n"));
+    assert!(dump.contains("      Param@5..11\n"));
+}
+
+#[test]
+fn literal_tokens_keep_their_lexed_value() {
+    let root = tree("[12, \"a\\n\"]");
+    let values: Vec<TokenKind> = root
+        .tokens()
+        .iter()
+        .filter_map(|t| t.value().cloned())
+        .collect();
+    assert_eq!(
+        values,
+        [TokenKind::Int(12), TokenKind::String("a\n".to_string())]
+    );
+}
+
+#[test]
+fn reports_the_parser_errors() {
+    let src = "let x = 1\nx";
+    let (tokens, trivia) = lex_with_trivia(src, FileId(0)).unwrap();
+    let err = cst::parse(src, &tokens, &trivia, FileId(0)).unwrap_err();
+    let expected = parse(tokens).unwrap_err();
+    assert_eq!((err.message, err.span), (expected.message, expected.span));
+
+    let src = "1 + 2 = 3;";
+    let (tokens, trivia) = lex_with_trivia(src, FileId(0)).unwrap();
+    let err = cst::parse(src, &tokens, &trivia, FileId(0)).unwrap_err();
+    assert_eq!(err.message, "invalid assignment target");
+}
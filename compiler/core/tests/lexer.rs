@@ -0,0 +1,36 @@
+use moon_core::lexer::{lex_file, lex_with_trivia, TokenKind, TriviaKind};
+use moon_core::span::FileId;
+
+#[test]
+fn trivia_and_tokens_cover_the_input() {
+    let src = "// header\nlet x = 1; // one\n\n  x";
+    let (tokens, trivia) = lex_with_trivia(src, FileId(0)).unwrap();
+
+    let mut pieces: Vec<(usize, usize)> = tokens
+        .iter()
+        .filter(|t| !matches!(t.kind, TokenKind::Eof))
+        .map(|t| (t.span.start, t.span.end))
+        .chain(trivia.iter().map(|t| (t.span.start, t.span.end)))
+        .collect();
+    pieces.sort();
+    let mut pos = 0;
+    for (start, end) in pieces {
+        assert_eq!(start, pos, "gap or overlap at {start}");
+        pos = end;
+    }
+    assert_eq!(pos, src.len());
+
+    let comments: Vec<&str> = trivia
+        .iter()
+        .filter(|t| t.kind == TriviaKind::LineComment)
+        .map(|t| &src[t.span.start..t.span.end])
+        .collect();
+    assert_eq!(comments, ["// header", "// one"]);
+}
+
+#[test]
+fn trivia_does_not_change_tokens() {
+    let src = "fn f() -> Int { 1 } // done\nf()";
+    let (tokens, _) = lex_with_trivia(src, FileId(3)).unwrap();
+    assert_eq!(tokens, lex_file(src, FileId(3)).unwrap());
+}
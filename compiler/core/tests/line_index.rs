@@ -0,0 +1,58 @@
+use moon_core::line_index::{LineCol, LineIndex};
+use moon_core::source::Source;
+
+// Reference implementation: what `Source::line_col` and the LSP used to compute by scanning.
+fn naive_utf16(text: &str, offset: usize) -> (u32, u32) {
+    let mut line = 0u32;
+    let mut col = 0u32;
+    for (i, ch) in text.char_indices() {
+        if i >= offset {
+            break;
+        }
+        if ch == '\n' {
+            line += 1;
+            col = 0;
+        } else {
+            col += ch.len_utf16() as u32;
+        }
+    }
+    (line, col)
+}
+
+const TEXT: &str = "let s = \"h\u{e9}llo\";\n\n// \u{1F600} x \u{1F600}\nlet y = s;";
+
+#[test]
+fn utf16_columns_match_a_linear_scan() {
+    let index = LineIndex::new(TEXT);
+    for (offset, _) in TEXT.char_indices().chain([(TEXT.len(), ' ')]) {
+        let LineCol { line, col } = index.utf16_line_col(offset);
+        assert_eq!((line, col), naive_utf16(TEXT, offset), "offset {offset}");
+        assert_eq!(index.offset_utf16(LineCol { line, col }), offset);
+    }
+}
+
+#[test]
+fn utf8_columns_roundtrip() {
+    let index = LineIndex::new(TEXT);
+    assert_eq!(index.line_count(), 4);
+    for offset in 0..=TEXT.len() {
+        let pos = index.line_col(offset);
+        assert_eq!(index.offset(pos), offset);
+    }
+}
+
+#[test]
+fn positions_past_the_line_end_clamp() {
+    let index = LineIndex::new("ab\ncd");
+    assert_eq!(index.offset_utf16(LineCol { line: 0, col: 10 }), 2);
+    assert_eq!(index.offset_utf16(LineCol { line: 7, col: 0 }), 5);
+    assert_eq!(index.line_range(1), Some((3, 5)));
+}
+
+#[test]
+fn source_line_col_is_one_based() {
+    let source = Source::new("t.moon", "a\nbc\n");
+    assert_eq!(source.line_col(0), (1, 1));
+    assert_eq!(source.line_col(3), (2, 2));
+    assert_eq!(source.line_col(5), (3, 1));
+}
@@ -0,0 +1,85 @@
+use moon_core::lexer::lex;
+use moon_core::parser::parse;
+use moon_core::resolver::{resolve, DefKind, Resolution};
+
+fn resolve_src(src: &str) -> Resolution {
+    resolve(&parse(lex(src).unwrap()).unwrap())
+}
+
+// Offset of the `nth` occurrence of `needle` in `src`.
+fn at(src: &str, needle: &str, nth: usize) -> usize {
+    src.match_indices(needle).nth(nth).unwrap().0
+}
+
+#[test]
+fn uses_bind_to_the_innermost_shadowing_definition() {
+    let src = "let x = 1; { let x = 2; x } + x";
+    let res = resolve_src(src);
+
+    let inner = res.def_at(at(src, "x", 2)).unwrap();
+    assert_eq!(res.definition(inner).kind, DefKind::Local);
+    assert_eq!(res.definition(inner).span.start, at(src, "x", 1));
+
+    let outer = res.def_at(at(src, "x", 3)).unwrap();
+    assert_eq!(res.definition(outer).kind, DefKind::Global);
+    assert_eq!(res.definition(outer).span.start, at(src, "x", 0));
+}
+
+#[test]
+fn let_initializer_sees_the_outer_binding() {
+    let src = "let x = 1; { let x = x + 1; x }";
+    let res = resolve_src(src);
+    let def = res.def_at(at(src, "x", 2)).unwrap();
+    assert_eq!(res.definition(def).span.start, at(src, "x", 0));
+}
+
+#[test]
+fn params_and_functions_resolve_regardless_of_order() {
+    let src = "f(1); fn f(x: Int) -> Int { x + 1 }";
+    let res = resolve_src(src);
+
+    let f = res.def_at(0).unwrap();
+    assert_eq!(res.definition(f).kind, DefKind::Function);
+    assert_eq!(res.definition(f).span.start, at(src, "f(", 1));
+
+    let x = res.def_at(at(src, "x", 1)).unwrap();
+    assert_eq!(res.definition(x).kind, DefKind::Param);
+    assert!(res.unresolved.is_empty());
+}
+
+#[test]
+fn functions_do_not_see_caller_locals_but_closures_capture() {
+    let src = "{ let y = 1; fn(a: Int) -> Int { a + y } }; fn g() -> Int { y }";
+    let res = resolve_src(src);
+
+    let captured = res.def_at(at(src, "y", 1)).unwrap();
+    assert_eq!(res.definition(captured).kind, DefKind::Local);
+    assert_eq!(res.unresolved.len(), 1);
+    assert_eq!(res.unresolved[0].1.start, at(src, "y", 2));
+}
+
+#[test]
+fn references_include_writes() {
+    let src = "let c = 0; c = c + 1; c";
+    let res = resolve_src(src);
+    let def = res.def_at(at(src, "c", 0)).unwrap();
+    let refs: Vec<_> = res.references(def).collect();
+    assert_eq!(refs.len(), 3);
+    assert!(refs[0].write);
+    assert!(!refs[1].write);
+}
+
+#[test]
+fn visible_at_skips_shadowed_bindings() {
+    let src = "fn f() -> Int { 1 } let x = 1; { let x = 2; x }";
+    let res = resolve_src(src);
+    let visible = res.visible_at(at(src, "x", 2));
+    let names: Vec<_> = visible
+        .iter()
+        .map(|&id| (res.definition(id).name.as_str(), res.definition(id).kind))
+        .collect();
+    assert!(names.contains(&("x", DefKind::Local)));
+    assert!(!names.contains(&("x", DefKind::Global)));
+    assert!(names.contains(&("f", DefKind::Function)));
+    assert!(names.contains(&("gc", DefKind::Builtin)));
+}
@@ -0,0 +1,34 @@
+use moon_core::lexer::lex_file;
+use moon_core::parser::parse;
+use moon_core::source::{Source, SourceMap};
+
+#[test]
+fn spans_resolve_to_their_own_file() {
+    let mut sources = SourceMap::new();
+    let a = sources.add(Source::new("a.moon", "let x = 1;\nx"));
+    let b = sources.add(Source::new("b.moon", "\n\nlet y = 2;"));
+
+    let program_a = parse(lex_file(sources[a].text(), a).unwrap()).unwrap();
+    let program_b = parse(lex_file(sources[b].text(), b).unwrap()).unwrap();
+
+    let tail = program_a.tail.unwrap().span();
+    assert_eq!(tail.file, a);
+    let (path, line, col) = sources.location(tail).unwrap();
+    assert_eq!((path.to_str().unwrap(), line, col), ("a.moon", 2, 1));
+
+    let stmt = program_b.stmts[0].span();
+    assert_eq!(stmt.file, b);
+    let (path, line, col) = sources.location(stmt).unwrap();
+    assert_eq!((path.to_str().unwrap(), line, col), ("b.moon", 3, 1));
+}
+
+#[test]
+fn render_span_uses_the_span_file() {
+    let mut sources = SourceMap::new();
+    sources.add(Source::new("a.moon", "1"));
+    let b = sources.add(Source::new("b.moon", "let z = true;"));
+
+    let program = parse(lex_file(sources[b].text(), b).unwrap()).unwrap();
+    let rendered = sources.render_span(program.stmts[0].span(), "boom");
+    assert!(rendered.starts_with("b.moon:1:1: boom"));
+}
@@ -0,0 +1,170 @@
+use moon_runtime::{Heap, Value};
+use moon_typechecker::Type;
+
+/// A Rust type a Moon value can be read as: the parameters of host functions, and what
+/// `Engine::eval` returns.
+pub trait FromMoon: Sized {
+    /// The Moon type of the values this reads.
+    fn moon_type() -> Type;
+
+    /// Reads `value`, whose arrays and objects live in `heap`. Fails only if `value` is not of
+    /// `moon_type()`, which the typechecker rules out.
+    fn from_moon(value: &Value, heap: &Heap) -> Result<Self, String>;
+}
+
+/// A Rust type that becomes a Moon value: what host functions return.
+pub trait IntoMoon {
+    /// The Moon type of the values this makes.
+    fn moon_type() -> Type;
+
+    /// Makes the value, allocating arrays and objects in `heap`. An `Err` is a runtime error.
+    fn into_moon(self, heap: &mut Heap) -> Result<Value, String>;
+}
+
+fn mismatch(expected: Type, value: &Value) -> String {
+    format!("expected {expected}, got {value:?}")
+}
+
+impl FromMoon for i64 {
+    fn moon_type() -> Type {
+        Type::Int
+    }
+
+    fn from_moon(value: &Value, _: &Heap) -> Result<Self, String> {
+        match value {
+            Value::Int(i) => Ok(*i),
+            other => Err(mismatch(Type::Int, other)),
+        }
+    }
+}
+
+impl IntoMoon for i64 {
+    fn moon_type() -> Type {
+        Type::Int
+    }
+
+    fn into_moon(self, _: &mut Heap) -> Result<Value, String> {
+        Ok(Value::Int(self))
+    }
+}
+
+impl FromMoon for bool {
+    fn moon_type() -> Type {
+        Type::Bool
+    }
+
+    fn from_moon(value: &Value, _: &Heap) -> Result<Self, String> {
+        match value {
+            Value::Bool(b) => Ok(*b),
+            other => Err(mismatch(Type::Bool, other)),
+        }
+    }
+}
+
+impl IntoMoon for bool {
+    fn moon_type() -> Type {
+        Type::Bool
+    }
+
+    fn into_moon(self, _: &mut Heap) -> Result<Value, String> {
+        Ok(Value::Bool(self))
+    }
+}
+
+impl FromMoon for String {
+    fn moon_type() -> Type {
+        Type::String
+    }
+
+    fn from_moon(value: &Value, _: &Heap) -> Result<Self, String> {
+        match value {
+            Value::String(s) => Ok(s.clone()),
+            other => Err(mismatch(Type::String, other)),
+        }
+    }
+}
+
+impl IntoMoon for String {
+    fn moon_type() -> Type {
+        Type::String
+    }
+
+    fn into_moon(self, _: &mut Heap) -> Result<Value, String> {
+        Ok(Value::String(self))
+    }
+}
+
+impl IntoMoon for &str {
+    fn moon_type() -> Type {
+        Type::String
+    }
+
+    fn into_moon(self, _: &mut Heap) -> Result<Value, String> {
+        Ok(Value::String(self.to_string()))
+    }
+}
+
+impl FromMoon for () {
+    fn moon_type() -> Type {
+        Type::Unit
+    }
+
+    fn from_moon(value: &Value, _: &Heap) -> Result<Self, String> {
+        match value {
+            Value::Unit => Ok(()),
+            other => Err(mismatch(Type::Unit, other)),
+        }
+    }
+}
+
+impl IntoMoon for () {
+    fn moon_type() -> Type {
+        Type::Unit
+    }
+
+    fn into_moon(self, _: &mut Heap) -> Result<Value, String> {
+        Ok(Value::Unit)
+    }
+}
+
+impl<T: FromMoon> FromMoon for Vec<T> {
+    fn moon_type() -> Type {
+        Type::Array(Box::new(T::moon_type()))
+    }
+
+    fn from_moon(value: &Value, heap: &Heap) -> Result<Self, String> {
+        let h = match value {
+            Value::Array(h) => *h,
+            other => return Err(mismatch(Self::moon_type(), other)),
+        };
+        let len = heap.array_len(h).ok_or("invalid array handle")?;
+        (0..len)
+            .map(|i| T::from_moon(heap.array_get(h, i).expect("index in bounds"), heap))
+            .collect()
+    }
+}
+
+impl<T: IntoMoon> IntoMoon for Vec<T> {
+    fn moon_type() -> Type {
+        Type::Array(Box::new(T::moon_type()))
+    }
+
+    fn into_moon(self, heap: &mut Heap) -> Result<Value, String> {
+        let elems = self
+            .into_iter()
+            .map(|v| v.into_moon(heap))
+            .collect::<Result<_, _>>()?;
+        Ok(Value::Array(heap.alloc_array(elems)))
+    }
+}
+
+/// A host function that can fail: the error becomes a Moon runtime error at the call.
+impl<T: IntoMoon, E: std::fmt::Display> IntoMoon for Result<T, E> {
+    fn moon_type() -> Type {
+        T::moon_type()
+    }
+
+    fn into_moon(self, heap: &mut Heap) -> Result<Value, String> {
+        self.map_err(|e| e.to_string())?.into_moon(heap)
+    }
+}
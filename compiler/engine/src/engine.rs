@@ -0,0 +1,178 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use moon_bytecode::{compile_with_natives, CompileError, Module};
+use moon_core::ast::Program;
+use moon_core::error::{LexError, ParseError};
+use moon_core::lexer::lex;
+use moon_core::parser::parse;
+use moon_core::span::Span;
+use moon_runtime::{Limits, Value};
+use moon_typechecker::{check_program_in, Type, TypeEnv, TypeError};
+use moon_vm::{Vm, VmError, DEFAULT_MAX_DEPTH};
+
+use crate::convert::FromMoon;
+use crate::native::{IntoNative, NativeFn};
+
+/// Runs Moon scripts for a host, with the functions it registered. Every run starts from
+/// scratch: scripts share nothing but the host functions.
+#[derive(Debug, Clone)]
+pub struct Engine {
+    // By name, so modules get the same function ids on every run.
+    natives: BTreeMap<String, NativeFn>,
+    limits: Limits,
+    max_depth: usize,
+}
+
+/// Why a script did not run: the first stage that failed, with its error.
+#[derive(Debug, Clone)]
+pub enum Error {
+    Lex(LexError),
+    Parse(ParseError),
+    Type(TypeError),
+    Compile(CompileError),
+    Runtime(VmError),
+}
+
+impl Error {
+    pub fn message(&self) -> &str {
+        match self {
+            Error::Lex(e) => &e.message,
+            Error::Parse(e) => &e.message,
+            Error::Type(e) => &e.message,
+            Error::Compile(e) => &e.message,
+            Error::Runtime(e) => &e.message,
+        }
+    }
+
+    /// Where in the script it failed.
+    pub fn span(&self) -> Span {
+        match self {
+            Error::Lex(e) => e.span,
+            Error::Parse(e) => e.span,
+            Error::Type(e) => e.span,
+            Error::Compile(e) => e.span,
+            Error::Runtime(e) => e.span,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Lex(e) => e.fmt(f),
+            Error::Parse(e) => e.fmt(f),
+            Error::Type(e) => e.fmt(f),
+            Error::Compile(e) => e.fmt(f),
+            Error::Runtime(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Engine {
+    pub fn new() -> Self {
+        Self {
+            natives: BTreeMap::new(),
+            limits: Limits::default(),
+            max_depth: DEFAULT_MAX_DEPTH,
+        }
+    }
+
+    /// Lets scripts call `f` as `name`. Its Moon signature comes from the Rust one (see
+    /// `FromMoon` and `IntoMoon`), so calls are typechecked like calls to a Moon function; a
+    /// script defining a function with the same name fails to compile. Registering `name`
+    /// again replaces the function.
+    pub fn register<Args>(&mut self, name: &str, f: impl IntoNative<Args>) {
+        self.natives.insert(name.to_string(), f.into_native());
+    }
+
+    /// Sets the limits of every run (see `Vm::set_limits`).
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// Sets how many calls may be running at once (see `Vm::set_max_depth`).
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+    }
+
+    /// Typechecks `source`, with the host functions in scope, and returns its type.
+    pub fn check(&self, source: &str) -> Result<Type, Error> {
+        self.frontend(source).map(|(_, ty)| ty)
+    }
+
+    pub fn compile(&self, source: &str) -> Result<Module, Error> {
+        let (program, _) = self.frontend(source)?;
+        self.compile_program(&program)
+    }
+
+    /// A VM ready to run `source`, with the host functions and limits set: for a host that
+    /// runs it a few steps at a time (`Vm::step`) or may interrupt it.
+    pub fn vm(&self, source: &str) -> Result<Vm, Error> {
+        let module = self.compile(source)?;
+        Ok(self.vm_for(module))
+    }
+
+    /// Runs `source` and returns its value. Arrays, objects and closures in it point into a
+    /// heap that is gone by then: use `eval` to read those.
+    pub fn run(&self, source: &str) -> Result<Value, Error> {
+        self.vm(source)?.run().map_err(Error::Runtime)
+    }
+
+    /// Runs `source` and reads its value as a `T`. A script whose type is not `T`'s is a type
+    /// error, found before it runs.
+    pub fn eval<T: FromMoon>(&self, source: &str) -> Result<T, Error> {
+        let (program, ty) = self.frontend(source)?;
+        let expected = T::moon_type();
+        let span = program.tail.as_ref().map_or(Span::new(0, 0), |e| e.span());
+        if ty != expected && ty != Type::Never {
+            return Err(Error::Type(TypeError {
+                message: format!("type mismatch: expected {expected}, got {ty}"),
+                span,
+            }));
+        }
+        let mut vm = self.vm_for(self.compile_program(&program)?);
+        let value = vm.resume().map_err(Error::Runtime)?;
+        T::from_moon(&value, vm.heap())
+            .map_err(|message| Error::Runtime(VmError::new(message, span)))
+    }
+
+    fn frontend(&self, source: &str) -> Result<(Program, Type), Error> {
+        let tokens = lex(source).map_err(Error::Lex)?;
+        let program = parse(tokens).map_err(Error::Parse)?;
+        let mut env = TypeEnv::new();
+        for (name, native) in &self.natives {
+            env.define_fn(name.clone(), native.params.clone(), native.ret.clone())
+                .map_err(Error::Type)?;
+        }
+        let ty = check_program_in(&program, &mut env).map_err(Error::Type)?;
+        Ok((program, ty))
+    }
+
+    fn compile_program(&self, program: &Program) -> Result<Module, Error> {
+        let natives: Vec<(&str, usize)> = self
+            .natives
+            .iter()
+            .map(|(name, native)| (name.as_str(), native.params.len()))
+            .collect();
+        compile_with_natives(program, &natives).map_err(Error::Compile)
+    }
+
+    fn vm_for(&self, module: Module) -> Vm {
+        let mut vm = Vm::new(module);
+        vm.set_limits(self.limits);
+        vm.set_max_depth(self.max_depth);
+        for (name, native) in &self.natives {
+            vm.register_native(name, native.func.clone());
+        }
+        vm
+    }
+}
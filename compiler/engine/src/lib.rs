@@ -0,0 +1,10 @@
+//! Embedding Moon in a Rust program: `Engine` runs scripts through the whole pipeline (lexer,
+//! parser, typechecker, compiler, VM) and lets the host add functions scripts can call.
+
+mod convert;
+mod engine;
+mod native;
+
+pub use convert::{FromMoon, IntoMoon};
+pub use engine::{Engine, Error};
+pub use native::{IntoNative, NativeFn};
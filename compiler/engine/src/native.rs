@@ -0,0 +1,67 @@
+use std::sync::Arc;
+
+use moon_runtime::{Heap, Value};
+use moon_typechecker::Type;
+use moon_vm::Native;
+
+use crate::convert::{FromMoon, IntoMoon};
+
+/// A host function as the engine keeps it: its Moon signature, for the typechecker, and the
+/// code the VM runs.
+#[derive(Clone)]
+pub struct NativeFn {
+    pub(crate) params: Vec<Type>,
+    pub(crate) ret: Type,
+    pub(crate) func: Native,
+}
+
+impl std::fmt::Debug for NativeFn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ty = Type::Function {
+            params: self.params.clone(),
+            ret: Box::new(self.ret.clone()),
+        };
+        write!(f, "NativeFn({ty})")
+    }
+}
+
+/// A Rust function or closure `Engine::register` takes: one whose parameters are `FromMoon`
+/// and whose result is `IntoMoon`, with up to 6 parameters. `Args` is the tuple of the
+/// parameter types, only there to tell the implementations apart.
+pub trait IntoNative<Args> {
+    #[doc(hidden)]
+    fn into_native(self) -> NativeFn;
+}
+
+macro_rules! impl_into_native {
+    ($($arg:ident),*) => {
+        impl<F, R, $($arg),*> IntoNative<($($arg,)*)> for F
+        where
+            F: Fn($($arg),*) -> R + Send + Sync + 'static,
+            R: IntoMoon,
+            $($arg: FromMoon,)*
+        {
+            #[allow(non_snake_case, unused_variables, unused_mut)]
+            fn into_native(self) -> NativeFn {
+                NativeFn {
+                    params: vec![$($arg::moon_type()),*],
+                    ret: R::moon_type(),
+                    func: Arc::new(move |heap: &mut Heap, args: &[Value]| {
+                        // The VM checks the number of arguments.
+                        let mut args = args.iter();
+                        $(let $arg = $arg::from_moon(args.next().expect("argument"), heap)?;)*
+                        self($($arg),*).into_moon(heap)
+                    }),
+                }
+            }
+        }
+    };
+}
+
+impl_into_native!();
+impl_into_native!(A);
+impl_into_native!(A, B);
+impl_into_native!(A, B, C);
+impl_into_native!(A, B, C, D);
+impl_into_native!(A, B, C, D, E);
+impl_into_native!(A, B, C, D, E, G);
@@ -0,0 +1,139 @@
+use std::sync::{Arc, Mutex};
+
+use moon_engine::{Engine, Error};
+use moon_runtime::{ErrorKind, Limits, Value};
+use moon_typechecker::Type;
+use moon_vm::Status;
+
+fn engine() -> Engine {
+    let mut engine = Engine::new();
+    engine.register("add", |a: i64, b: i64| a + b);
+    engine.register("shout", |s: String| s.to_uppercase());
+    engine.register("range", |n: i64| (0..n).collect::<Vec<i64>>());
+    engine.register("sum", |xs: Vec<i64>| xs.iter().sum::<i64>());
+    engine.register("parse_int", |s: String| s.parse::<i64>());
+    engine
+}
+
+#[test]
+fn scripts_call_host_functions() {
+    let engine = engine();
+    assert_eq!(engine.eval::<i64>("add(1, add(2, 3))").unwrap(), 6);
+    assert_eq!(
+        engine.eval::<String>("shout(\"moon\") + \"!\"").unwrap(),
+        "MOON!"
+    );
+    assert_eq!(engine.eval::<i64>("sum(range(5))").unwrap(), 10);
+    assert_eq!(
+        engine
+            .eval::<Vec<i64>>("let xs = range(3); xs[0] = 7; xs")
+            .unwrap(),
+        vec![7, 1, 2]
+    );
+    assert_eq!(engine.run("add(2, 2)").unwrap(), Value::Int(4));
+}
+
+#[test]
+fn host_functions_are_values_and_can_be_tail_called() {
+    let engine = engine();
+    let src = "fn last(x: Int) -> Int { add(x, 1) }
+let f = add;
+f(last(20), 21)";
+    assert_eq!(engine.eval::<i64>(src).unwrap(), 42);
+}
+
+#[test]
+fn the_typechecker_knows_host_signatures() {
+    let engine = engine();
+    assert_eq!(engine.check("add(1, 2)").unwrap(), Type::Int);
+    assert_eq!(
+        engine.check("range(2)").unwrap(),
+        Type::Array(Box::new(Type::Int))
+    );
+
+    let Err(Error::Type(err)) = engine.check("add(1, \"two\")") else {
+        panic!("expected a type error");
+    };
+    assert_eq!(
+        err.message,
+        "argument type mismatch: expected Int, got String"
+    );
+    assert!(matches!(engine.check("add(1)"), Err(Error::Type(_))));
+    assert!(matches!(engine.check("nope(1)"), Err(Error::Type(_))));
+
+    let err = engine.eval::<bool>("add(1, 2)").unwrap_err();
+    assert_eq!(err.message(), "type mismatch: expected Bool, got Int");
+}
+
+#[test]
+fn host_errors_are_runtime_errors_at_the_call() {
+    let engine = engine();
+    let src = "fn read(s: String) -> Int { parse_int(s) + 0 }\nread(\"12\") + read(\"x\")";
+    let Err(Error::Runtime(err)) = engine.eval::<i64>(src) else {
+        panic!("expected a runtime error");
+    };
+    assert_eq!(err.kind, ErrorKind::Failed);
+    assert_eq!(err.message, "invalid digit found in string");
+    assert_eq!(&src[err.span.start..err.span.end], "parse_int(s)");
+    assert_eq!(err.trace[0].function, "read");
+}
+
+#[test]
+fn host_functions_can_keep_state() {
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let mut engine = Engine::new();
+    let sink = Arc::clone(&log);
+    engine.register("log", move |line: String| sink.lock().unwrap().push(line));
+    engine
+        .eval::<()>("log(\"one\"); let x = 2; log(\"two\")")
+        .unwrap();
+    assert_eq!(*log.lock().unwrap(), ["one", "two"]);
+}
+
+#[test]
+fn a_script_cannot_redefine_a_host_function() {
+    let engine = engine();
+    let err = engine
+        .run("fn add(a: Int, b: Int) -> Int { a - b } add(1, 2)")
+        .unwrap_err();
+    assert_eq!(err.message(), "duplicate function: add");
+}
+
+#[test]
+fn engine_vms_step_and_keep_the_limits() {
+    let mut engine = engine();
+    let mut vm = engine.vm("sum(range(10))").unwrap();
+    assert_eq!(vm.step(1).unwrap(), Status::Yielded);
+    assert_eq!(vm.resume().unwrap(), Value::Int(45));
+
+    engine.set_limits(Limits {
+        fuel: Some(100),
+        ..Limits::default()
+    });
+    let err = engine
+        .run("fn spin(n: Int) -> Int { spin(add(n, 1)) } spin(0)")
+        .unwrap_err();
+    let Error::Runtime(err) = err else {
+        panic!("expected a runtime error");
+    };
+    assert_eq!(err.kind, ErrorKind::OutOfFuel);
+}
+
+#[test]
+fn modules_do_not_depend_on_registration_order() {
+    let mut a = Engine::new();
+    let mut b = Engine::new();
+    let names = ["one", "two", "three", "four", "five"];
+    for name in names {
+        a.register(name, || 1i64);
+    }
+    for name in names.iter().rev() {
+        b.register(name, || 1i64);
+    }
+    let src = "one() + two() + three() + four() + five()";
+    let ids = |engine: &Engine| {
+        let module = engine.compile(src).unwrap();
+        names.map(|name| module.by_name[name])
+    };
+    assert_eq!(ids(&a), ids(&b));
+}
@@ -0,0 +1,657 @@
+//! Canonical pretty-printer for Moon source.
+//!
+//! The layout comes from the AST alone (4-space indentation, one statement per line, a construct
+//! stays on one line while it fits in `WIDTH` columns), except for two things taken from the
+//! source: comments, which the parser never sees and are re-attached from the lexer's trivia,
+//! and single blank lines between statements, which are kept.
+//!
+//! Comments are never dropped. One that sits where the printer has no line break (inside
+//! `1 + // note` for example) moves to the end of its statement.
+
+use std::fmt;
+
+use moon_core::ast::{BinaryOp, Expr, Param, Program, Stmt, TypeExpr, UnaryOp};
+use moon_core::error::{LexError, ParseError};
+use moon_core::lexer::{lex_with_trivia, Token, TokenKind, TriviaKind};
+use moon_core::parser::parse;
+use moon_core::span::{FileId, Span};
+
+const INDENT: &str = "    ";
+const WIDTH: usize = 100;
+
+#[derive(Debug, Clone)]
+pub enum FormatError {
+    Lex(LexError),
+    Parse(ParseError),
+}
+
+impl FormatError {
+    pub fn span(&self) -> Span {
+        match self {
+            FormatError::Lex(e) => e.span,
+            FormatError::Parse(e) => e.span,
+        }
+    }
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormatError::Lex(e) => e.fmt(f),
+            FormatError::Parse(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for FormatError {}
+
+/// Formats a whole file. Only syntactically valid code can be formatted.
+pub fn format_source(text: &str, file: FileId) -> Result<String, FormatError> {
+    let (tokens, program, comments) = parse_source(text, file)?;
+    let mut printer = Printer::new(text, &tokens, comments);
+    let items = items(&program);
+    printer.items(&items, text.len());
+    if !printer.out.is_empty() {
+        printer.out.push('\n');
+    }
+    Ok(printer.out)
+}
+
+/// Formats the top-level statements overlapping `start..end`. Returns the source span to
+/// replace and its formatted text, or `None` if the range touches no statement.
+pub fn format_range(
+    text: &str,
+    file: FileId,
+    start: usize,
+    end: usize,
+) -> Result<Option<(Span, String)>, FormatError> {
+    let (tokens, program, comments) = parse_source(text, file)?;
+    let mut printer = Printer::new(text, &tokens, comments);
+    let items: Vec<Item> = items(&program)
+        .into_iter()
+        .filter(|item| {
+            let sp = item.span();
+            sp.start <= end && start <= printer.item_end(item)
+        })
+        .collect();
+    let Some(first) = items.first() else {
+        return Ok(None);
+    };
+
+    // Take the indentation in front of the first statement too, unless other code shares its line.
+    let first_start = first.span().start;
+    let line_start = text[..first_start].rfind('\n').map_or(0, |i| i + 1);
+    let region_start = if text[line_start..first_start].trim().is_empty() {
+        line_start
+    } else {
+        first_start
+    };
+    let skipped = printer.comments.partition_point(|c| c.start < region_start);
+    printer.next = skipped;
+    printer.last_end = region_start;
+
+    let mut first = true;
+    for item in &items {
+        printer.item(item, &mut first);
+    }
+    let region = Span::in_file(file, region_start, printer.last_end);
+    Ok(Some((region, printer.out)))
+}
+
+fn parse_source(text: &str, file: FileId) -> Result<(Vec<Token>, Program, Vec<Span>), FormatError> {
+    let (tokens, trivia) = lex_with_trivia(text, file).map_err(FormatError::Lex)?;
+    let program = parse(tokens.clone()).map_err(FormatError::Parse)?;
+    let comments = trivia
+        .into_iter()
+        .filter(|t| t.kind == TriviaKind::LineComment)
+        .map(|t| t.span)
+        .collect();
+    Ok((tokens, program, comments))
+}
+
+/// A statement or the trailing expression of a sequence.
+enum Item<'a> {
+    Stmt(&'a Stmt),
+    Tail(&'a Expr),
+}
+
+impl Item<'_> {
+    fn span(&self) -> Span {
+        match self {
+            Item::Stmt(stmt) => stmt.span(),
+            Item::Tail(expr) => expr.span(),
+        }
+    }
+}
+
+fn items(program: &Program) -> Vec<Item<'_>> {
+    program
+        .stmts
+        .iter()
+        .map(Item::Stmt)
+        .chain(program.tail.as_ref().map(Item::Tail))
+        .collect()
+}
+
+struct Printer<'a> {
+    text: &'a str,
+    tokens: &'a [Token],
+    // Line comments in source order; `next` is the first one not printed yet.
+    comments: Vec<Span>,
+    next: usize,
+    out: String,
+    indent: usize,
+    // Source offset where the last printed statement, element or comment ended; blank lines
+    // are looked for between it and the next one.
+    last_end: usize,
+}
+
+impl<'a> Printer<'a> {
+    fn new(text: &'a str, tokens: &'a [Token], comments: Vec<Span>) -> Self {
+        Self {
+            text,
+            tokens,
+            comments,
+            next: 0,
+            out: String::new(),
+            indent: 0,
+            last_end: 0,
+        }
+    }
+
+    // ---- Output -------------------------------------------------------------------------
+
+    fn write(&mut self, s: &str) {
+        self.out.push_str(s);
+    }
+
+    fn column(&self) -> usize {
+        let line_start = self.out.rfind('\n').map_or(0, |i| i + 1);
+        self.out[line_start..].chars().count()
+    }
+
+    fn break_line(&mut self, blank: bool) {
+        // Nothing goes before the first line of the output.
+        if self.out.is_empty() {
+            return;
+        }
+        if blank {
+            self.out.push('\n');
+        }
+        self.out.push('\n');
+        for _ in 0..self.indent {
+            self.out.push_str(INDENT);
+        }
+    }
+
+    // ---- Source queries -----------------------------------------------------------------
+
+    fn has_comment(&self, span: Span) -> bool {
+        let i = self.comments.partition_point(|c| c.start <= span.start);
+        self.comments.get(i).is_some_and(|c| c.start < span.end)
+    }
+
+    fn blank_line_before(&self, offset: usize) -> bool {
+        self.last_end < offset && self.text[self.last_end..offset].matches('\n').count() >= 2
+    }
+
+    /// Index of the first token starting at or after `offset`.
+    fn token_at(&self, offset: usize) -> usize {
+        self.tokens.partition_point(|t| t.span.start < offset)
+    }
+
+    /// `end`, extended over a `;` or `,` that directly follows it.
+    fn end_with(&self, end: usize, kind: TokenKind) -> usize {
+        match self.tokens.get(self.token_at(end)) {
+            Some(tok) if tok.kind == kind => tok.span.end,
+            _ => end,
+        }
+    }
+
+    /// Where an item ends in the source, including its `;` (statement spans stop before it).
+    fn item_end(&self, item: &Item) -> usize {
+        match item {
+            Item::Stmt(Stmt::Let { span, .. })
+            | Item::Stmt(Stmt::Assign { span, .. })
+            | Item::Stmt(Stmt::Expr { span, .. }) => self.end_with(span.end, TokenKind::Semicolon),
+            Item::Stmt(stmt) => stmt.span().end,
+            Item::Tail(expr) => expr.span().end,
+        }
+    }
+
+    // ---- Comments and line structure ----------------------------------------------------
+
+    /// Prints the comments before `offset`, one per line.
+    fn flush(&mut self, offset: usize, first: &mut bool) {
+        while let Some(&c) = self.comments.get(self.next).filter(|c| c.start < offset) {
+            self.next += 1;
+            let blank = !*first && self.blank_line_before(c.start);
+            self.break_line(blank);
+            self.write(&self.text[c.start..c.end]);
+            self.last_end = c.end;
+            *first = false;
+        }
+    }
+
+    /// Starts the line of something at `offset`, after the comments in front of it.
+    fn start_line(&mut self, offset: usize, first: &mut bool) {
+        self.flush(offset, first);
+        let blank = !*first && self.blank_line_before(offset);
+        self.break_line(blank);
+        *first = false;
+    }
+
+    /// After something ending at `end`: comments left inside it, and one following it on the
+    /// same line with no code in between.
+    fn trailing(&mut self, end: usize) {
+        let next_token = self
+            .tokens
+            .get(self.token_at(end))
+            .map_or(self.text.len(), |t| t.span.start);
+        let line_end = self.text[end..]
+            .find('\n')
+            .map_or(self.text.len(), |i| end + i);
+        let limit = next_token.min(line_end);
+
+        let from = self.next;
+        while self
+            .comments
+            .get(self.next)
+            .is_some_and(|c| c.start < limit)
+        {
+            self.next += 1;
+        }
+        let text = self.text;
+        let found = from..self.next;
+        if found.len() == 1 {
+            let c = self.comments[from];
+            self.write(" ");
+            self.write(&text[c.start..c.end]);
+        } else {
+            for i in found {
+                let c = self.comments[i];
+                self.break_line(false);
+                self.write(&text[c.start..c.end]);
+            }
+        }
+        self.last_end = self.comments[..self.next]
+            .last()
+            .map_or(end, |c| c.end.max(end));
+    }
+
+    // ---- Statements ---------------------------------------------------------------------
+
+    /// A statement sequence (a block body or the program) up to the `close` offset.
+    fn items(&mut self, items: &[Item], close: usize) {
+        let mut first = true;
+        for item in items {
+            self.item(item, &mut first);
+        }
+        self.flush(close, &mut first);
+    }
+
+    fn item(&mut self, item: &Item, first: &mut bool) {
+        self.start_line(item.span().start, first);
+        match item {
+            Item::Stmt(stmt) => self.stmt(stmt),
+            Item::Tail(expr) => self.expr(expr),
+        }
+        let end = self.item_end(item);
+        self.trailing(end);
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Let { name, ty, expr, .. } => {
+                self.write("let ");
+                self.write(name);
+                if let Some(ty) = ty {
+                    self.write(": ");
+                    self.write(&type_text(ty));
+                }
+                self.write(" = ");
+                self.expr(expr);
+                self.write(";");
+            }
+            Stmt::Assign { target, expr, .. } => {
+                self.expr(target);
+                self.write(" = ");
+                self.expr(expr);
+                self.write(";");
+            }
+            Stmt::Return { expr, .. } => {
+                self.write("return");
+                if let Some(expr) = expr {
+                    self.write(" ");
+                    self.expr(expr);
+                }
+                self.write(";");
+            }
+            Stmt::Fn {
+                name,
+                params,
+                ret_ty,
+                body,
+                ..
+            } => {
+                self.write(&format!(
+                    "fn {name}({}) -> {} ",
+                    params_text(params),
+                    type_text(ret_ty)
+                ));
+                self.expr(body);
+            }
+            Stmt::Expr { expr, .. } => {
+                self.expr(expr);
+                self.write(";");
+            }
+        }
+    }
+
+    // ---- Expressions --------------------------------------------------------------------
+
+    fn expr(&mut self, expr: &Expr) {
+        if let Some(flat) = self.flat(expr) {
+            if self.column() + flat.chars().count() <= WIDTH {
+                self.write(&flat);
+                return;
+            }
+        }
+
+        match expr {
+            Expr::Block { stmts, tail, span } => self.block(stmts, tail.as_deref(), *span),
+            Expr::Fn {
+                params,
+                ret_ty,
+                body,
+                ..
+            } => {
+                self.write(&format!(
+                    "fn({}) -> {} ",
+                    params_text(params),
+                    type_text(ret_ty)
+                ));
+                self.broken_block(body);
+            }
+            Expr::If {
+                cond,
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                self.write("if ");
+                self.expr(cond);
+                self.write(" ");
+                self.broken_block(then_branch);
+                self.write(" else ");
+                match &**else_branch {
+                    Expr::If { .. } => self.expr(else_branch),
+                    _ => self.broken_block(else_branch),
+                }
+            }
+            Expr::Array { elements, span } => {
+                let spans: Vec<Span> = elements.iter().map(Expr::span).collect();
+                self.list("[", elements, &spans, "]", *span, Self::expr);
+            }
+            Expr::Object { props, span } => {
+                let spans: Vec<Span> = props.iter().map(|(_, v)| self.key_span(v)).collect();
+                self.list("#{", props, &spans, "}", *span, Self::prop);
+            }
+            Expr::Call { callee, args, span } => {
+                self.expr(callee);
+                self.args(args, *span);
+            }
+            Expr::Index { target, index, .. } => {
+                self.expr(target);
+                self.write("[");
+                self.expr(index);
+                self.write("]");
+            }
+            Expr::Group { expr, .. } => {
+                self.write("(");
+                self.expr(expr);
+                self.write(")");
+            }
+            Expr::Unary { op, expr, .. } => {
+                self.write(unary_text(*op));
+                self.expr(expr);
+            }
+            Expr::Binary { lhs, op, rhs, .. } => {
+                self.expr(lhs);
+                self.write(&format!(" {} ", binary_text(*op)));
+                self.expr(rhs);
+            }
+            // Leaves always have a flat form; they only get here when too long for the line.
+            Expr::Int(..) | Expr::Bool(..) | Expr::String(..) | Expr::Ident(..) => {
+                let text = self.leaf(expr);
+                self.write(&text);
+            }
+        }
+    }
+
+    /// Function bodies and `if` branches, once their construct does not fit on one line.
+    fn broken_block(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Block { stmts, tail, span } => self.block(stmts, tail.as_deref(), *span),
+            other => self.expr(other),
+        }
+    }
+
+    fn block(&mut self, stmts: &[Stmt], tail: Option<&Expr>, span: Span) {
+        if stmts.is_empty() && tail.is_none() && !self.has_comment(span) {
+            self.write("{}");
+            return;
+        }
+        let items: Vec<Item> = stmts
+            .iter()
+            .map(Item::Stmt)
+            .chain(tail.map(Item::Tail))
+            .collect();
+        self.write("{");
+        self.indent += 1;
+        self.items(&items, span.end - 1);
+        self.indent -= 1;
+        self.break_line(false);
+        self.write("}");
+    }
+
+    /// One element per line, each followed by a comma.
+    fn list<T>(
+        &mut self,
+        open: &str,
+        elements: &[T],
+        spans: &[Span],
+        close: &str,
+        span: Span,
+        print: impl Fn(&mut Self, &T),
+    ) {
+        self.write(open);
+        self.indent += 1;
+        let mut first = true;
+        for (element, &element_span) in elements.iter().zip(spans) {
+            self.start_line(element_span.start, &mut first);
+            print(self, element);
+            self.write(",");
+            let end = self.end_with(element_span.end, TokenKind::Comma);
+            self.trailing(end);
+        }
+        self.flush(span.end - close.len(), &mut first);
+        self.indent -= 1;
+        self.break_line(false);
+        self.write(close);
+    }
+
+    fn args(&mut self, args: &[Expr], span: Span) {
+        // `f(a, fn(x: Int) -> Int { .. })`: a trailing block-like argument keeps the call open
+        // on the first line instead of putting every argument on its own line.
+        if let Some((last, init)) = args.split_last() {
+            let hugs = matches!(
+                last,
+                Expr::Fn { .. } | Expr::Block { .. } | Expr::Array { .. } | Expr::Object { .. }
+            );
+            let init_flat: Option<Vec<String>> = init.iter().map(|a| self.flat(a)).collect();
+            if let (true, Some(init_flat)) = (hugs, init_flat) {
+                let mut head = String::from("(");
+                for a in init_flat {
+                    head.push_str(&a);
+                    head.push_str(", ");
+                }
+                if self.column() + head.len() < WIDTH
+                    && !self.has_comment(Span::new(span.start, last.span().start))
+                {
+                    self.write(&head);
+                    self.expr(last);
+                    self.write(")");
+                    return;
+                }
+            }
+        }
+        let spans: Vec<Span> = args.iter().map(Expr::span).collect();
+        self.list("(", args, &spans, ")", span, Self::expr);
+    }
+
+    fn prop(&mut self, (_, value): &(String, Expr)) {
+        let key = self.key_text(value);
+        self.write(&key);
+        self.write(": ");
+        self.expr(value);
+    }
+
+    /// Object keys have no span in the AST; the key token is two tokens before the value
+    /// (`key`, `:`). Its source text is kept as written, quoted or not.
+    fn key_token(&self, value: &Expr) -> &Token {
+        &self.tokens[self.token_at(value.span().start) - 2]
+    }
+
+    fn key_span(&self, value: &Expr) -> Span {
+        let key = self.key_token(value).span;
+        Span::new(key.start, value.span().end)
+    }
+
+    fn key_text(&self, value: &Expr) -> String {
+        let key = self.key_token(value).span;
+        self.text[key.start..key.end].to_string()
+    }
+
+    fn leaf(&self, expr: &Expr) -> String {
+        match expr {
+            // Literals are printed as written, so string escapes stay untouched.
+            Expr::Int(_, span) | Expr::String(_, span) => {
+                self.text[span.start..span.end].to_string()
+            }
+            Expr::Bool(b, _) => b.to_string(),
+            Expr::Ident(name, _) => name.clone(),
+            _ => unreachable!("not a leaf"),
+        }
+    }
+
+    /// The single-line form of `expr`, or `None` if it must span lines (a block with
+    /// statements, or comments inside).
+    fn flat(&self, expr: &Expr) -> Option<String> {
+        if self.has_comment(expr.span()) {
+            return None;
+        }
+        let text = match expr {
+            Expr::Int(..) | Expr::Bool(..) | Expr::String(..) | Expr::Ident(..) => self.leaf(expr),
+            Expr::Fn {
+                params,
+                ret_ty,
+                body,
+                ..
+            } => format!(
+                "fn({}) -> {} {}",
+                params_text(params),
+                type_text(ret_ty),
+                self.flat(body)?
+            ),
+            Expr::Array { elements, .. } => format!("[{}]", self.flat_list(elements)?),
+            Expr::Object { props, .. } if props.is_empty() => "#{}".to_string(),
+            Expr::Object { props, .. } => {
+                let props: Option<Vec<String>> = props
+                    .iter()
+                    .map(|(_, v)| Some(format!("{}: {}", self.key_text(v), self.flat(v)?)))
+                    .collect();
+                format!("#{{ {} }}", props?.join(", "))
+            }
+            Expr::Block { stmts, tail, .. } => match (stmts.is_empty(), tail) {
+                (false, _) => return None,
+                (true, None) => "{}".to_string(),
+                (true, Some(tail)) => format!("{{ {} }}", self.flat(tail)?),
+            },
+            Expr::If {
+                cond,
+                then_branch,
+                else_branch,
+                ..
+            } => format!(
+                "if {} {} else {}",
+                self.flat(cond)?,
+                self.flat(then_branch)?,
+                self.flat(else_branch)?
+            ),
+            Expr::Unary { op, expr, .. } => format!("{}{}", unary_text(*op), self.flat(expr)?),
+            Expr::Binary { lhs, op, rhs, .. } => format!(
+                "{} {} {}",
+                self.flat(lhs)?,
+                binary_text(*op),
+                self.flat(rhs)?
+            ),
+            Expr::Call { callee, args, .. } => {
+                format!("{}({})", self.flat(callee)?, self.flat_list(args)?)
+            }
+            Expr::Index { target, index, .. } => {
+                format!("{}[{}]", self.flat(target)?, self.flat(index)?)
+            }
+            Expr::Group { expr, .. } => format!("({})", self.flat(expr)?),
+        };
+        Some(text)
+    }
+
+    fn flat_list(&self, exprs: &[Expr]) -> Option<String> {
+        let parts: Option<Vec<String>> = exprs.iter().map(|e| self.flat(e)).collect();
+        Some(parts?.join(", "))
+    }
+}
+
+fn params_text(params: &[Param]) -> String {
+    params
+        .iter()
+        .map(|p| format!("{}: {}", p.name, type_text(&p.ty)))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn type_text(ty: &TypeExpr) -> String {
+    match ty {
+        TypeExpr::Named(name, _) => name.clone(),
+        TypeExpr::Generic { base, args, .. } => {
+            let args: Vec<String> = args.iter().map(type_text).collect();
+            format!("{base}<{}>", args.join(", "))
+        }
+    }
+}
+
+fn unary_text(op: UnaryOp) -> &'static str {
+    match op {
+        UnaryOp::Neg => "-",
+        UnaryOp::Not => "!",
+    }
+}
+
+fn binary_text(op: BinaryOp) -> &'static str {
+    match op {
+        BinaryOp::Add => "+",
+        BinaryOp::Sub => "-",
+        BinaryOp::Mul => "*",
+        BinaryOp::Div => "/",
+        BinaryOp::Mod => "%",
+        BinaryOp::Eq => "==",
+        BinaryOp::Ne => "!=",
+        BinaryOp::Lt => "<",
+        BinaryOp::Le => "<=",
+        BinaryOp::Gt => ">",
+        BinaryOp::Ge => ">=",
+        BinaryOp::And => "&&",
+        BinaryOp::Or => "||",
+    }
+}
@@ -0,0 +1,128 @@
+use std::fs;
+use std::path::PathBuf;
+
+use moon_core::lexer::lex;
+use moon_core::parser::parse;
+use moon_core::span::FileId;
+use moon_formatter::{format_range, format_source};
+use moon_interpreter::eval_program;
+
+fn fmt(src: &str) -> String {
+    format_source(src, FileId(0)).unwrap_or_else(|e| panic!("{e}"))
+}
+
+fn eval(src: &str) -> String {
+    let program = parse(lex(src).unwrap()).unwrap();
+    format!("{:?}", eval_program(&program).map_err(|e| e.message))
+}
+
+fn examples() -> Vec<(PathBuf, String)> {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../../examples");
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension().is_some_and(|e| e == "moon"))
+        .collect();
+    files.sort();
+    assert!(!files.is_empty());
+    files
+        .into_iter()
+        .map(|p| {
+            let src = fs::read_to_string(&p).unwrap();
+            (p, src)
+        })
+        .collect()
+}
+
+#[test]
+fn examples_are_formatted_and_formatting_is_idempotent() {
+    for (path, src) in examples() {
+        let once = fmt(&src);
+        assert_eq!(once, src, "{} is not formatted", path.display());
+        assert_eq!(fmt(&once), once, "{}", path.display());
+    }
+}
+
+#[test]
+fn formatting_messy_examples_is_idempotent_and_keeps_meaning() {
+    for (path, src) in examples() {
+        // Flatten every line and add noise: the layout has to come back the same.
+        let messy: String = src
+            .lines()
+            .map(|l| format!("   {}   \n\n\n", l.trim()))
+            .collect();
+        let once = fmt(&messy);
+        assert_eq!(fmt(&once), once, "{}", path.display());
+        assert_eq!(eval(&once), eval(&src), "{}", path.display());
+    }
+}
+
+#[test]
+fn normalizes_spacing_and_indentation() {
+    let src = "let x=1+2*3;\nfn  f( a:Int,b : Array< Int > )->Int{let y=a;\n y+b[0]}\nif x>1{f(x,[1])}else{-x}";
+    assert_eq!(
+        fmt(src),
+        "let x = 1 + 2 * 3;\nfn f(a: Int, b: Array<Int>) -> Int {\n    let y = a;\n    y + b[0]\n}\nif x > 1 { f(x, [1]) } else { -x }\n"
+    );
+}
+
+#[test]
+fn keeps_comments_and_single_blank_lines() {
+    let src = "// header\n\n\n\nlet a = 1; // one\nlet b = {\n  // inside\n  a\n  // before close\n};\n\n// done\nb\n";
+    assert_eq!(
+        fmt(src),
+        "// header\n\nlet a = 1; // one\nlet b = {\n    // inside\n    a\n    // before close\n};\n\n// done\nb\n"
+    );
+}
+
+#[test]
+fn comments_without_a_line_of_their_own_move_but_are_kept() {
+    let src = "let a = 1 + // note\n  2;\na";
+    let out = fmt(src);
+    assert_eq!(out, "let a = 1 + 2; // note\na\n");
+    assert_eq!(fmt(&out), out);
+}
+
+#[test]
+fn breaks_long_literals_one_element_per_line() {
+    let long = (0..30)
+        .map(|i| i.to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+    let out = fmt(&format!(
+        "let xs = [{long}];\nlet o = #{{ a: [1, // first\n2] }};"
+    ));
+    let expected_xs: String = (0..30).map(|i| format!("    {i},\n")).collect();
+    assert_eq!(
+        out,
+        format!("let xs = [\n{expected_xs}];\nlet o = #{{\n    a: [\n        1, // first\n        2,\n    ],\n}};\n")
+    );
+    assert_eq!(fmt(&out), out);
+}
+
+#[test]
+fn trailing_block_arguments_stay_on_the_call_line() {
+    let src = "let r = apply(1, fn(x: Int) -> Int { let y = x; y });";
+    assert_eq!(
+        fmt(src),
+        "let r = apply(1, fn(x: Int) -> Int {\n    let y = x;\n    y\n});\n"
+    );
+}
+
+#[test]
+fn range_formatting_touches_only_the_selected_statements() {
+    let src = "let a=1;\n  let b=2;   // keep\nlet c=3;\n";
+    let start = src.find("let b").unwrap();
+    let (span, text) = format_range(src, FileId(0), start, start).unwrap().unwrap();
+    assert_eq!(text, "let b = 2; // keep");
+
+    let mut out = src.to_string();
+    out.replace_range(span.start..span.end, &text);
+    assert_eq!(out, "let a=1;\nlet b = 2; // keep\nlet c=3;\n");
+}
+
+#[test]
+fn refuses_invalid_code() {
+    let err = format_source("let x = ;", FileId(0)).unwrap_err();
+    assert!(err.to_string().starts_with("parse error"));
+}
@@ -1,24 +1,65 @@
 use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::Instant;
 
-use moon_core::ast::Expr;
-use moon_runtime::{GcRef, Heap};
+use moon_core::ast::{Expr, Param};
+use moon_core::span::Span;
+use moon_runtime::{ErrorKind, GcRef, Heap, Limits, Upvalue, DEADLINE_CHECK_STEPS};
 
+use crate::error::{RuntimeError, StackFrame};
 use crate::Value;
 
+/// How many calls may be running at once unless `Env::set_max_depth` says otherwise.
+pub const DEFAULT_MAX_DEPTH: usize = 1000;
+
+/// Stack a call needs left to go in: enough for the Rust frames of one call (tens of KB in
+/// debug builds) and for unwinding with an error.
+pub(crate) const STACK_RED_ZONE: usize = 256 * 1024;
+
+/// A function definition. Cheap to clone: every call takes a copy.
 #[derive(Debug, Clone)]
 pub struct Function {
-    pub params: Vec<String>,
-    pub body: Expr,
+    pub params: Rc<[String]>,
+    pub body: Rc<Expr>,
+}
+
+/// A local variable. Once a closure captures it, its value moves into an upvalue on the heap
+/// that the scope and every closure capturing it share.
+#[derive(Debug, Clone)]
+pub(crate) enum Binding {
+    Value(Value),
+    Upvalue(GcRef),
 }
 
-#[derive(Debug, Default)]
+type Scope = HashMap<String, Binding>;
+
+#[derive(Debug)]
 pub struct Env {
     globals: HashMap<String, Value>,
-    scopes: Vec<HashMap<String, Value>>,
+    scopes: Vec<Scope>,
     funcs: HashMap<String, Function>,
     pub heap: Heap,
     closure: Option<GcRef>,
+    // Locals and closure of each function waiting for a call to return, innermost last.
+    callers: Vec<(Vec<Scope>, Option<GcRef>)>,
+    // The running calls, outermost first: one per entry of `callers`.
+    calls: Vec<StackFrame>,
+    max_depth: usize,
+    // Calls stop when the Rust stack gets below this address (see `STACK_RED_ZONE`); set when
+    // the outermost call starts.
+    pub(crate) stack_floor: usize,
+    limits: Limits,
+    // Steps taken since `set_limits`.
+    steps: u64,
     next_lambda_id: usize,
+    // The function each `fn` expression defined, by the expression's span.
+    lambdas: HashMap<Span, String>,
+}
+
+impl Default for Env {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Env {
@@ -29,18 +70,110 @@ impl Env {
             funcs: HashMap::new(),
             heap: Heap::new(),
             closure: None,
+            callers: Vec::new(),
+            calls: Vec::new(),
+            max_depth: DEFAULT_MAX_DEPTH,
+            stack_floor: 0,
+            limits: Limits::default(),
+            steps: 0,
             next_lambda_id: 0,
+            lambdas: HashMap::new(),
         }
     }
 
+    /// Sets how many calls may be running at once; one more is a `stack overflow` error.
+    /// Tail calls do not count, since they replace the running call.
+    ///
+    /// Each call the interpreter runs also takes space on the Rust stack, so the thread
+    /// evaluating programs needs a stack big enough for `max_depth` calls. If it runs out
+    /// first, the call fails with a `stack overflow` error all the same.
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+    }
+
+    pub fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+
+    /// Sets the fuel, heap and time limits for what runs next; the fuel starts full again.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.heap.set_limits(limits.heap);
+        self.limits = limits;
+        self.steps = 0;
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    /// How much of `Limits::fuel` is left, if there is a limit.
+    pub fn fuel_left(&self) -> Option<u64> {
+        self.limits.fuel.map(|fuel| fuel.saturating_sub(self.steps))
+    }
+
+    /// Counts one evaluation step (at `span`) against the fuel and the deadline.
+    pub(crate) fn step(&mut self, span: Span) -> Result<(), RuntimeError> {
+        if let Some(fuel) = self.limits.fuel.filter(|fuel| self.steps >= *fuel) {
+            return Err(
+                RuntimeError::new(format!("out of fuel: ran {fuel} steps"), span)
+                    .with_kind(ErrorKind::OutOfFuel),
+            );
+        }
+        self.steps += 1;
+        if self.steps.is_multiple_of(DEADLINE_CHECK_STEPS)
+            && self.limits.deadline.is_some_and(|d| Instant::now() >= d)
+        {
+            return Err(RuntimeError::new("deadline exceeded", span).with_kind(ErrorKind::Deadline));
+        }
+        Ok(())
+    }
+
+    /// Fails if the heap has grown past its limits, blaming `span`.
+    pub(crate) fn check_heap(&self, span: Span) -> Result<(), RuntimeError> {
+        self.heap
+            .check_limits()
+            .map_err(|message| RuntimeError::new(message, span).with_kind(ErrorKind::HeapLimit))
+    }
+
+    /// How many calls are running.
+    pub fn depth(&self) -> usize {
+        self.calls.len()
+    }
+
+    /// The running calls, innermost first.
+    pub fn call_stack(&self) -> Vec<StackFrame> {
+        self.calls.iter().rev().cloned().collect()
+    }
+
     pub fn fresh_lambda_name(&mut self) -> String {
         let id = self.next_lambda_id;
         self.next_lambda_id += 1;
         format!("<lambda#{id}>")
     }
 
-    pub fn set_closure(&mut self, closure: Option<GcRef>) -> Option<GcRef> {
-        std::mem::replace(&mut self.closure, closure)
+    /// The function for the `fn` expression at `span`, defined the first time it is evaluated
+    /// and shared by every closure made from it after that.
+    pub(crate) fn lambda(&mut self, span: Span, params: &[Param], body: &Expr) -> String {
+        // Another program run in this `Env` may have a different `fn` at the same span.
+        let same = |func: &Function| {
+            *func.body == *body && func.params.iter().eq(params.iter().map(|p| &p.name))
+        };
+        if let Some(name) = self.lambdas.get(&span) {
+            if self.funcs.get(name).is_some_and(same) {
+                return name.clone();
+            }
+        }
+
+        let name = self.fresh_lambda_name();
+        self.define_fn(
+            name.clone(),
+            Function {
+                params: params.iter().map(|p| p.name.clone()).collect(),
+                body: Rc::new(body.clone()),
+            },
+        );
+        self.lambdas.insert(span, name.clone());
+        name
     }
 
     pub fn push_scope(&mut self) {
@@ -53,13 +186,15 @@ impl Env {
 
     pub fn get_var(&self, name: &str) -> Option<&Value> {
         for scope in self.scopes.iter().rev() {
-            if let Some(v) = scope.get(name) {
-                return Some(v);
+            match scope.get(name) {
+                Some(Binding::Value(v)) => return Some(v),
+                Some(Binding::Upvalue(h)) => return self.upvalue_value(*h),
+                None => {}
             }
         }
         if let Some(h) = self.closure {
-            if let Some(v) = self.heap.closure_get(h, name) {
-                return Some(v);
+            if let Some(up) = self.heap.closure_upvalue(h, name) {
+                return self.upvalue_value(up);
             }
         }
         self.globals.get(name)
@@ -67,32 +202,45 @@ impl Env {
 
     pub fn define_var(&mut self, name: String, value: Value) {
         if let Some(scope) = self.scopes.last_mut() {
-            scope.insert(name, value);
+            scope.insert(name, Binding::Value(value));
         } else {
             self.globals.insert(name, value);
         }
     }
 
-    pub fn assign_var(&mut self, name: &str, value: Value) -> Result<(), ()> {
+    /// Assigns to the nearest existing binding. Returns `false` if `name` is not defined.
+    pub fn assign_var(&mut self, name: &str, value: Value) -> bool {
         for scope in self.scopes.iter_mut().rev() {
-            if scope.contains_key(name) {
-                scope.insert(name.to_string(), value);
-                return Ok(());
+            match scope.get_mut(name) {
+                Some(Binding::Value(v)) => {
+                    *v = value;
+                    return true;
+                }
+                Some(Binding::Upvalue(h)) => {
+                    let h = *h;
+                    return self.heap.set_upvalue(h, Upvalue::Closed(value)).is_ok();
+                }
+                None => {}
             }
         }
         if let Some(h) = self.closure {
-            if self.heap.closure_contains(h, name) {
-                self.heap
-                    .closure_set(h, name.to_string(), value)
-                    .map_err(|_| ())?;
-                return Ok(());
+            if let Some(up) = self.heap.closure_upvalue(h, name) {
+                return self.heap.set_upvalue(up, Upvalue::Closed(value)).is_ok();
             }
         }
         if self.globals.contains_key(name) {
             self.globals.insert(name.to_string(), value);
-            return Ok(());
+            return true;
+        }
+        false
+    }
+
+    fn upvalue_value(&self, h: GcRef) -> Option<&Value> {
+        match self.heap.upvalue(h)? {
+            Upvalue::Closed(v) => Some(v),
+            // The interpreter keeps no variables on a stack: its upvalues start closed.
+            Upvalue::Open(_) => None,
         }
-        Err(())
     }
 
     pub fn define_fn(&mut self, name: String, func: Function) {
@@ -103,16 +251,27 @@ impl Env {
         self.funcs.get(name)
     }
 
-    pub fn take_scopes(&mut self) -> Vec<HashMap<String, Value>> {
-        std::mem::take(&mut self.scopes)
+    /// Starts a call (`frame`): the caller's locals and closure are set aside (the callee
+    /// cannot see them) and `closure` becomes the active one.
+    pub(crate) fn enter_call(&mut self, closure: Option<GcRef>, frame: StackFrame) {
+        let scopes = std::mem::take(&mut self.scopes);
+        let closure = std::mem::replace(&mut self.closure, closure);
+        self.callers.push((scopes, closure));
+        self.calls.push(frame);
     }
 
-    pub fn restore_scopes(&mut self, scopes: Vec<HashMap<String, Value>>) {
+    pub(crate) fn leave_call(&mut self) {
+        self.calls.pop();
+        let (scopes, closure) = self.callers.pop().expect("a call is in progress");
         self.scopes = scopes;
+        self.closure = closure;
     }
 
-    pub fn capture_visible_locals(&self) -> HashMap<String, Value> {
-        let mut captured: HashMap<String, Value> = HashMap::new();
+    /// Captures every visible local for a new closure, by reference: a local not captured yet
+    /// moves into an upvalue, which the closure then shares with its scope and with earlier
+    /// closures over it.
+    pub fn capture_visible_locals(&mut self) -> HashMap<String, GcRef> {
+        let mut captured: HashMap<String, GcRef> = HashMap::new();
 
         // Flatten outer closure env first, then let local scopes override it.
         if let Some(h) = self.closure {
@@ -121,24 +280,41 @@ impl Env {
             }
         }
 
-        for scope in &self.scopes {
-            for (k, v) in scope {
-                captured.insert(k.clone(), v.clone());
+        for scope in &mut self.scopes {
+            for (name, binding) in scope.iter_mut() {
+                if let Binding::Value(v) = binding {
+                    let h = self.heap.alloc_upvalue(Upvalue::Closed(v.clone()));
+                    *binding = Binding::Upvalue(h);
+                }
+                if let Binding::Upvalue(h) = binding {
+                    captured.insert(name.clone(), *h);
+                }
             }
         }
 
         captured
     }
 
-    pub fn roots(&self) -> Vec<Value> {
-        let mut roots = Vec::new();
-        roots.extend(self.globals.values().cloned());
-        for scope in &self.scopes {
-            roots.extend(scope.values().cloned());
-        }
-        if let Some(h) = self.closure {
-            roots.push(Value::Closure(h));
+    /// Values and upvalues the GC must keep: globals, and the locals and closures of the
+    /// running function and of every caller.
+    pub fn roots(&self) -> (Vec<Value>, Vec<GcRef>) {
+        let mut values: Vec<Value> = self.globals.values().cloned().collect();
+        let mut upvalues = Vec::new();
+        let callers = self
+            .callers
+            .iter()
+            .map(|(scopes, closure)| (scopes, closure));
+        for (scopes, closure) in callers.chain([(&self.scopes, &self.closure)]) {
+            for binding in scopes.iter().flat_map(|scope| scope.values()) {
+                match binding {
+                    Binding::Value(v) => values.push(v.clone()),
+                    Binding::Upvalue(h) => upvalues.push(*h),
+                }
+            }
+            if let Some(h) = closure {
+                values.push(Value::Closure(*h));
+            }
         }
-        roots
+        (values, upvalues)
     }
 }
@@ -1,18 +1,46 @@
+use std::rc::Rc;
+
 use moon_core::ast::{BinaryOp, Expr, Program, Stmt, UnaryOp};
 use moon_core::span::Span;
 
-use crate::env::Function;
+use moon_runtime::{ErrorKind, GcRef, Heap};
+
+use crate::env::{Function, STACK_RED_ZONE};
+use crate::error::StackFrame;
 use crate::{Env, RuntimeError, Value};
 
 #[derive(Debug, Clone)]
 enum Exec {
     Value(Value),
-    Return(Value, Span),
+    Return(Returned, Span),
+}
+
+/// What leaves a function through `return` or as its body's value in tail position.
+#[derive(Debug, Clone)]
+enum Returned {
+    Value(Value),
+    // A call in tail position, not made yet: the call that is returning makes it in its own
+    // loop (a trampoline), so tail recursion does not nest Rust calls.
+    TailCall(PendingCall),
+}
+
+/// A call with its callee resolved and its arguments evaluated, about to be entered.
+#[derive(Debug, Clone)]
+struct PendingCall {
+    name: String,
+    span: Span,
+    func: Function,
+    closure: Option<GcRef>,
+    args: Vec<Value>,
 }
 
 pub fn eval_program(program: &Program) -> Result<Value, RuntimeError> {
-    let mut env = Env::new();
+    eval_program_in(program, &mut Env::new())
+}
 
+/// Evaluates `program` in an existing environment: it sees the globals, functions and heap
+/// objects left there by earlier programs, and its own top-level definitions stay behind.
+pub fn eval_program_in(program: &Program, env: &mut Env) -> Result<Value, RuntimeError> {
     // Pre-pass: register functions so they can be called before their definition (Rust-style items).
     for stmt in &program.stmts {
         if let Stmt::Fn {
@@ -23,35 +51,35 @@ pub fn eval_program(program: &Program) -> Result<Value, RuntimeError> {
                 name.clone(),
                 Function {
                     params: params.iter().map(|p| p.name.clone()).collect(),
-                    body: body.clone(),
+                    body: Rc::new(body.clone()),
                 },
             );
         }
     }
 
     for stmt in &program.stmts {
-        match eval_stmt(stmt, &mut env)? {
+        match eval_stmt(stmt, env)? {
             Exec::Value(_) => {}
             Exec::Return(_, span) => {
-                return Err(RuntimeError {
-                    message: "return is only allowed inside functions".to_string(),
+                return Err(RuntimeError::new(
+                    "return is only allowed inside functions",
                     span,
-                })
+                ))
             }
         }
     }
 
     let result = match &program.tail {
-        Some(expr) => eval_expr(expr, &mut env)?,
+        Some(expr) => eval_expr(expr, env)?,
         None => Exec::Value(Value::Unit),
     };
 
     match result {
         Exec::Value(v) => Ok(v),
-        Exec::Return(_, span) => Err(RuntimeError {
-            message: "return is only allowed inside functions".to_string(),
+        Exec::Return(_, span) => Err(RuntimeError::new(
+            "return is only allowed inside functions",
             span,
-        }),
+        )),
     }
 }
 
@@ -74,10 +102,13 @@ fn eval_stmt(stmt: &Stmt, env: &mut Env) -> Result<Exec, RuntimeError> {
                         Exec::Return(v, sp) => return Ok(Exec::Return(v, sp)),
                     };
 
-                    env.assign_var(name, value).map_err(|()| RuntimeError {
-                        message: format!("undefined variable: {name}"),
-                        span: *span,
-                    })?;
+                    if !env.assign_var(name, value) {
+                        return Err(RuntimeError::new(
+                            format!("undefined variable: {name}"),
+                            *span,
+                        ));
+                    }
+                    env.check_heap(*span)?;
 
                     Ok(Exec::Value(Value::Unit))
                 }
@@ -100,24 +131,22 @@ fn eval_stmt(stmt: &Stmt, env: &mut Env) -> Result<Exec, RuntimeError> {
                     };
 
                     assign_index(env, base_v, index_v, value, *span)?;
+                    env.check_heap(*span)?;
                     Ok(Exec::Value(Value::Unit))
                 }
 
-                _ => Err(RuntimeError {
-                    message: "invalid assignment target".to_string(),
-                    span: *span,
-                }),
+                _ => Err(RuntimeError::new("invalid assignment target", *span)),
             }
         }
 
         Stmt::Return { expr, span } => {
             if let Some(expr) = expr {
-                match eval_expr(expr, env)? {
-                    Exec::Value(v) => Ok(Exec::Return(v, *span)),
+                match eval_tail(expr, env)? {
+                    Exec::Value(v) => Ok(Exec::Return(Returned::Value(v), *span)),
                     Exec::Return(v, sp) => Ok(Exec::Return(v, sp)),
                 }
             } else {
-                Ok(Exec::Return(Value::Unit, *span))
+                Ok(Exec::Return(Returned::Value(Value::Unit), *span))
             }
         }
 
@@ -131,6 +160,7 @@ fn eval_stmt(stmt: &Stmt, env: &mut Env) -> Result<Exec, RuntimeError> {
 }
 
 fn eval_expr(expr: &Expr, env: &mut Env) -> Result<Exec, RuntimeError> {
+    env.step(expr.span())?;
     match expr {
         Expr::Int(i, _) => Ok(Exec::Value(Value::Int(*i))),
         Expr::Bool(b, _) => Ok(Exec::Value(Value::Bool(*b))),
@@ -145,27 +175,22 @@ fn eval_expr(expr: &Expr, env: &mut Env) -> Result<Exec, RuntimeError> {
                 return Ok(Exec::Value(Value::Function(name.clone())));
             }
 
-            Err(RuntimeError {
-                message: format!("undefined variable: {name}"),
-                span: *sp,
-            })
+            Err(RuntimeError::new(
+                format!("undefined variable: {name}"),
+                *sp,
+            ))
         }
-        Expr::Fn { params, body, .. } => {
-            let name = env.fresh_lambda_name();
-            env.define_fn(
-                name.clone(),
-                Function {
-                    params: params.iter().map(|p| p.name.clone()).collect(),
-                    body: *body.clone(),
-                },
-            );
-
+        Expr::Fn {
+            params, body, span, ..
+        } => {
+            let name = env.lambda(*span, params, body);
             let captured = env.capture_visible_locals();
             let handle = env.heap.alloc_closure(name, captured);
+            env.check_heap(*span)?;
             Ok(Exec::Value(Value::Closure(handle)))
         }
 
-        Expr::Array { elements, .. } => {
+        Expr::Array { elements, span } => {
             let mut values = Vec::with_capacity(elements.len());
             for e in elements {
                 match eval_expr(e, env)? {
@@ -174,10 +199,11 @@ fn eval_expr(expr: &Expr, env: &mut Env) -> Result<Exec, RuntimeError> {
                 }
             }
             let handle = env.heap.alloc_array(values);
+            env.check_heap(*span)?;
             Ok(Exec::Value(Value::Array(handle)))
         }
 
-        Expr::Object { props, .. } => {
+        Expr::Object { props, span } => {
             let mut map = std::collections::HashMap::new();
             for (k, vexpr) in props {
                 let v = match eval_expr(vexpr, env)? {
@@ -187,135 +213,14 @@ fn eval_expr(expr: &Expr, env: &mut Env) -> Result<Exec, RuntimeError> {
                 map.insert(k.clone(), v);
             }
             let handle = env.heap.alloc_object(map);
+            env.check_heap(*span)?;
             Ok(Exec::Value(Value::Object(handle)))
         }
 
         Expr::Group { expr, .. } => eval_expr(expr, env),
 
-        Expr::Block { stmts, tail, .. } => {
-            env.push_scope();
-            let result = (|| {
-                for stmt in stmts {
-                    match eval_stmt(stmt, env)? {
-                        Exec::Value(_) => {}
-                        Exec::Return(v, sp) => return Ok(Exec::Return(v, sp)),
-                    }
-                }
-
-                match tail {
-                    Some(expr) => eval_expr(expr, env),
-                    None => Ok(Exec::Value(Value::Unit)),
-                }
-            })();
-            env.pop_scope();
-            result
-        }
-
-        Expr::If {
-            cond,
-            then_branch,
-            else_branch,
-            span,
-        } => {
-            let v = match eval_expr(cond, env)? {
-                Exec::Value(v) => v,
-                Exec::Return(v, sp) => return Ok(Exec::Return(v, sp)),
-            };
-
-            let b = match v {
-                Value::Bool(b) => b,
-                other => {
-                    return Err(RuntimeError {
-                        message: format!("if condition must be bool, got {other:?}"),
-                        span: *span,
-                    })
-                }
-            };
-
-            if b {
-                eval_expr(then_branch, env)
-            } else {
-                eval_expr(else_branch, env)
-            }
-        }
-
-        Expr::Call { callee, args, span } => {
-            let callee_v = match eval_expr(callee, env)? {
-                Exec::Value(v) => v,
-                Exec::Return(v, sp) => return Ok(Exec::Return(v, sp)),
-            };
-
-            let (name, closure) = match callee_v {
-                Value::Function(name) => (name, None),
-                Value::Closure(h) => {
-                    let func = env.heap.closure_func_name(h).ok_or_else(|| RuntimeError {
-                        message: "invalid closure handle".to_string(),
-                        span: *span,
-                    })?;
-                    (func.to_string(), Some(h))
-                }
-                other => {
-                    return Err(RuntimeError {
-                        message: format!("cannot call non-function value: {other:?}"),
-                        span: *span,
-                    })
-                }
-            };
-
-            // Builtins (minimal):
-            // - gc(): triggers a GC cycle for heap-allocated objects.
-            if name == "gc" {
-                if !args.is_empty() {
-                    return Err(RuntimeError {
-                        message: "gc() takes no arguments".to_string(),
-                        span: *span,
-                    });
-                }
-                let roots = env.roots();
-                let _ = env.heap.collect_garbage(&roots);
-                return Ok(Exec::Value(Value::Unit));
-            }
-
-            let func = env.get_fn(&name).cloned().ok_or_else(|| RuntimeError {
-                message: format!("undefined function: {name}"),
-                span: *span,
-            })?;
-
-            if func.params.len() != args.len() {
-                return Err(RuntimeError {
-                    message: format!(
-                        "wrong number of arguments for {name}: expected {}, got {}",
-                        func.params.len(),
-                        args.len()
-                    ),
-                    span: *span,
-                });
-            }
-
-            let mut values = Vec::with_capacity(args.len());
-            for arg in args {
-                match eval_expr(arg, env)? {
-                    Exec::Value(v) => values.push(v),
-                    Exec::Return(v, sp) => return Ok(Exec::Return(v, sp)),
-                }
-            }
-
-            // New call frame: only globals + function locals. Caller locals are not visible.
-            let saved_scopes = env.take_scopes();
-            let saved_closure = env.set_closure(closure);
-            env.push_scope();
-            for (param, value) in func.params.iter().zip(values) {
-                env.define_var(param.clone(), value);
-            }
-
-            let result = eval_expr(&func.body, env);
-            env.restore_scopes(saved_scopes);
-            env.set_closure(saved_closure);
-
-            match result? {
-                Exec::Value(v) => Ok(Exec::Value(v)),
-                Exec::Return(v, _) => Ok(Exec::Value(v)),
-            }
+        Expr::Block { .. } | Expr::If { .. } | Expr::Call { .. } => {
+            eval_in_position(expr, env, false)
         }
 
         Expr::Index {
@@ -342,14 +247,14 @@ fn eval_expr(expr: &Expr, env: &mut Env) -> Result<Exec, RuntimeError> {
             match (op, v) {
                 (UnaryOp::Neg, Value::Int(i)) => Ok(Exec::Value(Value::Int(-i))),
                 (UnaryOp::Not, Value::Bool(b)) => Ok(Exec::Value(Value::Bool(!b))),
-                (UnaryOp::Neg, other) => Err(RuntimeError {
-                    message: format!("cannot apply unary '-' to {other:?}"),
-                    span: *span,
-                }),
-                (UnaryOp::Not, other) => Err(RuntimeError {
-                    message: format!("cannot apply unary '!' to {other:?}"),
-                    span: *span,
-                }),
+                (UnaryOp::Neg, other) => Err(RuntimeError::new(
+                    format!("cannot apply unary '-' to {other:?}"),
+                    *span,
+                )),
+                (UnaryOp::Not, other) => Err(RuntimeError::new(
+                    format!("cannot apply unary '!' to {other:?}"),
+                    *span,
+                )),
             }
         }
 
@@ -362,10 +267,10 @@ fn eval_expr(expr: &Expr, env: &mut Env) -> Result<Exec, RuntimeError> {
                 let lb = match left {
                     Value::Bool(b) => b,
                     other => {
-                        return Err(RuntimeError {
-                            message: format!("left side of '&&' must be bool, got {other:?}"),
-                            span: *span,
-                        })
+                        return Err(RuntimeError::new(
+                            format!("left side of '&&' must be bool, got {other:?}"),
+                            *span,
+                        ))
                     }
                 };
                 if !lb {
@@ -377,10 +282,10 @@ fn eval_expr(expr: &Expr, env: &mut Env) -> Result<Exec, RuntimeError> {
                 };
                 match right {
                     Value::Bool(b) => Ok(Exec::Value(Value::Bool(b))),
-                    other => Err(RuntimeError {
-                        message: format!("right side of '&&' must be bool, got {other:?}"),
-                        span: *span,
-                    }),
+                    other => Err(RuntimeError::new(
+                        format!("right side of '&&' must be bool, got {other:?}"),
+                        *span,
+                    )),
                 }
             }
             BinaryOp::Or => {
@@ -391,10 +296,10 @@ fn eval_expr(expr: &Expr, env: &mut Env) -> Result<Exec, RuntimeError> {
                 let lb = match left {
                     Value::Bool(b) => b,
                     other => {
-                        return Err(RuntimeError {
-                            message: format!("left side of '||' must be bool, got {other:?}"),
-                            span: *span,
-                        })
+                        return Err(RuntimeError::new(
+                            format!("left side of '||' must be bool, got {other:?}"),
+                            *span,
+                        ))
                     }
                 };
                 if lb {
@@ -406,10 +311,10 @@ fn eval_expr(expr: &Expr, env: &mut Env) -> Result<Exec, RuntimeError> {
                 };
                 match right {
                     Value::Bool(b) => Ok(Exec::Value(Value::Bool(b))),
-                    other => Err(RuntimeError {
-                        message: format!("right side of '||' must be bool, got {other:?}"),
-                        span: *span,
-                    }),
+                    other => Err(RuntimeError::new(
+                        format!("right side of '||' must be bool, got {other:?}"),
+                        *span,
+                    )),
                 }
             }
             _ => {
@@ -421,57 +326,261 @@ fn eval_expr(expr: &Expr, env: &mut Env) -> Result<Exec, RuntimeError> {
                     Exec::Value(v) => v,
                     Exec::Return(v, sp) => return Ok(Exec::Return(v, sp)),
                 };
-                Ok(Exec::Value(eval_binary(*op, l, r, *span)?))
+                Ok(Exec::Value(eval_binary(*op, l, r, &env.heap, *span)?))
             }
         },
     }
 }
 
+/// Evaluates `expr` as the value a function returns: a call there (or in the tail of a block
+/// or a branch of an `if` there) is not made but handed back as `Returned::TailCall`.
+fn eval_tail(expr: &Expr, env: &mut Env) -> Result<Exec, RuntimeError> {
+    match expr {
+        Expr::Group { expr, .. } => eval_tail(expr, env),
+        Expr::Block { .. } | Expr::If { .. } | Expr::Call { .. } => {
+            env.step(expr.span())?;
+            eval_in_position(expr, env, true)
+        }
+        _ => eval_expr(expr, env),
+    }
+}
+
+/// Blocks, `if`s and calls, in tail position (`tail`) or not.
+fn eval_in_position(expr: &Expr, env: &mut Env, tail: bool) -> Result<Exec, RuntimeError> {
+    let eval_sub = |expr: &Expr, env: &mut Env| match tail {
+        true => eval_tail(expr, env),
+        false => eval_expr(expr, env),
+    };
+    match expr {
+        Expr::Block {
+            stmts,
+            tail: tail_expr,
+            ..
+        } => {
+            env.push_scope();
+            let result = (|| {
+                for stmt in stmts {
+                    match eval_stmt(stmt, env)? {
+                        Exec::Value(_) => {}
+                        Exec::Return(v, sp) => return Ok(Exec::Return(v, sp)),
+                    }
+                }
+
+                match tail_expr {
+                    Some(expr) => eval_sub(expr, env),
+                    None => Ok(Exec::Value(Value::Unit)),
+                }
+            })();
+            env.pop_scope();
+            result
+        }
+
+        Expr::If {
+            cond,
+            then_branch,
+            else_branch,
+            span,
+        } => {
+            let v = match eval_expr(cond, env)? {
+                Exec::Value(v) => v,
+                Exec::Return(v, sp) => return Ok(Exec::Return(v, sp)),
+            };
+
+            let b = match v {
+                Value::Bool(b) => b,
+                other => {
+                    return Err(RuntimeError::new(
+                        format!("if condition must be bool, got {other:?}"),
+                        *span,
+                    ))
+                }
+            };
+
+            if b {
+                eval_sub(then_branch, env)
+            } else {
+                eval_sub(else_branch, env)
+            }
+        }
+
+        Expr::Call { callee, args, span } => {
+            let call = match prepare_call(callee, args, *span, env)? {
+                Ok(call) => call,
+                Err(done) => return Ok(done),
+            };
+            if tail {
+                return Ok(Exec::Return(Returned::TailCall(call), *span));
+            }
+            run_call(call, env)
+        }
+
+        _ => unreachable!("not a block, if or call"),
+    }
+}
+
+/// Resolves the callee and evaluates the arguments of a call. `Err` carries what the call
+/// evaluates to instead when there is nothing to enter: a builtin's result, or a `return`
+/// inside the callee or argument expressions.
+fn prepare_call(
+    callee: &Expr,
+    args: &[Expr],
+    span: Span,
+    env: &mut Env,
+) -> Result<Result<PendingCall, Exec>, RuntimeError> {
+    let callee_v = match eval_expr(callee, env)? {
+        Exec::Value(v) => v,
+        Exec::Return(v, sp) => return Ok(Err(Exec::Return(v, sp))),
+    };
+
+    let (name, closure) = match callee_v {
+        Value::Function(name) => (name, None),
+        Value::Closure(h) => {
+            let func = env
+                .heap
+                .closure_func_name(h)
+                .ok_or_else(|| RuntimeError::new("invalid closure handle", span))?;
+            (func.to_string(), Some(h))
+        }
+        other => {
+            return Err(RuntimeError::new(
+                format!("cannot call non-function value: {other:?}"),
+                span,
+            ))
+        }
+    };
+
+    // Builtins (minimal):
+    // - gc(): triggers a GC cycle for heap-allocated objects.
+    if name == "gc" {
+        if !args.is_empty() {
+            return Err(RuntimeError::new("gc() takes no arguments", span));
+        }
+        let (roots, upvalues) = env.roots();
+        let _ = env.heap.collect_garbage(&roots, &upvalues);
+        return Ok(Err(Exec::Value(Value::Unit)));
+    }
+
+    let func = env
+        .get_fn(&name)
+        .cloned()
+        .ok_or_else(|| RuntimeError::new(format!("undefined function: {name}"), span))?;
+
+    if func.params.len() != args.len() {
+        return Err(RuntimeError::new(
+            format!(
+                "wrong number of arguments for {name}: expected {}, got {}",
+                func.params.len(),
+                args.len()
+            ),
+            span,
+        ));
+    }
+
+    let mut values = Vec::with_capacity(args.len());
+    for arg in args {
+        match eval_expr(arg, env)? {
+            Exec::Value(v) => values.push(v),
+            Exec::Return(v, sp) => return Ok(Err(Exec::Return(v, sp))),
+        }
+    }
+
+    Ok(Ok(PendingCall {
+        name,
+        span,
+        func,
+        closure,
+        args: values,
+    }))
+}
+
+/// Makes `call`, and then each tail call its body returns, one after the other.
+fn run_call(mut call: PendingCall, env: &mut Env) -> Result<Exec, RuntimeError> {
+    loop {
+        // New call frame: only globals + function locals. Caller locals are not visible.
+        // The Rust stack grows down; asking the OS how much is left only once per program
+        // keeps calls cheap.
+        let here = &call as *const PendingCall as usize;
+        if env.depth() == 0 {
+            env.stack_floor = stacker::remaining_stack()
+                .map_or(0, |left| here.saturating_sub(left) + STACK_RED_ZONE);
+        }
+        let overflow = if env.depth() >= env.max_depth() {
+            Some(format!("more than {} nested calls", env.max_depth()))
+        } else if here < env.stack_floor {
+            // The thread's stack is too small for `max_depth` calls: stop while it can unwind.
+            Some(format!("out of stack after {} nested calls", env.depth()))
+        } else {
+            None
+        };
+        if let Some(overflow) = overflow {
+            let mut err = RuntimeError::new(format!("stack overflow: {overflow}"), call.span)
+                .with_kind(ErrorKind::StackOverflow);
+            err.trace = env.call_stack();
+            return Err(err);
+        }
+        let frame = StackFrame {
+            function: call.name,
+            call_span: call.span,
+        };
+        env.enter_call(call.closure, frame);
+        env.push_scope();
+        for (param, value) in call.func.params.iter().zip(call.args) {
+            env.define_var(param.clone(), value);
+        }
+
+        let result = eval_tail(&call.func.body, env).map_err(|mut e| {
+            // The innermost call an error leaves records the calls running at that point.
+            if e.trace.is_empty() {
+                e.trace = env.call_stack();
+            }
+            e
+        });
+        env.leave_call();
+
+        match result? {
+            Exec::Value(v) | Exec::Return(Returned::Value(v), _) => return Ok(Exec::Value(v)),
+            Exec::Return(Returned::TailCall(next), _) => call = next,
+        }
+    }
+}
+
 fn eval_index(env: &mut Env, base: Value, index: Value, span: Span) -> Result<Value, RuntimeError> {
     match base {
         Value::Array(h) => {
             let idx = match index {
-                Value::Int(i) => usize::try_from(i).map_err(|_| RuntimeError {
-                    message: "array index must be >= 0".to_string(),
-                    span,
-                })?,
+                Value::Int(i) => usize::try_from(i)
+                    .map_err(|_| RuntimeError::new("array index must be >= 0", span))?,
                 other => {
-                    return Err(RuntimeError {
-                        message: format!("array index must be int, got {other:?}"),
+                    return Err(RuntimeError::new(
+                        format!("array index must be int, got {other:?}"),
                         span,
-                    })
+                    ))
                 }
             };
             env.heap
                 .array_get(h, idx)
                 .cloned()
-                .ok_or_else(|| RuntimeError {
-                    message: format!("index out of bounds: {idx}"),
-                    span,
-                })
+                .ok_or_else(|| RuntimeError::new(format!("index out of bounds: {idx}"), span))
         }
         Value::Object(h) => {
             let key = match index {
                 Value::String(s) => s,
                 other => {
-                    return Err(RuntimeError {
-                        message: format!("object key must be string, got {other:?}"),
+                    return Err(RuntimeError::new(
+                        format!("object key must be string, got {other:?}"),
                         span,
-                    })
+                    ))
                 }
             };
             env.heap
                 .object_get(h, &key)
                 .cloned()
-                .ok_or_else(|| RuntimeError {
-                    message: format!("missing key: {key}"),
-                    span,
-                })
+                .ok_or_else(|| RuntimeError::new(format!("missing key: {key}"), span))
         }
-        other => Err(RuntimeError {
-            message: format!("cannot index into {other:?}"),
+        other => Err(RuntimeError::new(
+            format!("cannot index into {other:?}"),
             span,
-        }),
+        )),
     }
 }
 
@@ -485,51 +594,59 @@ fn assign_index(
     match base {
         Value::Array(h) => {
             let idx = match index {
-                Value::Int(i) => usize::try_from(i).map_err(|_| RuntimeError {
-                    message: "array index must be >= 0".to_string(),
-                    span,
-                })?,
+                Value::Int(i) => usize::try_from(i)
+                    .map_err(|_| RuntimeError::new("array index must be >= 0", span))?,
                 other => {
-                    return Err(RuntimeError {
-                        message: format!("array index must be int, got {other:?}"),
+                    return Err(RuntimeError::new(
+                        format!("array index must be int, got {other:?}"),
                         span,
-                    })
+                    ))
                 }
             };
             env.heap
                 .array_set(h, idx, value)
-                .map_err(|e| RuntimeError { message: e, span })
+                .map_err(|e| RuntimeError::new(e, span))
         }
         Value::Object(h) => {
             let key = match index {
                 Value::String(s) => s,
                 other => {
-                    return Err(RuntimeError {
-                        message: format!("object key must be string, got {other:?}"),
+                    return Err(RuntimeError::new(
+                        format!("object key must be string, got {other:?}"),
                         span,
-                    })
+                    ))
                 }
             };
             env.heap
                 .object_set(h, key, value)
-                .map_err(|e| RuntimeError { message: e, span })
+                .map_err(|e| RuntimeError::new(e, span))
         }
-        other => Err(RuntimeError {
-            message: format!("cannot assign through index on {other:?}"),
+        other => Err(RuntimeError::new(
+            format!("cannot assign through index on {other:?}"),
             span,
-        }),
+        )),
     }
 }
 
-fn eval_binary(op: BinaryOp, l: Value, r: Value, span: Span) -> Result<Value, RuntimeError> {
+fn eval_binary(
+    op: BinaryOp,
+    l: Value,
+    r: Value,
+    heap: &Heap,
+    span: Span,
+) -> Result<Value, RuntimeError> {
     use Value::*;
 
-    let err = |message: std::string::String| RuntimeError { message, span };
+    let err = |message: std::string::String| RuntimeError::new(message, span);
 
     match op {
         BinaryOp::Add => match (l, r) {
             (Int(a), Int(b)) => Ok(Int(a + b)),
-            (String(a), String(b)) => Ok(String(format!("{a}{b}"))),
+            (String(a), String(b)) => {
+                heap.check_string(a.len() + b.len())
+                    .map_err(|message| err(message).with_kind(ErrorKind::HeapLimit))?;
+                Ok(String(format!("{a}{b}")))
+            }
             (a, b) => Err(err(format!("cannot add {a:?} and {b:?}"))),
         },
         BinaryOp::Sub => match (l, r) {
@@ -2,7 +2,7 @@ mod env;
 mod error;
 mod eval;
 
-pub use env::Env;
-pub use error::RuntimeError;
-pub use eval::eval_program;
+pub use env::{Env, DEFAULT_MAX_DEPTH};
+pub use error::{RuntimeError, StackFrame};
+pub use eval::{eval_program, eval_program_in};
 pub use moon_runtime::Value;
@@ -1,11 +1,11 @@
 use moon_core::lexer::lex;
 use moon_core::parser::parse;
 use moon_core::source::Source;
-use moon_interpreter::{eval_program, RuntimeError, Value};
+use moon_interpreter::{eval_program, eval_program_in, Env, RuntimeError, Value};
 
 fn run_result(src: &str) -> Result<Value, RuntimeError> {
     let source = Source::new("<test>", src.to_string());
-    let tokens = lex(&source.text).unwrap();
+    let tokens = lex(source.text()).unwrap();
     let program = parse(tokens).unwrap();
     eval_program(&program)
 }
@@ -88,6 +88,62 @@ fn closures_can_mutate_captured_state() {
     assert_eq!(v, Value::Int(3));
 }
 
+#[test]
+fn closures_share_a_captured_variable() {
+    let v = run("{
+             let n = 0;
+             let inc = fn() -> Int { n = n + 1; n };
+             let get = fn() -> Int { n };
+             inc();
+             inc();
+             get() * 10 + n
+         }");
+    assert_eq!(v, Value::Int(22));
+}
+
+#[test]
+fn assignments_after_capture_are_seen_by_the_closure() {
+    let v = run("{ let x = 1; let f = fn() -> Int { x }; x = 5; f() }");
+    assert_eq!(v, Value::Int(5));
+}
+
+#[test]
+fn closures_keep_sharing_after_their_scope_ends() {
+    let v = run("let fs = {
+             let n = 0;
+             [fn() -> Int { n = n + 1; n }, fn() -> Int { n * 100 }]
+         };
+         fs[0]();
+         fs[0]();
+         fs[1]()");
+    assert_eq!(v, Value::Int(200));
+}
+
+#[test]
+fn a_closed_variable_is_not_affected_by_slot_reuse() {
+    let v = run("{
+             let f = { let x = 1; fn() -> Int { x } };
+             let y = 50;
+             f() + y
+         }");
+    assert_eq!(v, Value::Int(51));
+}
+
+#[test]
+fn captured_state_survives_gc() {
+    let v = run("fn collect() -> Int { gc(); 0 }
+         let c = { let n = 40; fn() -> Int { n = n + 1; n } };
+         gc();
+         c();
+         {
+             let x = 1;
+             let f = fn() -> Int { x };
+             collect();
+             c() + x + f()
+         }");
+    assert_eq!(v, Value::Int(44));
+}
+
 #[test]
 fn array_literal_index_and_assignment() {
     let v = run("let a = [1, 2, 3]; a[0] = 10; a[0] + a[1]");
@@ -118,3 +174,278 @@ fn return_exits_function_early() {
         run("fn f(x: Int) -> Int { if x > 0 { return x; } else { }; x + 1 }\n         f(0) + f(2)");
     assert_eq!(v, Value::Int(3));
 }
+
+#[test]
+fn programs_evaluated_in_one_env_share_globals_functions_and_heap() {
+    let program = |src: &str| parse(lex(src).unwrap()).unwrap();
+    let mut env = Env::new();
+    eval_program_in(
+        &program("let xs = [1, 2]; fn first() -> Int { xs[0] }"),
+        &mut env,
+    )
+    .unwrap();
+    eval_program_in(&program("xs[0] = 7; gc();"), &mut env).unwrap();
+    let v = eval_program_in(&program("first() + xs[1]"), &mut env).unwrap();
+    assert_eq!(v, Value::Int(9));
+}
+
+#[test]
+fn tail_calls_do_not_grow_the_stack() {
+    let v = run(
+        "fn count(n: Int, acc: Int) -> Int { if n == 0 { acc } else { count(n - 1, acc + 1) } }
+         count(1000000, 0)",
+    );
+    assert_eq!(v, Value::Int(1000000));
+}
+
+#[test]
+fn mutual_recursion_through_return_is_a_tail_call() {
+    let v = run(
+        "fn even(n: Int) -> Bool { if n == 0 { return true; } else { 0 }; odd(n - 1) }
+         fn odd(n: Int) -> Bool { if n == 0 { false } else { even(n - 1) } }
+         even(1000001)",
+    );
+    assert_eq!(v, Value::Bool(false));
+}
+
+const DEEP: &str = "fn down(n: Int) -> Int { if n == 0 { 0 } else { 1 + down(n - 1) } }
+fn start(n: Int) -> Int { down(n) + 0 }
+start(100)";
+
+#[test]
+fn too_many_nested_calls_are_a_stack_overflow_error() {
+    let program = parse(lex(DEEP).unwrap()).unwrap();
+    let mut env = Env::new();
+    env.set_max_depth(20);
+    let err = eval_program_in(&program, &mut env).unwrap_err();
+    assert_eq!(err.message, "stack overflow: more than 20 nested calls");
+    assert_eq!(&DEEP[err.span.start..err.span.end], "down(n - 1)");
+
+    // Innermost first: 19 recursive calls, then `start`'s call, then main's.
+    assert_eq!(err.trace.len(), 20);
+    assert!(err.trace[..18]
+        .iter()
+        .all(|f| f.function == "down" && f.call_span == err.span));
+    assert_eq!(err.trace[18].function, "down");
+    let start_call = err.trace[18].call_span;
+    assert_eq!(&DEEP[start_call.start..start_call.end], "down(n)");
+    assert_eq!(err.trace[19].function, "start");
+    let main_call = err.trace[19].call_span;
+    assert_eq!(&DEEP[main_call.start..main_call.end], "start(100)");
+
+    // The environment is left as it was: it can keep running programs.
+    assert_eq!(env.depth(), 0);
+    let again = parse(lex("start(10)").unwrap()).unwrap();
+    assert_eq!(eval_program_in(&again, &mut env).unwrap(), Value::Int(10));
+}
+
+#[test]
+fn running_out_of_thread_stack_is_a_stack_overflow_error() {
+    // Past the default depth, on the test thread's default stack, which is far too small for
+    // that many calls: the interpreter must stop with an error rather than crash.
+    let src = DEEP.replace("start(100)", "start(5000)");
+    let program = parse(lex(&src).unwrap()).unwrap();
+    let mut env = Env::new();
+    let err = eval_program_in(&program, &mut env).unwrap_err();
+    assert_eq!(err.kind, moon_runtime::ErrorKind::StackOverflow);
+    assert!(
+        err.message.starts_with("stack overflow: "),
+        "{}",
+        err.message
+    );
+
+    assert_eq!(env.depth(), 0);
+    let again = parse(lex("start(10)").unwrap()).unwrap();
+    assert_eq!(eval_program_in(&again, &mut env).unwrap(), Value::Int(10));
+}
+
+#[test]
+fn tail_calls_do_not_count_towards_the_depth() {
+    let program = parse(
+        lex(
+            "fn count(n: Int, acc: Int) -> Int { if n == 0 { acc } else { count(n - 1, acc + 1) } }
+             count(5000, 0)",
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    let mut env = Env::new();
+    env.set_max_depth(1);
+    assert_eq!(
+        eval_program_in(&program, &mut env).unwrap(),
+        Value::Int(5000)
+    );
+}
+
+const NESTED: &str = "fn get(xs: Array<Int>, i: Int) -> Int { xs[i] }
+fn second(xs: Array<Int>) -> Int { get(xs, 1) + 0 }
+fn last(xs: Array<Int>) -> Int { second(xs) }
+let pick = fn(xs: Array<Int>) -> Int { last(xs) * 2 };
+pick([1])";
+
+#[test]
+fn runtime_errors_carry_the_calls_that_led_to_them() {
+    let err = run_result(NESTED).unwrap_err();
+    assert_eq!(err.message, "index out of bounds: 1");
+    let trace: Vec<_> = err
+        .trace
+        .iter()
+        .map(|f| {
+            (
+                f.function.as_str(),
+                &NESTED[f.call_span.start..f.call_span.end],
+            )
+        })
+        .collect();
+    // `last` called `second` as a tail call: its frame is gone.
+    assert_eq!(
+        trace,
+        [
+            ("get", "get(xs, 1)"),
+            ("second", "second(xs)"),
+            ("<lambda#0>", "pick([1])"),
+        ]
+    );
+
+    // Outside of any call there is nothing to list.
+    assert!(run_result("[1][5]").unwrap_err().trace.is_empty());
+}
+
+#[test]
+fn a_fn_expression_defines_one_function_however_often_it_runs() {
+    let src = "fn f(n: Int) -> Int {
+                   let g = fn(i: Int) -> Int { [i][i] };
+                   if n == 0 { g(5) } else { f(n - 1) + 0 }
+               }
+               f(3)";
+    let err = run_result(src).unwrap_err();
+    assert_eq!(err.trace[0].function, "<lambda#0>");
+
+    // Another program in the same `Env` may have another `fn` at the same span.
+    let mut env = Env::new();
+    let first = parse(lex("let h = fn(i: Int) -> Int { i + 1 }; h(1)").unwrap()).unwrap();
+    let second = parse(lex("let h = fn(i: Int) -> Int { i * 5 }; h(1)").unwrap()).unwrap();
+    assert_eq!(eval_program_in(&first, &mut env).unwrap(), Value::Int(2));
+    assert_eq!(eval_program_in(&second, &mut env).unwrap(), Value::Int(5));
+}
+
+fn run_limited(src: &str, limits: moon_runtime::Limits) -> Result<Value, RuntimeError> {
+    let mut env = Env::new();
+    env.set_limits(limits);
+    eval_program_in(&parse(lex(src).unwrap()).unwrap(), &mut env)
+}
+
+const SPIN: &str = "fn spin(n: Int) -> Int { spin(n + 1) } spin(0)";
+
+#[test]
+fn running_out_of_fuel_stops_the_program() {
+    use moon_runtime::{ErrorKind, Limits};
+
+    let limits = Limits {
+        fuel: Some(10_000),
+        ..Limits::default()
+    };
+    let err = run_limited(SPIN, limits).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::OutOfFuel);
+    assert_eq!(err.message, "out of fuel: ran 10000 steps");
+
+    // Enough fuel: the same limit does not get in the way.
+    assert_eq!(run_limited("1 + 2", limits).unwrap(), Value::Int(3));
+}
+
+#[test]
+fn a_passed_deadline_stops_the_program() {
+    use moon_runtime::{ErrorKind, Limits};
+
+    let limits = Limits {
+        deadline: Some(std::time::Instant::now()),
+        ..Limits::default()
+    };
+    let err = run_limited(SPIN, limits).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::Deadline);
+    assert_eq!(err.message, "deadline exceeded");
+}
+
+#[test]
+fn growing_the_heap_past_its_limits_stops_the_program() {
+    use moon_runtime::{ErrorKind, HeapLimits, Limits};
+
+    let objects = Limits {
+        heap: HeapLimits {
+            max_objects: Some(50),
+            max_bytes: None,
+        },
+        ..Limits::default()
+    };
+    let src = "fn grow(n: Int) -> Int { let xs = [n]; if n == 0 { 0 } else { grow(n - 1) } }
+               grow(100)";
+    let err = run_limited(src, objects).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::HeapLimit);
+    assert_eq!(
+        err.message,
+        "heap limit exceeded: 51 objects (the limit is 50)"
+    );
+    assert_eq!(&src[err.span.start..err.span.end], "[n]");
+
+    // Garbage collected along the way leaves room.
+    let src = "fn grow(n: Int) -> Int { let xs = [n]; gc(); if n == 0 { 0 } else { grow(n - 1) } }
+               grow(100)";
+    assert_eq!(run_limited(src, objects).unwrap(), Value::Int(0));
+
+    let bytes = Limits {
+        heap: HeapLimits {
+            max_objects: None,
+            max_bytes: Some(1000),
+        },
+        ..Limits::default()
+    };
+    let src = "let s = \"0123456789\";
+               let t = s + s + s + s + s + s + s + s + s + s;
+               let u = t + t + t + t + t + t + t + t + t + t;
+               let small = [t];
+               let big = [u];
+               0";
+    let err = run_limited(src, bytes).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::HeapLimit);
+    assert_eq!(&src[err.span.start..err.span.end], "[u]");
+    assert!(
+        err.message.ends_with("bytes (the limit is 1000)"),
+        "{}",
+        err.message
+    );
+}
+
+#[test]
+fn a_string_over_the_byte_limit_is_not_built() {
+    use moon_runtime::{ErrorKind, HeapLimits, Limits};
+
+    let bytes = Limits {
+        heap: HeapLimits {
+            max_objects: None,
+            max_bytes: Some(1000),
+        },
+        ..Limits::default()
+    };
+    // Doubling a string never puts it in a heap object: only its length says it is too big.
+    let src = "fn f(s: String, n: Int) -> String { if n == 0 { s } else { f(s + s, n - 1) } }
+               f(\"ab\", 40)";
+    let err = run_limited(src, bytes).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::HeapLimit);
+    assert_eq!(
+        err.message,
+        "heap limit exceeded: a string of 1024 bytes (the limit is 1000)"
+    );
+    assert_eq!(&src[err.span.start..err.span.end], "s + s");
+}
+
+#[test]
+fn other_errors_have_their_own_kinds() {
+    use moon_runtime::ErrorKind;
+
+    assert_eq!(run_result("1 / 0").unwrap_err().kind, ErrorKind::Failed);
+    let program = parse(lex("fn f(n: Int) -> Int { 1 + f(n) } f(0)").unwrap()).unwrap();
+    let mut env = Env::new();
+    env.set_max_depth(10);
+    let err = eval_program_in(&program, &mut env).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::StackOverflow);
+}
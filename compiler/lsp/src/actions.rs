@@ -0,0 +1,351 @@
+use std::collections::HashMap;
+
+use moon_core::ast::{Expr, Stmt};
+use moon_core::lexer::TokenKind;
+use moon_core::span::Span;
+use moon_typechecker::Type;
+use tower_lsp::lsp_types::{
+    CodeAction, CodeActionKind, Diagnostic, NumberOrString, TextEdit, Url, WorkspaceEdit,
+};
+
+use crate::analysis::Document;
+use crate::completion::expr_type;
+use crate::diagnostics::{
+    diagnostics, MISSING_ELSE, MISSING_SEMICOLON, UNDEFINED_NAME, UNUSED_BINDING,
+};
+use crate::hints::annotatable;
+use crate::walk::{type_expr_text, walk, walk_expr, Node};
+
+/// Quick fixes for the diagnostics overlapping `start..end`, plus annotation refactors for the
+/// `let`s in it.
+pub fn code_actions(doc: &Document, uri: &Url, start: usize, end: usize) -> Vec<CodeAction> {
+    let overlaps = |sp: Span| sp.start <= end && start <= sp.end;
+    let mut actions = Vec::new();
+
+    for diag in diagnostics(doc) {
+        let span = Span::in_file(
+            doc.file,
+            doc.offset(diag.range.start),
+            doc.offset(diag.range.end),
+        );
+        if !overlaps(span) {
+            continue;
+        }
+        if let Some((title, edits)) = quick_fix(doc, &diag, span) {
+            actions.push(CodeAction {
+                title,
+                kind: Some(CodeActionKind::QUICKFIX),
+                diagnostics: Some(vec![diag]),
+                edit: Some(edit(uri, edits)),
+                is_preferred: Some(true),
+                ..Default::default()
+            });
+        }
+    }
+
+    for (title, edits) in annotations(doc, overlaps) {
+        actions.push(CodeAction {
+            title,
+            kind: Some(CodeActionKind::REFACTOR_REWRITE),
+            edit: Some(edit(uri, edits)),
+            ..Default::default()
+        });
+    }
+    actions
+}
+
+fn edit(uri: &Url, edits: Vec<TextEdit>) -> WorkspaceEdit {
+    WorkspaceEdit {
+        changes: Some(HashMap::from([(uri.clone(), edits)])),
+        ..Default::default()
+    }
+}
+
+fn insert(doc: &Document, offset: usize, text: &str) -> TextEdit {
+    let at = doc.range(Span::in_file(doc.file, offset, offset));
+    TextEdit {
+        range: at,
+        new_text: text.to_string(),
+    }
+}
+
+fn quick_fix(doc: &Document, diag: &Diagnostic, span: Span) -> Option<(String, Vec<TextEdit>)> {
+    let Some(NumberOrString::String(code)) = &diag.code else {
+        return None;
+    };
+    match code.as_str() {
+        UNUSED_BINDING => remove_binding(doc, span),
+        MISSING_SEMICOLON => {
+            let at = end_of_previous_token(doc, span.start)?;
+            Some(("Add missing `;`".to_string(), vec![insert(doc, at, ";")]))
+        }
+        MISSING_ELSE => {
+            let at = end_of_previous_token(doc, span.start)?;
+            Some((
+                "Insert missing `else` branch".to_string(),
+                vec![insert(doc, at, " else { }")],
+            ))
+        }
+        UNDEFINED_NAME => {
+            // The error points at the name.
+            let name = doc.text.get(span.start..span.end)?;
+            stub_function(doc, name, span)
+        }
+        _ => None,
+    }
+}
+
+/// Parse errors point at the token that was unexpected; fixes go right after the one before.
+fn end_of_previous_token(doc: &Document, offset: usize) -> Option<usize> {
+    doc.tokens()
+        .ok()?
+        .iter()
+        .rev()
+        .find(|t| t.span.end <= offset && !matches!(t.kind, TokenKind::Eof))
+        .map(|t| t.span.end)
+}
+
+/// `fn name(...) -> T { .. }` for a call to an undefined `name`, inserted before the top-level
+/// item containing the call. Parameter types come from the arguments; the return type from an
+/// annotated `let` the call initializes, or `Unit`.
+fn stub_function(doc: &Document, name: &str, callee: Span) -> Option<(String, Vec<TextEdit>)> {
+    let program = doc.program().ok()?;
+    let info = doc.check().ok()?;
+
+    let mut call = None;
+    let mut ret = None;
+    walk(program, &mut |node| match node {
+        Node::Expr(Expr::Call {
+            callee: c, args, ..
+        }) if c.span() == callee => call = Some(args),
+        Node::Stmt(Stmt::Let {
+            ty: Some(ty),
+            expr: Expr::Call { callee: c, .. },
+            ..
+        }) if c.span() == callee => ret = Some(type_expr_text(ty)),
+        _ => {}
+    });
+    let args = call?;
+
+    let mut params: Vec<String> = Vec::new();
+    for (i, arg) in args.iter().enumerate() {
+        let ty = expr_type(Some(info), arg.span()).filter(|t| annotatable(t))?;
+        let param = match arg {
+            Expr::Ident(n, _) if !params.iter().any(|p| p.starts_with(&format!("{n}:"))) => {
+                n.clone()
+            }
+            _ => format!("arg{}", i + 1),
+        };
+        params.push(format!("{param}: {ty}"));
+    }
+    let ret = ret.unwrap_or_else(|| Type::Unit.to_string());
+    let body = match ret.as_str() {
+        "Int" => "\n    0\n",
+        "Bool" => "\n    false\n",
+        "String" => "\n    \"\"\n",
+        _ => "\n",
+    };
+    let stub = format!("fn {name}({}) -> {ret} {{{body}}}\n\n", params.join(", "));
+
+    // Functions are hoisted, so any top-level position works; right before the item that
+    // needs it keeps it close.
+    let item_start = program
+        .stmts
+        .iter()
+        .map(Stmt::span)
+        .chain(program.tail.as_ref().map(Expr::span))
+        .find(|sp| sp.start <= callee.start && callee.end <= sp.end)?
+        .start;
+    let at = doc.text[..item_start].rfind('\n').map_or(0, |i| i + 1);
+
+    Some((
+        format!("Create function `{name}`"),
+        vec![insert(doc, at, &stub)],
+    ))
+}
+
+/// Deletes an unused `let`. If its initializer may have effects (calls or assignments), only
+/// `let name =` is removed and the value is kept as an expression statement.
+fn remove_binding(doc: &Document, name_span: Span) -> Option<(String, Vec<TextEdit>)> {
+    let program = doc.program().ok()?;
+    let mut found = None;
+    walk(program, &mut |node| {
+        if let Node::Stmt(Stmt::Let {
+            name_span: sp,
+            name,
+            expr,
+            span,
+            ..
+        }) = node
+        {
+            if *sp == name_span {
+                found = Some((name, expr, *span));
+            }
+        }
+    });
+    let (name, expr, span) = found?;
+
+    let mut effects = false;
+    walk_expr(expr, &mut |node| {
+        effects |= matches!(
+            node,
+            Node::Expr(Expr::Call { .. }) | Node::Stmt(Stmt::Assign { .. })
+        );
+    });
+    if effects {
+        let range = doc.range(Span::in_file(doc.file, span.start, expr.span().start));
+        return Some((
+            format!("Remove unused binding `{name}` (keep its value)"),
+            vec![TextEdit {
+                range,
+                new_text: String::new(),
+            }],
+        ));
+    }
+
+    // The statement span stops before `;`.
+    let semi = doc
+        .tokens()
+        .ok()?
+        .iter()
+        .find(|t| t.span.start >= span.end && matches!(t.kind, TokenKind::Semicolon))?
+        .span
+        .end;
+    let text = &doc.text;
+    let line_start = text[..span.start].rfind('\n').map_or(0, |i| i + 1);
+    let rest = &text[semi..];
+    let line_end = rest.find('\n').map_or(text.len(), |i| semi + i + 1);
+    let own_line =
+        text[line_start..span.start].trim().is_empty() && text[semi..line_end].trim().is_empty();
+    let (start, end) = if own_line {
+        (line_start, line_end)
+    } else {
+        (span.start, semi)
+    };
+
+    Some((
+        format!("Remove unused binding `{name}`"),
+        vec![TextEdit {
+            range: doc.range(Span::in_file(doc.file, start, end)),
+            new_text: String::new(),
+        }],
+    ))
+}
+
+/// "Add type annotation" for unannotated `let`s (selected by `wanted`) whose inferred type can
+/// be written down.
+fn annotations(doc: &Document, wanted: impl Fn(Span) -> bool) -> Vec<(String, Vec<TextEdit>)> {
+    let (Ok(program), Ok(info)) = (doc.program(), doc.check()) else {
+        return Vec::new();
+    };
+    let mut out = Vec::new();
+    walk(program, &mut |node| {
+        if let Node::Stmt(Stmt::Let {
+            ty: None,
+            name_span,
+            expr,
+            ..
+        }) = node
+        {
+            if !wanted(*name_span) || matches!(expr, Expr::Fn { .. }) {
+                return;
+            }
+            if let Some(ty) = expr_type(Some(info), expr.span()).filter(|t| annotatable(t)) {
+                let edit = insert(doc, name_span.end, &format!(": {ty}"));
+                out.push((format!("Add type annotation `: {ty}`"), vec![edit]));
+            }
+        }
+    });
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use moon_core::span::FileId;
+
+    // Applies the action titled `title` (offered for the whole document) and returns the text.
+    fn apply(src: &str, title: &str) -> String {
+        let doc = Document::new(src.to_string(), None, FileId(0));
+        let uri = Url::parse("file:///tmp/a.moon").unwrap();
+        let actions = code_actions(&doc, &uri, 0, src.len());
+        let action = actions
+            .iter()
+            .find(|a| a.title == title)
+            .unwrap_or_else(|| {
+                let titles: Vec<&str> = actions.iter().map(|a| a.title.as_str()).collect();
+                panic!("no action `{title}` in {titles:?}")
+            });
+
+        let mut edits = action.edit.as_ref().unwrap().changes.as_ref().unwrap()[&uri].clone();
+        edits.sort_by_key(|e| std::cmp::Reverse(doc.offset(e.range.start)));
+        let mut out = src.to_string();
+        for e in edits {
+            out.replace_range(
+                doc.offset(e.range.start)..doc.offset(e.range.end),
+                &e.new_text,
+            );
+        }
+        out
+    }
+
+    #[test]
+    fn adds_type_annotation() {
+        let out = apply("let xs = [1, 2];\nxs", "Add type annotation `: Array<Int>`");
+        assert_eq!(out, "let xs: Array<Int> = [1, 2];\nxs");
+    }
+
+    #[test]
+    fn adds_missing_semicolon_after_let() {
+        let out = apply("let x = 1\nx", "Add missing `;`");
+        assert_eq!(out, "let x = 1;\nx");
+    }
+
+    #[test]
+    fn inserts_missing_else() {
+        let out = apply("if true { gc(); }\n", "Insert missing `else` branch");
+        assert_eq!(out, "if true { gc(); } else { }\n");
+    }
+
+    #[test]
+    fn creates_function_stub_from_call() {
+        let src = "let name = \"moon\";\nlet n: Int = greet(name, 3);\nn";
+        let out = apply(src, "Create function `greet`");
+        assert_eq!(
+            out,
+            "let name = \"moon\";\nfn greet(name: String, arg2: Int) -> Int {\n    0\n}\n\nlet n: Int = greet(name, 3);\nn"
+        );
+        let doc = Document::new(out, None, FileId(0));
+        assert!(doc.errors().is_empty());
+    }
+
+    #[test]
+    fn removes_unused_bindings() {
+        let src = "let used = 1;\n  let unused = used + 1;\nused";
+        assert_eq!(
+            apply(src, "Remove unused binding `unused`"),
+            "let used = 1;\nused"
+        );
+
+        // Calls are kept.
+        let src = "let x = gc(); 1";
+        assert_eq!(
+            apply(src, "Remove unused binding `x` (keep its value)"),
+            "gc(); 1"
+        );
+    }
+
+    #[test]
+    fn quick_fixes_key_off_diagnostic_codes() {
+        let code_of = |src: &str| {
+            let doc = Document::new(src.to_string(), None, FileId(0));
+            match diagnostics(&doc).remove(0).code {
+                Some(NumberOrString::String(code)) => code,
+                other => panic!("no code: {other:?}"),
+            }
+        };
+        assert_eq!(code_of("let x = 1\nx"), MISSING_SEMICOLON);
+        assert_eq!(code_of("if true { 1 }"), MISSING_ELSE);
+        assert_eq!(code_of("greet(1)"), UNDEFINED_NAME);
+    }
+}
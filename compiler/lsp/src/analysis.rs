@@ -0,0 +1,336 @@
+use std::sync::{Arc, Mutex, OnceLock};
+
+use moon_core::ast::Program;
+use moon_core::error::{LexError, ParseError};
+use moon_core::lexer::{lex_file, lex_with_trivia, Token, TokenKind, Trivia};
+use moon_core::line_index::{LineCol, LineIndex};
+use moon_core::parser::parse;
+use moon_core::resolver::{resolve, Resolution};
+use moon_core::span::{FileId, Span};
+use moon_typechecker::{check_program_incremental, CheckInfo, FnCache, Type, TypeError};
+use tower_lsp::lsp_types::{Position, Range, TextDocumentContentChangeEvent};
+
+type Lexed = (Vec<Token>, Vec<Trivia>);
+
+/// One revision of an open document.
+///
+/// Every analysis result is a query computed lazily on first use and memoised for the lifetime
+/// of the revision, so diagnostics, hover, definition, etc. share one lex/parse/check. An edit
+/// creates a new revision; the per-function type cache is carried over, so only functions the
+/// edit touched are re-checked.
+#[derive(Debug)]
+pub struct Document {
+    pub text: String,
+    pub version: Option<i32>,
+    pub line_index: LineIndex,
+    // Spans produced for this document carry this id, so they can be mapped back to its `Url`.
+    pub file: FileId,
+    tokens: OnceLock<Result<Lexed, LexError>>,
+    program: OnceLock<Result<Program, ParseError>>,
+    resolution: OnceLock<Resolution>,
+    check: OnceLock<CheckInfo>,
+    fn_cache: Arc<Mutex<FnCache>>,
+}
+
+/// An error from one of the analysis stages.
+#[derive(Debug, Clone, Copy)]
+pub enum AnalysisError<'a> {
+    Lex(&'a LexError),
+    Parse(&'a ParseError),
+    Type(&'a TypeError),
+}
+
+impl AnalysisError<'_> {
+    pub fn span(&self) -> Span {
+        match self {
+            AnalysisError::Lex(e) => e.span,
+            AnalysisError::Parse(e) => e.span,
+            AnalysisError::Type(e) => e.span,
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            AnalysisError::Lex(e) => format!("lex error: {}", e.message),
+            AnalysisError::Parse(e) => format!("parse error: {}", e.message),
+            AnalysisError::Type(e) => format!("type error: {}", e.message),
+        }
+    }
+}
+
+impl Document {
+    pub fn new(text: String, version: Option<i32>, file: FileId) -> Self {
+        Self::with_cache(text, version, file, Arc::default())
+    }
+
+    fn with_cache(
+        text: String,
+        version: Option<i32>,
+        file: FileId,
+        fn_cache: Arc<Mutex<FnCache>>,
+    ) -> Self {
+        let line_index = LineIndex::new(&text);
+        Self {
+            text,
+            version,
+            line_index,
+            file,
+            tokens: OnceLock::new(),
+            program: OnceLock::new(),
+            resolution: OnceLock::new(),
+            check: OnceLock::new(),
+            fn_cache,
+        }
+    }
+
+    /// Applies LSP content changes (incremental or full) and returns the next revision.
+    pub fn apply_changes(
+        &self,
+        version: Option<i32>,
+        changes: Vec<TextDocumentContentChangeEvent>,
+    ) -> Document {
+        let mut text = self.text.clone();
+        let mut index: Option<LineIndex> = None;
+        for change in changes {
+            match change.range {
+                Some(range) => {
+                    // Ranges of later changes refer to the text after earlier ones.
+                    let idx = index.get_or_insert_with(|| LineIndex::new(&text));
+                    let start = offset_from_position_utf16(idx, range.start);
+                    let end = offset_from_position_utf16(idx, range.end).max(start);
+                    text.replace_range(start..end, &change.text);
+                    index = None;
+                }
+                None => {
+                    text = change.text;
+                    index = None;
+                }
+            }
+        }
+        Document::with_cache(text, version, self.file, self.fn_cache.clone())
+    }
+
+    pub fn tokens(&self) -> Result<&[Token], &LexError> {
+        self.lexed().map(|(tokens, _)| tokens.as_slice())
+    }
+
+    /// Whitespace and comments, which the parser never sees.
+    pub fn trivia(&self) -> Result<&[Trivia], &LexError> {
+        self.lexed().map(|(_, trivia)| trivia.as_slice())
+    }
+
+    fn lexed(&self) -> Result<&Lexed, &LexError> {
+        self.tokens
+            .get_or_init(|| lex_with_trivia(&self.text, self.file))
+            .as_ref()
+    }
+
+    pub fn program(&self) -> Result<&Program, AnalysisError<'_>> {
+        let tokens = self.tokens().map_err(AnalysisError::Lex)?;
+        self.program
+            .get_or_init(|| parse(tokens.to_vec()))
+            .as_ref()
+            .map_err(AnalysisError::Parse)
+    }
+
+    pub fn resolution(&self) -> Result<&Resolution, AnalysisError<'_>> {
+        let program = self.program()?;
+        Ok(self.resolution.get_or_init(|| resolve(program)))
+    }
+
+    /// Types for the document. Type errors do not make this fail: they are collected in
+    /// `CheckInfo::errors`, and everything that does not depend on them is still typed.
+    pub fn check(&self) -> Result<&CheckInfo, AnalysisError<'_>> {
+        let program = self.program()?;
+        Ok(self.check.get_or_init(|| {
+            let mut cache = self.fn_cache.lock().unwrap_or_else(|e| e.into_inner());
+            check_program_incremental(program, &self.text, &mut cache)
+        }))
+    }
+
+    /// Every problem to report: the lex/parse error that stopped the pipeline, or all type
+    /// errors.
+    pub fn errors(&self) -> Vec<AnalysisError<'_>> {
+        match self.check() {
+            Ok(info) => info.errors.iter().map(AnalysisError::Type).collect(),
+            Err(e) => vec![e],
+        }
+    }
+
+    /// A copy of this revision that parses, for features that run while the user is typing
+    /// at `offset` (completion, signature help). Two repairs are tried in order; both keep the
+    /// text before the cursor line byte-for-byte, so spans stay comparable with this revision.
+    pub fn repaired(&self, offset: usize) -> Option<Document> {
+        let text = &self.text;
+        let line_start = text[..offset].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = text[offset..].find('\n').map_or(text.len(), |i| offset + i);
+
+        // 1) Blank out the line being edited; often the rest of the file is fine.
+        let mut blanked = String::with_capacity(text.len());
+        blanked.push_str(&text[..line_start]);
+        blanked.extend(std::iter::repeat_n(' ', line_end - line_start));
+        blanked.push_str(&text[line_end..]);
+
+        // 2) Keep only the lines before the cursor and close whatever they left open
+        //    (e.g. a function whose `}` has not been typed yet).
+        let truncated = lex_file(&text[..line_start], self.file).ok().map(|tokens| {
+            let mut open = Vec::new();
+            for tok in tokens {
+                match tok.kind {
+                    TokenKind::LParen => open.push(')'),
+                    TokenKind::LBrace => open.push('}'),
+                    TokenKind::LBracket => open.push(']'),
+                    TokenKind::RParen | TokenKind::RBrace | TokenKind::RBracket => {
+                        open.pop();
+                    }
+                    _ => {}
+                }
+            }
+            let mut out = text[..line_start].to_string();
+            out.extend(std::iter::repeat_n(' ', offset - line_start));
+            out.extend(open.iter().rev());
+            out
+        });
+
+        [Some(blanked), truncated]
+            .into_iter()
+            .flatten()
+            .map(|t| Document::new(t, None, self.file))
+            .find(|d| d.program().is_ok())
+    }
+
+    /// The innermost typed expression at `offset` (or ending right before it, so hovering just
+    /// after an identifier works). Parts whose type is unknown because of an error are skipped.
+    pub fn type_at(&self, offset: usize) -> Option<(Span, &Type)> {
+        let info = self.check().ok()?;
+        let innermost = |at: usize| {
+            info.expr_types
+                .iter()
+                .filter(|(sp, ty)| sp.start <= at && at < sp.end && *ty != Type::Unknown)
+                .min_by_key(|(sp, _)| sp.end - sp.start)
+        };
+        innermost(offset)
+            .or_else(|| offset.checked_sub(1).and_then(innermost))
+            .map(|(sp, ty)| (*sp, ty))
+    }
+
+    pub fn range(&self, span: Span) -> Range {
+        range_from_span_utf16(&self.line_index, span)
+    }
+
+    pub fn offset(&self, position: Position) -> usize {
+        offset_from_position_utf16(&self.line_index, position)
+    }
+}
+
+pub fn range_from_span_utf16(index: &LineIndex, span: Span) -> Range {
+    let start = position_from_offset_utf16(index, span.start);
+    let end = position_from_offset_utf16(index, span.end);
+    Range { start, end }
+}
+
+pub fn position_from_offset_utf16(index: &LineIndex, offset: usize) -> Position {
+    // LSP positions are (line, character) where character is UTF-16 code units.
+    let LineCol { line, col } = index.utf16_line_col(offset);
+    Position {
+        line,
+        character: col,
+    }
+}
+
+pub fn offset_from_position_utf16(index: &LineIndex, position: Position) -> usize {
+    // Convert an LSP position (UTF-16 line/col) into a byte offset.
+    index.offset_utf16(LineCol {
+        line: position.line,
+        col: position.character,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn change(
+        range: Option<((u32, u32), (u32, u32))>,
+        text: &str,
+    ) -> TextDocumentContentChangeEvent {
+        TextDocumentContentChangeEvent {
+            range: range.map(|((l1, c1), (l2, c2))| Range {
+                start: Position::new(l1, c1),
+                end: Position::new(l2, c2),
+            }),
+            range_length: None,
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn incremental_changes_apply_in_order() {
+        let doc = Document::new("let x = 1;\nx".to_string(), Some(1), FileId(0));
+        let doc = doc.apply_changes(
+            Some(2),
+            vec![
+                change(Some(((0, 8), (0, 9))), "42"),
+                change(Some(((1, 0), (1, 1))), "x + 1"),
+                change(Some(((1, 5), (1, 5))), "\n"),
+            ],
+        );
+        assert_eq!(doc.text, "let x = 42;\nx + 1\n");
+        assert_eq!(doc.version, Some(2));
+
+        let doc = doc.apply_changes(Some(3), vec![change(None, "true")]);
+        assert_eq!(doc.text, "true");
+    }
+
+    #[test]
+    fn revisions_share_the_function_cache() {
+        let doc = Document::new(
+            "fn f() -> Int { 1 }\nfn g() -> Int { 2 }\nf()".to_string(),
+            None,
+            FileId(0),
+        );
+        assert!(doc.check().is_ok());
+
+        // Edit only `g`.
+        let doc = doc.apply_changes(None, vec![change(Some(((1, 16), (1, 17))), "3")]);
+        assert!(doc.check().is_ok());
+        let stats = doc.fn_cache.lock().unwrap().stats();
+        assert_eq!((stats.hits, stats.misses), (1, 3));
+    }
+
+    #[test]
+    fn types_survive_errors_elsewhere() {
+        let src = "fn f(x: Int) -> Int { x + 1 }\nlet bad: Bool = 1;\nlet s = \"a\";\nbad";
+        let doc = Document::new(src.to_string(), None, FileId(0));
+        assert_eq!(doc.errors().len(), 1);
+
+        let ty_at = |needle: &str| {
+            doc.type_at(src.find(needle).unwrap())
+                .map(|(_, t)| t.clone())
+        };
+        assert_eq!(ty_at("x + 1"), Some(Type::Int));
+        assert_eq!(ty_at("\"a\""), Some(Type::String));
+        // `bad` failed to check but keeps its annotated type.
+        assert_eq!(
+            doc.type_at(src.len()).map(|(_, t)| t.clone()),
+            Some(Type::Bool)
+        );
+    }
+
+    #[test]
+    fn queries_report_the_first_failing_stage() {
+        let doc = Document::new("let x = ;".to_string(), None, FileId(0));
+        assert!(matches!(doc.check(), Err(AnalysisError::Parse(_))));
+
+        // Type errors are reported without failing the check.
+        let doc = Document::new(
+            "let x: Int = true;\nlet y = 1 + \"a\";\nx".to_string(),
+            None,
+            FileId(0),
+        );
+        assert!(doc.check().is_ok());
+        let errors = doc.errors();
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].message().starts_with("type error:"));
+    }
+}
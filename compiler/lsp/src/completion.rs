@@ -0,0 +1,459 @@
+use moon_core::ast::{Expr, Param, Program, TypeExpr};
+use moon_core::resolver::{DefId, DefKind, Definition, Resolution};
+use moon_core::span::Span;
+use moon_typechecker::{CheckInfo, Type};
+use tower_lsp::lsp_types::{
+    CompletionItem, CompletionItemKind, CompletionTextEdit, InsertTextFormat, TextEdit,
+};
+
+use crate::analysis::Document;
+use crate::walk::{binding, type_expr_text, walk, Binding, Node};
+
+const KEYWORDS: [&str; 7] = ["let", "fn", "return", "if", "else", "true", "false"];
+const TYPES: [&str; 6] = ["Int", "Bool", "String", "Unit", "Array", "Object"];
+
+/// What the text right before the cursor asks for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Context {
+    /// Names in scope, keywords and types.
+    Scope,
+    /// `obj.` - Moon has no field syntax, so the item rewrites it to `obj["key"]`.
+    Member { receiver: String, dot: usize },
+    /// `obj["` - a key inside an index string.
+    Key { receiver: String },
+    /// Somewhere completion makes no sense (e.g. the start of a string literal).
+    None,
+}
+
+/// Completion items for the cursor at byte `offset`.
+///
+/// While typing, the document usually does not parse. In that case the analysis runs on a
+/// repaired copy of the text (see `Document::repaired`) which keeps every offset before the cursor intact,
+/// so scopes still line up with the real document.
+pub fn completions(doc: &Document, offset: usize) -> Vec<CompletionItem> {
+    let offset = offset.min(doc.text.len());
+    let word_start = ident_start(&doc.text, offset);
+    let context = context(&doc.text, word_start);
+    if context == Context::None {
+        return Vec::new();
+    }
+
+    let repaired;
+    let analysed = if doc.program().is_ok() {
+        doc
+    } else if let Some(r) = doc.repaired(offset) {
+        repaired = r;
+        &repaired
+    } else {
+        return match context {
+            Context::Scope => keyword_items(),
+            _ => Vec::new(),
+        };
+    };
+    let (Ok(program), Ok(res)) = (analysed.program(), analysed.resolution()) else {
+        return Vec::new();
+    };
+    let info = analysed.check().ok();
+    let visible = visible(res, offset);
+
+    match context {
+        Context::Scope => {
+            let mut items: Vec<CompletionItem> = visible
+                .iter()
+                .enumerate()
+                .map(|(rank, &id)| def_item(res.definition(id), rank, program, info))
+                .collect();
+            items.extend(keyword_items());
+            items
+        }
+        Context::Member { receiver, dot } => {
+            let replace = doc.range(Span::in_file(doc.file, dot, offset));
+            key_items(&receiver, offset, &visible, res, program, info)
+                .into_iter()
+                .map(|(key, detail)| CompletionItem {
+                    filter_text: Some(format!(".{key}")),
+                    text_edit: Some(CompletionTextEdit::Edit(TextEdit {
+                        range: replace,
+                        new_text: format!("[\"{key}\"]"),
+                    })),
+                    label: key,
+                    kind: Some(CompletionItemKind::FIELD),
+                    detail,
+                    ..Default::default()
+                })
+                .collect()
+        }
+        Context::Key { receiver } => {
+            let replace = doc.range(Span::in_file(doc.file, word_start, offset));
+            key_items(&receiver, offset, &visible, res, program, info)
+                .into_iter()
+                .map(|(key, detail)| CompletionItem {
+                    text_edit: Some(CompletionTextEdit::Edit(TextEdit {
+                        range: replace,
+                        new_text: key.clone(),
+                    })),
+                    label: key,
+                    kind: Some(CompletionItemKind::FIELD),
+                    detail,
+                    ..Default::default()
+                })
+                .collect()
+        }
+        Context::None => Vec::new(),
+    }
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Start of the identifier the cursor is in the middle (or at the end) of.
+fn ident_start(text: &str, offset: usize) -> usize {
+    text[..offset]
+        .char_indices()
+        .rev()
+        .take_while(|(_, c)| is_ident_char(*c))
+        .last()
+        .map_or(offset, |(i, _)| i)
+}
+
+fn context(text: &str, word_start: usize) -> Context {
+    let before = &text[..word_start];
+    let receiver_before = |end: usize| {
+        let start = ident_start(text, end);
+        let name = &text[start..end];
+        (!name.is_empty() && !name.starts_with(|c: char| c.is_ascii_digit()))
+            .then(|| name.to_string())
+    };
+
+    if let Some(rest) = before.strip_suffix('.') {
+        return match receiver_before(rest.len()) {
+            Some(receiver) => Context::Member {
+                receiver,
+                dot: rest.len(),
+            },
+            None => Context::None,
+        };
+    }
+    if let Some(rest) = before.strip_suffix('"') {
+        return match rest
+            .strip_suffix('[')
+            .and_then(|r| receiver_before(r.len()))
+        {
+            Some(receiver) => Context::Key { receiver },
+            None => Context::None,
+        };
+    }
+    Context::Scope
+}
+
+/// Definitions visible at `offset`, innermost first.
+pub fn visible(res: &Resolution, offset: usize) -> Vec<DefId> {
+    // Item and global scopes end with the last statement, but the cursor is often past it
+    // (typing at the end of the file); there only top-level names are visible anyway.
+    let program_end = res
+        .defs
+        .iter()
+        .find(|d| d.kind == DefKind::Builtin)
+        .map_or(offset, |d| d.scope.end);
+    res.visible_at(offset.min(program_end))
+}
+
+fn def_item(
+    def: &Definition,
+    rank: usize,
+    program: &Program,
+    info: Option<&CheckInfo>,
+) -> CompletionItem {
+    let callable = callable(def, program, info);
+    let kind = match def.kind {
+        DefKind::Function | DefKind::Builtin => CompletionItemKind::FUNCTION,
+        _ if callable.is_some() => CompletionItemKind::FUNCTION,
+        _ => CompletionItemKind::VARIABLE,
+    };
+    let detail = match &callable {
+        Some(c) => Some(c.label("fn").0),
+        None => value_type(def, program, info),
+    };
+
+    let mut item = CompletionItem {
+        label: def.name.clone(),
+        kind: Some(kind),
+        detail,
+        // Innermost bindings first, then keywords and types.
+        sort_text: Some(format!("0{rank:04}")),
+        ..Default::default()
+    };
+    if let Some(c) = callable {
+        item.insert_text = Some(c.snippet(&def.name));
+        item.insert_text_format = Some(InsertTextFormat::SNIPPET);
+    }
+    item
+}
+
+/// Parameters and return type of something that can be called.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Callable {
+    /// `(name, type)`; values only known through their function type have no names.
+    pub params: Vec<(Option<String>, String)>,
+    pub ret: String,
+}
+
+impl Callable {
+    pub fn from_params(params: &[Param], ret_ty: &TypeExpr) -> Self {
+        Callable {
+            params: params
+                .iter()
+                .map(|p| (Some(p.name.clone()), type_expr_text(&p.ty)))
+                .collect(),
+            ret: type_expr_text(ret_ty),
+        }
+    }
+
+    /// `add(a: Int, b: Int) -> Int`, plus the byte range of each parameter in it.
+    pub fn label(&self, name: &str) -> (String, Vec<(usize, usize)>) {
+        let mut label = format!("{name}(");
+        let mut ranges = Vec::new();
+        for (i, (name, ty)) in self.params.iter().enumerate() {
+            if i > 0 {
+                label.push_str(", ");
+            }
+            let start = label.len();
+            match name {
+                Some(name) => label.push_str(&format!("{name}: {ty}")),
+                None => label.push_str(ty),
+            }
+            ranges.push((start, label.len()));
+        }
+        label.push_str(&format!(") -> {}", self.ret));
+        (label, ranges)
+    }
+
+    /// `f(${1:a}, ${2:b})$0`
+    fn snippet(&self, name: &str) -> String {
+        let args: Vec<String> = self
+            .params
+            .iter()
+            .enumerate()
+            .map(|(i, (param, ty))| format!("${{{}:{}}}", i + 1, param.as_ref().unwrap_or(ty)))
+            .collect();
+        format!("{name}({})$0", args.join(", "))
+    }
+}
+
+/// The signature of `def`, if it is a function or a binding whose value is one.
+pub fn callable(def: &Definition, program: &Program, info: Option<&CheckInfo>) -> Option<Callable> {
+    if def.kind == DefKind::Builtin {
+        return Some(Callable {
+            params: Vec::new(),
+            ret: "Unit".to_string(),
+        });
+    }
+
+    match binding(program, def.span)? {
+        Binding::Fn { params, ret_ty } => Some(Callable::from_params(params, ret_ty)),
+        Binding::Let {
+            expr: Expr::Fn { params, ret_ty, .. },
+            ..
+        } => Some(Callable::from_params(params, ret_ty)),
+        Binding::Let { expr, .. } => match expr_type(info, expr.span())? {
+            Type::Function { params, ret } => Some(Callable {
+                params: params.iter().map(|p| (None, p.to_string())).collect(),
+                ret: ret.to_string(),
+            }),
+            _ => None,
+        },
+        Binding::Param(_) => None,
+    }
+}
+
+/// Type of a non-callable binding: its annotation, or else the inferred type of its value.
+pub fn value_type(def: &Definition, program: &Program, info: Option<&CheckInfo>) -> Option<String> {
+    match binding(program, def.span)? {
+        Binding::Let { ty: Some(ty), .. } => Some(type_expr_text(ty)),
+        Binding::Let { ty: None, expr } => expr_type(info, expr.span()).map(Type::to_string),
+        Binding::Param(p) => Some(type_expr_text(&p.ty)),
+        Binding::Fn { .. } => None,
+    }
+}
+
+pub fn expr_type(info: Option<&CheckInfo>, span: Span) -> Option<&Type> {
+    info?
+        .expr_types
+        .iter()
+        .rev()
+        .find(|(sp, _)| *sp == span)
+        .map(|(_, ty)| ty)
+}
+
+/// Keys known for the object bound to `receiver`: those of its literal initializer plus every
+/// constant key it is indexed with (except the one being typed at `cursor`), paired with the
+/// object's element type when known.
+fn key_items(
+    receiver: &str,
+    cursor: usize,
+    visible: &[DefId],
+    res: &Resolution,
+    program: &Program,
+    info: Option<&CheckInfo>,
+) -> Vec<(String, Option<String>)> {
+    let Some(&def) = visible
+        .iter()
+        .find(|&&id| res.definition(id).name == receiver)
+    else {
+        return Vec::new();
+    };
+
+    let mut keys: Vec<String> = Vec::new();
+    let mut element = None;
+    match binding(program, res.definition(def).span) {
+        Some(Binding::Let { ty, expr }) => {
+            if let Expr::Object { props, .. } = expr {
+                keys.extend(props.iter().map(|(k, _)| k.clone()));
+            }
+            element = match (ty, expr_type(info, expr.span())) {
+                (Some(ann), _) => object_element(ann),
+                (None, Some(Type::Object(inner))) => Some(inner.to_string()),
+                _ => None,
+            };
+        }
+        Some(Binding::Param(p)) => element = object_element(&p.ty),
+        _ => {}
+    }
+
+    walk(program, &mut |node| {
+        if let Node::Expr(Expr::Index { target, index, .. }) = node {
+            if let (Expr::Ident(_, sp), Expr::String(key, key_sp)) = (&**target, &**index) {
+                let same = res.references(def).any(|r| r.span == *sp);
+                let typing = key_sp.start < cursor && cursor < key_sp.end;
+                if same && !typing && !keys.contains(key) {
+                    keys.push(key.clone());
+                }
+            }
+        }
+    });
+
+    keys.into_iter().map(|k| (k, element.clone())).collect()
+}
+
+fn object_element(ty: &TypeExpr) -> Option<String> {
+    match ty {
+        TypeExpr::Generic { base, args, .. } if base == "Object" && args.len() == 1 => {
+            Some(type_expr_text(&args[0]))
+        }
+        _ => None,
+    }
+}
+
+fn keyword_items() -> Vec<CompletionItem> {
+    let keywords = KEYWORDS
+        .iter()
+        .map(|kw| (kw, CompletionItemKind::KEYWORD, '1'));
+    let types = TYPES.iter().map(|ty| (ty, CompletionItemKind::CLASS, '2'));
+    keywords
+        .chain(types)
+        .map(|(label, kind, group)| CompletionItem {
+            label: label.to_string(),
+            kind: Some(kind),
+            insert_text: Some(label.to_string()),
+            sort_text: Some(format!("{group}{label}")),
+            ..Default::default()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use moon_core::span::FileId;
+
+    // `|` marks the cursor.
+    fn complete(src: &str) -> Vec<CompletionItem> {
+        let offset = src.find('|').unwrap();
+        let text = src.replacen('|', "", 1);
+        let doc = Document::new(text, None, FileId(0));
+        completions(&doc, offset)
+    }
+
+    fn find<'a>(items: &'a [CompletionItem], label: &str) -> Option<&'a CompletionItem> {
+        items.iter().find(|i| i.label == label)
+    }
+
+    #[test]
+    fn offers_names_in_scope_with_types() {
+        let items =
+            complete("let g = 1;\nfn f(a: Int) -> Int { let b = a + g; | b }\nlet later = true;");
+        assert_eq!(find(&items, "a").unwrap().detail.as_deref(), Some("Int"));
+        assert_eq!(find(&items, "b").unwrap().detail.as_deref(), Some("Int"));
+        assert_eq!(find(&items, "g").unwrap().detail.as_deref(), Some("Int"));
+        assert!(find(&items, "later").is_none());
+        assert!(find(&items, "let").is_some());
+
+        // Innermost bindings sort first.
+        let rank = |l: &str| find(&items, l).unwrap().sort_text.clone();
+        assert!(rank("b") < rank("g"));
+        assert!(rank("g") < rank("let"));
+    }
+
+    #[test]
+    fn functions_complete_as_call_snippets() {
+        let items = complete(
+            "fn add(a: Int, b: Int) -> Int { a + b }\nlet inc = fn(x: Int) -> Int { x + 1 };\n|",
+        );
+        let add = find(&items, "add").unwrap();
+        assert_eq!(add.detail.as_deref(), Some("fn(a: Int, b: Int) -> Int"));
+        assert_eq!(add.insert_text.as_deref(), Some("add(${1:a}, ${2:b})$0"));
+        assert_eq!(add.insert_text_format, Some(InsertTextFormat::SNIPPET));
+        assert_eq!(
+            find(&items, "inc").unwrap().insert_text.as_deref(),
+            Some("inc(${1:x})$0")
+        );
+        assert_eq!(
+            find(&items, "gc").unwrap().insert_text.as_deref(),
+            Some("gc()$0")
+        );
+    }
+
+    #[test]
+    fn works_while_the_document_does_not_parse() {
+        // Half-typed statement in the middle of a function.
+        let items = complete("let g = 1;\nfn f(a: Int) -> Int {\n  let b = a + |\n  b\n}\n");
+        assert!(find(&items, "a").is_some());
+        assert!(find(&items, "g").is_some());
+
+        // Unclosed function at the end of the file.
+        let items = complete("fn f(a: Int) -> Int {\n  let b = 2;\n  a + |");
+        assert!(find(&items, "a").is_some());
+        assert!(find(&items, "b").is_some());
+        assert!(find(&items, "f").is_some());
+    }
+
+    #[test]
+    fn dot_completes_object_keys_as_index_expressions() {
+        let items = complete("let o = #{ a: 1, b: 2 };\no[\"c\"] = 3;\no.|");
+        let labels: Vec<&str> = items.iter().map(|i| i.label.as_str()).collect();
+        assert_eq!(labels, ["a", "b", "c"]);
+
+        let a = &items[0];
+        assert_eq!(a.detail.as_deref(), Some("Int"));
+        let Some(CompletionTextEdit::Edit(edit)) = &a.text_edit else {
+            panic!("expected a text edit");
+        };
+        assert_eq!(edit.new_text, "[\"a\"]");
+        assert_eq!(
+            (edit.range.start.character, edit.range.end.character),
+            (1, 2)
+        );
+    }
+
+    #[test]
+    fn completes_keys_inside_index_strings() {
+        let items = complete("fn f(o: Object<Bool>) -> Bool { o[\"on\"] && o[\"|\"] }");
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].label, "on");
+        assert_eq!(items[0].detail.as_deref(), Some("Bool"));
+
+        // A plain string is not a completion site.
+        assert!(complete("let s = \"|\";").is_empty());
+    }
+}
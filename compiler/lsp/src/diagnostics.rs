@@ -0,0 +1,74 @@
+use moon_core::parser::{EXPECTED_ELSE, EXPECTED_LET_SEMICOLON};
+use moon_core::resolver::{DefId, DefKind, Resolution};
+use moon_typechecker::UNDEFINED_VARIABLE;
+use tower_lsp::lsp_types::{Diagnostic, DiagnosticSeverity, DiagnosticTag, NumberOrString};
+
+use crate::analysis::{AnalysisError, Document};
+
+/// Code of the warning for a `let` that is never used; code actions key off it.
+pub const UNUSED_BINDING: &str = "unused-binding";
+/// Codes of the errors code actions key off.
+pub const MISSING_SEMICOLON: &str = "missing-semicolon";
+pub const MISSING_ELSE: &str = "missing-else";
+pub const UNDEFINED_NAME: &str = "undefined-name";
+
+/// Everything to publish for a document: errors from the analysis, then lints.
+pub fn diagnostics(doc: &Document) -> Vec<Diagnostic> {
+    // Lex, parse and check are memoised on the document revision; this just reads the result.
+    let mut diags: Vec<Diagnostic> = doc
+        .errors()
+        .into_iter()
+        .map(|e| Diagnostic {
+            range: doc.range(e.span()),
+            severity: Some(DiagnosticSeverity::ERROR),
+            code: error_code(&e).map(|code| NumberOrString::String(code.to_string())),
+            source: Some("moon".to_string()),
+            message: e.message(),
+            ..Default::default()
+        })
+        .collect();
+
+    if let Ok(res) = doc.resolution() {
+        for def in unused_bindings(res) {
+            let def = res.definition(def);
+            diags.push(Diagnostic {
+                range: doc.range(def.span),
+                severity: Some(DiagnosticSeverity::WARNING),
+                code: Some(NumberOrString::String(UNUSED_BINDING.to_string())),
+                source: Some("moon".to_string()),
+                message: format!("unused variable `{}`", def.name),
+                tags: Some(vec![DiagnosticTag::UNNECESSARY]),
+                ..Default::default()
+            });
+        }
+    }
+    diags
+}
+
+fn error_code(e: &AnalysisError) -> Option<&'static str> {
+    match e {
+        AnalysisError::Parse(e) if e.message == EXPECTED_LET_SEMICOLON => Some(MISSING_SEMICOLON),
+        AnalysisError::Parse(e) if e.message == EXPECTED_ELSE => Some(MISSING_ELSE),
+        AnalysisError::Type(e)
+            if e.message
+                .strip_prefix(UNDEFINED_VARIABLE)
+                .is_some_and(|rest| rest.starts_with(": ")) =>
+        {
+            Some(UNDEFINED_NAME)
+        }
+        _ => None,
+    }
+}
+
+/// `let` bindings that are never read or assigned. A leading `_` marks a name as
+/// intentionally unused.
+pub fn unused_bindings(res: &Resolution) -> Vec<DefId> {
+    (0..res.defs.len())
+        .filter(|&id| {
+            let def = res.definition(id);
+            matches!(def.kind, DefKind::Local | DefKind::Global)
+                && !def.name.starts_with('_')
+                && res.references(id).next().is_none()
+        })
+        .collect()
+}
@@ -0,0 +1,111 @@
+use moon_core::ast::{Expr, Stmt};
+use moon_core::lexer::TriviaKind;
+use moon_core::span::Span;
+use tower_lsp::lsp_types::{FoldingRange, FoldingRangeKind};
+
+use crate::analysis::Document;
+use crate::walk::{walk, Node};
+
+/// Folding ranges for functions, blocks and array/object literals spanning several lines, plus
+/// runs of consecutive line comments.
+///
+/// Clients fold by start line, so of several ranges starting on the same line only the outermost
+/// is kept (`fn f() {` is one range, not a function and its body).
+pub fn folding_ranges(doc: &Document) -> Vec<FoldingRange> {
+    let mut ranges: Vec<(u32, u32, Option<FoldingRangeKind>)> = Vec::new();
+
+    if let Ok(program) = doc.program() {
+        let mut spans: Vec<Span> = Vec::new();
+        walk(program, &mut |node| match node {
+            Node::Stmt(stmt @ Stmt::Fn { .. }) => spans.push(stmt.span()),
+            Node::Expr(
+                expr @ (Expr::Fn { .. }
+                | Expr::Block { .. }
+                | Expr::Array { .. }
+                | Expr::Object { .. }),
+            ) => spans.push(expr.span()),
+            _ => {}
+        });
+        for span in spans {
+            let range = doc.range(span);
+            if range.end.line > range.start.line {
+                ranges.push((range.start.line, range.end.line, None));
+            }
+        }
+    }
+
+    if let Ok(trivia) = doc.trivia() {
+        let mut run: Option<(u32, u32)> = None;
+        // Only comments on their own line; a trailing `// note` does not join a run.
+        let own_line = |start: usize| {
+            let line_start = doc.text[..start].rfind('\n').map_or(0, |i| i + 1);
+            doc.text[line_start..start].trim().is_empty()
+        };
+        for t in trivia
+            .iter()
+            .filter(|t| t.kind == TriviaKind::LineComment && own_line(t.span.start))
+        {
+            let line = doc.range(t.span).start.line;
+            run = match run {
+                Some((start, end)) if line == end + 1 => Some((start, line)),
+                Some((start, end)) => {
+                    if end > start {
+                        ranges.push((start, end, Some(FoldingRangeKind::Comment)));
+                    }
+                    Some((line, line))
+                }
+                None => Some((line, line)),
+            };
+        }
+        if let Some((start, end)) = run.filter(|(start, end)| end > start) {
+            ranges.push((start, end, Some(FoldingRangeKind::Comment)));
+        }
+    }
+
+    // Outermost first within a start line, then drop the rest of that line.
+    ranges.sort_by_key(|&(start, end, _)| (start, std::cmp::Reverse(end)));
+    ranges.dedup_by_key(|r| r.0);
+    ranges
+        .into_iter()
+        .map(|(start_line, end_line, kind)| FoldingRange {
+            start_line,
+            start_character: None,
+            end_line,
+            end_character: None,
+            kind,
+            collapsed_text: None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use moon_core::span::FileId;
+
+    fn folds(src: &str) -> Vec<(u32, u32, bool)> {
+        let doc = Document::new(src.to_string(), None, FileId(0));
+        folding_ranges(&doc)
+            .into_iter()
+            .map(|r| {
+                let comment = r.kind == Some(FoldingRangeKind::Comment);
+                (r.start_line, r.end_line, comment)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn folds_functions_blocks_and_literals() {
+        let src = "fn f(x: Int) -> Int {\n    let y = {\n        x\n    };\n    y\n}\nlet xs = [\n    1,\n];\nlet o = #{\n    a: 1 };\nlet one = [1];\nf(1)";
+        assert_eq!(
+            folds(src),
+            [(0, 5, false), (1, 3, false), (6, 8, false), (9, 10, false)]
+        );
+    }
+
+    #[test]
+    fn folds_comment_runs() {
+        let src = "// a\n// b\n// c\nlet x = 1; // single\n\n// d\nx";
+        assert_eq!(folds(src), [(0, 2, true)]);
+    }
+}
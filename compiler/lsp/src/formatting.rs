@@ -0,0 +1,71 @@
+use moon_core::span::Span;
+use moon_formatter::{format_range, format_source};
+use tower_lsp::lsp_types::TextEdit;
+
+use crate::analysis::Document;
+
+/// The whole document reformatted, as one edit. Nothing is offered while it does not parse.
+pub fn format_document(doc: &Document) -> Option<Vec<TextEdit>> {
+    let formatted = format_source(&doc.text, doc.file).ok()?;
+    if formatted == doc.text {
+        return Some(Vec::new());
+    }
+    let whole = Span::in_file(doc.file, 0, doc.text.len());
+    Some(vec![TextEdit {
+        range: doc.range(whole),
+        new_text: formatted,
+    }])
+}
+
+/// The top-level statements overlapping `start..end` reformatted.
+pub fn format_selection(doc: &Document, start: usize, end: usize) -> Option<Vec<TextEdit>> {
+    let Some((span, formatted)) = format_range(&doc.text, doc.file, start, end).ok()? else {
+        return Some(Vec::new());
+    };
+    if doc.text[span.start..span.end] == formatted {
+        return Some(Vec::new());
+    }
+    Some(vec![TextEdit {
+        range: doc.range(span),
+        new_text: formatted,
+    }])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use moon_core::span::FileId;
+
+    fn apply(doc: &Document, edits: Vec<TextEdit>) -> String {
+        let mut out = doc.text.clone();
+        for e in edits.into_iter().rev() {
+            out.replace_range(
+                doc.offset(e.range.start)..doc.offset(e.range.end),
+                &e.new_text,
+            );
+        }
+        out
+    }
+
+    #[test]
+    fn formats_the_document() {
+        let doc = Document::new("let x=1;\n// hi\nx+1".to_string(), None, FileId(0));
+        let edits = format_document(&doc).unwrap();
+        assert_eq!(apply(&doc, edits), "let x = 1;\n// hi\nx + 1\n");
+
+        let clean = Document::new("let x = 1;\nx\n".to_string(), None, FileId(0));
+        assert!(format_document(&clean).unwrap().is_empty());
+
+        let broken = Document::new("let x = ".to_string(), None, FileId(0));
+        assert!(format_document(&broken).is_none());
+    }
+
+    #[test]
+    fn formats_only_the_selection() {
+        let src = "let a=1;\nlet b=[1,2];\na";
+        let doc = Document::new(src.to_string(), None, FileId(0));
+        let at = src.find("[1").unwrap();
+        let edits = format_selection(&doc, at, at + 2).unwrap();
+        assert_eq!(apply(&doc, edits), "let a=1;\nlet b = [1, 2];\na");
+    }
+}
@@ -0,0 +1,115 @@
+use moon_core::ast::{Expr, Stmt};
+use moon_typechecker::Type;
+use tower_lsp::lsp_types::{InlayHint, InlayHintKind, InlayHintLabel, Range, TextEdit};
+
+use crate::analysis::Document;
+use crate::completion::expr_type;
+use crate::walk::{walk, Node};
+
+/// Inferred types after `let` names that have no annotation, for bindings in `start..end`.
+///
+/// Lambda parameters need no hints: Moon requires annotations on every parameter.
+pub fn inlay_hints(doc: &Document, start: usize, end: usize) -> Vec<InlayHint> {
+    let (Ok(program), Ok(info)) = (doc.program(), doc.check()) else {
+        return Vec::new();
+    };
+
+    let mut hints = Vec::new();
+    walk(program, &mut |node| {
+        let Node::Stmt(Stmt::Let {
+            ty: None,
+            expr,
+            name_span,
+            ..
+        }) = node
+        else {
+            return;
+        };
+        // `let f = fn(x: Int) -> Int { .. }` already spells out its type.
+        if matches!(expr, Expr::Fn { .. }) || name_span.end < start || name_span.end > end {
+            return;
+        }
+        let Some(ty) = expr_type(Some(info), expr.span()).filter(|t| **t != Type::Unknown) else {
+            return;
+        };
+
+        let position = doc.range(*name_span).end;
+        let label = format!(": {ty}");
+        // Accepting the hint writes the annotation, when the type can be written as one.
+        let text_edits = annotatable(ty).then(|| {
+            vec![TextEdit {
+                range: Range::new(position, position),
+                new_text: label.clone(),
+            }]
+        });
+        hints.push(InlayHint {
+            position,
+            label: InlayHintLabel::String(label),
+            kind: Some(InlayHintKind::TYPE),
+            text_edits,
+            tooltip: None,
+            padding_left: None,
+            padding_right: None,
+            data: None,
+        });
+    });
+    hints
+}
+
+/// Whether `ty` has a surface syntax (there is none for function types or `Never`).
+pub fn annotatable(ty: &Type) -> bool {
+    match ty {
+        Type::Int | Type::Bool | Type::String | Type::Unit => true,
+        Type::Array(inner) | Type::Object(inner) => annotatable(inner),
+        Type::Never | Type::Unknown | Type::Function { .. } => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use moon_core::span::FileId;
+
+    fn hints(src: &str) -> Vec<(u32, String)> {
+        let doc = Document::new(src.to_string(), None, FileId(0));
+        inlay_hints(&doc, 0, src.len())
+            .into_iter()
+            .map(|h| match h.label {
+                InlayHintLabel::String(s) => (h.position.character, s),
+                InlayHintLabel::LabelParts(_) => panic!("expected a plain label"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn hints_unannotated_lets_only() {
+        let src = "let a = 1; let b: Int = 2; fn f(x: Int) -> Array<Int> { let xs = [x]; xs }";
+        assert_eq!(
+            hints(src),
+            [(5, ": Int".to_string()), (62, ": Array<Int>".to_string())]
+        );
+    }
+
+    #[test]
+    fn skips_lambdas_and_offers_edits_only_for_writable_types() {
+        let src = "let f = fn(x: Int) -> Int { x };\nlet g = f;\nlet o = #{ a: true };";
+        let doc = Document::new(src.to_string(), None, FileId(0));
+        let found = inlay_hints(&doc, 0, src.len());
+        assert_eq!(found.len(), 2);
+        assert!(
+            found[0].text_edits.is_none(),
+            "`(Int) -> Int` is not writable"
+        );
+        assert_eq!(
+            found[1].text_edits.as_ref().unwrap()[0].new_text,
+            ": Object<Bool>"
+        );
+    }
+
+    #[test]
+    fn respects_the_requested_range() {
+        let src = "let a = 1;\nlet b = 2;";
+        let doc = Document::new(src.to_string(), None, FileId(0));
+        assert_eq!(inlay_hints(&doc, 11, src.len()).len(), 1);
+    }
+}
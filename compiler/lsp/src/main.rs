@@ -1,26 +1,37 @@
+mod actions;
+mod analysis;
+mod completion;
+mod diagnostics;
+mod folding;
+mod formatting;
+mod hints;
+mod rename;
+mod semantic;
+mod signature;
+mod symbols;
+mod walk;
+
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
 
-use moon_core::lexer::lex;
-use moon_core::parser::parse;
-use moon_core::source::Source;
-use moon_core::span::Span;
-use moon_typechecker::{check_program, check_program_with_spans};
+use moon_core::resolver::DefKind;
+use moon_core::span::FileId;
 use tokio::sync::RwLock;
-use tower_lsp::jsonrpc::Result;
+use tower_lsp::jsonrpc::{Error, Result};
 use tower_lsp::lsp_types::*;
 use tower_lsp::{Client, LanguageServer, LspService, Server};
 
-#[derive(Debug, Clone)]
-struct Document {
-    text: String,
-    version: Option<i32>,
-}
+use crate::analysis::Document;
 
 #[derive(Debug)]
 struct Backend {
     client: Client,
-    documents: RwLock<HashMap<Url, Document>>,
+    documents: RwLock<HashMap<Url, Arc<Document>>>,
+    next_file_id: AtomicU32,
+    // Last semantic tokens sent per document, keyed by result id, for `semanticTokens/full/delta`.
+    semantic_tokens: RwLock<HashMap<Url, (String, Vec<SemanticToken>)>>,
+    next_result_id: AtomicU32,
 }
 
 impl Backend {
@@ -28,25 +39,70 @@ impl Backend {
         Self {
             client,
             documents: RwLock::new(HashMap::new()),
+            next_file_id: AtomicU32::new(0),
+            semantic_tokens: RwLock::new(HashMap::new()),
+            next_result_id: AtomicU32::new(0),
         }
     }
 
-    async fn upsert_document(&self, uri: Url, text: String, version: Option<i32>) {
+    async fn open_document(&self, uri: Url, text: String, version: Option<i32>) {
         let mut docs = self.documents.write().await;
-        docs.insert(uri, Document { text, version });
+        let file = match docs.get(&uri) {
+            Some(doc) => doc.file,
+            None => FileId(self.next_file_id.fetch_add(1, Ordering::Relaxed)),
+        };
+        docs.insert(uri, Arc::new(Document::new(text, version, file)));
     }
 
-    async fn get_document(&self, uri: &Url) -> Option<Document> {
+    async fn change_document(
+        &self,
+        uri: Url,
+        version: Option<i32>,
+        changes: Vec<TextDocumentContentChangeEvent>,
+    ) {
+        let mut docs = self.documents.write().await;
+        let Some(doc) = docs.get(&uri) else {
+            return;
+        };
+        let next = doc.apply_changes(version, changes);
+        docs.insert(uri, Arc::new(next));
+    }
+
+    async fn uri_for_file(&self, file: FileId) -> Option<Url> {
+        let docs = self.documents.read().await;
+        docs.iter()
+            .find(|(_, doc)| doc.file == file)
+            .map(|(uri, _)| uri.clone())
+    }
+
+    async fn get_document(&self, uri: &Url) -> Option<Arc<Document>> {
         let docs = self.documents.read().await;
         docs.get(uri).cloned()
     }
 
+    /// Computes the document's semantic tokens and remembers them under a fresh result id.
+    async fn semantic_tokens_for(&self, uri: &Url, doc: &Document) -> SemanticTokens {
+        let data = semantic::semantic_tokens(doc);
+        let result_id = self
+            .next_result_id
+            .fetch_add(1, Ordering::Relaxed)
+            .to_string();
+        self.semantic_tokens
+            .write()
+            .await
+            .insert(uri.clone(), (result_id.clone(), data.clone()));
+        SemanticTokens {
+            result_id: Some(result_id),
+            data,
+        }
+    }
+
     async fn publish_diagnostics(&self, uri: Url) {
         let Some(doc) = self.get_document(&uri).await else {
             return;
         };
 
-        let diags = diagnostics_for(&uri, &doc.text);
+        let diags = diagnostics::diagnostics(&doc);
         self.client
             .publish_diagnostics(uri, diags, doc.version)
             .await;
@@ -57,13 +113,48 @@ impl Backend {
 impl LanguageServer for Backend {
     async fn initialize(&self, _: InitializeParams) -> Result<InitializeResult> {
         let capabilities = ServerCapabilities {
-            text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+            text_document_sync: Some(TextDocumentSyncCapability::Kind(
+                TextDocumentSyncKind::INCREMENTAL,
+            )),
             definition_provider: Some(OneOf::Left(true)),
+            references_provider: Some(OneOf::Left(true)),
+            document_highlight_provider: Some(OneOf::Left(true)),
+            rename_provider: Some(OneOf::Right(RenameOptions {
+                prepare_provider: Some(true),
+                work_done_progress_options: Default::default(),
+            })),
             hover_provider: Some(HoverProviderCapability::Simple(true)),
+            signature_help_provider: Some(SignatureHelpOptions {
+                trigger_characters: Some(vec!["(".to_string(), ",".to_string()]),
+                retrigger_characters: None,
+                work_done_progress_options: Default::default(),
+            }),
+            inlay_hint_provider: Some(OneOf::Left(true)),
+            code_action_provider: Some(CodeActionProviderCapability::Options(CodeActionOptions {
+                code_action_kinds: Some(vec![
+                    CodeActionKind::QUICKFIX,
+                    CodeActionKind::REFACTOR_REWRITE,
+                ]),
+                ..Default::default()
+            })),
+            document_symbol_provider: Some(OneOf::Left(true)),
+            semantic_tokens_provider: Some(
+                SemanticTokensOptions {
+                    legend: semantic::legend(),
+                    full: Some(SemanticTokensFullOptions::Delta { delta: Some(true) }),
+                    range: None,
+                    work_done_progress_options: Default::default(),
+                }
+                .into(),
+            ),
+            folding_range_provider: Some(FoldingRangeProviderCapability::Simple(true)),
+            document_formatting_provider: Some(OneOf::Left(true)),
+            document_range_formatting_provider: Some(OneOf::Left(true)),
+            workspace_symbol_provider: Some(OneOf::Left(true)),
             completion_provider: Some(CompletionOptions {
-                // The MVP server does not do context-sensitive completion yet.
                 resolve_provider: Some(false),
-                trigger_characters: None,
+                // `o.` and `o["` complete object keys.
+                trigger_characters: Some(vec![".".to_string(), "\"".to_string()]),
                 ..Default::default()
             }),
             ..Default::default()
@@ -92,7 +183,7 @@ impl LanguageServer for Backend {
         let uri = params.text_document.uri;
         let text = params.text_document.text;
         let version = Some(params.text_document.version);
-        self.upsert_document(uri.clone(), text, version).await;
+        self.open_document(uri.clone(), text, version).await;
         self.publish_diagnostics(uri).await;
     }
 
@@ -100,15 +191,9 @@ impl LanguageServer for Backend {
         let uri = params.text_document.uri;
         let version = Some(params.text_document.version);
 
-        // We advertise FULL sync, so we expect the full text on each change.
-        let text = params
-            .content_changes
-            .into_iter()
-            .last()
-            .map(|c| c.text)
-            .unwrap_or_default();
-
-        self.upsert_document(uri.clone(), text, version).await;
+        // We advertise INCREMENTAL sync: changes carry ranges (or the full text, if omitted).
+        self.change_document(uri.clone(), version, params.content_changes)
+            .await;
         self.publish_diagnostics(uri).await;
     }
 
@@ -120,6 +205,10 @@ impl LanguageServer for Backend {
     async fn did_close(&self, params: DidCloseTextDocumentParams) {
         let mut docs = self.documents.write().await;
         docs.remove(&params.text_document.uri);
+        self.semantic_tokens
+            .write()
+            .await
+            .remove(&params.text_document.uri);
 
         // Clear diagnostics when the document is closed.
         self.client
@@ -127,328 +216,324 @@ impl LanguageServer for Backend {
             .await;
     }
 
-    async fn completion(&self, _: CompletionParams) -> Result<Option<CompletionResponse>> {
-        Ok(Some(CompletionResponse::Array(static_completions())))
-    }
-
-    async fn goto_definition(
-        &self,
-        params: GotoDefinitionParams,
-    ) -> Result<Option<GotoDefinitionResponse>> {
-        let uri = params
-            .text_document_position_params
-            .text_document
-            .uri
-            .clone();
-
+    async fn completion(&self, params: CompletionParams) -> Result<Option<CompletionResponse>> {
+        let uri = params.text_document_position.text_document.uri;
         let Some(doc) = self.get_document(&uri).await else {
             return Ok(None);
         };
 
-        let position = params.text_document_position_params.position;
-        let offset = offset_from_position_utf16(&doc.text, position);
+        let offset = doc.offset(params.text_document_position.position);
+        let items = completion::completions(&doc, offset);
+        Ok(Some(CompletionResponse::Array(items)))
+    }
 
-        let name = match ident_at_offset(&doc.text, offset) {
-            Some(n) => n,
-            None => return Ok(None),
+    async fn signature_help(&self, params: SignatureHelpParams) -> Result<Option<SignatureHelp>> {
+        let uri = params.text_document_position_params.text_document.uri;
+        let Some(doc) = self.get_document(&uri).await else {
+            return Ok(None);
         };
 
-        let tokens = match lex(&doc.text) {
-            Ok(t) => t,
-            Err(_) => return Ok(None),
-        };
-        let program = match parse(tokens) {
-            Ok(p) => p,
-            Err(_) => return Ok(None),
-        };
+        let offset = doc.offset(params.text_document_position_params.position);
+        Ok(signature::signature_help(&doc, offset))
+    }
 
-        let defs = collect_top_level_defs(&program);
-        let Some(span) = defs.get(&name) else {
+    async fn inlay_hint(&self, params: InlayHintParams) -> Result<Option<Vec<InlayHint>>> {
+        let Some(doc) = self.get_document(&params.text_document.uri).await else {
             return Ok(None);
         };
 
-        let location = Location {
-            uri,
-            range: range_from_span_utf16(&doc.text, *span),
-        };
-        Ok(Some(GotoDefinitionResponse::Scalar(location)))
+        let start = doc.offset(params.range.start);
+        let end = doc.offset(params.range.end);
+        Ok(Some(hints::inlay_hints(&doc, start, end)))
     }
 
-    async fn hover(&self, params: HoverParams) -> Result<Option<Hover>> {
-        let uri = params
-            .text_document_position_params
-            .text_document
-            .uri
-            .clone();
-
+    async fn code_action(&self, params: CodeActionParams) -> Result<Option<CodeActionResponse>> {
+        let uri = params.text_document.uri;
         let Some(doc) = self.get_document(&uri).await else {
             return Ok(None);
         };
 
-        let position = params.text_document_position_params.position;
-        let offset = offset_from_position_utf16(&doc.text, position);
+        let start = doc.offset(params.range.start);
+        let end = doc.offset(params.range.end);
+        let actions = actions::code_actions(&doc, &uri, start, end)
+            .into_iter()
+            .map(CodeActionOrCommand::CodeAction)
+            .collect();
+        Ok(Some(actions))
+    }
 
-        let tokens = match lex(&doc.text) {
-            Ok(t) => t,
-            Err(_) => return Ok(None),
-        };
-        let program = match parse(tokens) {
-            Ok(p) => p,
-            Err(_) => return Ok(None),
+    async fn document_symbol(
+        &self,
+        params: DocumentSymbolParams,
+    ) -> Result<Option<DocumentSymbolResponse>> {
+        let Some(doc) = self.get_document(&params.text_document.uri).await else {
+            return Ok(None);
         };
 
-        let info = match check_program_with_spans(&program) {
-            Ok(i) => i,
-            Err(_) => return Ok(None),
+        let symbols = symbols::document_symbols(&doc);
+        Ok(Some(DocumentSymbolResponse::Nested(symbols)))
+    }
+
+    async fn semantic_tokens_full(
+        &self,
+        params: SemanticTokensParams,
+    ) -> Result<Option<SemanticTokensResult>> {
+        let uri = params.text_document.uri;
+        let Some(doc) = self.get_document(&uri).await else {
+            return Ok(None);
         };
 
-        let mut best: Option<(Span, moon_typechecker::Type)> = None;
-        let mut best_len: usize = usize::MAX;
+        let tokens = self.semantic_tokens_for(&uri, &doc).await;
+        Ok(Some(SemanticTokensResult::Tokens(tokens)))
+    }
 
-        for (sp, ty) in &info.expr_types {
-            if sp.start <= offset && offset < sp.end {
-                let len = sp.end.saturating_sub(sp.start).max(1);
-                if len < best_len {
-                    best = Some((*sp, ty.clone()));
-                    best_len = len;
-                }
-            }
-        }
-        if best.is_none() && offset > 0 {
-            let off = offset - 1;
-            for (sp, ty) in &info.expr_types {
-                if sp.start <= off && off < sp.end {
-                    let len = sp.end.saturating_sub(sp.start).max(1);
-                    if len < best_len {
-                        best = Some((*sp, ty.clone()));
-                        best_len = len;
-                    }
-                }
-            }
+    async fn semantic_tokens_full_delta(
+        &self,
+        params: SemanticTokensDeltaParams,
+    ) -> Result<Option<SemanticTokensFullDeltaResult>> {
+        let uri = params.text_document.uri;
+        let Some(doc) = self.get_document(&uri).await else {
+            return Ok(None);
+        };
+
+        let previous = self.semantic_tokens.read().await.get(&uri).cloned();
+        let tokens = self.semantic_tokens_for(&uri, &doc).await;
+        // Without the client's previous result we can only send everything again.
+        match previous {
+            Some((id, old)) if id == params.previous_result_id => Ok(Some(
+                SemanticTokensFullDeltaResult::TokensDelta(SemanticTokensDelta {
+                    result_id: tokens.result_id,
+                    edits: semantic::diff(&old, &tokens.data),
+                }),
+            )),
+            _ => Ok(Some(SemanticTokensFullDeltaResult::Tokens(tokens))),
         }
+    }
 
-        let Some((span, ty)) = best else {
+    async fn folding_range(&self, params: FoldingRangeParams) -> Result<Option<Vec<FoldingRange>>> {
+        let Some(doc) = self.get_document(&params.text_document.uri).await else {
             return Ok(None);
         };
 
-        let contents = HoverContents::Markup(MarkupContent {
-            kind: MarkupKind::Markdown,
-            value: format!("**Type:** `{ty}`"),
-        });
-
-        Ok(Some(Hover {
-            contents,
-            range: Some(range_from_span_utf16(&doc.text, span)),
-        }))
+        Ok(Some(folding::folding_ranges(&doc)))
     }
-}
 
-fn diagnostics_for(uri: &Url, text: &str) -> Vec<Diagnostic> {
-    let path = uri_to_path(uri);
-    let source = Source::new(path, text.to_string());
-
-    let tokens = match lex(&source.text) {
-        Ok(t) => t,
-        Err(e) => {
-            return vec![Diagnostic {
-                range: range_from_span_utf16(&source.text, e.span),
-                severity: Some(DiagnosticSeverity::ERROR),
-                source: Some("moon".to_string()),
-                message: format!("lex error: {}", e.message),
-                ..Default::default()
-            }]
-        }
-    };
-
-    let program = match parse(tokens) {
-        Ok(p) => p,
-        Err(e) => {
-            return vec![Diagnostic {
-                range: range_from_span_utf16(&source.text, e.span),
-                severity: Some(DiagnosticSeverity::ERROR),
-                source: Some("moon".to_string()),
-                message: format!("parse error: {}", e.message),
-                ..Default::default()
-            }]
-        }
-    };
-
-    if let Err(e) = check_program(&program) {
-        return vec![Diagnostic {
-            range: range_from_span_utf16(&source.text, e.span),
-            severity: Some(DiagnosticSeverity::ERROR),
-            source: Some("moon".to_string()),
-            message: format!("type error: {}", e.message),
-            ..Default::default()
-        }];
+    async fn formatting(&self, params: DocumentFormattingParams) -> Result<Option<Vec<TextEdit>>> {
+        let Some(doc) = self.get_document(&params.text_document.uri).await else {
+            return Ok(None);
+        };
+
+        Ok(formatting::format_document(&doc))
     }
 
-    Vec::new()
-}
+    async fn range_formatting(
+        &self,
+        params: DocumentRangeFormattingParams,
+    ) -> Result<Option<Vec<TextEdit>>> {
+        let Some(doc) = self.get_document(&params.text_document.uri).await else {
+            return Ok(None);
+        };
+
+        let start = doc.offset(params.range.start);
+        let end = doc.offset(params.range.end);
+        Ok(formatting::format_selection(&doc, start, end))
+    }
 
-fn uri_to_path(uri: &Url) -> PathBuf {
-    if uri.scheme() == "file" {
-        // Some clients may send URLs that cannot be converted (e.g. non-UTF8 paths).
-        if let Ok(path) = uri.to_file_path() {
-            return path;
+    async fn symbol(
+        &self,
+        params: WorkspaceSymbolParams,
+    ) -> Result<Option<Vec<SymbolInformation>>> {
+        // Only open documents are indexed; the server does not scan the workspace folder.
+        let docs = self.documents.read().await;
+        let mut found = Vec::new();
+        for (uri, doc) in docs.iter() {
+            found.extend(symbols::workspace_symbols(uri, doc, &params.query));
         }
+        found.sort_by(|a, b| {
+            (a.location.uri.as_str(), &a.name).cmp(&(b.location.uri.as_str(), &b.name))
+        });
+        Ok(Some(found))
     }
-    PathBuf::from(uri.as_str())
-}
 
-fn range_from_span_utf16(text: &str, span: Span) -> Range {
-    let start = position_from_offset_utf16(text, span.start);
-    let end = position_from_offset_utf16(text, span.end);
-    Range { start, end }
-}
+    async fn goto_definition(
+        &self,
+        params: GotoDefinitionParams,
+    ) -> Result<Option<GotoDefinitionResponse>> {
+        let uri = params
+            .text_document_position_params
+            .text_document
+            .uri
+            .clone();
 
-fn position_from_offset_utf16(text: &str, offset: usize) -> Position {
-    // LSP positions are (line, character) where character is UTF-16 code units.
-    let clamped = offset.min(text.len());
-    let mut line: u32 = 0;
-    let mut col_utf16: u32 = 0;
+        let Some(doc) = self.get_document(&uri).await else {
+            return Ok(None);
+        };
 
-    for (i, ch) in text.char_indices() {
-        if i >= clamped {
-            break;
-        }
+        let position = params.text_document_position_params.position;
+        let offset = doc.offset(position);
 
-        if ch == '\n' {
-            line += 1;
-            col_utf16 = 0;
-            continue;
+        let Ok(res) = doc.resolution() else {
+            return Ok(None);
+        };
+        let Some(def) = res.def_at(offset).map(|id| res.definition(id)) else {
+            return Ok(None);
+        };
+        if def.kind == DefKind::Builtin {
+            return Ok(None);
         }
 
-        let mut buf = [0u16; 2];
-        let n = ch.encode_utf16(&mut buf).len();
-        col_utf16 += n as u32;
-    }
-
-    Position {
-        line,
-        character: col_utf16,
+        let Some(def_uri) = self.uri_for_file(def.span.file).await else {
+            return Ok(None);
+        };
+        let location = Location {
+            uri: def_uri,
+            range: doc.range(def.span),
+        };
+        Ok(Some(GotoDefinitionResponse::Scalar(location)))
     }
-}
-
-fn offset_from_position_utf16(text: &str, position: Position) -> usize {
-    // Convert an LSP position (UTF-16 line/col) into a byte offset in `text`.
-    let target_line = position.line;
-    let target_col = position.character;
-
-    let mut line: u32 = 0;
-    let mut col_utf16: u32 = 0;
 
-    for (i, ch) in text.char_indices() {
-        if line > target_line {
-            return i;
-        }
+    async fn references(&self, params: ReferenceParams) -> Result<Option<Vec<Location>>> {
+        let uri = params.text_document_position.text_document.uri;
+        let Some(doc) = self.get_document(&uri).await else {
+            return Ok(None);
+        };
 
-        if line == target_line && col_utf16 >= target_col {
-            return i;
-        }
+        let offset = doc.offset(params.text_document_position.position);
+        let Ok(res) = doc.resolution() else {
+            return Ok(None);
+        };
+        let Some(def) = res.def_at(offset) else {
+            return Ok(None);
+        };
 
-        if ch == '\n' {
-            line += 1;
-            col_utf16 = 0;
-            continue;
+        let mut spans = Vec::new();
+        if params.context.include_declaration && res.definition(def).kind != DefKind::Builtin {
+            spans.push(res.definition(def).span);
         }
+        spans.extend(res.references(def).map(|r| r.span));
 
-        let mut buf = [0u16; 2];
-        let n = ch.encode_utf16(&mut buf).len();
-        col_utf16 += n as u32;
+        let locations = spans
+            .into_iter()
+            .map(|span| Location {
+                uri: uri.clone(),
+                range: doc.range(span),
+            })
+            .collect();
+        Ok(Some(locations))
     }
 
-    text.len()
-}
+    async fn document_highlight(
+        &self,
+        params: DocumentHighlightParams,
+    ) -> Result<Option<Vec<DocumentHighlight>>> {
+        let uri = params.text_document_position_params.text_document.uri;
+        let Some(doc) = self.get_document(&uri).await else {
+            return Ok(None);
+        };
 
-fn is_ident_char(b: u8) -> bool {
-    b.is_ascii_alphanumeric() || b == b'_'
-}
+        let offset = doc.offset(params.text_document_position_params.position);
+        let Ok(res) = doc.resolution() else {
+            return Ok(None);
+        };
+        let Some(def) = res.def_at(offset) else {
+            return Ok(None);
+        };
 
-fn ident_at_offset(text: &str, offset: usize) -> Option<String> {
-    let bytes = text.as_bytes();
-    if bytes.is_empty() {
-        return None;
+        let mut highlights = Vec::new();
+        if res.definition(def).kind != DefKind::Builtin {
+            highlights.push(DocumentHighlight {
+                range: doc.range(res.definition(def).span),
+                kind: Some(DocumentHighlightKind::WRITE),
+            });
+        }
+        for r in res.references(def) {
+            let kind = if r.write {
+                DocumentHighlightKind::WRITE
+            } else {
+                DocumentHighlightKind::READ
+            };
+            highlights.push(DocumentHighlight {
+                range: doc.range(r.span),
+                kind: Some(kind),
+            });
+        }
+        Ok(Some(highlights))
     }
 
-    let mut i = offset.min(bytes.len().saturating_sub(1));
+    async fn prepare_rename(
+        &self,
+        params: TextDocumentPositionParams,
+    ) -> Result<Option<PrepareRenameResponse>> {
+        let Some(doc) = self.get_document(&params.text_document.uri).await else {
+            return Ok(None);
+        };
 
-    // If we're on whitespace/punctuation, try stepping left once.
-    if !is_ident_char(bytes[i]) {
-        if i == 0 || !is_ident_char(bytes[i - 1]) {
-            return None;
-        }
-        i -= 1;
-    }
+        let offset = doc.offset(params.position);
+        let Ok(res) = doc.resolution() else {
+            return Ok(None);
+        };
+        let (def, span) = rename::prepare_rename(res, offset).map_err(Error::invalid_params)?;
 
-    if !is_ident_char(bytes[i]) {
-        return None;
+        Ok(Some(PrepareRenameResponse::RangeWithPlaceholder {
+            range: doc.range(span),
+            placeholder: res.definition(def).name.clone(),
+        }))
     }
 
-    let mut start = i;
-    while start > 0 && is_ident_char(bytes[start - 1]) {
-        start -= 1;
-    }
-    let mut end = i + 1;
-    while end < bytes.len() && is_ident_char(bytes[end]) {
-        end += 1;
-    }
+    async fn rename(&self, params: RenameParams) -> Result<Option<WorkspaceEdit>> {
+        let uri = params.text_document_position.text_document.uri;
+        let Some(doc) = self.get_document(&uri).await else {
+            return Ok(None);
+        };
 
-    Some(text[start..end].to_string())
-}
+        let offset = doc.offset(params.text_document_position.position);
+        let res = doc
+            .resolution()
+            .map_err(|e| Error::invalid_params(e.message()))?;
+        let spans = rename::rename(&doc.text, doc.file, res, offset, &params.new_name)
+            .map_err(Error::invalid_params)?;
 
-fn collect_top_level_defs(program: &moon_core::ast::Program) -> HashMap<String, Span> {
-    use moon_core::ast::Stmt;
-
-    let mut defs = HashMap::new();
-    for stmt in &program.stmts {
-        match stmt {
-            Stmt::Fn { name, span, .. } => {
-                defs.insert(name.clone(), *span);
-            }
-            Stmt::Let { name, span, .. } => {
-                defs.insert(name.clone(), *span);
-            }
-            _ => {}
-        }
+        let edits = spans
+            .into_iter()
+            .map(|span| TextEdit {
+                range: doc.range(span),
+                new_text: params.new_name.clone(),
+            })
+            .collect();
+        Ok(Some(WorkspaceEdit {
+            changes: Some(HashMap::from([(uri, edits)])),
+            ..Default::default()
+        }))
     }
-    defs
-}
 
-fn static_completions() -> Vec<CompletionItem> {
-    use CompletionItemKind as K;
+    async fn hover(&self, params: HoverParams) -> Result<Option<Hover>> {
+        let uri = params
+            .text_document_position_params
+            .text_document
+            .uri
+            .clone();
 
-    let mut items = Vec::new();
+        let Some(doc) = self.get_document(&uri).await else {
+            return Ok(None);
+        };
 
-    // Keywords.
-    for kw in ["let", "fn", "return", "if", "else", "true", "false"] {
-        items.push(CompletionItem {
-            label: kw.to_string(),
-            kind: Some(K::KEYWORD),
-            insert_text: Some(kw.to_string()),
-            ..Default::default()
-        });
-    }
+        let position = params.text_document_position_params.position;
+        let offset = doc.offset(position);
 
-    // Types.
-    for ty in ["Int", "Bool", "String", "Unit", "Array", "Object"] {
-        items.push(CompletionItem {
-            label: ty.to_string(),
-            kind: Some(K::CLASS),
-            insert_text: Some(ty.to_string()),
-            ..Default::default()
-        });
-    }
+        let Some((span, ty)) = doc.type_at(offset) else {
+            return Ok(None);
+        };
 
-    // Builtins.
-    items.push(CompletionItem {
-        label: "gc".to_string(),
-        kind: Some(K::FUNCTION),
-        insert_text: Some("gc".to_string()),
-        ..Default::default()
-    });
+        let contents = HoverContents::Markup(MarkupContent {
+            kind: MarkupKind::Markdown,
+            value: format!("**Type:** `{ty}`"),
+        });
 
-    items
+        Ok(Some(Hover {
+            contents,
+            range: Some(doc.range(span)),
+        }))
+    }
 }
 
 #[tokio::main]
@@ -463,13 +548,16 @@ async fn main() {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::analysis::{offset_from_position_utf16, position_from_offset_utf16};
+    use moon_core::line_index::LineIndex;
 
     #[test]
     fn utf16_position_roundtrip_ascii() {
         let text = "let x = 1;\nlet y = x;\n";
+        let index = LineIndex::new(text);
         for offset in 0..=text.len() {
-            let pos = position_from_offset_utf16(text, offset);
-            let back = offset_from_position_utf16(text, pos);
+            let pos = position_from_offset_utf16(&index, offset);
+            let back = offset_from_position_utf16(&index, pos);
             assert_eq!(back, offset);
         }
     }
@@ -481,19 +569,20 @@ mod tests {
         text.push(emoji);
         text.push_str("\";\n");
 
+        let index = LineIndex::new(&text);
         let emoji_offset = text.find(emoji).unwrap();
-        let before = position_from_offset_utf16(&text, emoji_offset);
+        let before = position_from_offset_utf16(&index, emoji_offset);
         assert_eq!(before.line, 0);
         assert_eq!(before.character, 9);
 
         let after_offset = emoji_offset + emoji.len_utf8();
-        let after = position_from_offset_utf16(&text, after_offset);
+        let after = position_from_offset_utf16(&index, after_offset);
         assert_eq!(after.line, 0);
         assert_eq!(after.character, 11);
 
         // Targeting the middle of the surrogate pair should land on the end of the char.
         let mid = offset_from_position_utf16(
-            &text,
+            &index,
             Position {
                 line: 0,
                 character: 10,
@@ -0,0 +1,191 @@
+use moon_core::lexer::{lex_file, TokenKind};
+use moon_core::line_index::LineIndex;
+use moon_core::parser::parse;
+use moon_core::resolver::{resolve, DefId, DefKind, Definition, Resolution};
+use moon_core::span::{FileId, Span};
+
+// Moon has no modules yet, so a rename never leaves the document it starts in.
+
+/// The span of the renameable name at `offset`.
+pub fn prepare_rename(res: &Resolution, offset: usize) -> Result<(DefId, Span), String> {
+    let def = res
+        .def_at(offset)
+        .ok_or_else(|| "no renameable symbol at cursor".to_string())?;
+    if res.definition(def).kind == DefKind::Builtin {
+        return Err(format!(
+            "cannot rename builtin `{}`",
+            res.definition(def).name
+        ));
+    }
+
+    let hit = |sp: Span| sp.start <= offset && offset <= sp.end;
+    let span = res
+        .references(def)
+        .map(|r| r.span)
+        .chain([res.definition(def).span])
+        .find(|sp| hit(*sp))
+        .expect("def_at found a span under the cursor");
+    Ok((def, span))
+}
+
+/// Spans to replace with `new_name` to rename the symbol at `offset`.
+///
+/// The renamed text is re-resolved and every identifier must still bind to the same
+/// definition; otherwise the rename would change meaning (capture/shadowing) and is refused.
+pub fn rename(
+    text: &str,
+    file: FileId,
+    res: &Resolution,
+    offset: usize,
+    new_name: &str,
+) -> Result<Vec<Span>, String> {
+    let (def, _) = prepare_rename(res, offset)?;
+    let old_name = res.definition(def).name.clone();
+
+    if !is_valid_ident(new_name) {
+        return Err(format!("`{new_name}` is not a valid identifier"));
+    }
+    let is_builtin = |d: &Definition| d.kind == DefKind::Builtin && d.name == new_name;
+    if res.defs.iter().any(is_builtin) {
+        return Err(format!("`{new_name}` is a builtin"));
+    }
+    if new_name == old_name {
+        return Ok(Vec::new());
+    }
+
+    let mut spans: Vec<Span> = res.references(def).map(|r| r.span).collect();
+    spans.push(res.definition(def).span);
+    spans.sort_by_key(|sp| sp.start);
+
+    // Apply the edits and re-resolve.
+    let mut new_text = String::with_capacity(text.len());
+    let mut last = 0;
+    for sp in &spans {
+        new_text.push_str(&text[last..sp.start]);
+        new_text.push_str(new_name);
+        last = sp.end;
+    }
+    new_text.push_str(&text[last..]);
+
+    let renamed = lex_file(&new_text, file)
+        .ok()
+        .and_then(|tokens| parse(tokens).ok())
+        .map(|program| resolve(&program))
+        .ok_or_else(|| "rename produces a program that does not parse".to_string())?;
+
+    // Old offset -> new offset (only needed at span starts).
+    let delta = new_name.len() as isize - old_name.len() as isize;
+    let shift = |pos: usize| {
+        let before = spans.iter().filter(|sp| sp.start < pos).count() as isize;
+        (pos as isize + before * delta) as usize
+    };
+    let index = LineIndex::new(text);
+    let at = |sp: Span| {
+        let lc = index.line_col(sp.start);
+        format!("{}:{}", lc.line + 1, lc.col + 1)
+    };
+
+    for r in &res.refs {
+        let old_def = res.definition(r.def);
+        let new_def = renamed
+            .refs
+            .iter()
+            .find(|nr| nr.span.start == shift(r.span.start))
+            .map(|nr| renamed.definition(nr.def));
+        let same = new_def.is_some_and(|nd| {
+            nd.kind == old_def.kind
+                && (old_def.kind == DefKind::Builtin || nd.span.start == shift(old_def.span.start))
+        });
+        if same {
+            continue;
+        }
+
+        let name = &text[r.span.start..r.span.end];
+        return Err(if r.def == def {
+            format!(
+                "renaming to `{new_name}` would capture the use at {} (another `{new_name}` is in scope)",
+                at(r.span)
+            )
+        } else {
+            format!(
+                "renaming to `{new_name}` would shadow `{name}` used at {}",
+                at(r.span)
+            )
+        });
+    }
+    if renamed.unresolved.len() != res.unresolved.len() {
+        return Err(format!(
+            "renaming to `{new_name}` would leave a use of `{new_name}` unresolved"
+        ));
+    }
+
+    Ok(spans)
+}
+
+fn is_valid_ident(name: &str) -> bool {
+    matches!(
+        lex_file(name, FileId::default()).as_deref(),
+        Ok([tok, eof]) if matches!(tok.kind, TokenKind::Ident(_))
+            && matches!(eof.kind, TokenKind::Eof)
+            && tok.span.start == 0
+            && tok.span.end == name.len()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn try_rename(src: &str, at: &str, new_name: &str) -> Result<String, String> {
+        let program = parse(lex_file(src, FileId(0)).unwrap()).unwrap();
+        let res = resolve(&program);
+        let offset = src.find(at).unwrap();
+        let spans = rename(src, FileId(0), &res, offset, new_name)?;
+
+        let mut out = src.to_string();
+        for sp in spans.iter().rev() {
+            out.replace_range(sp.start..sp.end, new_name);
+        }
+        Ok(out)
+    }
+
+    #[test]
+    fn renames_local_and_its_uses_only() {
+        let out = try_rename("let x = 1; { let x = 2; x + x } + x", "x = 2", "y").unwrap();
+        assert_eq!(out, "let x = 1; { let y = 2; y + y } + x");
+    }
+
+    #[test]
+    fn renames_function_across_calls() {
+        let out = try_rename("f(1); fn f(a: Int) -> Int { a } f(2)", "f(a", "g").unwrap();
+        assert_eq!(out, "g(1); fn g(a: Int) -> Int { a } g(2)");
+    }
+
+    #[test]
+    fn renames_parameter() {
+        let out = try_rename("fn f(a: Int) -> Int { a + 1 }", "a:", "n").unwrap();
+        assert_eq!(out, "fn f(n: Int) -> Int { n + 1 }");
+    }
+
+    #[test]
+    fn refuses_capture_by_inner_binding() {
+        // Renaming outer `x` to `y` would make `x + y` read the inner `y` twice.
+        let err = try_rename("let x = 1; { let y = 2; x + y }", "x = 1", "y").unwrap_err();
+        assert!(err.contains("capture"), "{err}");
+    }
+
+    #[test]
+    fn refuses_shadowing_an_outer_use() {
+        let err = try_rename("let x = 1; { let y = 2; x + y }", "y = 2", "x").unwrap_err();
+        assert!(err.contains("shadow"), "{err}");
+    }
+
+    #[test]
+    fn refuses_keywords_and_builtins() {
+        assert!(try_rename("let x = 1; x", "x =", "let").is_err());
+        assert!(try_rename("let x = 1; x", "x =", "a b").is_err());
+        assert!(try_rename("gc()", "gc", "collect").is_err());
+        let err = try_rename("fn f() -> Int { 1 } f()", "f()", "gc").unwrap_err();
+        assert!(err.contains("builtin"), "{err}");
+    }
+}
@@ -0,0 +1,319 @@
+use std::collections::{HashMap, HashSet};
+
+use moon_core::ast::{Expr, Stmt, TypeExpr};
+use moon_core::lexer::{TokenKind, TriviaKind};
+use moon_core::resolver::DefKind;
+use tower_lsp::lsp_types::{
+    SemanticToken, SemanticTokenModifier, SemanticTokenType, SemanticTokensEdit,
+    SemanticTokensLegend,
+};
+
+use crate::analysis::Document;
+use crate::walk::{walk, Node};
+
+// Indices into `legend().token_types`.
+const FUNCTION: u32 = 0;
+const PARAMETER: u32 = 1;
+const VARIABLE: u32 = 2;
+const TYPE: u32 = 3;
+const KEYWORD: u32 = 4;
+const STRING: u32 = 5;
+const NUMBER: u32 = 6;
+const COMMENT: u32 = 7;
+const PROPERTY: u32 = 8;
+
+// Bits of `legend().token_modifiers`.
+const DECLARATION: u32 = 1 << 0;
+const DEFAULT_LIBRARY: u32 = 1 << 1;
+
+pub fn legend() -> SemanticTokensLegend {
+    SemanticTokensLegend {
+        token_types: vec![
+            SemanticTokenType::FUNCTION,
+            SemanticTokenType::PARAMETER,
+            SemanticTokenType::VARIABLE,
+            SemanticTokenType::TYPE,
+            SemanticTokenType::KEYWORD,
+            SemanticTokenType::STRING,
+            SemanticTokenType::NUMBER,
+            SemanticTokenType::COMMENT,
+            SemanticTokenType::PROPERTY,
+        ],
+        token_modifiers: vec![
+            SemanticTokenModifier::DECLARATION,
+            SemanticTokenModifier::DEFAULT_LIBRARY,
+        ],
+    }
+}
+
+/// Semantic tokens for the whole document, in the LSP's relative encoding.
+///
+/// The lexer decides keywords, literals and comments. Identifiers are classified by what the
+/// resolver binds them to; if the document does not parse, a call-shaped identifier (`f(`)
+/// counts as a function and any other as a variable.
+pub fn semantic_tokens(doc: &Document) -> Vec<SemanticToken> {
+    let (Ok(tokens), Ok(trivia)) = (doc.tokens(), doc.trivia()) else {
+        return Vec::new();
+    };
+    let idents = ident_classes(doc);
+
+    // (start, end, type, modifiers), sorted by start.
+    let mut raw: Vec<(usize, usize, u32, u32)> = Vec::new();
+    for (i, tok) in tokens.iter().enumerate() {
+        let (start, end) = (tok.span.start, tok.span.end);
+        let class = match &tok.kind {
+            TokenKind::Let
+            | TokenKind::Fn
+            | TokenKind::Return
+            | TokenKind::If
+            | TokenKind::Else
+            | TokenKind::True
+            | TokenKind::False => Some((KEYWORD, 0)),
+            TokenKind::Int(_) => Some((NUMBER, 0)),
+            TokenKind::String(_) => Some((STRING, 0)),
+            TokenKind::Ident(_) => match &idents {
+                Some(idents) => idents.get(&start).copied(),
+                None => {
+                    let call =
+                        matches!(tokens.get(i + 1).map(|t| &t.kind), Some(TokenKind::LParen));
+                    Some((if call { FUNCTION } else { VARIABLE }, 0))
+                }
+            },
+            _ => None,
+        };
+        if let Some((ty, mods)) = class {
+            raw.push((start, end, ty, mods));
+        }
+    }
+    for t in trivia {
+        if t.kind == TriviaKind::LineComment {
+            raw.push((t.span.start, t.span.end, COMMENT, 0));
+        }
+    }
+    raw.sort_by_key(|r| r.0);
+
+    encode(doc, &raw)
+}
+
+/// Token type and modifiers for each identifier, keyed by its start offset.
+fn ident_classes(doc: &Document) -> Option<HashMap<usize, (u32, u32)>> {
+    let program = doc.program().ok()?;
+    let res = doc.resolution().ok()?;
+
+    // Type names and object keys are identifiers the resolver does not see.
+    let mut classes = HashMap::new();
+    let mut lambdas = HashSet::new();
+    walk(program, &mut |node| {
+        let mut types: Vec<&TypeExpr> = Vec::new();
+        match node {
+            Node::Stmt(Stmt::Let {
+                ty,
+                expr,
+                name_span,
+                ..
+            }) => {
+                types.extend(ty);
+                if matches!(expr, Expr::Fn { .. }) {
+                    lambdas.insert(name_span.start);
+                }
+            }
+            Node::Stmt(Stmt::Fn { params, ret_ty, .. })
+            | Node::Expr(Expr::Fn { params, ret_ty, .. }) => {
+                types.extend(params.iter().map(|p| &p.ty));
+                types.push(ret_ty);
+            }
+            Node::Expr(Expr::Object { span, .. }) => {
+                // Keys are the identifiers followed by `:` directly inside the braces; nested
+                // parameter lists, blocks and literals are one level deeper.
+                let Ok(tokens) = doc.tokens() else { return };
+                let inner: Vec<_> = tokens
+                    .iter()
+                    .filter(|t| t.span.start > span.start && t.span.end < span.end)
+                    .collect();
+                let mut depth = 0;
+                for (tok, next) in inner.iter().zip(inner.iter().skip(1)) {
+                    match tok.kind {
+                        TokenKind::LBrace | TokenKind::LBracket | TokenKind::LParen => depth += 1,
+                        TokenKind::RBrace | TokenKind::RBracket | TokenKind::RParen => depth -= 1,
+                        TokenKind::Ident(_) if depth == 1 && next.kind == TokenKind::Colon => {
+                            classes.insert(tok.span.start, (PROPERTY, 0));
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+        while let Some(ty) = types.pop() {
+            classes.insert(ty.span().start, (TYPE, 0));
+            if let TypeExpr::Generic { args, .. } = ty {
+                types.extend(args);
+            }
+        }
+    });
+
+    let class_of = |kind: DefKind, name_start: usize| match kind {
+        DefKind::Function => (FUNCTION, 0),
+        DefKind::Builtin => (FUNCTION, DEFAULT_LIBRARY),
+        DefKind::Param => (PARAMETER, 0),
+        DefKind::Local | DefKind::Global if lambdas.contains(&name_start) => (FUNCTION, 0),
+        DefKind::Local | DefKind::Global => (VARIABLE, 0),
+    };
+    for def in &res.defs {
+        if def.kind != DefKind::Builtin {
+            let (ty, mods) = class_of(def.kind, def.span.start);
+            classes.insert(def.span.start, (ty, mods | DECLARATION));
+        }
+    }
+    for r in &res.refs {
+        let def = res.definition(r.def);
+        classes.insert(r.span.start, class_of(def.kind, def.span.start));
+    }
+    for (_, sp) in &res.unresolved {
+        classes.insert(sp.start, (VARIABLE, 0));
+    }
+    Some(classes)
+}
+
+/// Relative (delta line / delta start) encoding in UTF-16 units. Tokens spanning lines (string
+/// literals with newlines) are split, since LSP tokens cannot cross lines.
+fn encode(doc: &Document, raw: &[(usize, usize, u32, u32)]) -> Vec<SemanticToken> {
+    let mut out = Vec::new();
+    let (mut prev_line, mut prev_col) = (0u32, 0u32);
+    for &(start, end, ty, mods) in raw {
+        let mut piece_start = start;
+        while piece_start < end {
+            let piece_end = doc.text[piece_start..end]
+                .find('\n')
+                .map_or(end, |i| piece_start + i);
+            let from = doc.line_index.utf16_line_col(piece_start);
+            let to = doc.line_index.utf16_line_col(piece_end);
+            if to.col > from.col {
+                let delta_line = from.line - prev_line;
+                let delta_start = if delta_line == 0 {
+                    from.col - prev_col
+                } else {
+                    from.col
+                };
+                out.push(SemanticToken {
+                    delta_line,
+                    delta_start,
+                    length: to.col - from.col,
+                    token_type: ty,
+                    token_modifiers_bitset: mods,
+                });
+                (prev_line, prev_col) = (from.line, from.col);
+            }
+            piece_start = piece_end + 1;
+        }
+    }
+    out
+}
+
+/// A single edit turning `old` into `new` (common prefix and suffix are kept). Offsets count
+/// `u32`s of the flattened encoding, 5 per token.
+pub fn diff(old: &[SemanticToken], new: &[SemanticToken]) -> Vec<SemanticTokensEdit> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let deleted = old.len() - prefix - suffix;
+    let inserted = &new[prefix..new.len() - suffix];
+    if deleted == 0 && inserted.is_empty() {
+        return Vec::new();
+    }
+    vec![SemanticTokensEdit {
+        start: (prefix * 5) as u32,
+        delete_count: (deleted * 5) as u32,
+        data: Some(inserted.to_vec()),
+    }]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use moon_core::span::FileId;
+
+    // (line, col, text, type, modifiers) with absolute positions.
+    fn tokens(src: &str) -> Vec<(u32, u32, String, u32, u32)> {
+        let doc = Document::new(src.to_string(), None, FileId(0));
+        let (mut line, mut col) = (0, 0);
+        let lines: Vec<&str> = src.split('\n').collect();
+        semantic_tokens(&doc)
+            .into_iter()
+            .map(|t| {
+                line += t.delta_line;
+                col = if t.delta_line == 0 {
+                    col + t.delta_start
+                } else {
+                    t.delta_start
+                };
+                let text =
+                    lines[line as usize][col as usize..(col + t.length) as usize].to_string();
+                (line, col, text, t.token_type, t.token_modifiers_bitset)
+            })
+            .collect()
+    }
+
+    fn class_of(toks: &[(u32, u32, String, u32, u32)], text: &str, nth: usize) -> (u32, u32) {
+        let t = toks.iter().filter(|t| t.2 == text).nth(nth).unwrap();
+        (t.3, t.4)
+    }
+
+    #[test]
+    fn classifies_tokens_by_meaning() {
+        let src = "// add them\nfn add(a: Int, b: Int) -> Int { a + b }\nlet f = fn(x: Int) -> Int { x };\nlet o = #{ k: 1 };\nadd(f(1), 2); gc();\n\"s\"";
+        let toks = tokens(src);
+
+        assert_eq!(class_of(&toks, "// add them", 0), (COMMENT, 0));
+        assert_eq!(class_of(&toks, "fn", 0), (KEYWORD, 0));
+        assert_eq!(class_of(&toks, "add", 0), (FUNCTION, DECLARATION));
+        assert_eq!(class_of(&toks, "add", 1), (FUNCTION, 0));
+        assert_eq!(class_of(&toks, "a", 0), (PARAMETER, DECLARATION));
+        assert_eq!(class_of(&toks, "a", 1), (PARAMETER, 0));
+        assert_eq!(class_of(&toks, "Int", 0), (TYPE, 0));
+        assert_eq!(class_of(&toks, "f", 1), (FUNCTION, 0));
+        assert_eq!(class_of(&toks, "o", 0), (VARIABLE, DECLARATION));
+        assert_eq!(class_of(&toks, "k", 0), (PROPERTY, 0));
+        assert_eq!(class_of(&toks, "1", 0), (NUMBER, 0));
+        assert_eq!(class_of(&toks, "gc", 0), (FUNCTION, DEFAULT_LIBRARY));
+        assert_eq!(class_of(&toks, "\"s\"", 0), (STRING, 0));
+    }
+
+    #[test]
+    fn falls_back_to_lexer_classes_when_parsing_fails() {
+        let toks = tokens("let x = f(1\nx +");
+        assert_eq!(class_of(&toks, "f", 0), (FUNCTION, 0));
+        assert_eq!(class_of(&toks, "x", 1), (VARIABLE, 0));
+    }
+
+    #[test]
+    fn splits_multiline_strings() {
+        let toks = tokens("\"a\nbc\"");
+        let pieces: Vec<&str> = toks.iter().map(|t| t.2.as_str()).collect();
+        assert_eq!(pieces, ["\"a", "bc\""]);
+    }
+
+    #[test]
+    fn delta_replaces_only_the_changed_tokens() {
+        let old = Document::new("let a = 1;\nlet b = 2;\na".to_string(), None, FileId(0));
+        let new = Document::new(
+            "let a = 1;\nlet bb = \"x\";\na".to_string(),
+            None,
+            FileId(0),
+        );
+        let (old, new) = (semantic_tokens(&old), semantic_tokens(&new));
+
+        let edits = diff(&old, &new);
+        assert_eq!(edits.len(), 1);
+        let mut data = old.clone();
+        let e = &edits[0];
+        let (start, count) = (e.start as usize / 5, e.delete_count as usize / 5);
+        data.splice(start..start + count, e.data.clone().unwrap());
+        assert_eq!(data, new);
+        assert!(diff(&new, &new).is_empty());
+    }
+}
@@ -0,0 +1,163 @@
+use moon_core::lexer::{lex_file, TokenKind};
+use moon_core::span::FileId;
+use tower_lsp::lsp_types::{
+    ParameterInformation, ParameterLabel, SignatureHelp, SignatureInformation,
+};
+
+use crate::analysis::Document;
+use crate::completion::{callable, visible};
+
+/// Signature of the innermost call whose argument list the cursor is in, with the argument
+/// under the cursor as the active parameter.
+pub fn signature_help(doc: &Document, offset: usize) -> Option<SignatureHelp> {
+    let offset = offset.min(doc.text.len());
+    let (callee, active) = enclosing_call(&doc.text[..offset], doc.file)?;
+
+    // The call is usually unfinished, so fall back to a repaired copy like completion does.
+    let repaired;
+    let analysed = if doc.program().is_ok() {
+        doc
+    } else {
+        repaired = doc.repaired(offset)?;
+        &repaired
+    };
+    let (Ok(program), Ok(res)) = (analysed.program(), analysed.resolution()) else {
+        return None;
+    };
+    let def = visible(res, offset)
+        .into_iter()
+        .map(|id| res.definition(id))
+        .find(|d| d.name == callee)?;
+    let callable = callable(def, program, analysed.check().ok())?;
+
+    let (label, ranges) = callable.label(&callee);
+    let parameters = ranges
+        .into_iter()
+        .map(|(start, end)| ParameterInformation {
+            // Labels are ASCII (identifiers and type names), so bytes are UTF-16 units.
+            label: ParameterLabel::LabelOffsets([start as u32, end as u32]),
+            documentation: None,
+        })
+        .collect();
+
+    Some(SignatureHelp {
+        signatures: vec![SignatureInformation {
+            label,
+            documentation: None,
+            parameters: Some(parameters),
+            active_parameter: Some(active),
+        }],
+        active_signature: Some(0),
+        active_parameter: Some(active),
+    })
+}
+
+/// Callee name and argument index of the innermost unclosed call in `before` (the text up to
+/// the cursor). Calls through anything but a plain name (`f(1)(2)`, `o["f"](1)`) are skipped.
+fn enclosing_call(before: &str, file: FileId) -> Option<(String, u32)> {
+    // An argument that is an unterminated string does not lex; the call starts before it.
+    let tokens = match lex_file(before, file) {
+        Ok(tokens) => tokens,
+        Err(e) => lex_file(&before[..e.span.start], file).ok()?,
+    };
+
+    enum Frame {
+        Call { callee: Option<String>, commas: u32 },
+        Other,
+    }
+    let mut frames: Vec<Frame> = Vec::new();
+    let mut prev: Option<&TokenKind> = None;
+    for tok in &tokens {
+        match &tok.kind {
+            TokenKind::LParen => {
+                let callee = match prev {
+                    Some(TokenKind::Ident(name)) => Some(name.clone()),
+                    _ => None,
+                };
+                frames.push(Frame::Call { callee, commas: 0 });
+            }
+            TokenKind::LBrace | TokenKind::LBracket => frames.push(Frame::Other),
+            TokenKind::RParen | TokenKind::RBrace | TokenKind::RBracket => {
+                frames.pop();
+            }
+            TokenKind::Comma => {
+                if let Some(Frame::Call { commas, .. }) = frames.last_mut() {
+                    *commas += 1;
+                }
+            }
+            _ => {}
+        }
+        prev = Some(&tok.kind);
+    }
+
+    match frames.pop()? {
+        Frame::Call {
+            callee: Some(callee),
+            commas,
+        } => Some((callee, commas)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // `|` marks the cursor.
+    fn help(src: &str) -> Option<(String, u32, String)> {
+        let offset = src.find('|').unwrap();
+        let doc = Document::new(src.replacen('|', "", 1), None, FileId(0));
+        let help = signature_help(&doc, offset)?;
+        let sig = &help.signatures[0];
+        let active = help.active_parameter.unwrap();
+        let ParameterLabel::LabelOffsets([s, e]) =
+            sig.parameters.as_ref().unwrap()[active as usize].label
+        else {
+            panic!("expected offsets");
+        };
+        let param = sig.label[s as usize..e as usize].to_string();
+        Some((sig.label.clone(), active, param))
+    }
+
+    const ADD: &str = "fn add(a: Int, b: Int) -> Int { a + b }\n";
+
+    #[test]
+    fn tracks_the_active_parameter_while_typing() {
+        let (label, active, param) = help(&format!("{ADD}add(1, |")).unwrap();
+        assert_eq!(label, "add(a: Int, b: Int) -> Int");
+        assert_eq!((active, param.as_str()), (1, "b: Int"));
+
+        let (_, active, _) = help(&format!("{ADD}add(|)")).unwrap();
+        assert_eq!(active, 0);
+    }
+
+    #[test]
+    fn picks_the_innermost_call() {
+        let src = format!("{ADD}fn neg(x: Int) -> Int {{ -x }}\nadd(neg(|), 2)");
+        let (label, active, _) = help(&src).unwrap();
+        assert_eq!((label.as_str(), active), ("neg(x: Int) -> Int", 0));
+
+        // Commas inside array literals are not argument separators.
+        let src = format!("{ADD}let xs = [1, 2];\nadd([1, 2, 3][0], |");
+        assert_eq!(help(&src).unwrap().1, 1);
+    }
+
+    #[test]
+    fn no_help_outside_a_call_or_for_unknown_callees() {
+        assert!(help(&format!("{ADD}add(1, 2)|")).is_none());
+        assert!(help("nope(|").is_none());
+    }
+
+    #[test]
+    fn survives_unterminated_string_arguments() {
+        let src = "fn greet(name: String, times: Int) -> String { name }\ngreet(\"bo|";
+        let (_, active, param) = help(src).unwrap();
+        assert_eq!((active, param.as_str()), (0, "name: String"));
+    }
+
+    #[test]
+    fn lambda_bindings_have_named_parameters() {
+        let src = "let inc = fn(step: Int) -> Int { step + 1 };\ninc(|";
+        assert_eq!(help(src).unwrap().2, "step: Int");
+    }
+}
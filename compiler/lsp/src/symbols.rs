@@ -0,0 +1,134 @@
+use moon_core::ast::{Expr, Stmt};
+use tower_lsp::lsp_types::{DocumentSymbol, Location, SymbolInformation, SymbolKind, Url};
+
+use crate::analysis::Document;
+use crate::completion::{expr_type, Callable};
+use crate::walk::type_expr_text;
+
+/// Functions and top-level bindings of a document, in source order.
+pub fn document_symbols(doc: &Document) -> Vec<DocumentSymbol> {
+    let Ok(program) = doc.program() else {
+        return Vec::new();
+    };
+    let info = doc.check().ok();
+
+    let mut symbols = Vec::new();
+    for stmt in &program.stmts {
+        let (name, name_span, kind, detail) = match stmt {
+            Stmt::Fn {
+                name,
+                name_span,
+                params,
+                ret_ty,
+                ..
+            } => {
+                let detail = Callable::from_params(params, ret_ty).label("fn").0;
+                (name, name_span, SymbolKind::FUNCTION, Some(detail))
+            }
+            Stmt::Let {
+                name,
+                name_span,
+                expr: Expr::Fn { params, ret_ty, .. },
+                ..
+            } => {
+                let detail = Callable::from_params(params, ret_ty).label("fn").0;
+                (name, name_span, SymbolKind::FUNCTION, Some(detail))
+            }
+            Stmt::Let {
+                name,
+                name_span,
+                ty,
+                expr,
+                ..
+            } => {
+                let detail = match ty {
+                    Some(ty) => Some(type_expr_text(ty)),
+                    None => expr_type(info, expr.span()).map(ToString::to_string),
+                };
+                (name, name_span, SymbolKind::VARIABLE, detail)
+            }
+            _ => continue,
+        };
+
+        #[allow(deprecated)]
+        symbols.push(DocumentSymbol {
+            name: name.clone(),
+            detail,
+            kind,
+            tags: None,
+            deprecated: None,
+            range: doc.range(stmt.span()),
+            selection_range: doc.range(*name_span),
+            children: None,
+        });
+    }
+    symbols
+}
+
+/// Symbols of `doc` whose name contains `query` (case-insensitive), for `workspace/symbol`.
+pub fn workspace_symbols(uri: &Url, doc: &Document, query: &str) -> Vec<SymbolInformation> {
+    let query = query.to_lowercase();
+    document_symbols(doc)
+        .into_iter()
+        .filter(|s| s.name.to_lowercase().contains(&query))
+        .map(|s| {
+            #[allow(deprecated)]
+            SymbolInformation {
+                name: s.name,
+                kind: s.kind,
+                tags: None,
+                deprecated: None,
+                location: Location {
+                    uri: uri.clone(),
+                    range: s.selection_range,
+                },
+                container_name: None,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use moon_core::span::FileId;
+
+    const SRC: &str = "let limit = 10;\nfn clamp(x: Int) -> Int { let y = x; y }\nlet twice = fn(n: Int) -> Int { n * 2 };\nclamp(limit)";
+
+    #[test]
+    fn lists_functions_and_top_level_bindings() {
+        let doc = Document::new(SRC.to_string(), None, FileId(0));
+        let symbols: Vec<(String, SymbolKind, Option<String>)> = document_symbols(&doc)
+            .into_iter()
+            .map(|s| (s.name, s.kind, s.detail))
+            .collect();
+        assert_eq!(
+            symbols,
+            [
+                ("limit".into(), SymbolKind::VARIABLE, Some("Int".into())),
+                (
+                    "clamp".into(),
+                    SymbolKind::FUNCTION,
+                    Some("fn(x: Int) -> Int".into())
+                ),
+                (
+                    "twice".into(),
+                    SymbolKind::FUNCTION,
+                    Some("fn(n: Int) -> Int".into())
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn workspace_symbols_filter_by_query() {
+        let doc = Document::new(SRC.to_string(), None, FileId(0));
+        let uri = Url::parse("file:///tmp/a.moon").unwrap();
+        let names: Vec<String> = workspace_symbols(&uri, &doc, "LI")
+            .into_iter()
+            .map(|s| s.name)
+            .collect();
+        assert_eq!(names, ["limit"]);
+        assert_eq!(workspace_symbols(&uri, &doc, "").len(), 3);
+    }
+}
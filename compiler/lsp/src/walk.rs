@@ -0,0 +1,153 @@
+use moon_core::ast::{Expr, Param, Program, Stmt, TypeExpr};
+use moon_core::span::Span;
+
+/// A node handed to the `walk` callback.
+#[derive(Debug, Clone, Copy)]
+pub enum Node<'a> {
+    Stmt(&'a Stmt),
+    Expr(&'a Expr),
+}
+
+/// Visits every statement and expression in source order (parents before children).
+pub fn walk<'a>(program: &'a Program, f: &mut impl FnMut(Node<'a>)) {
+    walk_stmts(&program.stmts, f);
+    if let Some(tail) = &program.tail {
+        walk_expr(tail, f);
+    }
+}
+
+fn walk_stmts<'a>(stmts: &'a [Stmt], f: &mut impl FnMut(Node<'a>)) {
+    for stmt in stmts {
+        f(Node::Stmt(stmt));
+        match stmt {
+            Stmt::Let { expr, .. } | Stmt::Expr { expr, .. } => walk_expr(expr, f),
+            Stmt::Assign { target, expr, .. } => {
+                walk_expr(target, f);
+                walk_expr(expr, f);
+            }
+            Stmt::Return { expr, .. } => {
+                if let Some(expr) = expr {
+                    walk_expr(expr, f);
+                }
+            }
+            Stmt::Fn { body, .. } => walk_expr(body, f),
+        }
+    }
+}
+
+/// Like `walk`, for a single expression.
+pub fn walk_expr<'a>(expr: &'a Expr, f: &mut impl FnMut(Node<'a>)) {
+    f(Node::Expr(expr));
+    match expr {
+        Expr::Int(..) | Expr::Bool(..) | Expr::String(..) | Expr::Ident(..) => {}
+        Expr::Fn { body, .. } => walk_expr(body, f),
+        Expr::Array { elements, .. } => {
+            for e in elements {
+                walk_expr(e, f);
+            }
+        }
+        Expr::Object { props, .. } => {
+            for (_, v) in props {
+                walk_expr(v, f);
+            }
+        }
+        Expr::Block { stmts, tail, .. } => {
+            walk_stmts(stmts, f);
+            if let Some(tail) = tail {
+                walk_expr(tail, f);
+            }
+        }
+        Expr::If {
+            cond,
+            then_branch,
+            else_branch,
+            ..
+        } => {
+            walk_expr(cond, f);
+            walk_expr(then_branch, f);
+            walk_expr(else_branch, f);
+        }
+        Expr::Unary { expr, .. } | Expr::Group { expr, .. } => walk_expr(expr, f),
+        Expr::Binary { lhs, rhs, .. } => {
+            walk_expr(lhs, f);
+            walk_expr(rhs, f);
+        }
+        Expr::Call { callee, args, .. } => {
+            walk_expr(callee, f);
+            for a in args {
+                walk_expr(a, f);
+            }
+        }
+        Expr::Index { target, index, .. } => {
+            walk_expr(target, f);
+            walk_expr(index, f);
+        }
+    }
+}
+
+/// The syntax that introduces a name, looked up by the name's span.
+#[derive(Debug, Clone, Copy)]
+pub enum Binding<'a> {
+    Let {
+        ty: Option<&'a TypeExpr>,
+        expr: &'a Expr,
+    },
+    Param(&'a Param),
+    Fn {
+        params: &'a [Param],
+        ret_ty: &'a TypeExpr,
+    },
+}
+
+pub fn binding(program: &Program, name_span: Span) -> Option<Binding<'_>> {
+    let mut found = None;
+    walk(program, &mut |node| {
+        if found.is_some() {
+            return;
+        }
+        let params = match node {
+            Node::Stmt(Stmt::Let {
+                name_span: sp,
+                ty,
+                expr,
+                ..
+            }) if *sp == name_span => {
+                found = Some(Binding::Let {
+                    ty: ty.as_ref(),
+                    expr,
+                });
+                return;
+            }
+            Node::Stmt(Stmt::Fn {
+                name_span: sp,
+                params,
+                ret_ty,
+                ..
+            }) => {
+                if *sp == name_span {
+                    found = Some(Binding::Fn { params, ret_ty });
+                    return;
+                }
+                params
+            }
+            Node::Expr(Expr::Fn { params, .. }) => params,
+            _ => return,
+        };
+        found = params
+            .iter()
+            .find(|p| p.name_span == name_span)
+            .map(Binding::Param);
+    });
+    found
+}
+
+/// Renders a type annotation the way it is written (`Array<Int>`).
+pub fn type_expr_text(ty: &TypeExpr) -> String {
+    match ty {
+        TypeExpr::Named(name, _) => name.clone(),
+        TypeExpr::Generic { base, args, .. } => {
+            let args: Vec<String> = args.iter().map(type_expr_text).collect();
+            format!("{base}<{}>", args.join(", "))
+        }
+    }
+}
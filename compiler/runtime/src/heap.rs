@@ -5,10 +5,29 @@ use crate::value::Value;
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub struct GcRef(pub usize);
 
+/// A captured variable, shared by every closure that captured it and by the scope that
+/// defined it (as in Lua). While that scope is live the variable stays where it is, in a VM
+/// stack slot (`Open`); when the scope ends its last value moves here (`Closed`).
+#[derive(Debug, Clone)]
+pub enum Upvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+/// A closure made by the interpreter: the function it runs and the upvalues it captured, by
+/// variable name.
 #[derive(Debug, Clone)]
 pub struct ClosureObject {
     pub func_name: String,
-    pub env: HashMap<String, Value>,
+    pub env: HashMap<String, GcRef>,
+}
+
+/// A closure made by the VM: the function it runs (an index into the module) and the
+/// upvalues it captured, in the order the compiler numbered them.
+#[derive(Debug, Clone)]
+pub struct CompiledClosure {
+    pub func: usize,
+    pub upvalues: Vec<GcRef>,
 }
 
 #[derive(Debug, Clone)]
@@ -16,6 +35,8 @@ pub enum HeapObjectKind {
     Array(Vec<Value>),
     Object(HashMap<String, Value>),
     Closure(ClosureObject),
+    CompiledClosure(CompiledClosure),
+    Upvalue(Upvalue),
 }
 
 #[derive(Debug, Clone)]
@@ -28,12 +49,24 @@ struct HeapObject {
 pub struct HeapStats {
     pub live_objects: usize,
     pub freed_objects: usize,
+    /// An estimate of the memory the live objects take (see `Heap::bytes`).
+    pub bytes: usize,
+}
+
+/// How big the heap may grow. `None` is no limit.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct HeapLimits {
+    pub max_objects: Option<usize>,
+    pub max_bytes: Option<usize>,
 }
 
 #[derive(Debug, Default)]
 pub struct Heap {
     objects: Vec<Option<HeapObject>>,
     free_list: Vec<usize>,
+    // Kept up to date on every allocation, change and collection.
+    bytes: usize,
+    limits: HeapLimits,
 }
 
 impl Heap {
@@ -42,10 +75,59 @@ impl Heap {
     }
 
     pub fn stats(&self) -> HeapStats {
-        let live_objects = self.objects.iter().filter(|o| o.is_some()).count();
         HeapStats {
-            live_objects,
+            live_objects: self.live_objects(),
             freed_objects: self.free_list.len(),
+            bytes: self.bytes,
+        }
+    }
+
+    pub fn live_objects(&self) -> usize {
+        self.objects.len() - self.free_list.len()
+    }
+
+    /// An estimate of the memory the live objects take: each object, the values it holds and
+    /// the text of the strings among them. Strings held outside of the heap (in variables or
+    /// on a stack) are not counted.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn limits(&self) -> HeapLimits {
+        self.limits
+    }
+
+    /// Allocations never fail: a backend calls `check_limits` after the operations that make
+    /// the heap grow, and stops the program there. Only `collect_garbage` makes it smaller.
+    pub fn set_limits(&mut self, limits: HeapLimits) {
+        self.limits = limits;
+    }
+
+    /// Why the heap is over its limits, if it is.
+    pub fn check_limits(&self) -> Result<(), String> {
+        let objects = self.live_objects();
+        if let Some(max) = self.limits.max_objects.filter(|max| objects > *max) {
+            return Err(format!(
+                "heap limit exceeded: {objects} objects (the limit is {max})"
+            ));
+        }
+        if let Some(max) = self.limits.max_bytes.filter(|max| self.bytes > *max) {
+            return Err(format!(
+                "heap limit exceeded: {} bytes (the limit is {max})",
+                self.bytes
+            ));
+        }
+        Ok(())
+    }
+
+    /// Why a string of `len` bytes may not be built, if it may not. Strings are values, not
+    /// heap objects, but one that alone is over `max_bytes` is refused before it is built.
+    pub fn check_string(&self, len: usize) -> Result<(), String> {
+        match self.limits.max_bytes.filter(|max| len > *max) {
+            Some(max) => Err(format!(
+                "heap limit exceeded: a string of {len} bytes (the limit is {max})"
+            )),
+            None => Ok(()),
         }
     }
 
@@ -57,46 +139,86 @@ impl Heap {
         self.alloc(HeapObjectKind::Object(entries))
     }
 
-    pub fn alloc_closure(&mut self, func_name: String, env: HashMap<String, Value>) -> GcRef {
+    pub fn alloc_closure(&mut self, func_name: String, env: HashMap<String, GcRef>) -> GcRef {
         self.alloc(HeapObjectKind::Closure(ClosureObject { func_name, env }))
     }
 
-    pub fn closure_func_name(&self, handle: GcRef) -> Option<&str> {
+    pub fn alloc_compiled_closure(&mut self, func: usize, upvalues: Vec<GcRef>) -> GcRef {
+        self.alloc(HeapObjectKind::CompiledClosure(CompiledClosure {
+            func,
+            upvalues,
+        }))
+    }
+
+    pub fn alloc_upvalue(&mut self, upvalue: Upvalue) -> GcRef {
+        self.alloc(HeapObjectKind::Upvalue(upvalue))
+    }
+
+    pub fn compiled_closure_func(&self, handle: GcRef) -> Option<usize> {
         match &self.get(handle)?.kind {
-            HeapObjectKind::Closure(c) => Some(c.func_name.as_str()),
+            HeapObjectKind::CompiledClosure(c) => Some(c.func),
             _ => None,
         }
     }
 
-    pub fn closure_get(&self, handle: GcRef, key: &str) -> Option<&Value> {
+    /// The `idx`-th upvalue of a VM closure.
+    pub fn compiled_closure_upvalue(&self, handle: GcRef, idx: usize) -> Option<GcRef> {
         match &self.get(handle)?.kind {
-            HeapObjectKind::Closure(c) => c.env.get(key),
+            HeapObjectKind::CompiledClosure(c) => c.upvalues.get(idx).copied(),
             _ => None,
         }
     }
 
-    pub fn closure_contains(&self, handle: GcRef, key: &str) -> bool {
-        self.closure_get(handle, key).is_some()
+    pub fn upvalue(&self, handle: GcRef) -> Option<&Upvalue> {
+        match &self.get(handle)?.kind {
+            HeapObjectKind::Upvalue(u) => Some(u),
+            _ => None,
+        }
     }
 
-    pub fn closure_set(&mut self, handle: GcRef, key: String, value: Value) -> Result<(), String> {
+    pub fn set_upvalue(&mut self, handle: GcRef, upvalue: Upvalue) -> Result<(), String> {
         let obj = self.get_mut(handle)?;
         match obj.kind {
-            HeapObjectKind::Closure(ref mut c) => {
-                c.env.insert(key, value);
+            HeapObjectKind::Upvalue(ref mut u) => {
+                let added = upvalue_bytes(&upvalue);
+                let removed = upvalue_bytes(u);
+                *u = upvalue;
+                self.bytes = self.bytes + added - removed;
                 Ok(())
             }
-            _ => Err("not a closure".to_string()),
+            _ => Err("not an upvalue".to_string()),
         }
     }
 
-    pub fn closure_env_clone(&self, handle: GcRef) -> Option<HashMap<String, Value>> {
+    pub fn closure_func_name(&self, handle: GcRef) -> Option<&str> {
+        match &self.get(handle)?.kind {
+            HeapObjectKind::Closure(c) => Some(c.func_name.as_str()),
+            _ => None,
+        }
+    }
+
+    /// The upvalue an interpreter closure captured for `key`.
+    pub fn closure_upvalue(&self, handle: GcRef, key: &str) -> Option<GcRef> {
+        match &self.get(handle)?.kind {
+            HeapObjectKind::Closure(c) => c.env.get(key).copied(),
+            _ => None,
+        }
+    }
+
+    pub fn closure_env_clone(&self, handle: GcRef) -> Option<HashMap<String, GcRef>> {
         match &self.get(handle)?.kind {
             HeapObjectKind::Closure(c) => Some(c.env.clone()),
             _ => None,
         }
     }
 
+    pub fn array_len(&self, handle: GcRef) -> Option<usize> {
+        match self.get(handle)?.kind {
+            HeapObjectKind::Array(ref v) => Some(v.len()),
+            _ => None,
+        }
+    }
+
     pub fn array_get(&self, handle: GcRef, idx: usize) -> Option<&Value> {
         match self.get(handle)?.kind {
             HeapObjectKind::Array(ref v) => v.get(idx),
@@ -111,7 +233,10 @@ impl Heap {
                 if idx >= v.len() {
                     return Err(format!("index out of bounds: {idx} (len={})", v.len()));
                 }
+                let added = value_bytes(&value);
+                let removed = value_bytes(&v[idx]);
                 v[idx] = value;
+                self.bytes = self.bytes + added - removed;
                 Ok(())
             }
             _ => Err("not an array".to_string()),
@@ -129,18 +254,102 @@ impl Heap {
         let obj = self.get_mut(handle)?;
         match obj.kind {
             HeapObjectKind::Object(ref mut m) => {
+                let (entry, removed) = match m.get(&key) {
+                    Some(old) => (0, value_bytes(old)),
+                    None => (entry_bytes(&key), 0),
+                };
+                let added = value_bytes(&value);
                 m.insert(key, value);
+                self.bytes = self.bytes + entry + added - removed;
                 Ok(())
             }
             _ => Err("not an object".to_string()),
         }
     }
 
-    pub fn collect_garbage(&mut self, roots: &[Value]) -> HeapStats {
+    /// Writes `value` the way it would be written in source: arrays and objects show their
+    /// contents (object keys sorted), strings are quoted. Unlike `Display`, which prints only
+    /// the handle of heap values.
+    pub fn render(&self, value: &Value) -> String {
+        let mut out = String::new();
+        self.render_into(value, &mut Vec::new(), &mut out);
+        out
+    }
+
+    fn render_into(&self, value: &Value, path: &mut Vec<GcRef>, out: &mut String) {
+        let handle = match value {
+            Value::String(s) => {
+                out.push_str(&format!("{s:?}"));
+                return;
+            }
+            Value::Array(h) | Value::Object(h) => *h,
+            other => {
+                out.push_str(&other.to_string());
+                return;
+            }
+        };
+        let Some(obj) = self.get(handle) else {
+            out.push_str(&value.to_string());
+            return;
+        };
+        // A container reached again while printing itself.
+        if path.contains(&handle) {
+            out.push_str("...");
+            return;
+        }
+
+        path.push(handle);
+        match &obj.kind {
+            HeapObjectKind::Array(elems) => {
+                out.push('[');
+                for (i, v) in elems.iter().enumerate() {
+                    if i > 0 {
+                        out.push_str(", ");
+                    }
+                    self.render_into(v, path, out);
+                }
+                out.push(']');
+            }
+            HeapObjectKind::Object(map) => {
+                let mut entries: Vec<_> = map.iter().collect();
+                entries.sort_by(|a, b| a.0.cmp(b.0));
+                out.push_str("#{");
+                for (i, (k, v)) in entries.into_iter().enumerate() {
+                    if i > 0 {
+                        out.push_str(", ");
+                    }
+                    let is_ident = k
+                        .chars()
+                        .next()
+                        .is_some_and(|c| c.is_alphabetic() || c == '_')
+                        && k.chars().all(|c| c.is_alphanumeric() || c == '_');
+                    if is_ident {
+                        out.push_str(k);
+                    } else {
+                        out.push_str(&format!("{k:?}"));
+                    }
+                    out.push_str(": ");
+                    self.render_into(v, path, out);
+                }
+                out.push('}');
+            }
+            HeapObjectKind::Closure(_)
+            | HeapObjectKind::CompiledClosure(_)
+            | HeapObjectKind::Upvalue(_) => out.push_str(&value.to_string()),
+        }
+        path.pop();
+    }
+
+    /// Frees every object not reachable from `roots` or from the upvalues in `upvalues` (the
+    /// ones a backend holds outside of any value, like the VM's open upvalues).
+    pub fn collect_garbage(&mut self, roots: &[Value], upvalues: &[GcRef]) -> HeapStats {
         // Mark phase.
         for v in roots {
             self.mark_value(v);
         }
+        for h in upvalues {
+            self.mark_object(*h);
+        }
 
         // Sweep phase.
         let mut freed = 0usize;
@@ -149,20 +358,22 @@ impl Heap {
             if obj.marked {
                 obj.marked = false;
             } else {
+                self.bytes -= object_bytes(&obj.kind);
                 *slot = None;
                 self.free_list.push(i);
                 freed += 1;
             }
         }
 
-        let live_objects = self.objects.iter().filter(|o| o.is_some()).count();
         HeapStats {
-            live_objects,
+            live_objects: self.live_objects(),
             freed_objects: freed,
+            bytes: self.bytes,
         }
     }
 
     fn alloc(&mut self, kind: HeapObjectKind) -> GcRef {
+        self.bytes += object_bytes(&kind);
         let obj = HeapObject {
             marked: false,
             kind,
@@ -226,11 +437,67 @@ impl Heap {
                 }
             }
             HeapObjectKind::Closure(ref c) => {
-                let values: Vec<Value> = c.env.values().cloned().collect();
-                for v in &values {
-                    self.mark_value(v);
+                let upvalues: Vec<GcRef> = c.env.values().copied().collect();
+                for h in upvalues {
+                    self.mark_object(h);
+                }
+            }
+            HeapObjectKind::CompiledClosure(ref c) => {
+                let upvalues = c.upvalues.clone();
+                for h in upvalues {
+                    self.mark_object(h);
                 }
             }
+            // An open upvalue's value is on the VM stack, which is a root already.
+            HeapObjectKind::Upvalue(Upvalue::Open(_)) => {}
+            HeapObjectKind::Upvalue(Upvalue::Closed(ref v)) => {
+                let v = v.clone();
+                self.mark_value(&v);
+            }
+        }
+    }
+}
+
+/// What `object` adds to `Heap::bytes`: the object itself and what it holds.
+fn object_bytes(object: &HeapObjectKind) -> usize {
+    let held = match object {
+        HeapObjectKind::Array(elems) => elems
+            .iter()
+            .map(|v| size_of::<Value>() + value_bytes(v))
+            .sum(),
+        HeapObjectKind::Object(map) => map
+            .iter()
+            .map(|(k, v)| entry_bytes(k) + value_bytes(v))
+            .sum(),
+        HeapObjectKind::Closure(c) => {
+            c.func_name.len()
+                + c.env
+                    .keys()
+                    .map(|k| size_of::<String>() + k.len() + size_of::<GcRef>())
+                    .sum::<usize>()
         }
+        HeapObjectKind::CompiledClosure(c) => c.upvalues.len() * size_of::<GcRef>(),
+        HeapObjectKind::Upvalue(u) => upvalue_bytes(u),
+    };
+    size_of::<Option<HeapObject>>() + held
+}
+
+/// An object entry without its value's text: the key and the value slot.
+fn entry_bytes(key: &str) -> usize {
+    size_of::<String>() + key.len() + size_of::<Value>()
+}
+
+fn upvalue_bytes(upvalue: &Upvalue) -> usize {
+    match upvalue {
+        Upvalue::Open(_) => 0,
+        Upvalue::Closed(v) => value_bytes(v),
+    }
+}
+
+/// The text a value owns, outside of its own slot.
+fn value_bytes(value: &Value) -> usize {
+    match value {
+        Value::String(s) | Value::Function(s) => s.len(),
+        _ => 0,
     }
 }
@@ -1,5 +1,9 @@
 mod heap;
+mod limits;
 mod value;
 
-pub use heap::{GcRef, Heap, HeapObjectKind, HeapStats};
+pub use heap::{
+    ClosureObject, CompiledClosure, GcRef, Heap, HeapLimits, HeapObjectKind, HeapStats, Upvalue,
+};
+pub use limits::{ErrorKind, Limits, DEADLINE_CHECK_STEPS};
 pub use value::Value;
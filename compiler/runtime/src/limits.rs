@@ -0,0 +1,38 @@
+use std::time::Instant;
+
+use crate::heap::HeapLimits;
+
+/// Bounds on what a program may use, for running code that is not trusted. `None` (and the
+/// `Default`) is no limit. Both backends take them (`Env::set_limits`, `Vm::set_limits`).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// How many steps the program may take: VM instructions, or expressions the interpreter
+    /// evaluates. The two backends take a different number of steps for the same program.
+    pub fuel: Option<u64>,
+    pub heap: HeapLimits,
+    /// When to stop the program, checked every `DEADLINE_CHECK_STEPS` steps.
+    pub deadline: Option<Instant>,
+}
+
+/// How often, in steps, a backend looks at the clock for `Limits::deadline`.
+pub const DEADLINE_CHECK_STEPS: u64 = 1024;
+
+/// Why a backend stopped a program. All but `Failed` mean the program went past a limit the
+/// host set, rather than that it is wrong.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// An operation failed: a value of the wrong type, a division by zero, an index out of
+    /// bounds...
+    #[default]
+    Failed,
+    /// More nested calls than the maximum depth.
+    StackOverflow,
+    /// `Limits::fuel` ran out.
+    OutOfFuel,
+    /// The heap grew past `Limits::heap`.
+    HeapLimit,
+    /// `Limits::deadline` passed.
+    Deadline,
+    /// The host asked the program to stop (`moon_vm::InterruptHandle`).
+    Interrupted,
+}
@@ -0,0 +1,71 @@
+use std::collections::HashMap;
+
+use moon_runtime::{Heap, HeapLimits, Value};
+
+#[test]
+fn bytes_follow_allocations_changes_and_collections() {
+    let mut heap = Heap::new();
+    assert_eq!(heap.bytes(), 0);
+
+    let a = heap.alloc_array(vec![Value::Int(1), Value::Int(2)]);
+    let with_ints = heap.bytes();
+    assert!(with_ints > 0);
+
+    // Strings count by their text.
+    heap.array_set(a, 0, Value::String("x".repeat(100)))
+        .unwrap();
+    assert_eq!(heap.bytes(), with_ints + 100);
+    heap.array_set(a, 0, Value::Int(3)).unwrap();
+    assert_eq!(heap.bytes(), with_ints);
+
+    let o = heap.alloc_object(HashMap::new());
+    let empty = heap.bytes();
+    heap.object_set(o, "key".to_string(), Value::String("v".repeat(10)))
+        .unwrap();
+    let one_entry = heap.bytes();
+    assert!(one_entry > empty + 10);
+    heap.object_set(o, "key".to_string(), Value::Int(0))
+        .unwrap();
+    assert_eq!(heap.bytes(), one_entry - 10);
+
+    let stats = heap.collect_garbage(&[], &[]);
+    assert_eq!(stats.freed_objects, 2);
+    assert_eq!(stats.bytes, 0);
+    assert_eq!(heap.bytes(), 0);
+}
+
+#[test]
+fn limits_are_checked_on_demand() {
+    let mut heap = Heap::new();
+    heap.set_limits(HeapLimits {
+        max_objects: Some(1),
+        max_bytes: None,
+    });
+    let a = heap.alloc_array(Vec::new());
+    heap.check_limits().unwrap();
+    heap.alloc_array(Vec::new());
+    assert_eq!(
+        heap.check_limits().unwrap_err(),
+        "heap limit exceeded: 2 objects (the limit is 1)"
+    );
+
+    // Collecting what is not reachable makes room again.
+    heap.collect_garbage(&[Value::Array(a)], &[]);
+    heap.check_limits().unwrap();
+
+    let max_bytes = heap.bytes() + 10;
+    heap.set_limits(HeapLimits {
+        max_objects: None,
+        max_bytes: Some(max_bytes),
+    });
+    heap.alloc_array(vec![Value::String("y".repeat(1000))]);
+    assert_eq!(
+        heap.check_limits().unwrap_err(),
+        format!(
+            "heap limit exceeded: {} bytes (the limit is {max_bytes})",
+            heap.bytes()
+        )
+    );
+    heap.collect_garbage(&[Value::Array(a)], &[]);
+    heap.check_limits().unwrap();
+}
@@ -1,4 +1,6 @@
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
 
 use crate::error::TypeError;
 use crate::types::Type;
@@ -9,7 +11,8 @@ pub struct FuncSig {
     pub ret: Type,
 }
 
-#[derive(Debug, Default)]
+/// Types of the globals and functions in scope, plus the stack of local scopes being checked.
+#[derive(Debug, Default, Clone)]
 pub struct TypeEnv {
     globals: HashMap<String, Type>,
     scopes: Vec<HashMap<String, Type>>,
@@ -60,6 +63,32 @@ impl TypeEnv {
         self.funcs.get(name)
     }
 
+    /// Forgets a function, so a later `fn` with the same name is not a duplicate.
+    pub fn remove_fn(&mut self, name: &str) -> Option<FuncSig> {
+        self.funcs.remove(name)
+    }
+
+    /// Hash of everything a top-level function body can observe: every function signature
+    /// and the globals defined so far. Used to key the incremental function cache.
+    pub fn fingerprint(&self) -> u64 {
+        let mut funcs: Vec<_> = self.funcs.iter().collect();
+        funcs.sort_by(|a, b| a.0.cmp(b.0));
+        let mut globals: Vec<_> = self.globals.iter().collect();
+        globals.sort_by(|a, b| a.0.cmp(b.0));
+
+        let mut h = DefaultHasher::new();
+        for (name, sig) in funcs {
+            name.hash(&mut h);
+            sig.params.hash(&mut h);
+            sig.ret.hash(&mut h);
+        }
+        for (name, ty) in globals {
+            name.hash(&mut h);
+            ty.hash(&mut h);
+        }
+        h.finish()
+    }
+
     pub fn take_scopes(&mut self) -> Vec<HashMap<String, Type>> {
         std::mem::take(&mut self.scopes)
     }
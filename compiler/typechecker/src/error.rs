@@ -1,6 +1,10 @@
 use moon_core::span::Span;
 use std::fmt;
 
+/// Start of the message for a name that is not defined, followed by `: name`. Tools recognize
+/// it (the LSP offers to stub a missing function).
+pub const UNDEFINED_VARIABLE: &str = "undefined variable";
+
 #[derive(Debug, Clone)]
 pub struct TypeError {
     pub message: String,
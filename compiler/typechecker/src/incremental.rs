@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+
+use moon_core::span::Span;
+
+use crate::error::TypeError;
+use crate::types::Type;
+use crate::{Recovering, TypeSink};
+
+/// Memoised results of checking top-level functions, reused across edits.
+///
+/// A top-level `fn` body only observes its own source text plus the environment it is checked
+/// in (all function signatures and the globals defined before it). So the result is keyed by
+/// `(function text, TypeEnv::fingerprint())`, and stored with spans relative to the start of
+/// the function, so it stays valid when edits above the function shift it around.
+#[derive(Debug, Default)]
+pub struct FnCache {
+    entries: HashMap<FnKey, FnEntry>,
+    generation: u64,
+    stats: FnCacheStats,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FnCacheStats {
+    pub hits: usize,
+    pub misses: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct FnKey {
+    pub(crate) text: String,
+    pub(crate) env: u64,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct FnEntry {
+    // (start, end) relative to the function span start.
+    types: Vec<(usize, usize, Type)>,
+    errors: Vec<(String, usize, usize)>,
+    generation: u64,
+}
+
+impl FnCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn stats(&self) -> FnCacheStats {
+        self.stats
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub(crate) fn begin(&mut self) {
+        self.generation += 1;
+    }
+
+    /// Drops entries for functions that no longer exist (not touched by the last run).
+    pub(crate) fn finish(&mut self) {
+        let generation = self.generation;
+        self.entries.retain(|_, e| e.generation == generation);
+    }
+
+    pub(crate) fn lookup(&mut self, key: &FnKey) -> Option<&FnEntry> {
+        let generation = self.generation;
+        match self.entries.get_mut(key) {
+            Some(entry) => {
+                self.stats.hits += 1;
+                entry.generation = generation;
+                Some(entry)
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    pub(crate) fn insert(&mut self, key: FnKey, base: Span, checked: Recovering) -> &FnEntry {
+        let rel = |sp: Span| {
+            (
+                sp.start.saturating_sub(base.start),
+                sp.end.saturating_sub(base.start),
+            )
+        };
+        let entry = FnEntry {
+            types: checked
+                .types
+                .into_iter()
+                .map(|(sp, ty)| {
+                    let (s, e) = rel(sp);
+                    (s, e, ty)
+                })
+                .collect(),
+            errors: checked
+                .errors
+                .into_iter()
+                .map(|e| {
+                    let (s, end) = rel(e.span);
+                    (e.message, s, end)
+                })
+                .collect(),
+            generation: self.generation,
+        };
+        self.entries.entry(key).insert_entry(entry).into_mut()
+    }
+}
+
+impl FnEntry {
+    /// Re-anchors the cached spans at `base` (the function's current span) and feeds them
+    /// to `sink`, errors included.
+    pub(crate) fn replay<S: TypeSink>(&self, base: Span, sink: &mut S) -> Result<(), TypeError> {
+        let abs = |s: usize, e: usize| Span::in_file(base.file, base.start + s, base.start + e);
+        for (s, e, ty) in &self.types {
+            sink.record(abs(*s, *e), ty.clone());
+        }
+        for (message, s, e) in &self.errors {
+            sink.recover(TypeError {
+                message: message.clone(),
+                span: abs(*s, *e),
+            })?;
+        }
+        Ok(())
+    }
+}
@@ -1,33 +1,86 @@
 mod env;
 mod error;
+mod incremental;
 mod types;
 
 use moon_core::ast::{BinaryOp, Expr, Program, Stmt, TypeExpr, UnaryOp};
 use moon_core::span::Span;
 
-pub use error::TypeError;
+pub use env::TypeEnv;
+pub use error::{TypeError, UNDEFINED_VARIABLE};
+pub use incremental::{FnCache, FnCacheStats};
 pub use types::Type;
 
-use crate::env::TypeEnv;
+use crate::incremental::FnKey;
 
 #[derive(Debug, Clone)]
 pub struct CheckInfo {
     pub ty: Type,
     pub expr_types: Vec<(Span, Type)>,
+    // Every error found, in source order. Only the recovering entry points fill this; when
+    // it is non-empty, `ty` and the types of whatever depends on a failed part are `Unknown`.
+    pub errors: Vec<TypeError>,
 }
 
 pub fn check_program(program: &Program) -> Result<Type, TypeError> {
-    check_program_with_sink(program, &mut ())
+    check_program_with_sink(program, &mut TypeEnv::new(), &mut (), None)
+}
+
+/// Checks `program` on top of the globals and functions already in `env`, and leaves the ones
+/// it defines there. Used to check a program piece by piece (as the REPL does). On error `env`
+/// may hold part of the program's definitions; callers that want to go on should check against
+/// a clone.
+pub fn check_program_in(program: &Program, env: &mut TypeEnv) -> Result<Type, TypeError> {
+    check_program_with_sink(program, env, &mut (), None)
 }
 
 pub fn check_program_with_spans(program: &Program) -> Result<CheckInfo, TypeError> {
     let mut expr_types = Vec::new();
-    let ty = check_program_with_sink(program, &mut expr_types)?;
-    Ok(CheckInfo { ty, expr_types })
+    let ty = check_program_with_sink(program, &mut TypeEnv::new(), &mut expr_types, None)?;
+    Ok(CheckInfo {
+        ty,
+        expr_types,
+        errors: Vec::new(),
+    })
+}
+
+/// Checks the whole program even if parts of it are ill-typed.
+///
+/// A statement that fails to check is reported and skipped; a `let` it would have defined gets
+/// its annotated type, or `Unknown`, which is accepted everywhere without further errors. So
+/// the types of everything that does not depend on the error are still recorded.
+pub fn check_program_partial(program: &Program) -> CheckInfo {
+    let mut sink = Recovering::default();
+    let ty = check_program_with_sink(program, &mut TypeEnv::new(), &mut sink, None)
+        .expect("a recovering check does not fail");
+    sink.finish(ty)
 }
 
-trait TypeSink {
+/// Like `check_program_partial`, but reuses `cache` for top-level functions whose text and
+/// environment did not change since the previous call. `text` must be the source `program`
+/// was parsed from.
+pub fn check_program_incremental(program: &Program, text: &str, cache: &mut FnCache) -> CheckInfo {
+    cache.begin();
+    let mut sink = Recovering::default();
+    let ty = check_program_with_sink(
+        program,
+        &mut TypeEnv::new(),
+        &mut sink,
+        Some((text, &mut *cache)),
+    )
+    .expect("a recovering check does not fail");
+    cache.finish();
+    sink.finish(ty)
+}
+
+pub(crate) trait TypeSink {
     fn record(&mut self, span: Span, ty: Type);
+
+    /// Called with an error the checker knows how to continue after. Strict sinks hand it
+    /// back, which stops the check; recovering sinks keep it and let the check go on.
+    fn recover(&mut self, err: TypeError) -> Result<(), TypeError> {
+        Err(err)
+    }
 }
 
 impl TypeSink for () {
@@ -40,12 +93,40 @@ impl TypeSink for Vec<(Span, Type)> {
     }
 }
 
+#[derive(Debug, Default)]
+pub(crate) struct Recovering {
+    pub(crate) types: Vec<(Span, Type)>,
+    pub(crate) errors: Vec<TypeError>,
+}
+
+impl Recovering {
+    fn finish(mut self, ty: Type) -> CheckInfo {
+        self.errors.sort_by_key(|e| e.span.start);
+        CheckInfo {
+            ty,
+            expr_types: self.types,
+            errors: self.errors,
+        }
+    }
+}
+
+impl TypeSink for Recovering {
+    fn record(&mut self, span: Span, ty: Type) {
+        self.types.push((span, ty));
+    }
+
+    fn recover(&mut self, err: TypeError) -> Result<(), TypeError> {
+        self.errors.push(err);
+        Ok(())
+    }
+}
+
 fn check_program_with_sink<S: TypeSink>(
     program: &Program,
+    env: &mut TypeEnv,
     sink: &mut S,
+    mut cache: Option<(&str, &mut FnCache)>,
 ) -> Result<Type, TypeError> {
-    let mut env = TypeEnv::new();
-
     // Builtins.
     // `gc()` triggers a garbage collection cycle for heap-allocated objects.
     env.define_fn("gc".to_string(), Vec::new(), Type::Unit)?;
@@ -61,31 +142,104 @@ fn check_program_with_sink<S: TypeSink>(
         } = stmt
         {
             if env.get_fn(name).is_some() {
-                return Err(TypeError {
+                // The first definition wins; pass 2 checks this body against it.
+                sink.recover(TypeError {
                     message: format!("duplicate function: {name}"),
                     span: *span,
-                });
+                })?;
+                continue;
             }
             let params = params
                 .iter()
-                .map(|p| lower_type(&p.ty))
+                .map(|p| lower_type_or_unknown(&p.ty, sink))
                 .collect::<Result<Vec<_>, _>>()?;
-            let ret = lower_type(ret_ty)?;
+            let ret = lower_type_or_unknown(ret_ty, sink)?;
             env.define_fn(name.clone(), params, ret)?;
         }
     }
 
     // Pass 2: typecheck statements in order (strict: vars must be defined before use).
     for stmt in &program.stmts {
-        let _ = check_stmt(stmt, &mut env, sink, None)?;
+        if let (Stmt::Fn { .. }, Some((text, cache))) = (stmt, cache.as_mut()) {
+            check_fn_cached(stmt, text, cache, env, sink)?;
+            continue;
+        }
+        let _ = check_stmt_or_recover(stmt, env, sink, None)?;
     }
 
     match &program.tail {
-        Some(expr) => check_expr(expr, &mut env, sink, None),
+        Some(expr) => check_expr_or_recover(expr, env, sink, None),
         None => Ok(Type::Unit),
     }
 }
 
+fn check_fn_cached<S: TypeSink>(
+    stmt: &Stmt,
+    text: &str,
+    cache: &mut FnCache,
+    env: &mut TypeEnv,
+    sink: &mut S,
+) -> Result<(), TypeError> {
+    let span = stmt.span();
+    let Some(fn_text) = text.get(span.start..span.end) else {
+        // Text and program disagree; fall back to a plain check.
+        return check_stmt_or_recover(stmt, env, sink, None).map(|_| ());
+    };
+
+    let key = FnKey {
+        text: fn_text.to_string(),
+        env: env.fingerprint(),
+    };
+    let entry = match cache.lookup(&key) {
+        Some(entry) => entry,
+        None => {
+            // Always collect every error, so the entry serves strict and recovering runs alike.
+            let mut inner = Recovering::default();
+            if let Err(e) = check_stmt(stmt, env, &mut inner, None) {
+                inner.errors.push(e);
+            }
+            cache.insert(key, span, inner)
+        }
+    };
+    entry.replay(span, sink)
+}
+
+/// `check_stmt`, but on error lets `sink` decide whether to go on. If it does, a name the
+/// statement would have bound is bound anyway, so later uses do not cascade into errors.
+fn check_stmt_or_recover<S: TypeSink>(
+    stmt: &Stmt,
+    env: &mut TypeEnv,
+    sink: &mut S,
+    current_ret: Option<&Type>,
+) -> Result<bool, TypeError> {
+    match check_stmt(stmt, env, sink, current_ret) {
+        Ok(diverges) => Ok(diverges),
+        Err(e) => {
+            sink.recover(e)?;
+            if let Stmt::Let { name, ty, .. } = stmt {
+                let ty = ty
+                    .as_ref()
+                    .and_then(|t| lower_type(t).ok())
+                    .unwrap_or(Type::Unknown);
+                env.define_var(name.clone(), ty);
+            }
+            Ok(false)
+        }
+    }
+}
+
+fn check_expr_or_recover<S: TypeSink>(
+    expr: &Expr,
+    env: &mut TypeEnv,
+    sink: &mut S,
+    current_ret: Option<&Type>,
+) -> Result<Type, TypeError> {
+    match check_expr(expr, env, sink, current_ret) {
+        Ok(ty) => Ok(ty),
+        Err(e) => sink.recover(e).map(|()| Type::Unknown),
+    }
+}
+
 fn check_stmt<S: TypeSink>(
     stmt: &Stmt,
     env: &mut TypeEnv,
@@ -133,7 +287,7 @@ fn check_stmt<S: TypeSink>(
                 }
 
                 let var_ty = env.get_var(name).cloned().ok_or_else(|| TypeError {
-                    message: format!("undefined variable: {name}"),
+                    message: format!("{UNDEFINED_VARIABLE}: {name}"),
                     span: *sp,
                 })?;
 
@@ -168,8 +322,9 @@ fn check_stmt<S: TypeSink>(
                 }
 
                 match base_ty {
+                    Type::Unknown => Ok(false),
                     Type::Array(inner) => {
-                        if index_ty != Type::Int {
+                        if !compatible(&Type::Int, &index_ty) {
                             return Err(TypeError {
                                 message: format!("array index must be Int, got {index_ty}"),
                                 span: *span,
@@ -185,7 +340,7 @@ fn check_stmt<S: TypeSink>(
                         Ok(false)
                     }
                     Type::Object(inner) => {
-                        if index_ty != Type::String {
+                        if !compatible(&Type::String, &index_ty) {
                             return Err(TypeError {
                                 message: format!("object key must be String, got {index_ty}"),
                                 span: *span,
@@ -301,10 +456,13 @@ fn check_expr<S: TypeSink>(
                     ret: Box::new(sig.ret.clone()),
                 }
             } else {
-                return Err(TypeError {
-                    message: format!("undefined variable: {name}"),
+                // Recoverable right here, so e.g. the arguments of a call to a missing function
+                // still get types.
+                sink.recover(TypeError {
+                    message: format!("{UNDEFINED_VARIABLE}: {name}"),
                     span: *sp,
-                });
+                })?;
+                Type::Unknown
             }
         }
         Expr::Fn {
@@ -347,7 +505,7 @@ fn check_expr<S: TypeSink>(
                 });
             }
 
-            let first = check_expr(&elements[0], env, sink, current_ret)?;
+            let mut first = check_expr(&elements[0], env, sink, current_ret)?;
             if matches!(first, Type::Never) {
                 return Ok(Type::Never);
             }
@@ -357,14 +515,17 @@ fn check_expr<S: TypeSink>(
                 if matches!(ty, Type::Never) {
                     return Ok(Type::Never);
                 }
-                if ty != first {
-                    return Err(TypeError {
-                        message: format!(
-                            "array elements must have the same type: got {first} and {ty}"
-                        ),
-                        span: *span,
-                    });
-                }
+                first = match join(first.clone(), ty.clone()) {
+                    Some(joined) => joined,
+                    None => {
+                        return Err(TypeError {
+                            message: format!(
+                                "array elements must have the same type: got {first} and {ty}"
+                            ),
+                            span: *span,
+                        })
+                    }
+                };
             }
 
             Type::Array(Box::new(first))
@@ -378,7 +539,7 @@ fn check_expr<S: TypeSink>(
                 });
             }
 
-            let first = check_expr(&props[0].1, env, sink, current_ret)?;
+            let mut first = check_expr(&props[0].1, env, sink, current_ret)?;
             if matches!(first, Type::Never) {
                 return Ok(Type::Never);
             }
@@ -388,14 +549,17 @@ fn check_expr<S: TypeSink>(
                 if matches!(ty, Type::Never) {
                     return Ok(Type::Never);
                 }
-                if ty != first {
-                    return Err(TypeError {
-                        message: format!(
-                            "object values must have the same type: got {first} and {ty}"
-                        ),
-                        span: *span,
-                    });
-                }
+                first = match join(first.clone(), ty.clone()) {
+                    Some(joined) => joined,
+                    None => {
+                        return Err(TypeError {
+                            message: format!(
+                                "object values must have the same type: got {first} and {ty}"
+                            ),
+                            span: *span,
+                        })
+                    }
+                };
             }
 
             Type::Object(Box::new(first))
@@ -407,13 +571,13 @@ fn check_expr<S: TypeSink>(
             env.push_scope();
             let result = (|| {
                 for stmt in stmts {
-                    let diverges = check_stmt(stmt, env, sink, current_ret)?;
+                    let diverges = check_stmt_or_recover(stmt, env, sink, current_ret)?;
                     if diverges {
                         return Ok(Type::Never);
                     }
                 }
                 match tail {
-                    Some(expr) => check_expr(expr, env, sink, current_ret),
+                    Some(expr) => check_expr_or_recover(expr, env, sink, current_ret),
                     None => Ok(Type::Unit),
                 }
             })();
@@ -431,7 +595,7 @@ fn check_expr<S: TypeSink>(
             if matches!(cond_ty, Type::Never) {
                 return Ok(Type::Never);
             }
-            if cond_ty != Type::Bool {
+            if !compatible(&Type::Bool, &cond_ty) {
                 return Err(TypeError {
                     message: format!("if condition must be Bool, got {cond_ty}"),
                     span: *span,
@@ -441,19 +605,16 @@ fn check_expr<S: TypeSink>(
             let then_ty = check_expr(then_branch, env, sink, current_ret)?;
             let else_ty = check_expr(else_branch, env, sink, current_ret)?;
 
-            if then_ty == else_ty {
-                then_ty
-            } else if matches!(then_ty, Type::Never) {
-                else_ty
-            } else if matches!(else_ty, Type::Never) {
-                then_ty
-            } else {
-                return Err(TypeError {
-                    message: format!(
-                        "if branches must have the same type: got {then_ty} and {else_ty}"
-                    ),
-                    span: *span,
-                });
+            match join(then_ty.clone(), else_ty.clone()) {
+                Some(ty) => ty,
+                None => {
+                    return Err(TypeError {
+                        message: format!(
+                            "if branches must have the same type: got {then_ty} and {else_ty}"
+                        ),
+                        span: *span,
+                    })
+                }
             }
         }
 
@@ -465,6 +626,13 @@ fn check_expr<S: TypeSink>(
 
             let (params, ret) = match callee_ty {
                 Type::Function { params, ret } => (params, ret),
+                Type::Unknown => {
+                    // Still check the arguments, for their own errors and types.
+                    for arg in args {
+                        check_expr(arg, env, sink, current_ret)?;
+                    }
+                    return Ok(Type::Unknown);
+                }
                 other => {
                     return Err(TypeError {
                         message: format!("cannot call non-function value: {other}"),
@@ -518,8 +686,9 @@ fn check_expr<S: TypeSink>(
             }
 
             match base {
+                Type::Unknown => Type::Unknown,
                 Type::Array(inner) => {
-                    if idx != Type::Int {
+                    if !compatible(&Type::Int, &idx) {
                         return Err(TypeError {
                             message: format!("array index must be Int, got {idx}"),
                             span: *span,
@@ -528,7 +697,7 @@ fn check_expr<S: TypeSink>(
                     *inner
                 }
                 Type::Object(inner) => {
-                    if idx != Type::String {
+                    if !compatible(&Type::String, &idx) {
                         return Err(TypeError {
                             message: format!("object key must be String, got {idx}"),
                             span: *span,
@@ -552,7 +721,7 @@ fn check_expr<S: TypeSink>(
             }
             match op {
                 UnaryOp::Neg => {
-                    if inner != Type::Int {
+                    if !compatible(&Type::Int, &inner) {
                         return Err(TypeError {
                             message: format!("cannot apply unary '-' to {inner}"),
                             span: *span,
@@ -561,7 +730,7 @@ fn check_expr<S: TypeSink>(
                     Type::Int
                 }
                 UnaryOp::Not => {
-                    if inner != Type::Bool {
+                    if !compatible(&Type::Bool, &inner) {
                         return Err(TypeError {
                             message: format!("cannot apply unary '!' to {inner}"),
                             span: *span,
@@ -579,7 +748,7 @@ fn check_expr<S: TypeSink>(
                     if matches!(l, Type::Never) {
                         return Ok(Type::Never);
                     }
-                    if l != Type::Bool {
+                    if !compatible(&Type::Bool, &l) {
                         return Err(TypeError {
                             message: format!("logical operators require Bool, got {l} and ..."),
                             span: *span,
@@ -590,7 +759,7 @@ fn check_expr<S: TypeSink>(
                     if matches!(r, Type::Never) {
                         // Short-circuit means the expression can still evaluate to Bool.
                         Type::Bool
-                    } else if r == Type::Bool {
+                    } else if compatible(&Type::Bool, &r) {
                         Type::Bool
                     } else {
                         return Err(TypeError {
@@ -627,10 +796,13 @@ fn check_binary(op: BinaryOp, l: Type, r: Type, span: Span) -> Result<Type, Type
         BinaryOp::Add => match (&l, &r) {
             (Type::Int, Type::Int) => Ok(Type::Int),
             (Type::String, Type::String) => Ok(Type::String),
+            // `+` works on both Int and String; the known side decides.
+            (Type::Unknown, Type::Int | Type::String | Type::Unknown) => Ok(r),
+            (Type::Int | Type::String, Type::Unknown) => Ok(l),
             _ => Err(err(format!("cannot add {l} and {r}"))),
         },
         BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div | BinaryOp::Mod => {
-            if l == Type::Int && r == Type::Int {
+            if compatible(&Type::Int, &l) && compatible(&Type::Int, &r) {
                 Ok(Type::Int)
             } else {
                 Err(err(format!(
@@ -639,7 +811,7 @@ fn check_binary(op: BinaryOp, l: Type, r: Type, span: Span) -> Result<Type, Type
             }
         }
         BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge => {
-            if l == Type::Int && r == Type::Int {
+            if compatible(&Type::Int, &l) && compatible(&Type::Int, &r) {
                 Ok(Type::Bool)
             } else {
                 Err(err(format!(
@@ -648,7 +820,7 @@ fn check_binary(op: BinaryOp, l: Type, r: Type, span: Span) -> Result<Type, Type
             }
         }
         BinaryOp::Eq | BinaryOp::Ne => {
-            if l == r {
+            if join(l.clone(), r.clone()).is_some() {
                 Ok(Type::Bool)
             } else {
                 Err(err(format!("cannot compare {l} and {r}")))
@@ -664,7 +836,40 @@ fn check_binary(op: BinaryOp, l: Type, r: Type, span: Span) -> Result<Type, Type
 }
 
 fn compatible(expected: &Type, got: &Type) -> bool {
-    expected == got || matches!(got, Type::Never)
+    match (expected, got) {
+        (_, Type::Never) | (Type::Unknown, _) | (_, Type::Unknown) => true,
+        (Type::Array(a), Type::Array(b)) | (Type::Object(a), Type::Object(b)) => compatible(a, b),
+        (
+            Type::Function { params, ret },
+            Type::Function {
+                params: got_params,
+                ret: got_ret,
+            },
+        ) => {
+            params.len() == got_params.len()
+                && params.iter().zip(got_params).all(|(p, g)| compatible(g, p))
+                && compatible(ret, got_ret)
+        }
+        _ => expected == got,
+    }
+}
+
+/// The type two values must share (array elements, `if` branches), if they can.
+/// `Never` and `Unknown` give way to the other side.
+fn join(a: Type, b: Type) -> Option<Type> {
+    match (&a, &b) {
+        (Type::Never | Type::Unknown, _) => Some(b),
+        (_, Type::Never | Type::Unknown) => Some(a),
+        _ if compatible(&a, &b) && compatible(&b, &a) => Some(a),
+        _ => None,
+    }
+}
+
+fn lower_type_or_unknown<S: TypeSink>(ty: &TypeExpr, sink: &mut S) -> Result<Type, TypeError> {
+    match lower_type(ty) {
+        Ok(ty) => Ok(ty),
+        Err(e) => sink.recover(e).map(|()| Type::Unknown),
+    }
 }
 
 fn lower_type(ty: &TypeExpr) -> Result<Type, TypeError> {
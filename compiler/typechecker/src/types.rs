@@ -1,10 +1,13 @@
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Type {
     Int,
     Bool,
     String,
     Unit,
     Never,
+    // Stands in for the type of something that failed to check, so checking can go on
+    // without reporting follow-up errors. Only produced when recovering from errors.
+    Unknown,
     Array(Box<Type>),
     Object(Box<Type>),
     Function { params: Vec<Type>, ret: Box<Type> },
@@ -18,6 +21,7 @@ impl std::fmt::Display for Type {
             Type::String => write!(f, "String"),
             Type::Unit => write!(f, "Unit"),
             Type::Never => write!(f, "Never"),
+            Type::Unknown => write!(f, "unknown"),
             Type::Array(inner) => write!(f, "Array<{inner}>"),
             Type::Object(inner) => write!(f, "Object<{inner}>"),
             Type::Function { params, ret } => {
@@ -1,11 +1,11 @@
 use moon_core::lexer::lex;
 use moon_core::parser::parse;
 use moon_core::source::Source;
-use moon_typechecker::{check_program, Type};
+use moon_typechecker::{check_program, check_program_in, Type, TypeEnv};
 
 fn check(src: &str) -> Result<Type, String> {
     let source = Source::new("<test>", src.to_string());
-    let tokens = lex(&source.text).map_err(|e| format!("lex: {}", e.message))?;
+    let tokens = lex(source.text()).map_err(|e| format!("lex: {}", e.message))?;
     let program = parse(tokens).map_err(|e| format!("parse: {}", e.message))?;
     check_program(&program).map_err(|e| e.message)
 }
@@ -135,3 +135,105 @@ fn allows_function_with_only_return_statement() {
     let ty = check("fn f() -> Int { return 1; } f()").unwrap();
     assert_eq!(ty, Type::Int);
 }
+
+#[test]
+fn incremental_check_reuses_unchanged_functions() {
+    use moon_typechecker::{check_program_incremental, check_program_with_spans, FnCache};
+
+    let parse_src = |src: &str| parse(lex(src).unwrap()).unwrap();
+    let mut cache = FnCache::new();
+
+    let v1 = "fn f(x: Int) -> Int { x + 1 }\nfn g() -> Bool { true }\nf(1)";
+    let info = check_program_incremental(&parse_src(v1), v1, &mut cache);
+    assert_eq!(info.ty, Type::Int);
+    assert_eq!(cache.stats().misses, 2);
+
+    // Shift both functions down and edit only `g`: `f` is reused, with re-anchored spans.
+    let v2 = "\n\nfn f(x: Int) -> Int { x + 1 }\nfn g() -> Bool { false }\nf(1)";
+    let program = parse_src(v2);
+    let info = check_program_incremental(&program, v2, &mut cache);
+    assert!(info.errors.is_empty());
+    assert_eq!(cache.stats().hits, 1);
+    assert_eq!(cache.stats().misses, 3);
+    assert_eq!(cache.len(), 2);
+
+    let full = check_program_with_spans(&program).unwrap();
+    assert_eq!(info.expr_types, full.expr_types);
+}
+
+#[test]
+fn incremental_check_rechecks_when_signatures_change() {
+    use moon_typechecker::{check_program_incremental, FnCache};
+
+    let parse_src = |src: &str| parse(lex(src).unwrap()).unwrap();
+    let mut cache = FnCache::new();
+
+    let v1 = "fn f() -> Int { g() }\nfn g() -> Int { 1 }\nf()";
+    assert!(check_program_incremental(&parse_src(v1), v1, &mut cache)
+        .errors
+        .is_empty());
+
+    // `f`'s text is unchanged, but it now calls a function returning Bool.
+    let v2 = "fn f() -> Int { g() }\nfn g() -> Bool { true }\nf()";
+    let info = check_program_incremental(&parse_src(v2), v2, &mut cache);
+    assert_eq!(info.errors.len(), 1);
+    assert!(info.errors[0].message.contains("type mismatch"));
+    assert_eq!(cache.stats().hits, 0);
+
+    // A cached function replays its errors too.
+    let info = check_program_incremental(&parse_src(v2), v2, &mut cache);
+    assert_eq!(info.errors.len(), 1);
+    assert_eq!(cache.stats().hits, 2);
+}
+
+#[test]
+fn partial_check_keeps_going_after_errors() {
+    use moon_typechecker::check_program_partial;
+
+    let src = "let a = 1 + true;\nlet b = a * 2;\nfn f(x: Int) -> Int { let y: Bool = x; x + 1 }\nlet c = \"hi\";\nc";
+    let info = check_program_partial(&parse(lex(src).unwrap()).unwrap());
+
+    // Two real errors; uses of `a` (unknown) and `y` (annotated) do not cascade.
+    let messages: Vec<&str> = info.errors.iter().map(|e| e.message.as_str()).collect();
+    assert_eq!(
+        messages,
+        [
+            "cannot add Int and Bool",
+            "type mismatch: expected Bool, got Int"
+        ]
+    );
+    assert_eq!(info.ty, Type::String);
+
+    let type_at = |needle: &str| {
+        let start = src.find(needle).unwrap();
+        info.expr_types
+            .iter()
+            .find(|(sp, _)| sp.start == start && sp.end == start + needle.len())
+            .map(|(_, ty)| ty.clone())
+    };
+    assert_eq!(type_at("x + 1"), Some(Type::Int));
+    assert_eq!(type_at("a * 2"), Some(Type::Int));
+    assert_eq!(type_at("\"hi\""), Some(Type::String));
+}
+
+#[test]
+fn strict_check_still_stops_at_the_first_error() {
+    let err = check("let a = 1 + true; let b: Int = false; b").unwrap_err();
+    assert!(err.contains("cannot add"), "{err}");
+}
+
+#[test]
+fn checks_a_program_on_top_of_earlier_definitions() {
+    let parse_src = |src: &str| parse(lex(src).unwrap()).unwrap();
+    let mut env = TypeEnv::new();
+    check_program_in(
+        &parse_src("let x = 1; fn f(y: Int) -> Int { x + y }"),
+        &mut env,
+    )
+    .unwrap();
+    let ty = check_program_in(&parse_src("[f(x)]"), &mut env).unwrap();
+    assert_eq!(ty, Type::Array(Box::new(Type::Int)));
+
+    let err = check_program_in(&parse_src("fn f() -> Int { 0 }"), &mut env).unwrap_err();
+    assert!(err.message.contains("duplicate function"));
+}
@@ -0,0 +1,65 @@
+//! VM throughput on small call-, variable- and closure-heavy programs.
+//!
+//! Run with `cargo bench -p moon_vm`. Only execution is measured: each program is compiled
+//! once and the module is cloned into a fresh VM per iteration.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use moon_bytecode::{compile, Module};
+use moon_core::lexer::lex;
+use moon_core::parser::parse;
+use moon_runtime::Value;
+use moon_typechecker::check_program;
+use moon_vm::run;
+
+const FIB: &str = "
+fn fib(n: Int) -> Int { if n < 2 { n } else { fib(n - 1) + fib(n - 2) } }
+fib(20)";
+
+const LOCALS: &str = "
+fn sum(n: Int, acc: Int) -> Int {
+    if n == 0 {
+        acc
+    } else {
+        let a = n * 2;
+        let b = a - n;
+        let c = { let d = b + 1; d - 1 };
+        sum(n - 1, acc + a - b + c - n)
+    }
+}
+let total = 0;
+total = sum(2000, 0) + sum(2000, 0);
+total";
+
+const CLOSURES: &str = "
+fn repeat(f: Int, n: Int) -> Int { if n == 0 { f } else { repeat(f + 1, n - 1) } }
+let base = 3;
+let add = fn(x: Int) -> Int { x + base };
+fn apply(n: Int, acc: Int) -> Int { if n == 0 { acc } else { apply(n - 1, add(acc)) } }
+apply(3000, 0) + repeat(0, 10)";
+
+fn module(src: &str) -> Module {
+    let program = parse(lex(src).unwrap()).unwrap();
+    check_program(&program).unwrap();
+    compile(&program).unwrap()
+}
+
+fn bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("vm");
+    for (name, src, expected) in [
+        ("fib_20", FIB, 6765),
+        ("locals", LOCALS, 4002000),
+        ("closures", CLOSURES, 9010),
+    ] {
+        let module = module(src);
+        assert_eq!(run(module.clone()).unwrap(), Value::Int(expected), "{name}");
+        group.bench_function(name, |b| b.iter(|| run(module.clone()).unwrap()));
+    }
+    group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(20);
+    targets = bench
+}
+criterion_main!(benches);
@@ -1,18 +1,37 @@
 use moon_core::span::Span;
+use moon_runtime::ErrorKind;
 
 #[derive(Debug, Clone)]
 pub struct VmError {
+    pub kind: ErrorKind,
     pub message: String,
     pub span: Span,
+    /// The calls running when the error happened, innermost first; empty outside of any
+    /// call. A call replaced by a tail call is gone, so it is not listed.
+    pub trace: Vec<StackFrame>,
+}
+
+/// A call on the Moon stack: the function running, and where it was called from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackFrame {
+    pub function: String,
+    pub call_span: Span,
 }
 
 impl VmError {
     pub fn new(message: impl Into<String>, span: Span) -> Self {
         Self {
+            kind: ErrorKind::Failed,
             message: message.into(),
             span,
+            trace: Vec::new(),
         }
     }
+
+    pub fn with_kind(mut self, kind: ErrorKind) -> Self {
+        self.kind = kind;
+        self
+    }
 }
 
 impl std::fmt::Display for VmError {
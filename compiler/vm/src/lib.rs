@@ -1,5 +1,5 @@
 mod error;
 mod vm;
 
-pub use error::VmError;
-pub use vm::{run, Vm};
+pub use error::{StackFrame, VmError};
+pub use vm::{run, InterruptHandle, Native, Status, Vm, DEFAULT_MAX_DEPTH};
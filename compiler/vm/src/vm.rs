@@ -1,105 +1,324 @@
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
 
-use moon_bytecode::{FuncId, InstrKind, Module};
+use moon_bytecode::{verify_with_natives, Capture, FuncId, InstrKind, Module};
 use moon_core::span::Span;
-use moon_runtime::{GcRef, Heap, Value};
+use moon_runtime::{ErrorKind, GcRef, Heap, Limits, Upvalue, Value, DEADLINE_CHECK_STEPS};
 
-use crate::error::VmError;
+use crate::error::{StackFrame, VmError};
+
+/// How many calls may be running at once unless `Vm::set_max_depth` says otherwise.
+pub const DEFAULT_MAX_DEPTH: usize = 1000;
 
 #[derive(Debug, Clone)]
 struct Frame {
     func: FuncId,
     ip: usize,
+    // Function and offset of the call that made the frame (main has none).
+    call_site: Option<(FuncId, usize)>,
+    // Index in the stack of local slot 0. Arguments are the first slots, the other locals
+    // follow, then the temporaries of the function.
     stack_base: usize,
-    scopes: Vec<HashMap<String, Value>>,
     closure: Option<GcRef>,
 }
 
-#[derive(Debug)]
+/// A function the host implements. It gets the arguments of the call, as many as the
+/// function has parameters, and the heap they live in; an `Err` stops the program with a
+/// runtime error at the call.
+pub type Native = Arc<dyn Fn(&mut Heap, &[Value]) -> Result<Value, String> + Send + Sync>;
+
+/// What `Vm::step` left the program at.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Status {
+    /// It ran all the instructions it was given and has more to run.
+    Yielded,
+    /// Main returned this value.
+    Finished(Value),
+}
+
+/// Asks a running `Vm` to stop, from any thread. The VM sees it before its next instruction
+/// and stops with an `Interrupted` error.
+#[derive(Debug, Clone, Default)]
+pub struct InterruptHandle(Arc<AtomicBool>);
+
+impl InterruptHandle {
+    pub fn interrupt(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_interrupted(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    // Main has not started: the module is verified on the first step.
+    Ready,
+    Running,
+    // Main returned or an error stopped it.
+    Done,
+}
+
 pub struct Vm {
     module: Module,
     heap: Heap,
-    globals: HashMap<String, Value>,
+    // By global id; `None` until the `let` defining it has run.
+    globals: Vec<Option<Value>>,
     stack: Vec<Value>,
     frames: Vec<Frame>,
-    current_span: Span,
+    // Upvalues still pointing into the stack, by stack index (ascending). Closures capturing
+    // the same variable share the one listed here.
+    open_upvalues: Vec<(usize, GcRef)>,
+    // Function and offset of the instruction running, to find its span for errors.
+    current: (FuncId, usize),
+    max_depth: usize,
+    limits: Limits,
+    // Instructions run so far.
+    steps: u64,
+    interrupt: InterruptHandle,
+    state: State,
+    // By function id; `Some` for the functions the host implements.
+    natives: Vec<Option<Native>>,
+}
+
+impl std::fmt::Debug for Vm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Vm")
+            .field("frames", &self.frames.len())
+            .field("stack", &self.stack.len())
+            .field("steps", &self.steps)
+            .field("state", &self.state)
+            .finish_non_exhaustive()
+    }
 }
 
 impl Vm {
     pub fn new(module: Module) -> Self {
         Self {
+            globals: vec![None; module.globals.len()],
             module,
             heap: Heap::new(),
-            globals: HashMap::new(),
             stack: Vec::new(),
             frames: Vec::new(),
-            current_span: Span::new(0, 0),
+            open_upvalues: Vec::new(),
+            current: (0, 0),
+            max_depth: DEFAULT_MAX_DEPTH,
+            limits: Limits::default(),
+            steps: 0,
+            interrupt: InterruptHandle::default(),
+            state: State::Ready,
+            natives: Vec::new(),
         }
     }
 
+    /// Runs `native` for calls to `name`, a function with no code in the module (see
+    /// `moon_bytecode::compile_with_natives`). Returns false, and does nothing, if the module
+    /// has no such function.
+    pub fn register_native(&mut self, name: &str, native: Native) -> bool {
+        let Some(&id) = self.module.by_name.get(name) else {
+            return false;
+        };
+        if !self.module.functions[id].code.is_empty() {
+            return false;
+        }
+        if self.natives.len() <= id {
+            self.natives.resize(id + 1, None);
+        }
+        self.natives[id] = Some(native);
+        true
+    }
+
+    /// The heap the program's arrays, objects and closures live in, to read the values it
+    /// returns.
+    pub fn heap(&self) -> &Heap {
+        &self.heap
+    }
+
+    /// A handle to stop this VM from another thread, for a host that cannot wait for a
+    /// runaway script.
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.interrupt.clone()
+    }
+
+    /// Sets the fuel (one unit per instruction), heap and time limits.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.heap.set_limits(limits.heap);
+        self.limits = limits;
+    }
+
+    /// Sets how many calls may be running at once; one more is a `stack overflow` error.
+    /// Tail calls do not count, since they replace the running call.
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+    }
+
+    /// Runs the module's main function. The module is verified first (`moon_bytecode::verify`),
+    /// so bad bytecode fails before anything runs.
     pub fn run(mut self) -> Result<Value, VmError> {
-        // Main executes with no local scopes. Top-level `let` therefore defines globals.
-        self.frames.push(Frame {
-            func: self.module.main,
-            ip: 0,
-            stack_base: 0,
-            scopes: Vec::new(),
-            closure: None,
-        });
+        self.resume()
+    }
+
+    /// Runs at most `n` instructions of main, starting it on the first call, so a host can
+    /// share a thread between scripts. Once main returns or fails, there is nothing left to
+    /// run and calling again is an error.
+    pub fn step(&mut self, n: u64) -> Result<Status, VmError> {
+        self.start()?;
+        match self.exec(n)? {
+            Some(v) => Ok(Status::Finished(v)),
+            None => Ok(Status::Yielded),
+        }
+    }
 
+    /// Runs main to the end, or from where `step` left it.
+    pub fn resume(&mut self) -> Result<Value, VmError> {
+        self.start()?;
         loop {
+            if let Some(v) = self.exec(u64::MAX)? {
+                return Ok(v);
+            }
+        }
+    }
+
+    fn start(&mut self) -> Result<(), VmError> {
+        match self.state {
+            State::Running => Ok(()),
+            State::Done => Err(VmError::new("the program already stopped", Span::new(0, 0))),
+            State::Ready => {
+                self.state = State::Done;
+                let natives: Vec<&str> = self
+                    .natives
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, native)| native.is_some())
+                    .map(|(id, _)| self.module.functions[id].name.as_str())
+                    .collect();
+                verify_with_natives(&self.module, &natives).map_err(|e| {
+                    VmError::new(format!("invalid bytecode: {}", e.message), e.span)
+                })?;
+                self.push_call_frame(self.module.main, 0, None)?;
+                self.state = State::Running;
+                Ok(())
+            }
+        }
+    }
+
+    /// Runs at most `budget` instructions; `Some` is main's result. Main is done after either
+    /// that or an error.
+    fn exec(&mut self, budget: u64) -> Result<Option<Value>, VmError> {
+        let result = self.exec_instrs(budget);
+        if !matches!(result, Ok(None)) {
+            self.state = State::Done;
+        }
+        result
+    }
+
+    fn exec_instrs(&mut self, mut budget: u64) -> Result<Option<Value>, VmError> {
+        while budget > 0 {
+            budget -= 1;
             let frame_idx = self.frames.len() - 1;
             let func_id = self.frames[frame_idx].func;
             let ip = self.frames[frame_idx].ip;
 
-            let func = self.module.get_func(func_id).ok_or_else(|| {
-                VmError::new("invalid function id".to_string(), self.current_span)
-            })?;
+            let func = self
+                .module
+                .get_func(func_id)
+                .ok_or_else(|| self.err("invalid function id"))?;
 
             if ip >= func.code.len() {
-                return Err(VmError::new(
-                    format!("instruction pointer out of bounds in {}", func.name),
-                    self.current_span,
-                ));
+                return Err(self.err(format!(
+                    "instruction pointer out of bounds in {}",
+                    func.name
+                )));
             }
 
-            let instr = func.code[ip].clone();
-            self.frames[frame_idx].ip += 1;
-            self.current_span = instr.span;
+            let (kind, next) = InstrKind::decode(&func.code, ip)
+                .map_err(|e| self.err(format!("malformed bytecode in {}: {e}", func.name)))?;
+            self.frames[frame_idx].ip = next;
+            self.current = (func_id, ip);
+            self.count_step()?;
 
-            match instr.kind {
-                InstrKind::Push(v) => self.stack.push(v),
+            match kind {
+                InstrKind::Const(idx) => {
+                    let v = self
+                        .module
+                        .constants
+                        .get(idx as usize)
+                        .cloned()
+                        .ok_or_else(|| self.err(format!("invalid constant: {idx}")))?;
+                    self.stack.push(v);
+                }
+                InstrKind::Unit => self.stack.push(Value::Unit),
+                InstrKind::True => self.stack.push(Value::Bool(true)),
+                InstrKind::False => self.stack.push(Value::Bool(false)),
                 InstrKind::Pop => {
                     self.stack
                         .pop()
                         .ok_or_else(|| self.err("stack underflow"))?;
                 }
 
-                InstrKind::PushScope => self.frames[frame_idx].scopes.push(HashMap::new()),
-                InstrKind::PopScope => {
-                    self.frames[frame_idx]
-                        .scopes
-                        .pop()
-                        .ok_or_else(|| self.err("scope underflow"))?;
+                InstrKind::LoadLocal(slot) => {
+                    let v = self.local_slot(frame_idx, slot)?.clone();
+                    self.stack.push(v);
                 }
-
-                InstrKind::LoadVar(name) => {
-                    if let Some(v) = self.get_var(frame_idx, &name) {
-                        self.stack.push(v);
-                    } else if self.module.by_name.contains_key(&name) {
-                        // Functions are values too. Vars shadow functions.
-                        self.stack.push(Value::Function(name));
-                    } else {
-                        return Err(self.err(format!("undefined variable: {name}")));
+                InstrKind::StoreLocal(slot) => {
+                    let v = self.pop()?;
+                    *self.local_slot(frame_idx, slot)? = v;
+                }
+                InstrKind::LoadUpvalue(idx) => {
+                    let h = self.upvalue_of(frame_idx, idx)?;
+                    let v = match self.heap.upvalue(h) {
+                        Some(Upvalue::Open(at)) => self.stack[*at].clone(),
+                        Some(Upvalue::Closed(v)) => v.clone(),
+                        None => return Err(self.err("invalid upvalue handle")),
+                    };
+                    self.stack.push(v);
+                }
+                InstrKind::StoreUpvalue(idx) => {
+                    let v = self.pop()?;
+                    let h = self.upvalue_of(frame_idx, idx)?;
+                    match self.heap.upvalue(h) {
+                        Some(Upvalue::Open(at)) => self.stack[*at] = v,
+                        Some(Upvalue::Closed(_)) => self
+                            .heap
+                            .set_upvalue(h, Upvalue::Closed(v))
+                            .map_err(|e| self.err(e))?,
+                        None => return Err(self.err("invalid upvalue handle")),
                     }
                 }
-                InstrKind::DefineVar(name) => {
+                InstrKind::CloseUpvalues(slot) => {
+                    let from = self.frames[frame_idx].stack_base + slot as usize;
+                    self.close_upvalues(from);
+                }
+                InstrKind::LoadGlobal(id) => {
+                    let v = match self.global_slot(id)? {
+                        Some(v) => v.clone(),
+                        None => {
+                            // Functions are values too. Globals shadow them once defined.
+                            let name = &self.module.globals[id as usize];
+                            if !self.module.by_name.contains_key(name) {
+                                return Err(self.err(format!("undefined variable: {name}")));
+                            }
+                            Value::Function(name.clone())
+                        }
+                    };
+                    self.stack.push(v);
+                }
+                InstrKind::DefineGlobal(id) => {
                     let v = self.pop()?;
-                    self.define_var(frame_idx, name, v);
+                    *self.global_slot(id)? = Some(v);
                 }
-                InstrKind::SetVar(name) => {
+                InstrKind::StoreGlobal(id) => {
                     let v = self.pop()?;
-                    self.set_var(frame_idx, &name, v)?;
+                    match self.global_slot(id)? {
+                        Some(slot) => *slot = v,
+                        None => {
+                            let name = &self.module.globals[id as usize];
+                            return Err(self.err(format!("undefined variable: {name}")));
+                        }
+                    }
                 }
 
                 InstrKind::Neg => {
@@ -145,11 +364,11 @@ impl Vm {
                 InstrKind::Gt => self.bin_cmp(|a, b| a > b, ">")?,
                 InstrKind::Ge => self.bin_cmp(|a, b| a >= b, ">=")?,
 
-                InstrKind::Jump(dst) => self.frames[frame_idx].ip = dst,
+                InstrKind::Jump(dst) => self.frames[frame_idx].ip = dst as usize,
                 InstrKind::JumpIfFalse(dst) => {
                     let v = self.peek()?.clone();
                     match v {
-                        Value::Bool(false) => self.frames[frame_idx].ip = dst,
+                        Value::Bool(false) => self.frames[frame_idx].ip = dst as usize,
                         Value::Bool(true) => {}
                         other => {
                             return Err(self.err(format!("expected bool condition, got {other:?}")))
@@ -159,7 +378,7 @@ impl Vm {
                 InstrKind::JumpIfTrue(dst) => {
                     let v = self.peek()?.clone();
                     match v {
-                        Value::Bool(true) => self.frames[frame_idx].ip = dst,
+                        Value::Bool(true) => self.frames[frame_idx].ip = dst as usize,
                         Value::Bool(false) => {}
                         other => {
                             return Err(self.err(format!("expected bool condition, got {other:?}")))
@@ -167,103 +386,35 @@ impl Vm {
                     }
                 }
 
-                InstrKind::Call(id, argc) => {
-                    let func_obj = self
-                        .module
-                        .get_func(id)
-                        .ok_or_else(|| self.err("invalid function id"))?;
-
-                    // Builtins are treated like normal functions in bytecode, but executed by the VM.
-                    if func_obj.name == "gc" {
-                        // No args.
-                        if argc != 0 {
-                            return Err(self.err("gc() takes no arguments"));
-                        }
-
-                        let roots = self.roots();
-                        let _ = self.heap.collect_garbage(&roots);
-                        self.stack.push(Value::Unit);
-                        continue;
-                    }
-
-                    // Pop arguments from the stack.
-                    let mut args = Vec::with_capacity(argc);
-                    for _ in 0..argc {
-                        args.push(self.pop()?);
-                    }
-                    args.reverse();
-
-                    let stack_base = self.stack.len();
-                    self.push_call_frame(id, stack_base, args, None)?;
+                // The arguments stay on the stack: they become the callee's first slots.
+                InstrKind::Call(id, argc) => self.call(id as usize, argc as usize, None, false)?,
+                InstrKind::TailCall(id, argc) => {
+                    self.call(id as usize, argc as usize, None, true)?
                 }
-
                 InstrKind::CallValue(argc) => {
-                    // Pop arguments from the stack.
-                    let mut args = Vec::with_capacity(argc);
-                    for _ in 0..argc {
-                        args.push(self.pop()?);
-                    }
-                    args.reverse();
-
-                    let callee = self.pop()?;
-                    let (name, closure) = match callee {
-                        Value::Function(name) => (name, None),
-                        Value::Closure(h) => {
-                            let func = self
-                                .heap
-                                .closure_func_name(h)
-                                .ok_or_else(|| self.err("invalid closure handle"))?;
-                            (func.to_string(), Some(h))
-                        }
-                        other => {
-                            return Err(
-                                self.err(format!("cannot call non-function value: {other:?}"))
-                            )
-                        }
-                    };
-
-                    let id = self
-                        .module
-                        .by_name
-                        .get(&name)
-                        .copied()
-                        .ok_or_else(|| self.err(format!("undefined function: {name}")))?;
-
-                    let func_obj = self
-                        .module
-                        .get_func(id)
-                        .ok_or_else(|| self.err("invalid function id"))?;
-
-                    // Builtins are treated like normal functions in bytecode, but executed by the VM.
-                    if func_obj.name == "gc" {
-                        // No args.
-                        if argc != 0 {
-                            return Err(self.err("gc() takes no arguments"));
-                        }
-
-                        let roots = self.roots();
-                        let _ = self.heap.collect_garbage(&roots);
-                        self.stack.push(Value::Unit);
-                        continue;
-                    }
-
-                    let stack_base = self.stack.len();
-                    self.push_call_frame(id, stack_base, args, closure)?;
+                    let (id, closure) = self.take_callee(argc as usize)?;
+                    self.call(id, argc as usize, closure, false)?;
+                }
+                InstrKind::TailCallValue(argc) => {
+                    let (id, closure) = self.take_callee(argc as usize)?;
+                    self.call(id, argc as usize, closure, true)?;
                 }
 
                 InstrKind::Return => {
                     let ret = self.pop()?;
                     let frame = self.frames.pop().expect("frame exists");
+                    self.close_upvalues(frame.stack_base);
                     self.stack.truncate(frame.stack_base);
 
                     if self.frames.is_empty() {
-                        return Ok(ret);
+                        return Ok(Some(ret));
                     }
 
                     self.stack.push(ret);
                 }
 
                 InstrKind::MakeArray(n) => {
+                    let n = n as usize;
                     let mut elems = Vec::with_capacity(n);
                     for _ in 0..n {
                         elems.push(self.pop()?);
@@ -272,16 +423,20 @@ impl Vm {
                     let h = self.heap.alloc_array(elems);
                     self.stack.push(Value::Array(h));
                 }
-                InstrKind::MakeObject(keys) => {
-                    let n = keys.len();
-                    let mut values = Vec::with_capacity(n);
+                InstrKind::MakeObject(n) => {
+                    let mut map = HashMap::with_capacity(n as usize);
                     for _ in 0..n {
-                        values.push(self.pop()?);
-                    }
-                    values.reverse();
-                    let mut map = HashMap::new();
-                    for (k, v) in keys.into_iter().zip(values) {
-                        map.insert(k, v);
+                        let v = self.pop()?;
+                        match self.pop()? {
+                            Value::String(k) => {
+                                map.insert(k, v);
+                            }
+                            other => {
+                                return Err(
+                                    self.err(format!("object key must be string, got {other:?}"))
+                                )
+                            }
+                        }
                     }
                     let h = self.heap.alloc_object(map);
                     self.stack.push(Value::Object(h));
@@ -299,107 +454,269 @@ impl Vm {
                     self.index_set(base, index, value)?;
                 }
 
-                InstrKind::MakeClosure(name, captures) => {
-                    let mut env = HashMap::new();
-                    for cap in captures {
-                        if let Some(v) = self.get_local(frame_idx, &cap) {
-                            env.insert(cap, v);
-                        }
+                InstrKind::MakeClosure(id, captures) => {
+                    let mut upvalues = Vec::with_capacity(captures.len());
+                    for capture in captures {
+                        let h = match capture {
+                            Capture::Local(slot) => {
+                                self.local_slot(frame_idx, slot)?;
+                                let at = self.frames[frame_idx].stack_base + slot as usize;
+                                self.capture_upvalue(at)
+                            }
+                            Capture::Upvalue(idx) => self.upvalue_of(frame_idx, idx)?,
+                        };
+                        upvalues.push(h);
                     }
-                    let h = self.heap.alloc_closure(name, env);
+                    let h = self.heap.alloc_compiled_closure(id as usize, upvalues);
                     self.stack.push(Value::Closure(h));
                 }
             }
+
+            // Cheap: the heap keeps its size up to date.
+            self.heap
+                .check_limits()
+                .map_err(|message| self.err(message).with_kind(ErrorKind::HeapLimit))?;
+        }
+        Ok(None)
+    }
+
+    /// Counts the instruction about to run against the fuel and the deadline, and stops if
+    /// the host asked to.
+    fn count_step(&mut self) -> Result<(), VmError> {
+        if self.interrupt.is_interrupted() {
+            return Err(self.err("interrupted").with_kind(ErrorKind::Interrupted));
+        }
+        if let Some(fuel) = self.limits.fuel.filter(|fuel| self.steps >= *fuel) {
+            return Err(self
+                .err(format!("out of fuel: ran {fuel} steps"))
+                .with_kind(ErrorKind::OutOfFuel));
         }
+        self.steps += 1;
+        if self.steps.is_multiple_of(DEADLINE_CHECK_STEPS)
+            && self.limits.deadline.is_some_and(|d| Instant::now() >= d)
+        {
+            return Err(self.err("deadline exceeded").with_kind(ErrorKind::Deadline));
+        }
+        Ok(())
     }
 
     fn err(&self, message: impl Into<String>) -> VmError {
-        VmError::new(message, self.current_span)
+        let mut err = VmError::new(message, self.current_span());
+        err.trace = self.call_stack();
+        err
     }
 
-    fn push_call_frame(
+    fn current_span(&self) -> Span {
+        let (func, offset) = self.current;
+        self.module
+            .get_func(func)
+            .map_or(Span::new(0, 0), |f| f.span_at(offset))
+    }
+
+    /// The running calls, innermost first. A frame a tail call replaced is gone, so it is not
+    /// listed.
+    fn call_stack(&self) -> Vec<StackFrame> {
+        self.frames
+            .iter()
+            .rev()
+            .filter_map(|frame| {
+                let (func, offset) = frame.call_site?;
+                Some(StackFrame {
+                    function: self.module.functions[frame.func].name.clone(),
+                    call_span: self.module.functions[func].span_at(offset),
+                })
+            })
+            .collect()
+    }
+
+    /// Calls `func` with its `argc` arguments on top of the stack. A tail call (`tail`) reuses
+    /// the running frame: its upvalues are closed and the arguments moved down to its base.
+    fn call(
         &mut self,
         func: FuncId,
-        stack_base: usize,
-        args: Vec<Value>,
+        argc: usize,
         closure: Option<GcRef>,
+        tail: bool,
     ) -> Result<(), VmError> {
         let func_obj = self
             .module
             .get_func(func)
             .ok_or_else(|| self.err("invalid function id"))?;
 
-        let mut scope = HashMap::new();
-        for (name, value) in func_obj.params.iter().cloned().zip(args) {
-            scope.insert(name, value);
+        // Builtins are treated like normal functions in bytecode, but executed by the VM.
+        if func_obj.name == "gc" {
+            // No args.
+            if argc != 0 {
+                return Err(self.err("gc() takes no arguments"));
+            }
+
+            let roots = self.roots();
+            let open: Vec<GcRef> = self.open_upvalues.iter().map(|(_, h)| *h).collect();
+            let _ = self.heap.collect_garbage(&roots, &open);
+            self.stack.push(Value::Unit);
+            return Ok(());
+        }
+
+        // Host functions do not get a frame. A tail call to one returns through the `Return`
+        // the compiler leaves after every tail call.
+        if let Some(native) = self.natives.get(func).and_then(Option::clone) {
+            if argc != func_obj.params.len() {
+                return Err(self.err(format!(
+                    "{} expects {} arguments, got {argc}",
+                    func_obj.name,
+                    func_obj.params.len()
+                )));
+            }
+            let args_at = self
+                .stack
+                .len()
+                .checked_sub(argc)
+                .ok_or_else(|| self.err("stack underflow"))?;
+            let args = self.stack.split_off(args_at);
+            let v = native(&mut self.heap, &args).map_err(|message| self.err(message))?;
+            self.stack.push(v);
+            return Ok(());
+        }
+
+        if tail {
+            let base = self.frames.last().expect("a frame is running").stack_base;
+            let args_at = self
+                .stack
+                .len()
+                .checked_sub(argc)
+                .filter(|at| *at >= base)
+                .ok_or_else(|| self.err("stack underflow"))?;
+            self.close_upvalues(base);
+            self.stack.drain(base..args_at);
+            self.frames.pop();
+        }
+        self.push_call_frame(func, argc, closure)
+    }
+
+    /// Takes the value `CallValue` calls out of the stack, from below its `argc` arguments
+    /// (so they line up as for `Call`), and resolves it to a function.
+    fn take_callee(&mut self, argc: usize) -> Result<(FuncId, Option<GcRef>), VmError> {
+        let callee_at = self
+            .stack
+            .len()
+            .checked_sub(argc + 1)
+            .ok_or_else(|| self.err("stack underflow"))?;
+        match self.stack.remove(callee_at) {
+            Value::Function(name) => {
+                let id = self
+                    .module
+                    .by_name
+                    .get(&name)
+                    .copied()
+                    .ok_or_else(|| self.err(format!("undefined function: {name}")))?;
+                Ok((id, None))
+            }
+            Value::Closure(h) => {
+                let id = self
+                    .heap
+                    .compiled_closure_func(h)
+                    .ok_or_else(|| self.err("invalid closure handle"))?;
+                Ok((id, Some(h)))
+            }
+            other => Err(self.err(format!("cannot call non-function value: {other:?}"))),
+        }
+    }
+
+    /// Enters `func` with its `argc` arguments on top of the stack, and reserves the rest of
+    /// its local slots.
+    fn push_call_frame(
+        &mut self,
+        func: FuncId,
+        argc: usize,
+        closure: Option<GcRef>,
+    ) -> Result<(), VmError> {
+        let func_obj = self
+            .module
+            .get_func(func)
+            .ok_or_else(|| self.err("invalid function id"))?;
+        if argc != func_obj.params.len() {
+            return Err(self.err(format!(
+                "{} expects {} arguments, got {argc}",
+                func_obj.name,
+                func_obj.params.len()
+            )));
+        }
+        let stack_base = self
+            .stack
+            .len()
+            .checked_sub(argc)
+            .ok_or_else(|| self.err("stack underflow"))?;
+        let slots = func_obj.slots as usize;
+        // Main does not count: the limit is on calls, as in the interpreter.
+        if self.frames.len() > self.max_depth {
+            return Err(self
+                .err(format!(
+                    "stack overflow: more than {} nested calls",
+                    self.max_depth
+                ))
+                .with_kind(ErrorKind::StackOverflow));
         }
 
+        self.stack.resize(stack_base + slots.max(argc), Value::Unit);
+        let call_site = (!self.frames.is_empty()).then_some(self.current);
         self.frames.push(Frame {
             func,
             ip: 0,
+            call_site,
             stack_base,
-            scopes: vec![scope],
             closure,
         });
         Ok(())
     }
 
-    fn get_var(&self, frame_idx: usize, name: &str) -> Option<Value> {
-        for scope in self.frames[frame_idx].scopes.iter().rev() {
-            if let Some(v) = scope.get(name) {
-                return Some(v.clone());
-            }
-        }
-        if let Some(h) = self.frames[frame_idx].closure {
-            if let Some(v) = self.heap.closure_get(h, name) {
-                return Some(v.clone());
-            }
+    fn local_slot(&mut self, frame_idx: usize, slot: u16) -> Result<&mut Value, VmError> {
+        let at = self.frames[frame_idx].stack_base + slot as usize;
+        if at >= self.stack.len() {
+            return Err(self.err(format!("invalid local slot: {slot}")));
         }
-        self.globals.get(name).cloned()
+        Ok(&mut self.stack[at])
     }
 
-    fn get_local(&self, frame_idx: usize, name: &str) -> Option<Value> {
-        for scope in self.frames[frame_idx].scopes.iter().rev() {
-            if let Some(v) = scope.get(name) {
-                return Some(v.clone());
-            }
-        }
-        if let Some(h) = self.frames[frame_idx].closure {
-            if let Some(v) = self.heap.closure_get(h, name) {
-                return Some(v.clone());
-            }
-        }
-        None
+    /// The `idx`-th upvalue of the closure running in frame `frame_idx`.
+    fn upvalue_of(&self, frame_idx: usize, idx: u16) -> Result<GcRef, VmError> {
+        self.frames[frame_idx]
+            .closure
+            .and_then(|h| self.heap.compiled_closure_upvalue(h, idx as usize))
+            .ok_or_else(|| self.err(format!("invalid upvalue: {idx}")))
     }
 
-    fn define_var(&mut self, frame_idx: usize, name: String, value: Value) {
-        if let Some(scope) = self.frames[frame_idx].scopes.last_mut() {
-            scope.insert(name, value);
-        } else {
-            self.globals.insert(name, value);
+    /// The open upvalue over stack slot `at`, made on first capture.
+    fn capture_upvalue(&mut self, at: usize) -> GcRef {
+        match self.open_upvalues.binary_search_by_key(&at, |(i, _)| *i) {
+            Ok(pos) => self.open_upvalues[pos].1,
+            Err(pos) => {
+                let h = self.heap.alloc_upvalue(Upvalue::Open(at));
+                self.open_upvalues.insert(pos, (at, h));
+                h
+            }
         }
     }
 
-    fn set_var(&mut self, frame_idx: usize, name: &str, value: Value) -> Result<(), VmError> {
-        for scope in self.frames[frame_idx].scopes.iter_mut().rev() {
-            if scope.contains_key(name) {
-                scope.insert(name.to_string(), value);
-                return Ok(());
-            }
+    /// Closes every open upvalue at stack index `from` or above: each keeps the slot's
+    /// current value, since the slot is about to be freed or reused.
+    fn close_upvalues(&mut self, from: usize) {
+        if self.open_upvalues.last().is_none_or(|(at, _)| *at < from) {
+            return;
         }
-        if let Some(h) = self.frames[frame_idx].closure {
-            if self.heap.closure_contains(h, name) {
-                self.heap
-                    .closure_set(h, name.to_string(), value)
-                    .map_err(|e| self.err(e))?;
-                return Ok(());
-            }
+        let pos = self.open_upvalues.partition_point(|(i, _)| *i < from);
+        for (at, h) in self.open_upvalues.split_off(pos) {
+            let v = self.stack[at].clone();
+            self.heap
+                .set_upvalue(h, Upvalue::Closed(v))
+                .expect("open upvalues are live");
         }
-        if self.globals.contains_key(name) {
-            self.globals.insert(name.to_string(), value);
-            return Ok(());
+    }
+
+    fn global_slot(&mut self, id: u32) -> Result<&mut Option<Value>, VmError> {
+        if id as usize >= self.globals.len() {
+            return Err(self.err(format!("invalid global: {id}")));
         }
-        Err(self.err(format!("undefined variable: {name}")))
+        Ok(&mut self.globals[id as usize])
     }
 
     fn peek(&self) -> Result<&Value, VmError> {
@@ -434,6 +751,9 @@ impl Vm {
                 Ok(())
             }
             (Value::String(a), Value::String(b)) => {
+                self.heap
+                    .check_string(a.len() + b.len())
+                    .map_err(|message| self.err(message).with_kind(ErrorKind::HeapLimit))?;
                 self.stack.push(Value::String(format!("{a}{b}")));
                 Ok(())
             }
@@ -515,15 +835,13 @@ impl Vm {
 
     fn roots(&self) -> Vec<Value> {
         let mut roots = Vec::new();
-        roots.extend(self.globals.values().cloned());
+        roots.extend(self.globals.iter().flatten().cloned());
         for frame in &self.frames {
             if let Some(h) = frame.closure {
                 roots.push(Value::Closure(h));
             }
-            for scope in &frame.scopes {
-                roots.extend(scope.values().cloned());
-            }
         }
+        // Locals live on the stack too.
         roots.extend(self.stack.iter().cloned());
         roots
     }
@@ -0,0 +1,122 @@
+use std::time::Instant;
+
+use moon_bytecode::compile;
+use moon_core::lexer::lex;
+use moon_core::parser::parse;
+use moon_runtime::{ErrorKind, HeapLimits, Limits, Value};
+use moon_typechecker::check_program;
+use moon_vm::{Vm, VmError};
+
+fn run_limited(src: &str, limits: Limits) -> Result<Value, VmError> {
+    let program = parse(lex(src).unwrap()).unwrap();
+    check_program(&program).unwrap();
+    let mut vm = Vm::new(compile(&program).unwrap());
+    vm.set_limits(limits);
+    vm.run()
+}
+
+const SPIN: &str = "fn spin(n: Int) -> Int { spin(n + 1) } spin(0)";
+
+#[test]
+fn running_out_of_fuel_stops_the_program() {
+    let limits = Limits {
+        fuel: Some(10_000),
+        ..Limits::default()
+    };
+    let err = run_limited(SPIN, limits).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::OutOfFuel);
+    assert_eq!(err.message, "out of fuel: ran 10000 steps");
+
+    assert_eq!(run_limited("1 + 2", limits).unwrap(), Value::Int(3));
+}
+
+#[test]
+fn a_passed_deadline_stops_the_program() {
+    let limits = Limits {
+        deadline: Some(Instant::now()),
+        ..Limits::default()
+    };
+    let err = run_limited(SPIN, limits).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::Deadline);
+    assert_eq!(err.message, "deadline exceeded");
+}
+
+#[test]
+fn growing_the_heap_past_its_limits_stops_the_program() {
+    let objects = Limits {
+        heap: HeapLimits {
+            max_objects: Some(50),
+            max_bytes: None,
+        },
+        ..Limits::default()
+    };
+    let src = "fn grow(n: Int) -> Int { let xs = [n]; if n == 0 { 0 } else { grow(n - 1) } }
+               grow(100)";
+    let err = run_limited(src, objects).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::HeapLimit);
+    assert_eq!(
+        err.message,
+        "heap limit exceeded: 51 objects (the limit is 50)"
+    );
+    assert_eq!(&src[err.span.start..err.span.end], "[n]");
+
+    // Garbage collected along the way leaves room.
+    let src = "fn grow(n: Int) -> Int { let xs = [n]; gc(); if n == 0 { 0 } else { grow(n - 1) } }
+               grow(100)";
+    assert_eq!(run_limited(src, objects).unwrap(), Value::Int(0));
+
+    let bytes = Limits {
+        heap: HeapLimits {
+            max_objects: None,
+            max_bytes: Some(1000),
+        },
+        ..Limits::default()
+    };
+    let src = "let s = \"0123456789\";
+               let t = s + s + s + s + s + s + s + s + s + s;
+               let u = t + t + t + t + t + t + t + t + t + t;
+               let small = [t];
+               let big = [u];
+               0";
+    let err = run_limited(src, bytes).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::HeapLimit);
+    assert_eq!(&src[err.span.start..err.span.end], "[u]");
+    assert!(
+        err.message.ends_with("bytes (the limit is 1000)"),
+        "{}",
+        err.message
+    );
+}
+
+#[test]
+fn a_string_over_the_byte_limit_is_not_built() {
+    let bytes = Limits {
+        heap: HeapLimits {
+            max_objects: None,
+            max_bytes: Some(1000),
+        },
+        ..Limits::default()
+    };
+    // Doubling a string never puts it in a heap object: only its length says it is too big.
+    let src = "fn f(s: String, n: Int) -> String { if n == 0 { s } else { f(s + s, n - 1) } }
+               f(\"ab\", 40)";
+    let err = run_limited(src, bytes).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::HeapLimit);
+    assert_eq!(
+        err.message,
+        "heap limit exceeded: a string of 1024 bytes (the limit is 1000)"
+    );
+    assert_eq!(&src[err.span.start..err.span.end], "s + s");
+}
+
+#[test]
+fn other_errors_have_their_own_kinds() {
+    assert_eq!(
+        run_limited("1 / 0", Limits::default()).unwrap_err().kind,
+        ErrorKind::Failed
+    );
+    let program = parse(lex("fn f(n: Int) -> Int { 1 + f(n) } f(0)").unwrap()).unwrap();
+    let mut vm = Vm::new(compile(&program).unwrap());
+    vm.set_max_depth(10);
+    assert_eq!(vm.run().unwrap_err().kind, ErrorKind::StackOverflow);
+}
@@ -0,0 +1,118 @@
+use moon_bytecode::moonc::{self, MAGIC, VERSION};
+use moon_bytecode::{compile, Module};
+use moon_core::lexer::lex;
+use moon_core::parser::parse;
+use moon_runtime::Value;
+use moon_typechecker::check_program;
+use moon_vm::run;
+
+const PROGRAM: &str = "
+    let greeting = \"hi\";
+    fn fib(n: Int) -> Int { if n < 2 { n } else { fib(n - 1) + fib(n - 2) } }
+    let o = #{ a: fib(10), b: 2 };
+    let xs = [1, 2, 3];
+    let add = { let k = o[\"a\"]; fn(x: Int) -> Int { x + k } };
+    add(xs[o[\"b\"]]) + if greeting == \"hi\" { 2 } else { 0 }";
+
+fn compile_src(src: &str) -> Module {
+    let tokens = lex(src).unwrap();
+    let program = parse(tokens).unwrap();
+    check_program(&program).unwrap();
+    compile(&program).unwrap()
+}
+
+fn moonc_bytes() -> Vec<u8> {
+    moonc::write(&compile_src(PROGRAM), "prog.moon", PROGRAM)
+}
+
+#[test]
+fn a_module_runs_the_same_after_a_round_trip() {
+    let expected = run(compile_src(PROGRAM)).unwrap();
+    assert_eq!(expected, Value::Int(60));
+
+    let compiled = moonc::read(&moonc_bytes()).unwrap();
+    assert_eq!(compiled.source_path, "prog.moon");
+    assert_eq!(compiled.source_hash, moonc::source_hash(PROGRAM));
+    assert_eq!(run(compiled.module).unwrap(), expected);
+}
+
+#[test]
+fn a_round_trip_keeps_code_spans_and_constants() {
+    let module = compile_src(PROGRAM);
+    let back = moonc::read(&moonc::write(&module, "prog.moon", PROGRAM))
+        .unwrap()
+        .module;
+
+    assert_eq!(back.main, module.main);
+    assert_eq!(back.globals, module.globals);
+    assert_eq!(back.constants, module.constants);
+    assert_eq!(back.by_name, module.by_name);
+    assert_eq!(back.functions.len(), module.functions.len());
+    for (a, b) in back.functions.iter().zip(&module.functions) {
+        assert_eq!(a.name, b.name);
+        assert_eq!(a.params, b.params);
+        assert_eq!(a.slots, b.slots);
+        assert_eq!(a.code, b.code);
+        assert_eq!(a.spans, b.spans);
+    }
+}
+
+#[test]
+fn the_source_hash_tells_a_stale_file() {
+    let compiled = moonc::read(&moonc_bytes()).unwrap();
+    assert_ne!(
+        compiled.source_hash,
+        moonc::source_hash(&format!("{PROGRAM}\n// edited"))
+    );
+}
+
+#[test]
+fn files_that_are_not_moonc_are_rejected() {
+    let err = moonc::read(b"fn main() {}").unwrap_err();
+    assert_eq!(err.message, "not a .moonc file");
+    assert_eq!(err.offset, 0);
+}
+
+#[test]
+fn other_versions_are_rejected() {
+    let mut bytes = moonc_bytes();
+    bytes[MAGIC.len()..MAGIC.len() + 2].copy_from_slice(&(VERSION + 1).to_le_bytes());
+    let err = moonc::read(&bytes).unwrap_err();
+    assert_eq!(
+        err.message,
+        format!(
+            "unsupported .moonc version {} (expected {VERSION})",
+            VERSION + 1
+        )
+    );
+}
+
+#[test]
+fn truncated_files_are_rejected_wherever_they_end() {
+    let bytes = moonc_bytes();
+    for len in 0..bytes.len() {
+        assert!(
+            moonc::read(&bytes[..len]).is_err(),
+            "accepted the first {len} bytes"
+        );
+    }
+}
+
+#[test]
+fn trailing_bytes_are_rejected() {
+    let mut bytes = moonc_bytes();
+    let len = bytes.len();
+    bytes.push(0);
+    let err = moonc::read(&bytes).unwrap_err();
+    assert_eq!(err.message, "trailing bytes");
+    assert_eq!(err.offset, len);
+}
+
+#[test]
+fn code_that_does_not_decode_is_rejected() {
+    let mut module = compile_src("1 + 2");
+    let main = module.main;
+    module.functions[main].code[0] = 0xff;
+    let err = moonc::read(&moonc::write(&module, "bad.moon", "")).unwrap_err();
+    assert_eq!(err.message, "in <main>: unknown opcode 255");
+}
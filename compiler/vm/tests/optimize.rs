@@ -0,0 +1,185 @@
+use moon_bytecode::{compile, optimize, verify, InstrKind, Module};
+use moon_core::lexer::lex;
+use moon_core::parser::parse;
+use moon_typechecker::check_program;
+use moon_vm::run;
+
+fn compile_src(src: &str) -> Module {
+    let program = parse(lex(src).unwrap()).unwrap();
+    check_program(&program).unwrap();
+    compile(&program).unwrap()
+}
+
+fn optimized(src: &str) -> Module {
+    let mut module = compile_src(src);
+    optimize(&mut module);
+    verify(&module).unwrap();
+    module
+}
+
+fn kinds(module: &Module, name: &str) -> Vec<InstrKind> {
+    let id = match name {
+        "<main>" => module.main,
+        _ => module.by_name[name],
+    };
+    module.functions[id]
+        .instrs()
+        .unwrap()
+        .into_iter()
+        .map(|i| i.kind)
+        .collect()
+}
+
+const PROGRAMS: &[&str] = &[
+    "1 + 2 * 3 - 4 / 2 % 3",
+    "-(2 * 3) + 10",
+    "\"moon\" + \"light\" == \"moonlight\"",
+    "!(1 < 2) || 3 >= 3 && 4 != 5",
+    "if true { 1 } else { 2 }",
+    "if false { 1 } else if 2 > 3 { 2 } else { 3 }",
+    "let x = 10; if x > 5 && true { x } else { 0 }",
+    "let x = 1; { let y = 2 + 3; x + y }",
+    "fn fib(n: Int) -> Int { if n < 2 { n } else { fib(n - 1) + fib(n - 2) } }
+     fib(15)",
+    "let k = 2 * 5;
+     let add = fn(x: Int) -> Int { x + k + 0 * 7 };
+     add(1 + 1)",
+    "let c = { let n = 40; fn() -> Int { n = n + 1; n } };
+     c(); c()",
+    "let a = [1 + 1, 2 * 2, 3 - 3];
+     a[1 + 0] = 5;
+     a[0] + a[1] + a[2]",
+    "let o = #{ a: 1, b: 2 };
+     o[\"a\" + \"\"] = 10;
+     o[\"a\"] + o[\"b\"]",
+    "fn f(x: Int) -> Bool { if true { x > 0 } else { false } }
+     if f(3) { \"yes\" } else { \"no\" }",
+    "let unused = fn() -> Int { 1 + 1 }; gc(); 7",
+    // Errors must stay the same, at the same place.
+    "let a = 1; a / (2 - 2)",
+    "10 % 0",
+    "let a = [1, 2]; a[1 + 5]",
+];
+
+#[test]
+fn optimized_code_gives_the_same_results() {
+    for src in PROGRAMS {
+        let plain = run(compile_src(src));
+        let fast = run(optimized(src));
+        match (plain, fast) {
+            (Ok(a), Ok(b)) => assert_eq!(a, b, "{src}"),
+            (Err(a), Err(b)) => {
+                assert_eq!(a.message, b.message, "{src}");
+                assert_eq!(a.span, b.span, "{src}");
+            }
+            (a, b) => panic!("{src}: {a:?} vs {b:?}"),
+        }
+    }
+}
+
+#[test]
+fn optimized_code_is_smaller() {
+    for src in PROGRAMS {
+        let size = |m: &Module| m.functions.iter().map(|f| f.code.len()).sum::<usize>();
+        assert!(size(&optimized(src)) <= size(&compile_src(src)), "{src}");
+    }
+}
+
+#[test]
+fn constant_expressions_fold_to_one_push() {
+    let module = optimized("1 + 2 * 3");
+    assert_eq!(
+        kinds(&module, "<main>"),
+        [InstrKind::Const(0), InstrKind::Return]
+    );
+    assert_eq!(module.constants, [moon_runtime::Value::Int(7)]);
+
+    let module = optimized("\"a\" + \"b\" == \"ab\" && !false");
+    assert_eq!(
+        kinds(&module, "<main>"),
+        [InstrKind::True, InstrKind::Return]
+    );
+}
+
+#[test]
+fn failing_operations_are_not_folded() {
+    let module = optimized("1 / 0");
+    assert!(kinds(&module, "<main>").contains(&InstrKind::Div));
+}
+
+#[test]
+fn constant_conditions_drop_the_branch_not_taken() {
+    let module = optimized("fn f(x: Int) -> Int { if true { x } else { x + 100 } } f(1)");
+    assert_eq!(
+        kinds(&module, "f"),
+        [InstrKind::LoadLocal(0), InstrKind::Return]
+    );
+
+    let module = optimized("fn f(x: Int) -> Int { if false { x } else { x + 100 } } f(1)");
+    let f = kinds(&module, "f");
+    assert!(!f.iter().any(|k| matches!(
+        k,
+        InstrKind::Jump(_) | InstrKind::JumpIfFalse(_) | InstrKind::Pop
+    )));
+}
+
+#[test]
+fn jumps_to_jumps_are_threaded() {
+    // The inner `if` ends with a jump to the end of the outer one, which returns.
+    let module = optimized(
+        "fn f(a: Bool, b: Bool) -> Int { if a { if b { 1 } else { 2 } } else { 3 } }
+         f(true, false)",
+    );
+    let f = kinds(&module, "f");
+    let returns = f.iter().filter(|k| **k == InstrKind::Return).count();
+    assert!(returns > 1, "{f:?}");
+    for kind in &f {
+        if let InstrKind::Jump(dst) = kind {
+            let (target, _) =
+                InstrKind::decode(&module.functions[module.by_name["f"]].code, *dst as usize)
+                    .unwrap();
+            assert!(!matches!(target, InstrKind::Jump(_)), "{f:?}");
+        }
+    }
+}
+
+#[test]
+fn unused_functions_and_constants_are_dropped() {
+    let module = optimized("fn unused(x: Int) -> Int { x * 1000 } 1 + 1");
+    assert!(!module.by_name.contains_key("unused"));
+    assert_eq!(module.functions.len(), 1);
+    assert_eq!(module.constants, [moon_runtime::Value::Int(2)]);
+}
+
+#[test]
+fn optimizing_twice_changes_nothing() {
+    for src in PROGRAMS {
+        let once = optimized(src);
+        let mut twice = once.clone();
+        optimize(&mut twice);
+        for (a, b) in once.functions.iter().zip(&twice.functions) {
+            assert_eq!(a.code, b.code, "{src}");
+        }
+    }
+}
+
+#[test]
+fn malformed_modules_are_left_for_verify() {
+    let mut bad_call = compile_src("fn f() -> Int { 1 } f()");
+    let mut code = Vec::new();
+    InstrKind::Call(9, 0).encode(&mut code);
+    InstrKind::Return.encode(&mut code);
+    bad_call.functions[bad_call.main].code = code;
+
+    let mut bad_const = compile_src("1");
+    let mut code = Vec::new();
+    InstrKind::Const(5).encode(&mut code);
+    InstrKind::Return.encode(&mut code);
+    bad_const.functions[bad_const.main].code = code;
+
+    for mut module in [bad_call, bad_const] {
+        let err = verify(&module).unwrap_err();
+        optimize(&mut module);
+        assert_eq!(verify(&module).unwrap_err(), err);
+    }
+}
@@ -0,0 +1,77 @@
+use std::thread;
+use std::time::Duration;
+
+use moon_bytecode::compile;
+use moon_core::lexer::lex;
+use moon_core::parser::parse;
+use moon_runtime::{ErrorKind, Value};
+use moon_typechecker::check_program;
+use moon_vm::{Status, Vm};
+
+fn vm_for(src: &str) -> Vm {
+    let program = parse(lex(src).unwrap()).unwrap();
+    check_program(&program).unwrap();
+    Vm::new(compile(&program).unwrap())
+}
+
+const SUM: &str =
+    "fn sum(n: Int, acc: Int) -> Int { if n == 0 { acc } else { sum(n - 1, acc + n) } }
+sum(100, 0)";
+
+#[test]
+fn stepping_yields_until_main_returns() {
+    let mut vm = vm_for(SUM);
+    let mut slices = 0;
+    let result = loop {
+        match vm.step(10).unwrap() {
+            Status::Yielded => slices += 1,
+            Status::Finished(v) => break v,
+        }
+    };
+    assert_eq!(result, Value::Int(5050));
+    assert!(slices > 10);
+    assert_eq!(vm_for(SUM).run().unwrap(), Value::Int(5050));
+
+    let err = vm.step(10).unwrap_err();
+    assert_eq!(err.message, "the program already stopped");
+}
+
+#[test]
+fn resume_runs_to_the_end_after_a_step() {
+    let mut vm = vm_for(SUM);
+    assert_eq!(vm.step(5).unwrap(), Status::Yielded);
+    assert_eq!(vm.resume().unwrap(), Value::Int(5050));
+}
+
+#[test]
+fn an_error_stops_the_program() {
+    let mut vm = vm_for("let a = [1]; a[3]");
+    assert_eq!(vm.step(100).unwrap_err().message, "index out of bounds: 3");
+    assert_eq!(
+        vm.resume().unwrap_err().message,
+        "the program already stopped"
+    );
+}
+
+#[test]
+fn another_thread_can_interrupt_a_runaway_script() {
+    let mut vm = vm_for("fn spin(n: Int) -> Int { spin(n + 1) } spin(0)");
+    let handle = vm.interrupt_handle();
+    let stopper = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(20));
+        handle.interrupt();
+    });
+    let err = vm.resume().unwrap_err();
+    stopper.join().unwrap();
+    assert_eq!(err.kind, ErrorKind::Interrupted);
+    assert_eq!(err.message, "interrupted");
+    assert_eq!(err.trace[0].function, "spin");
+}
+
+#[test]
+fn an_interrupt_between_steps_stops_the_next_one() {
+    let mut vm = vm_for(SUM);
+    assert_eq!(vm.step(5).unwrap(), Status::Yielded);
+    vm.interrupt_handle().interrupt();
+    assert_eq!(vm.step(5).unwrap_err().kind, ErrorKind::Interrupted);
+}
@@ -7,7 +7,7 @@ use moon_vm::run;
 
 fn run_vm(src: &str) -> moon_runtime::Value {
     let source = Source::new("<test>", src.to_string());
-    let tokens = lex(&source.text).unwrap();
+    let tokens = lex(source.text()).unwrap();
     let program = parse(tokens).unwrap();
     check_program(&program).unwrap();
     let module = compile(&program).unwrap();
@@ -76,6 +76,70 @@ fn closures_can_mutate_captured_state() {
     assert_eq!(v, moon_runtime::Value::Int(3));
 }
 
+#[test]
+fn closures_share_a_captured_variable() {
+    let v = run_vm(
+        "{
+             let n = 0;
+             let inc = fn() -> Int { n = n + 1; n };
+             let get = fn() -> Int { n };
+             inc();
+             inc();
+             get() * 10 + n
+         }",
+    );
+    assert_eq!(v, moon_runtime::Value::Int(22));
+}
+
+#[test]
+fn assignments_after_capture_are_seen_by_the_closure() {
+    let v = run_vm("{ let x = 1; let f = fn() -> Int { x }; x = 5; f() }");
+    assert_eq!(v, moon_runtime::Value::Int(5));
+}
+
+#[test]
+fn closures_keep_sharing_after_their_scope_ends() {
+    let v = run_vm(
+        "let fs = {
+             let n = 0;
+             [fn() -> Int { n = n + 1; n }, fn() -> Int { n * 100 }]
+         };
+         fs[0]();
+         fs[0]();
+         fs[1]()",
+    );
+    assert_eq!(v, moon_runtime::Value::Int(200));
+}
+
+#[test]
+fn a_closed_variable_is_not_affected_by_slot_reuse() {
+    let v = run_vm(
+        "{
+             let f = { let x = 1; fn() -> Int { x } };
+             let y = 50;
+             f() + y
+         }",
+    );
+    assert_eq!(v, moon_runtime::Value::Int(51));
+}
+
+#[test]
+fn captured_state_survives_gc() {
+    let v = run_vm(
+        "fn collect() -> Int { gc(); 0 }
+         let c = { let n = 40; fn() -> Int { n = n + 1; n } };
+         gc();
+         c();
+         {
+             let x = 1;
+             let f = fn() -> Int { x };
+             collect();
+             c() + x + f()
+         }",
+    );
+    assert_eq!(v, moon_runtime::Value::Int(44));
+}
+
 #[test]
 fn arrays_objects_and_assignment() {
     let v = run_vm(
@@ -107,3 +171,315 @@ fn function_can_be_implemented_with_only_return() {
     let v = run_vm("fn f() -> Int { return 1; } f()");
     assert_eq!(v, moon_runtime::Value::Int(1));
 }
+
+fn compile_src(src: &str) -> moon_bytecode::Module {
+    let program = parse(lex(src).unwrap()).unwrap();
+    check_program(&program).unwrap();
+    compile(&program).unwrap()
+}
+
+fn instr_texts(func: &moon_bytecode::Function) -> Vec<String> {
+    func.instrs()
+        .unwrap()
+        .iter()
+        .map(|i| i.kind.to_string())
+        .collect()
+}
+
+#[test]
+fn variables_are_resolved_to_slots_at_compile_time() {
+    let module = compile_src(
+        "let g = 1;
+         fn f(a: Int) -> Int { let b = a + g; { let c = b; c } + { let d = 2; d } }
+         f(g)",
+    );
+    let f = &module.functions[module.by_name["f"]];
+    // `a` and `b`, then `c` and `d` reusing the same slot.
+    assert_eq!(f.slots, 3);
+    assert_eq!(module.globals, ["g"]);
+
+    let listing: Vec<String> = module.functions.iter().flat_map(instr_texts).collect();
+    assert!(listing.contains(&"LoadLocal 0".to_string()));
+    assert!(listing.contains(&"StoreLocal 2".to_string()));
+    assert!(listing.contains(&"LoadGlobal 0".to_string()));
+    // A name that can only be the function is called directly.
+    assert!(listing.contains(&format!("Call f{} argc=1", module.by_name["f"])));
+}
+
+#[test]
+fn nested_closures_capture_through_the_enclosing_closure() {
+    let v = run_vm(
+        "let f = {
+             let x = 1;
+             let y = 10;
+             fn(z: Int) -> Int { let g = fn() -> Int { x + z }; g() + y }
+         };
+         f(100)",
+    );
+    assert_eq!(v, moon_runtime::Value::Int(111));
+}
+
+#[test]
+fn a_global_shadows_a_function_only_once_defined() {
+    let v = run_vm(
+        "fn f() -> Int { 1 }
+         let a = f();
+         let f = 5;
+         a + f",
+    );
+    assert_eq!(v, moon_runtime::Value::Int(6));
+}
+
+#[test]
+fn captured_locals_are_closed_when_their_scope_ends() {
+    let module = compile_src(
+        "{
+             let a = 1;
+             let f = { let x = 2; fn() -> Int { x = x + a; x } };
+             { let y = 3; y } + f()
+         }",
+    );
+    let listing = instr_texts(&module.functions[module.main]);
+    let closure = module.by_name["<lambda#0>"];
+    assert!(listing.contains(&format!(
+        "MakeClosure f{closure} captures=[local 1, local 0]"
+    )));
+    // The block of `x` closes from its slot on; the one of `y` captures nothing.
+    assert_eq!(
+        listing
+            .iter()
+            .filter(|l| l.starts_with("CloseUpvalues"))
+            .collect::<Vec<_>>(),
+        ["CloseUpvalues 1", "CloseUpvalues 0"]
+    );
+
+    let lambda = instr_texts(&module.functions[closure]);
+    assert!(lambda.contains(&"LoadUpvalue 1".to_string()));
+    assert!(lambda.contains(&"StoreUpvalue 0".to_string()));
+}
+
+#[test]
+fn instructions_round_trip_through_the_byte_encoding() {
+    use moon_bytecode::{Capture, InstrKind};
+
+    let kinds = vec![
+        InstrKind::Const(70_000),
+        InstrKind::True,
+        InstrKind::LoadLocal(513),
+        InstrKind::CloseUpvalues(2),
+        InstrKind::StoreGlobal(u32::MAX),
+        InstrKind::JumpIfFalse(12),
+        InstrKind::Call(3, 2),
+        InstrKind::MakeClosure(4, vec![Capture::Local(1), Capture::Upvalue(0)]),
+        InstrKind::MakeObject(1),
+        InstrKind::Return,
+    ];
+    let mut code = Vec::new();
+    for kind in &kinds {
+        kind.encode(&mut code);
+    }
+
+    let mut decoded = Vec::new();
+    let mut at = 0;
+    while at < code.len() {
+        let (kind, next) = InstrKind::decode(&code, at).unwrap();
+        decoded.push(kind);
+        at = next;
+    }
+    assert_eq!(decoded, kinds);
+
+    // A cut operand and an unknown opcode are errors, not panics.
+    let err = InstrKind::decode(&code[..3], 0).unwrap_err();
+    assert_eq!(err.message, "truncated instruction");
+    let err = InstrKind::decode(&[255], 0).unwrap_err();
+    assert_eq!(err.message, "unknown opcode 255");
+}
+
+#[test]
+fn literals_live_in_a_deduplicated_constant_pool() {
+    use moon_runtime::Value;
+
+    let module = compile_src(
+        "let s = \"a\" + \"a\";
+         let o = #{a: 1};
+         let t = true;
+         [1, 1, 2][0] + o[\"a\"]",
+    );
+    assert_eq!(
+        module.constants,
+        [
+            Value::String("a".to_string()),
+            Value::Int(1),
+            Value::Int(2),
+            Value::Int(0)
+        ]
+    );
+
+    let main = instr_texts(&module.functions[module.main]);
+    assert!(main.contains(&"True".to_string()));
+    assert!(main.contains(&"MakeObject 1".to_string()));
+    let pushes = main.iter().filter(|l| l.starts_with("Const")).count();
+    assert_eq!(pushes, 9);
+}
+
+#[test]
+fn runtime_errors_point_at_the_failing_instruction() {
+    let src = "let a = 1;\nlet b = { let c = 2; a + c };\nlet d = b / (a - 1);\nd";
+    let program = parse(lex(src).unwrap()).unwrap();
+    let module = compile(&program).unwrap();
+
+    // Runs of instructions from one expression share a span table entry.
+    let main = &module.functions[module.main];
+    assert!(main.spans.len() < main.instrs().unwrap().len());
+
+    let err = run(module).unwrap_err();
+    assert_eq!(err.message, "division by zero");
+    assert_eq!(&src[err.span.start..err.span.end], "b / (a - 1)");
+}
+
+#[test]
+fn invalid_bytecode_is_rejected_before_running() {
+    use moon_bytecode::InstrKind;
+
+    let src = "let g = 1;\nfn f(x: Int) -> Int { x }\nf(g)";
+    let program = parse(lex(src).unwrap()).unwrap();
+    let mut module = compile(&program).unwrap();
+    moon_bytecode::verify(&module).unwrap();
+
+    // Call `f` with no arguments.
+    let f = module.by_name["f"];
+    let main = &mut module.functions[module.main];
+    let mut code = Vec::new();
+    InstrKind::Call(f as u32, 0).encode(&mut code);
+    InstrKind::Return.encode(&mut code);
+    main.code = code;
+    main.spans.clear();
+
+    let err = run(module).unwrap_err();
+    assert_eq!(
+        err.message,
+        "invalid bytecode: in <main> at 0: f expects 1 arguments, the call passes 0"
+    );
+}
+
+#[test]
+fn tail_calls_do_not_grow_the_stack() {
+    let src =
+        "fn count(n: Int, acc: Int) -> Int { if n == 0 { acc } else { count(n - 1, acc + 1) } }
+         count(1000000, 0)";
+    assert_eq!(run_vm(src), moon_runtime::Value::Int(1000000));
+
+    let program = parse(lex(src).unwrap()).unwrap();
+    let module = compile(&program).unwrap();
+    let count = module.functions[module.by_name["count"]].instrs().unwrap();
+    assert!(count
+        .iter()
+        .any(|i| matches!(i.kind, moon_bytecode::InstrKind::TailCall(..))));
+}
+
+#[test]
+fn mutual_recursion_through_return_is_a_tail_call() {
+    let v = run_vm(
+        "fn even(n: Int) -> Bool { if n == 0 { return true; } else { 0 }; odd(n - 1) }
+         fn odd(n: Int) -> Bool { if n == 0 { false } else { even(n - 1) } }
+         even(1000001)",
+    );
+    assert_eq!(v, moon_runtime::Value::Bool(false));
+}
+
+#[test]
+fn tail_calls_through_values_do_not_grow_the_stack() {
+    let v = run_vm(
+        "fn step(n: Int, next: Int) -> Int { if n == 0 { next } else { loop(n - 1, next + 1) } }
+         fn loop(n: Int, acc: Int) -> Int { let f = step; f(n, acc) }
+         loop(1000000, 0)",
+    );
+    assert_eq!(v, moon_runtime::Value::Int(1000000));
+}
+
+#[test]
+fn a_tail_call_closes_the_upvalues_of_the_frame_it_replaces() {
+    let v = run_vm(
+        "fn f(n: Int, acc: Int) -> Int {
+             let x = n;
+             let g = fn() -> Int { x };
+             if n == 0 { acc } else { f(n - 1, acc + g()) }
+         }
+         f(1000, 0)",
+    );
+    assert_eq!(v, moon_runtime::Value::Int(500500));
+}
+
+const DEEP: &str = "fn down(n: Int) -> Int { if n == 0 { 0 } else { 1 + down(n - 1) } }
+fn start(n: Int) -> Int { down(n) + 0 }
+start(100)";
+
+#[test]
+fn too_many_nested_calls_are_a_stack_overflow_error() {
+    let module = compile(&parse(lex(DEEP).unwrap()).unwrap()).unwrap();
+    let mut vm = moon_vm::Vm::new(module.clone());
+    vm.set_max_depth(20);
+    let err = vm.run().unwrap_err();
+    assert_eq!(err.message, "stack overflow: more than 20 nested calls");
+    assert_eq!(&DEEP[err.span.start..err.span.end], "down(n - 1)");
+
+    // Innermost first: 19 recursive calls, then `start`'s call, then main's.
+    assert_eq!(err.trace.len(), 20);
+    assert!(err.trace[..18]
+        .iter()
+        .all(|f| f.function == "down" && f.call_span == err.span));
+    assert_eq!(err.trace[18].function, "down");
+    let start_call = err.trace[18].call_span;
+    assert_eq!(&DEEP[start_call.start..start_call.end], "down(n)");
+    assert_eq!(err.trace[19].function, "start");
+    let main_call = err.trace[19].call_span;
+    assert_eq!(&DEEP[main_call.start..main_call.end], "start(100)");
+
+    let mut vm = moon_vm::Vm::new(module);
+    vm.set_max_depth(102);
+    assert_eq!(vm.run().unwrap(), moon_runtime::Value::Int(100));
+}
+
+#[test]
+fn tail_calls_do_not_count_towards_the_depth() {
+    let src =
+        "fn count(n: Int, acc: Int) -> Int { if n == 0 { acc } else { count(n - 1, acc + 1) } }
+         count(5000, 0)";
+    let mut vm = moon_vm::Vm::new(compile(&parse(lex(src).unwrap()).unwrap()).unwrap());
+    vm.set_max_depth(1);
+    assert_eq!(vm.run().unwrap(), moon_runtime::Value::Int(5000));
+}
+
+const NESTED: &str = "fn get(xs: Array<Int>, i: Int) -> Int { xs[i] }
+fn second(xs: Array<Int>) -> Int { get(xs, 1) + 0 }
+fn last(xs: Array<Int>) -> Int { second(xs) }
+let pick = fn(xs: Array<Int>) -> Int { last(xs) * 2 };
+pick([1])";
+
+#[test]
+fn runtime_errors_carry_the_calls_that_led_to_them() {
+    let err = run(compile(&parse(lex(NESTED).unwrap()).unwrap()).unwrap()).unwrap_err();
+    assert_eq!(err.message, "index out of bounds: 1");
+    let trace: Vec<_> = err
+        .trace
+        .iter()
+        .map(|f| {
+            (
+                f.function.as_str(),
+                &NESTED[f.call_span.start..f.call_span.end],
+            )
+        })
+        .collect();
+    // `last` called `second` as a tail call: its frame is gone.
+    assert_eq!(
+        trace,
+        [
+            ("get", "get(xs, 1)"),
+            ("second", "second(xs)"),
+            ("<lambda#0>", "pick([1])"),
+        ]
+    );
+
+    let err = run(compile(&parse(lex("[1][5]").unwrap()).unwrap()).unwrap()).unwrap_err();
+    assert!(err.trace.is_empty());
+}
@@ -1,13 +1,25 @@
+mod repl;
+
 use std::env;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use moon_bytecode::compile;
-use moon_core::lexer::lex;
+use moon_bytecode::{compile, constant_text, moonc, Module};
+use moon_core::lexer::lex_file;
 use moon_core::parser::parse;
-use moon_core::source::Source;
-use moon_interpreter::{eval_program, Value};
+use moon_core::source::{Source, SourceMap};
+use moon_core::span::{FileId, Span};
+use moon_formatter::format_source;
+use moon_interpreter::{eval_program_in, Env, Value};
 use moon_typechecker::check_program;
-use moon_vm::run as run_vm;
+use moon_vm::Vm;
+
+/// Rust stack the interpreter may need per Moon call (a call is several nested `eval_*`
+/// frames, far bigger without optimizations).
+const STACK_PER_CALL: usize = if cfg!(debug_assertions) {
+    128 << 10
+} else {
+    32 << 10
+};
 
 fn main() {
     let mut args = env::args().skip(1);
@@ -15,15 +27,22 @@ fn main() {
 
     match cmd.as_deref() {
         Some("run") => {
-            let path = match args.next() {
-                Some(p) => p,
-                None => {
-                    eprintln!("missing <file> for `moon run`.\n");
-                    print_help();
-                    std::process::exit(2);
+            let mut path = None;
+            let mut max_depth = moon_interpreter::DEFAULT_MAX_DEPTH;
+            let mut traces = true;
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--max-depth" => max_depth = max_depth_arg(args.next()),
+                    "--no-trace" => traces = false,
+                    _ => path = Some(arg),
                 }
+            }
+            let Some(path) = path else {
+                eprintln!("missing <file> for `moon run`.\n");
+                print_help();
+                std::process::exit(2);
             };
-            if let Err(code) = cmd_run(path) {
+            if let Err(code) = with_stack_for(max_depth, || cmd_run(path, max_depth, traces)) {
                 std::process::exit(code);
             }
         }
@@ -54,28 +73,105 @@ fn main() {
             }
         }
         Some("vm") => {
-            let path = match args.next() {
-                Some(p) => p,
-                None => {
-                    eprintln!("missing <file> for `moon vm`.\n");
-                    print_help();
-                    std::process::exit(2);
+            let mut path = None;
+            let mut optimize = false;
+            let mut max_depth = moon_vm::DEFAULT_MAX_DEPTH;
+            let mut traces = true;
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "-O" => optimize = true,
+                    "--max-depth" => max_depth = max_depth_arg(args.next()),
+                    "--no-trace" => traces = false,
+                    _ => path = Some(arg),
                 }
+            }
+            let Some(path) = path else {
+                eprintln!("missing <file> for `moon vm`.\n");
+                print_help();
+                std::process::exit(2);
             };
-            if let Err(code) = cmd_vm(path) {
+            if let Err(code) = cmd_vm(path, optimize, max_depth, traces) {
+                std::process::exit(code);
+            }
+        }
+        Some("build") => {
+            let mut path = None;
+            let mut out = None;
+            let mut optimize = false;
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "-O" => optimize = true,
+                    "-o" => match args.next() {
+                        Some(o) => out = Some(o),
+                        None => {
+                            eprintln!("missing <out> after -o.\n");
+                            print_help();
+                            std::process::exit(2);
+                        }
+                    },
+                    _ => path = Some(arg),
+                }
+            }
+            let Some(path) = path else {
+                eprintln!("missing <file> for `moon build`.\n");
+                print_help();
+                std::process::exit(2);
+            };
+            if let Err(code) = cmd_build(path, out, optimize) {
                 std::process::exit(code);
             }
         }
         Some("disasm") => {
-            let path = match args.next() {
-                Some(p) => p,
-                None => {
-                    eprintln!("missing <file> for `moon disasm`.\n");
-                    print_help();
-                    std::process::exit(2);
+            let mut path = None;
+            let mut optimize = false;
+            for arg in args {
+                match arg.as_str() {
+                    "-O" => optimize = true,
+                    _ => path = Some(arg),
                 }
+            }
+            let Some(path) = path else {
+                eprintln!("missing <file> for `moon disasm`.\n");
+                print_help();
+                std::process::exit(2);
             };
-            if let Err(code) = cmd_disasm(path) {
+            if let Err(code) = cmd_disasm(path, optimize) {
+                std::process::exit(code);
+            }
+        }
+        Some("fmt") => {
+            let mut check = false;
+            let mut paths = Vec::new();
+            for arg in args {
+                match arg.as_str() {
+                    "--check" => check = true,
+                    _ => paths.push(arg),
+                }
+            }
+            if paths.is_empty() {
+                eprintln!("missing <file> for `moon fmt`.\n");
+                print_help();
+                std::process::exit(2);
+            }
+            if let Err(code) = cmd_fmt(paths, check) {
+                std::process::exit(code);
+            }
+        }
+        Some("repl") => {
+            let mut max_depth = moon_interpreter::DEFAULT_MAX_DEPTH;
+            let mut traces = true;
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--max-depth" => max_depth = max_depth_arg(args.next()),
+                    "--no-trace" => traces = false,
+                    other => {
+                        eprintln!("unexpected argument for `moon repl`: {other}\n");
+                        print_help();
+                        std::process::exit(2);
+                    }
+                }
+            }
+            if let Err(code) = with_stack_for(max_depth, || repl::run(max_depth, traces)) {
                 std::process::exit(code);
             }
         }
@@ -90,16 +186,47 @@ fn main() {
     }
 }
 
-fn cmd_run(path: String) -> Result<(), i32> {
-    let source = load_source(&path).map_err(|e| {
+/// The value after `--max-depth`: a number of calls.
+fn max_depth_arg(arg: Option<String>) -> usize {
+    match arg.as_deref().map(str::parse) {
+        Some(Ok(depth)) => depth,
+        Some(Err(_)) | None => {
+            eprintln!("--max-depth needs a number of calls.\n");
+            print_help();
+            std::process::exit(2);
+        }
+    }
+}
+
+/// Runs `f` on a thread with enough stack for the interpreter to make `max_depth` nested
+/// calls, so that going deeper is a `stack overflow` error rather than a crash.
+fn with_stack_for(max_depth: usize, f: impl FnOnce() -> Result<(), i32> + Send) -> Result<(), i32> {
+    let size = STACK_PER_CALL.saturating_mul(max_depth).max(8 << 20);
+    std::thread::scope(|scope| {
+        let thread = std::thread::Builder::new()
+            .stack_size(size)
+            .spawn_scoped(scope, f)
+            .map_err(|e| {
+                eprintln!("cannot get {size} bytes of stack for --max-depth {max_depth}: {e}");
+                1
+            })?;
+        thread
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    })
+}
+
+fn cmd_run(path: String, max_depth: usize, traces: bool) -> Result<(), i32> {
+    let (sources, file) = load_source(&path).map_err(|e| {
         eprintln!("io error: {e}");
         1
     })?;
+    let source = &sources[file];
 
-    let tokens = lex(&source.text).map_err(|e| {
+    let tokens = lex_file(source.text(), file).map_err(|e| {
         eprintln!(
             "{}",
-            source.render_span(e.span, &format!("lex error: {}", e.message))
+            sources.render_span(e.span, &format!("lex error: {}", e.message))
         );
         1
     })?;
@@ -107,7 +234,7 @@ fn cmd_run(path: String) -> Result<(), i32> {
     let program = parse(tokens).map_err(|e| {
         eprintln!(
             "{}",
-            source.render_span(e.span, &format!("parse error: {}", e.message))
+            sources.render_span(e.span, &format!("parse error: {}", e.message))
         );
         1
     })?;
@@ -115,16 +242,22 @@ fn cmd_run(path: String) -> Result<(), i32> {
     let _ = check_program(&program).map_err(|e| {
         eprintln!(
             "{}",
-            source.render_span(e.span, &format!("type error: {}", e.message))
+            sources.render_span(e.span, &format!("type error: {}", e.message))
         );
         1
     })?;
 
-    let value = eval_program(&program).map_err(|e| {
+    let mut env = Env::new();
+    env.set_max_depth(max_depth);
+    let value = eval_program_in(&program, &mut env).map_err(|e| {
         eprintln!(
             "{}",
-            source.render_span(e.span, &format!("runtime error: {}", e.message))
+            sources.render_span(e.span, &format!("runtime error: {}", e.message))
         );
+        if traces {
+            let trace = e.trace.iter().map(|f| (f.function.as_str(), f.call_span));
+            eprint!("{}", render_trace(&sources, &path, trace));
+        }
         1
     })?;
 
@@ -136,15 +269,16 @@ fn cmd_run(path: String) -> Result<(), i32> {
 }
 
 fn cmd_ast(path: String) -> Result<(), i32> {
-    let source = load_source(&path).map_err(|e| {
+    let (sources, file) = load_source(&path).map_err(|e| {
         eprintln!("io error: {e}");
         1
     })?;
+    let source = &sources[file];
 
-    let tokens = lex(&source.text).map_err(|e| {
+    let tokens = lex_file(source.text(), file).map_err(|e| {
         eprintln!(
             "{}",
-            source.render_span(e.span, &format!("lex error: {}", e.message))
+            sources.render_span(e.span, &format!("lex error: {}", e.message))
         );
         1
     })?;
@@ -152,7 +286,7 @@ fn cmd_ast(path: String) -> Result<(), i32> {
     let program = parse(tokens).map_err(|e| {
         eprintln!(
             "{}",
-            source.render_span(e.span, &format!("parse error: {}", e.message))
+            sources.render_span(e.span, &format!("parse error: {}", e.message))
         );
         1
     })?;
@@ -162,15 +296,16 @@ fn cmd_ast(path: String) -> Result<(), i32> {
 }
 
 fn cmd_check(path: String) -> Result<(), i32> {
-    let source = load_source(&path).map_err(|e| {
+    let (sources, file) = load_source(&path).map_err(|e| {
         eprintln!("io error: {e}");
         1
     })?;
+    let source = &sources[file];
 
-    let tokens = lex(&source.text).map_err(|e| {
+    let tokens = lex_file(source.text(), file).map_err(|e| {
         eprintln!(
             "{}",
-            source.render_span(e.span, &format!("lex error: {}", e.message))
+            sources.render_span(e.span, &format!("lex error: {}", e.message))
         );
         1
     })?;
@@ -178,7 +313,7 @@ fn cmd_check(path: String) -> Result<(), i32> {
     let program = parse(tokens).map_err(|e| {
         eprintln!(
             "{}",
-            source.render_span(e.span, &format!("parse error: {}", e.message))
+            sources.render_span(e.span, &format!("parse error: {}", e.message))
         );
         1
     })?;
@@ -186,7 +321,7 @@ fn cmd_check(path: String) -> Result<(), i32> {
     let ty = check_program(&program).map_err(|e| {
         eprintln!(
             "{}",
-            source.render_span(e.span, &format!("type error: {}", e.message))
+            sources.render_span(e.span, &format!("type error: {}", e.message))
         );
         1
     })?;
@@ -195,69 +330,91 @@ fn cmd_check(path: String) -> Result<(), i32> {
     Ok(())
 }
 
-fn cmd_vm(path: String) -> Result<(), i32> {
-    let source = load_source(&path).map_err(|e| {
-        eprintln!("io error: {e}");
+fn cmd_vm(path: String, optimize: bool, max_depth: usize, traces: bool) -> Result<(), i32> {
+    let (module, sources, source_path) = load_module(path, optimize)?;
+
+    let mut vm = Vm::new(module);
+    vm.set_max_depth(max_depth);
+    let value = vm.run().map_err(|e| {
+        let message = format!("vm error: {}", e.message);
+        eprintln!("{}", render_span(&sources, &source_path, e.span, &message));
+        if traces {
+            let trace = e.trace.iter().map(|f| (f.function.as_str(), f.call_span));
+            eprint!("{}", render_trace(&sources, &source_path, trace));
+        }
         1
     })?;
 
-    let tokens = lex(&source.text).map_err(|e| {
-        eprintln!(
-            "{}",
-            source.render_span(e.span, &format!("lex error: {}", e.message))
-        );
-        1
-    })?;
+    if value != Value::Unit {
+        println!("{value}");
+    }
 
-    let program = parse(tokens).map_err(|e| {
-        eprintln!(
-            "{}",
-            source.render_span(e.span, &format!("parse error: {}", e.message))
-        );
-        1
-    })?;
+    Ok(())
+}
 
-    let _ = check_program(&program).map_err(|e| {
-        eprintln!(
-            "{}",
-            source.render_span(e.span, &format!("type error: {}", e.message))
-        );
-        1
-    })?;
+fn cmd_disasm(path: String, optimize: bool) -> Result<(), i32> {
+    let (module, sources, _) = load_module(path, optimize)?;
 
-    let module = compile(&program).map_err(|e| {
-        eprintln!(
-            "{}",
-            source.render_span(e.span, &format!("compile error: {}", e.message))
-        );
-        1
-    })?;
+    print!("{}", disasm_listing(&module, &sources));
+    Ok(())
+}
 
-    let value = run_vm(module).map_err(|e| {
-        eprintln!(
-            "{}",
-            source.render_span(e.span, &format!("vm error: {}", e.message))
-        );
+/// Compiles a source file to a `.moonc` file: `out`, or the source path with that extension.
+fn cmd_build(path: String, out: Option<String>, optimize: bool) -> Result<(), i32> {
+    let out = match out {
+        Some(out) => PathBuf::from(out),
+        None if path == "-" => {
+            eprintln!("`moon build -` needs -o <out>");
+            return Err(2);
+        }
+        None => Path::new(&path).with_extension("moonc"),
+    };
+    let (sources, file, mut module) = compile_path(&path)?;
+    if optimize {
+        moon_bytecode::optimize(&mut module);
+    }
+
+    let bytes = moonc::write(&module, &path, sources[file].text());
+    std::fs::write(&out, bytes).map_err(|e| {
+        eprintln!("io error: {}: {e}", out.display());
         1
-    })?;
+    })
+}
 
-    if value != Value::Unit {
-        println!("{value}");
+/// The module to run or list for `path`: read from a `.moonc` file or compiled from source,
+/// then optimized if asked. Also returns the sources to point errors into, and the path of the
+/// source the module comes from.
+fn load_module(path: String, optimize: bool) -> Result<(Module, SourceMap, String), i32> {
+    let (mut module, sources, source_path) = if is_compiled(&path) {
+        let (module, sources, source_path) = load_compiled(&path)?;
+        // The optimizer expects a well-formed module.
+        moon_bytecode::verify(&module).map_err(|e| {
+            eprintln!("{path}: invalid .moonc file: {e}");
+            1
+        })?;
+        (module, sources, source_path)
+    } else {
+        let (sources, _, module) = compile_path(&path)?;
+        (module, sources, path)
+    };
+    if optimize {
+        moon_bytecode::optimize(&mut module);
     }
-
-    Ok(())
+    Ok((module, sources, source_path))
 }
 
-fn cmd_disasm(path: String) -> Result<(), i32> {
-    let source = load_source(&path).map_err(|e| {
+/// Lexes, parses, checks and compiles the source at `path`, reporting the first error.
+fn compile_path(path: &str) -> Result<(SourceMap, FileId, Module), i32> {
+    let (sources, file) = load_source(path).map_err(|e| {
         eprintln!("io error: {e}");
         1
     })?;
+    let source = &sources[file];
 
-    let tokens = lex(&source.text).map_err(|e| {
+    let tokens = lex_file(source.text(), file).map_err(|e| {
         eprintln!(
             "{}",
-            source.render_span(e.span, &format!("lex error: {}", e.message))
+            sources.render_span(e.span, &format!("lex error: {}", e.message))
         );
         1
     })?;
@@ -265,7 +422,7 @@ fn cmd_disasm(path: String) -> Result<(), i32> {
     let program = parse(tokens).map_err(|e| {
         eprintln!(
             "{}",
-            source.render_span(e.span, &format!("parse error: {}", e.message))
+            sources.render_span(e.span, &format!("parse error: {}", e.message))
         );
         1
     })?;
@@ -273,7 +430,7 @@ fn cmd_disasm(path: String) -> Result<(), i32> {
     let _ = check_program(&program).map_err(|e| {
         eprintln!(
             "{}",
-            source.render_span(e.span, &format!("type error: {}", e.message))
+            sources.render_span(e.span, &format!("type error: {}", e.message))
         );
         1
     })?;
@@ -281,42 +438,226 @@ fn cmd_disasm(path: String) -> Result<(), i32> {
     let module = compile(&program).map_err(|e| {
         eprintln!(
             "{}",
-            source.render_span(e.span, &format!("compile error: {}", e.message))
+            sources.render_span(e.span, &format!("compile error: {}", e.message))
         );
         1
     })?;
 
-    println!("main: f{}", module.main);
+    Ok((sources, file, module))
+}
+
+fn is_compiled(path: &str) -> bool {
+    Path::new(path).extension().is_some_and(|e| e == "moonc")
+}
+
+/// Reads a `.moonc` file, and returns it with the path of the source it was built from. That
+/// source is loaded too, so errors can point into it, unless it is gone or has changed since.
+fn load_compiled(path: &str) -> Result<(Module, SourceMap, String), i32> {
+    let bytes = std::fs::read(path).map_err(|e| {
+        eprintln!("io error: {e}");
+        1
+    })?;
+    let compiled = moonc::read(&bytes).map_err(|e| {
+        eprintln!("{path}: invalid .moonc file: {e}");
+        1
+    })?;
+
+    let mut sources = SourceMap::new();
+    if let Ok(source) = Source::from_path(&compiled.source_path) {
+        if moonc::source_hash(source.text()) == compiled.source_hash {
+            sources.add(source);
+        }
+    }
+    Ok((compiled.module, sources, compiled.source_path))
+}
+
+/// Like `SourceMap::render_span`, but without the source at hand (a `.moonc` whose source
+/// is gone or changed) the span is shown as a byte range of `path`.
+fn render_span(sources: &SourceMap, path: &str, span: Span, message: &str) -> String {
+    if sources.get(span.file).is_some() {
+        sources.render_span(span, message)
+    } else {
+        format!("{path} [{}..{}]: {message}", span.start, span.end)
+    }
+}
+
+/// A runtime error's stack trace, innermost call first, one line per call: the function and
+/// where it was called. Runs of the same call (plain recursion) are shown once with a count,
+/// and a long trace keeps only its two ends.
+pub(crate) fn render_trace<'a>(
+    sources: &SourceMap,
+    path: &str,
+    trace: impl Iterator<Item = (&'a str, Span)>,
+) -> String {
+    const SHOWN: usize = 10;
+
+    let mut lines: Vec<(String, usize)> = Vec::new();
+    for (function, span) in trace {
+        let site = match sources.location(span) {
+            Some((file, line, col)) => format!("{}:{line}:{col}", file.display()),
+            None => format!("{path} [{}..{}]", span.start, span.end),
+        };
+        let line = format!("  in {function}, called at {site}");
+        match lines.last_mut() {
+            Some((last, count)) if *last == line => *count += 1,
+            _ => lines.push((line, 1)),
+        }
+    }
+    if lines.is_empty() {
+        return String::new();
+    }
+
+    let mut out = String::from("stack trace (innermost call first):\n");
+    let hidden = lines.len().saturating_sub(2 * SHOWN);
+    for (i, (line, count)) in lines.iter().enumerate() {
+        if hidden > 0 && (SHOWN..SHOWN + hidden).contains(&i) {
+            if i == SHOWN {
+                out.push_str(&format!("  ... {hidden} more\n"));
+            }
+            continue;
+        }
+        out.push_str(line);
+        if *count > 1 {
+            out.push_str(&format!(" ({count} times)"));
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// The constant pool, then one block per function: each instruction at its byte offset, with
+/// its line:col and byte range in the source.
+fn disasm_listing(module: &Module, sources: &SourceMap) -> String {
+    let mut out = format!("main: f{}\n", module.main);
+    if !module.constants.is_empty() {
+        out.push_str("\nconstants:\n");
+        for (idx, value) in module.constants.iter().enumerate() {
+            out.push_str(&format!("  {idx:>4}  {}\n", constant_text(value)));
+        }
+    }
     for (id, func) in module.functions.iter().enumerate() {
         let params = if func.params.is_empty() {
             String::new()
         } else {
             func.params.join(", ")
         };
-        println!("\nfn f{id} {}({})", func.name, params);
-        for (ip, instr) in func.code.iter().enumerate() {
-            let start = instr.span.start.min(source.text.len());
-            let end = instr.span.end.min(source.text.len());
-            let (line, col) = source.line_col(start);
-            println!(
-                "  {:04}  {:<24}  @{}:{}  [{}..{}]",
-                ip, instr.kind, line, col, start, end
-            );
+        out.push_str(&format!("\nfn f{id} {}({})\n", func.name, params));
+        let instrs = match func.instrs() {
+            Ok(instrs) => instrs,
+            Err(e) => {
+                out.push_str(&format!("  <malformed bytecode: {e}>\n"));
+                continue;
+            }
+        };
+        for instr in instrs {
+            let len = sources.get(instr.span.file).map_or(0, |s| s.text().len());
+            let start = instr.span.start.min(len);
+            let end = instr.span.end.min(len);
+            let (line, col) = sources
+                .location(instr.span)
+                .map(|(_, line, col)| (line, col))
+                .unwrap_or((0, 0));
+            out.push_str(&format!(
+                "  {:04}  {:<32}  @{}:{}  [{}..{}]\n",
+                instr.offset,
+                module.describe(&instr.kind),
+                line,
+                col,
+                start,
+                end
+            ));
+        }
+    }
+    out
+}
+
+/// Formats files in place (or prints stdin formatted). Directories are searched for `.moon`
+/// files. With `--check` nothing is written: files that would change are listed and the exit
+/// code is 1.
+fn cmd_fmt(paths: Vec<String>, check: bool) -> Result<(), i32> {
+    let mut files = Vec::new();
+    for path in paths {
+        collect_moon_files(PathBuf::from(path), &mut files).map_err(|e| {
+            eprintln!("io error: {e}");
+            1
+        })?;
+    }
+
+    let mut failed = false;
+    let mut unformatted = false;
+    for path in files {
+        let display = path.display().to_string();
+        let (sources, file) = load_source(&display).map_err(|e| {
+            eprintln!("io error: {e}");
+            1
+        })?;
+        let source = &sources[file];
+
+        let formatted = match format_source(source.text(), file) {
+            Ok(formatted) => formatted,
+            Err(e) => {
+                eprintln!("{}", sources.render_span(e.span(), &e.to_string()));
+                failed = true;
+                continue;
+            }
+        };
+
+        if display == "-" {
+            if check {
+                unformatted |= formatted != source.text();
+            } else {
+                print!("{formatted}");
+            }
+        } else if formatted != source.text() {
+            if check {
+                println!("{display}");
+                unformatted = true;
+            } else {
+                std::fs::write(&path, formatted).map_err(|e| {
+                    eprintln!("io error: {e}");
+                    1
+                })?;
+            }
         }
     }
 
+    if failed || unformatted {
+        Err(1)
+    } else {
+        Ok(())
+    }
+}
+
+fn collect_moon_files(path: PathBuf, out: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    if path.is_dir() {
+        let mut entries: Vec<PathBuf> = std::fs::read_dir(&path)?
+            .map(|e| e.map(|e| e.path()))
+            .collect::<std::io::Result<_>>()?;
+        entries.sort();
+        for entry in entries {
+            if entry.is_dir() || entry.extension().is_some_and(|e| e == "moon") {
+                collect_moon_files(entry, out)?;
+            }
+        }
+    } else {
+        out.push(path);
+    }
     Ok(())
 }
 
-fn load_source(path: &str) -> std::io::Result<Source> {
-    if path == "-" {
+fn load_source(path: &str) -> std::io::Result<(SourceMap, FileId)> {
+    let source = if path == "-" {
         use std::io::Read;
         let mut text = String::new();
         std::io::stdin().read_to_string(&mut text)?;
-        Ok(Source::new(PathBuf::from("<stdin>"), text))
+        Source::new(PathBuf::from("<stdin>"), text)
     } else {
-        Source::from_path(path)
-    }
+        Source::from_path(path)?
+    };
+
+    let mut sources = SourceMap::new();
+    let file = sources.add(source);
+    Ok((sources, file))
 }
 
 fn print_help() {
@@ -324,14 +665,25 @@ fn print_help() {
         "moon (prototype)
 
 USAGE:
-  moon run <file>
+  moon run [--max-depth <calls>] [--no-trace] <file>
   moon ast <file>
   moon check <file>
-  moon vm <file>
-  moon disasm <file>
+  moon vm [-O] [--max-depth <calls>] [--no-trace] <file|file.moonc>
+  moon build [-O] <file> [-o <out.moonc>]
+  moon disasm [-O] <file|file.moonc>
+  moon fmt [--check] <file|dir>...
+  moon repl [--max-depth <calls>] [--no-trace]
 
 NOTES:
   - Use '-' as <file> to read from stdin.
+  - `moon build` compiles to bytecode (default output: <file> with a .moonc extension);
+    `moon vm` and `moon disasm` run or list a .moonc file without recompiling.
+  - `-O` optimizes the bytecode (constant folding, dead code, jump threading).
+  - `--max-depth` sets how many calls may be nested before a `stack overflow` error
+    (default 1000; tail calls do not count).
+  - Runtime errors inside calls print the calls that led there; `--no-trace` hides them.
+  - `moon fmt` rewrites files in place; `--check` only lists the ones that would change.
+  - `moon repl` keeps definitions between inputs; type `:help` inside it for commands.
   - Semicolons discard values; the last expression without ';' is the program result.
   - Current features: let, assignment, blocks, if/else, fn/calls, arrays/objects, and expressions."
     );
@@ -0,0 +1,447 @@
+//! `moon repl`: read, check and evaluate input one piece at a time.
+//!
+//! The typechecker environment and the interpreter environment (globals, functions, heap) live
+//! across inputs, so `let`s and `fn`s entered earlier stay usable. Each input is added to the
+//! source map as its own file, so errors point into the input that caused them.
+
+use std::io::{self, BufRead, IsTerminal, Write};
+
+use moon_bytecode::compile;
+use moon_core::ast::{Program, Stmt};
+use moon_core::lexer::{lex, lex_file, TokenKind};
+use moon_core::parser::parse;
+use moon_core::source::{Source, SourceMap};
+use moon_core::span::Span;
+use moon_interpreter::{eval_program_in, Env, RuntimeError, Value};
+use moon_typechecker::{check_program_in, TypeEnv};
+
+use crate::{disasm_listing, render_trace};
+
+const HELP: &str = "\
+:type <expr>    show the type of an expression without evaluating it
+:ast <expr>     show the syntax tree of the input
+:disasm <expr>  show the bytecode the input compiles to
+:reset          forget every definition
+:help           show this help
+:quit           leave (Ctrl-D works too)";
+
+pub struct Repl {
+    types: TypeEnv,
+    env: Env,
+    sources: SourceMap,
+    // Whether runtime errors show the calls that led to them.
+    traces: bool,
+}
+
+/// What an input asks the loop to do after it has been handled.
+#[derive(Debug, PartialEq)]
+pub enum Reply {
+    // Text for stdout (empty when there is nothing to show).
+    Output(String),
+    // Text for stderr: the input was rejected and left the state as it was, or failed while
+    // running and kept what its statements before the failure did.
+    Error(String),
+    Quit,
+}
+
+impl Repl {
+    pub fn new() -> Self {
+        Self {
+            types: TypeEnv::new(),
+            env: Env::new(),
+            sources: SourceMap::new(),
+            traces: true,
+        }
+    }
+
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.env.set_max_depth(max_depth);
+    }
+
+    pub fn set_traces(&mut self, traces: bool) {
+        self.traces = traces;
+    }
+
+    /// Handles one complete input: a meta-command or Moon code.
+    pub fn eval(&mut self, input: &str) -> Reply {
+        let trimmed = input.trim();
+        let Some(command) = trimmed.strip_prefix(':') else {
+            return self.eval_code(input);
+        };
+        let (name, arg) = command
+            .split_once(char::is_whitespace)
+            .map_or((command, ""), |(name, arg)| (name, arg.trim()));
+
+        match (name, arg.is_empty()) {
+            ("quit" | "q", _) => Reply::Quit,
+            ("help" | "h", _) => Reply::Output(HELP.to_string()),
+            ("reset", _) => {
+                let (max_depth, traces) = (self.env.max_depth(), self.traces);
+                *self = Repl::new();
+                self.set_max_depth(max_depth);
+                self.set_traces(traces);
+                Reply::Output("state cleared".to_string())
+            }
+            ("type" | "t" | "ast" | "disasm", true) => {
+                Reply::Error(format!("usage: :{name} <expr>"))
+            }
+            ("type" | "t", false) => self.type_of(arg),
+            ("ast", false) => self.ast_of(arg),
+            ("disasm", false) => self.disasm_of(arg),
+            _ => Reply::Error(format!("unknown command :{name} (try :help)")),
+        }
+    }
+
+    fn eval_code(&mut self, input: &str) -> Reply {
+        let program = match self.parse(input) {
+            Ok(program) => program,
+            Err(e) => return Reply::Error(e),
+        };
+
+        // A rejected input leaves nothing behind.
+        let saved = self.types.clone();
+        // A `fn` entered again replaces the earlier one instead of being a duplicate.
+        for stmt in &program.stmts {
+            if let Stmt::Fn { name, .. } = stmt {
+                self.types.remove_fn(name);
+            }
+        }
+        let ty = match check_program_in(&program, &mut self.types) {
+            Ok(ty) => ty,
+            Err(e) => {
+                self.types = saved;
+                return Reply::Error(self.render(e.span, "type error", &e.message));
+            }
+        };
+        let value = match self.run(&program) {
+            Ok(value) => value,
+            Err((count, e)) => {
+                // A failing one keeps what its statements before the error did to the `Env`,
+                // so the types must describe those and nothing else.
+                let stmts = program.stmts.iter().enumerate();
+                let ran = stmts.filter(|&(i, stmt)| i < count || is_fn(stmt));
+                let ran = Program::new(ran.map(|(_, stmt)| stmt.clone()).collect(), None);
+                let mut types = saved.clone();
+                self.types = match check_program_in(&ran, &mut types) {
+                    Ok(_) => types,
+                    Err(_) => saved,
+                };
+                let mut message = self.render(e.span, "runtime error", &e.message);
+                let trace = e.trace.iter().map(|f| (f.function.as_str(), f.call_span));
+                let trace = render_trace(&self.sources, "<repl>", trace);
+                if self.traces && !trace.is_empty() {
+                    message.push('\n');
+                    message.push_str(trace.trim_end());
+                }
+                return Reply::Error(message);
+            }
+        };
+
+        if program.tail.is_none() {
+            return Reply::Output(String::new());
+        }
+        Reply::Output(format!("{} : {ty}", self.env.heap.render(&value)))
+    }
+
+    /// Evaluates `program` one statement at a time. Every `fn` is defined before anything
+    /// runs, as `eval_program_in` does. An error comes with how many statements ran before it.
+    fn run(&mut self, program: &Program) -> Result<Value, (usize, RuntimeError)> {
+        let fns = program.stmts.iter().filter(|stmt| is_fn(stmt)).cloned();
+        eval_program_in(&Program::new(fns.collect(), None), &mut self.env).map_err(|e| (0, e))?;
+        for (i, stmt) in program.stmts.iter().enumerate() {
+            if !is_fn(stmt) {
+                let single = Program::new(vec![stmt.clone()], None);
+                eval_program_in(&single, &mut self.env).map_err(|e| (i, e))?;
+            }
+        }
+        let tail = Program::new(Vec::new(), program.tail.clone());
+        eval_program_in(&tail, &mut self.env).map_err(|e| (program.stmts.len(), e))
+    }
+
+    fn type_of(&mut self, input: &str) -> Reply {
+        match self.check_scratch(input) {
+            Ok((_, ty)) => Reply::Output(ty),
+            Err(e) => Reply::Error(e),
+        }
+    }
+
+    fn ast_of(&mut self, input: &str) -> Reply {
+        match self.parse(input) {
+            Ok(program) => match (program.stmts.is_empty(), &program.tail) {
+                (true, Some(expr)) => Reply::Output(format!("{expr:#?}")),
+                _ => Reply::Output(format!("{program:#?}")),
+            },
+            Err(e) => Reply::Error(e),
+        }
+    }
+
+    fn disasm_of(&mut self, input: &str) -> Reply {
+        let program = match self.check_scratch(input) {
+            Ok((program, _)) => program,
+            Err(e) => return Reply::Error(e),
+        };
+        match compile(&program) {
+            Ok(module) => {
+                let listing = disasm_listing(&module, &self.sources);
+                Reply::Output(listing.trim_end().to_string())
+            }
+            Err(e) => Reply::Error(self.render(e.span, "compile error", &e.message)),
+        }
+    }
+
+    /// Parses and checks `input` against the current definitions without keeping its own.
+    fn check_scratch(&mut self, input: &str) -> Result<(Program, String), String> {
+        let program = self.parse(input)?;
+        let mut types = self.types.clone();
+        match check_program_in(&program, &mut types) {
+            Ok(ty) => Ok((program, ty.to_string())),
+            Err(e) => Err(self.render(e.span, "type error", &e.message)),
+        }
+    }
+
+    fn parse(&mut self, input: &str) -> Result<Program, String> {
+        // `let x = 1` on a line of its own is complete for a REPL user: supply the `;` when
+        // that is all that is missing.
+        let patched = format!("{};", input.trim_end());
+        let text = if !parses(input) && parses(&patched) {
+            patched
+        } else {
+            input.to_string()
+        };
+
+        let file = self.sources.add(Source::new("<repl>", text));
+        let text = self.sources[file].text();
+        let tokens = match lex_file(text, file) {
+            Ok(tokens) => tokens,
+            Err(e) => return Err(self.render(e.span, "lex error", &e.message)),
+        };
+        parse(tokens).map_err(|e| self.render(e.span, "parse error", &e.message))
+    }
+
+    fn render(&self, span: Span, kind: &str, message: &str) -> String {
+        self.sources
+            .render_span(span, &format!("{kind}: {message}"))
+    }
+}
+
+fn is_fn(stmt: &Stmt) -> bool {
+    matches!(stmt, Stmt::Fn { .. })
+}
+
+fn parses(text: &str) -> bool {
+    lex(text).is_ok_and(|tokens| parse(tokens).is_ok())
+}
+
+/// True while `input` has more opening than closing brackets, i.e. the user is still typing a
+/// block, call, array or object. Input that does not lex is complete: evaluating it reports
+/// the error.
+pub fn is_incomplete(input: &str) -> bool {
+    let Ok(tokens) = lex(input) else {
+        return false;
+    };
+    let mut depth = 0i32;
+    for token in tokens {
+        match token.kind {
+            TokenKind::LParen | TokenKind::LBrace | TokenKind::LBracket => depth += 1,
+            TokenKind::RParen | TokenKind::RBrace | TokenKind::RBracket => depth -= 1,
+            _ => {}
+        }
+    }
+    depth > 0
+}
+
+/// Runs the loop on stdin until `:quit` or end of input. Prompts are only shown when stdin is
+/// a terminal, so piping a script through `moon repl` prints just the results.
+pub fn run(max_depth: usize, traces: bool) -> Result<(), i32> {
+    let stdin = io::stdin();
+    let interactive = stdin.is_terminal();
+    let mut repl = Repl::new();
+    repl.set_max_depth(max_depth);
+    repl.set_traces(traces);
+    let mut buffer = String::new();
+
+    if interactive {
+        println!("moon repl. :help for commands, :quit to leave.");
+    }
+    loop {
+        if interactive {
+            print!(
+                "{}",
+                if buffer.is_empty() {
+                    "moon> "
+                } else {
+                    "  ... "
+                }
+            );
+            let _ = io::stdout().flush();
+        }
+
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("io error: {e}");
+                return Err(1);
+            }
+        }
+
+        // A blank line ends an unbalanced input anyway, so a stray `{` can be got out of.
+        if line.trim().is_empty() && buffer.is_empty() {
+            continue;
+        }
+        let blank = line.trim().is_empty();
+        buffer.push_str(&line);
+        if !blank && is_incomplete(&buffer) {
+            continue;
+        }
+
+        let input = std::mem::take(&mut buffer);
+        match repl.eval(&input) {
+            Reply::Output(text) if text.is_empty() => {}
+            Reply::Output(text) => println!("{text}"),
+            Reply::Error(text) => eprintln!("{text}"),
+            Reply::Quit => break,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(repl: &mut Repl, input: &str) -> String {
+        match repl.eval(input) {
+            Reply::Output(text) => text,
+            other => panic!("{input}: {other:?}"),
+        }
+    }
+
+    fn error(repl: &mut Repl, input: &str) -> String {
+        match repl.eval(input) {
+            Reply::Error(text) => text,
+            other => panic!("{input}: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn definitions_persist_between_inputs() {
+        let mut repl = Repl::new();
+        assert_eq!(output(&mut repl, "let xs = [1, 2];"), "");
+        assert_eq!(
+            output(&mut repl, "fn sum(a: Array<Int>) -> Int { a[0] + a[1] }"),
+            ""
+        );
+        assert_eq!(output(&mut repl, "xs[1] = 40;"), "");
+        assert_eq!(output(&mut repl, "sum(xs) + 1"), "42 : Int");
+        assert_eq!(output(&mut repl, "xs"), "[1, 40] : Array<Int>");
+        assert_eq!(
+            output(&mut repl, "#{b: \"x\", a: \"y\"}"),
+            "#{a: \"y\", b: \"x\"} : Object<String>"
+        );
+        assert_eq!(output(&mut repl, "sum"), "<fn sum> : (Array<Int>) -> Int");
+    }
+
+    #[test]
+    fn a_missing_semicolon_after_a_let_is_supplied() {
+        let mut repl = Repl::new();
+        assert_eq!(output(&mut repl, "let x = 2\n"), "");
+        assert_eq!(output(&mut repl, "x * 3\n"), "6 : Int");
+    }
+
+    #[test]
+    fn rejected_inputs_leave_no_definitions_behind() {
+        let mut repl = Repl::new();
+        assert!(error(&mut repl, "let a = 1; let b = a + true;").contains("type error"));
+        assert!(error(&mut repl, "a").contains("undefined variable"));
+
+        // The session goes on normally.
+        assert_eq!(output(&mut repl, "let a = \"ok\"; a"), "\"ok\" : String");
+    }
+
+    #[test]
+    fn failing_inputs_keep_what_ran_before_the_error() {
+        let mut repl = Repl::new();
+        output(&mut repl, "let a = 1;");
+        let message = error(
+            &mut repl,
+            "let a = \"s\"; let c = [1][5]; fn f() -> Int { 2 }",
+        );
+        assert!(message.contains("runtime error"), "{message}");
+        assert_eq!(output(&mut repl, ":type a"), "String");
+        assert!(error(&mut repl, "a + 1").contains("type error"));
+        assert_eq!(output(&mut repl, "a"), "\"s\" : String");
+        assert!(error(&mut repl, "c").contains("undefined variable"));
+        assert_eq!(output(&mut repl, "f()"), "2 : Int");
+
+        // An error in the tail keeps every statement.
+        assert!(error(&mut repl, "let d = 3; [d][5]").contains("runtime error"));
+        assert_eq!(output(&mut repl, "d"), "3 : Int");
+    }
+
+    #[test]
+    fn functions_can_be_redefined() {
+        let mut repl = Repl::new();
+        output(&mut repl, "fn f() -> Int { 1 }");
+        output(&mut repl, "fn f() -> Int { 2 }");
+        assert_eq!(output(&mut repl, "f()"), "2 : Int");
+        output(&mut repl, "fn f(s: String) -> String { s }");
+        assert_eq!(output(&mut repl, "f(\"x\")"), "\"x\" : String");
+
+        // A rejected redefinition keeps the old one.
+        assert!(error(&mut repl, "fn f() -> Int { true }").contains("type error"));
+        assert_eq!(output(&mut repl, ":type f"), "(String) -> String");
+        // Twice in one input is still a duplicate.
+        assert!(error(&mut repl, "fn g() -> Int { 1 } fn g() -> Int { 2 }").contains("duplicate"));
+    }
+
+    #[test]
+    fn runtime_errors_show_the_calls_that_led_to_them() {
+        let mut repl = Repl::new();
+        output(&mut repl, "fn get(a: Array<Int>) -> Int { a[5] }");
+        let message = error(&mut repl, "get([1]) + 1");
+        assert!(message.contains("index out of bounds"), "{message}");
+        assert!(
+            message
+                .ends_with("stack trace (innermost call first):\n  in get, called at <repl>:1:1"),
+            "{message}"
+        );
+
+        repl.set_traces(false);
+        assert!(!error(&mut repl, "get([1]) + 1").contains("stack trace"));
+        // No calls, no trace.
+        repl.set_traces(true);
+        assert!(!error(&mut repl, "[1][5]").contains("stack trace"));
+    }
+
+    #[test]
+    fn meta_commands() {
+        let mut repl = Repl::new();
+        output(&mut repl, "let n = 1;");
+        assert_eq!(output(&mut repl, ":type [n, 2]"), "Array<Int>");
+        // `:type` does not evaluate, nor keep definitions.
+        assert_eq!(output(&mut repl, ":type let m = 1 / 0; m"), "Int");
+        assert!(error(&mut repl, "m").contains("undefined variable"));
+
+        assert!(output(&mut repl, ":ast n + 1").starts_with("Binary {"));
+        let listing = output(&mut repl, ":disasm n + 1");
+        assert!(listing.starts_with("main: f0"));
+        assert!(listing.contains("LoadGlobal"));
+
+        assert_eq!(output(&mut repl, ":reset"), "state cleared");
+        assert!(error(&mut repl, "n").contains("undefined variable"));
+        assert!(error(&mut repl, ":type").starts_with("usage"));
+        assert!(error(&mut repl, ":nope").starts_with("unknown command"));
+        assert_eq!(repl.eval(":quit"), Reply::Quit);
+    }
+
+    #[test]
+    fn input_continues_while_brackets_are_open() {
+        assert!(is_incomplete("fn f() -> Int {\n"));
+        assert!(is_incomplete("let o = #{ a: [1,\n"));
+        assert!(!is_incomplete("fn f() -> Int {\n  1\n}\n"));
+        assert!(!is_incomplete("let s = \"{\";"));
+        assert!(!is_incomplete(")"));
+    }
+}